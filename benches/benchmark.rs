@@ -19,12 +19,16 @@ extern crate criterion;
 extern crate melissa;
 extern crate sodiumoxide;
 
-use criterion::Criterion;
+use criterion::{black_box, Criterion};
 use melissa::crypto::aesgcm::*;
 use melissa::crypto::hkdf::*;
 use melissa::crypto::hpke::*;
+use melissa::crypto::rng::SystemRng;
 use melissa::group::*;
 use melissa::keys::*;
+use melissa::messages::UpdatePath;
+use melissa::tree::*;
+use melissa::treemath;
 use melissa::utils::*;
 use sodiumoxide::randombytes;
 
@@ -74,6 +78,20 @@ fn criterion_aes(c: &mut Criterion) {
     });
 }
 
+fn criterion_aead(c: &mut Criterion) {
+    c.bench_function("AEAD seal", |b| {
+        let key = randombytes::randombytes(AES128KEYBYTES);
+        let nonce = Nonce::new_random();
+        b.iter(|| seal(DATA, &key, &nonce).unwrap());
+    });
+    c.bench_function("AEAD open", |b| {
+        let key = randombytes::randombytes(AES128KEYBYTES);
+        let nonce = Nonce::new_random();
+        let sealed = seal(DATA, &key, &nonce).unwrap();
+        b.iter(|| open(&sealed, &key, &nonce).unwrap());
+    });
+}
+
 fn criterion_hpke(c: &mut Criterion) {
     c.bench_function("HPKE gen key", |b| b.iter(|| X25519KeyPair::new_random()));
     c.bench_function("HPKE encrypt", |b| {
@@ -142,14 +160,89 @@ fn large_group() {
     for i in 0..GROUPSIZE {
         for j in 0..GROUPSIZE {
             if i != j {
-                let (_welcome_alice_bob, add_alice_bob) =
-                    groups[i].create_add(credentials[j].clone(), &uiks[j].init_key);
-                groups[i].process_add(&add_alice_bob);
+                let (_welcome_alice_bob, add_alice_bob) = groups[i]
+                    .create_add(credentials[j].clone(), &uiks[j].init_key)
+                    .unwrap();
+                groups[i].process_add(&add_alice_bob).unwrap();
             }
         }
     }
 }
 
+// Tree
+
+fn large_tree(n: usize) -> Tree {
+    let leaves: Vec<Node> = (0..n)
+        .map(|_| Node::new_from_public_key(&X25519KeyPair::new_random().public_key))
+        .collect();
+    Tree::from_leaves(leaves).unwrap()
+}
+
+// Every node holds a key pair, so each copath node resolves to itself
+fn full_tree(n: usize) -> Tree {
+    let mut tree = Tree::from_leaves(vec![Node::new_blank(); n]).unwrap();
+    for node in tree.nodes.iter_mut() {
+        *node = Node::from_secret(&NodeSecret::new_random());
+    }
+    tree
+}
+
+fn criterion_treemath(c: &mut Criterion) {
+    c.bench_function("dirpath 1024 leaves", |b| {
+        b.iter(|| treemath::dirpath(black_box(0), black_box(1024)))
+    });
+    c.bench_function("copath 1024 leaves", |b| {
+        b.iter(|| treemath::copath(black_box(0), black_box(1024)))
+    });
+}
+
+fn criterion_tree_hash(c: &mut Criterion) {
+    c.bench_function("Tree hash 1024 leaves", |b| {
+        let tree = large_tree(1024);
+        b.iter(|| tree.tree_hash_serial())
+    });
+    c.bench_function("Tree hash 2000 leaves", |b| {
+        let tree = large_tree(2000);
+        b.iter(|| tree.tree_hash_serial())
+    });
+    #[cfg(feature = "parallel")]
+    c.bench_function("Tree hash 2000 leaves parallel", |b| {
+        let tree = large_tree(2000);
+        b.iter(|| tree.tree_hash_parallel())
+    });
+}
+
+// Path secrets of leaf 0 in a full tree
+fn update_path_setup(n: usize) -> (Tree, X25519PublicKey, Vec<NodeSecret>) {
+    let path_nodes = Tree::hash_up(0, n, &NodeSecret::new_random());
+    let path_secrets = path_nodes
+        .iter()
+        .skip(1)
+        .map(|node| node.secret.unwrap())
+        .collect();
+    (
+        full_tree(n),
+        path_nodes[0].dh_public_key.unwrap(),
+        path_secrets,
+    )
+}
+
+fn criterion_update_path(c: &mut Criterion) {
+    c.bench_function("Update path encrypt 1024 leaves", |b| {
+        let (tree, _, path_secrets) = update_path_setup(1024);
+        b.iter(|| encrypt_path_secrets(&tree, 0, &path_secrets, &[], &mut SystemRng))
+    });
+    c.bench_function("Update path decrypt 1024 leaves", |b| {
+        let (mut tree, leaf_key, path_secrets) = update_path_setup(1024);
+        let update_path = UpdatePath {
+            leaf_key,
+            nodes: encrypt_path_secrets(&tree, 0, &path_secrets, &[], &mut SystemRng).unwrap(),
+        };
+        tree.own_leaf_index = treemath::rightmost_leaf(1024);
+        b.iter(|| decrypt_path_secret(&tree, 0, &[], &update_path).unwrap())
+    });
+}
+
 // Groups
 
 fn create_group() {
@@ -174,16 +267,18 @@ fn create_group() {
     let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
 
     // Alice adds Bob
-    let (welcome_alice_bob, add_alice_bob) = group_alice.create_add(bob_credential, &bob_init_key);
-    group_alice.process_add(&add_alice_bob);
+    let (welcome_alice_bob, add_alice_bob) = group_alice
+        .create_add(bob_credential, &bob_init_key)
+        .unwrap();
+    group_alice.process_add(&add_alice_bob).unwrap();
 
     let mut group_bob = Group::new_from_welcome(bob_identity, &welcome_alice_bob);
     assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
 
     // Bob updates
     let update_bob = group_bob.create_update();
-    group_bob.process_update(1, &update_bob);
-    group_alice.process_update(1, &update_bob);
+    group_bob.process_update(1, &update_bob).unwrap();
+    group_alice.process_update(1, &update_bob).unwrap();
     assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
 }
 
@@ -191,8 +286,12 @@ fn criterion_benchmark(c: &mut Criterion) {
     criterion_hkdf(c);
     criterion_hpke(c);
     criterion_aes(c);
+    criterion_aead(c);
     criterion_ed25519(c);
     criterion_uik_bundle(c);
+    criterion_treemath(c);
+    criterion_tree_hash(c);
+    criterion_update_path(c);
     c.bench_function("Create group: Alice & Bob", |b| b.iter(|| create_group()));
     c.bench_function("Create large group", |b| b.iter(|| large_group()));
 }
@@ -14,29 +14,68 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
-use std::convert::*;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(not(feature = "std"))]
+use core::fmt;
+#[cfg(feature = "std")]
+use std::fmt;
 
 #[derive(Debug)]
 pub enum CodecError {
     EncodingError,
     DecodingError,
+    UnsupportedVersion,
+    InconsistentTreeSize,
+    ExtensionTooLarge,
 }
 
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecError::EncodingError => write!(f, "encoding error"),
+            CodecError::DecodingError => write!(f, "decoding error"),
+            CodecError::UnsupportedVersion => write!(f, "unsupported version"),
+            CodecError::InconsistentTreeSize => write!(f, "inconsistent tree size"),
+            CodecError::ExtensionTooLarge => write!(f, "extension too large"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl ::std::error::Error for CodecError {}
+
+// Default cap on the extension data of one extension list, see Cursor::with_extension_limit()
+pub const MAX_EXTENSION_BYTES: usize = 16 * 1024;
+
+// Reads from the borrowed input, nested cursors borrow the same bytes and keep the limits
 #[derive(Debug, Clone)]
-pub struct Cursor {
-    buffer: Vec<u8>,
+pub struct Cursor<'a> {
+    buffer: &'a [u8],
     offset: usize,
+    extension_limit: usize,
 }
 
-impl<'a> Cursor {
-    pub fn new(bytes: &[u8]) -> Cursor {
+impl<'a> Cursor<'a> {
+    pub fn new(bytes: &'a [u8]) -> Cursor<'a> {
         Cursor {
-            buffer: bytes.to_vec(),
+            buffer: bytes,
             offset: 0,
+            extension_limit: MAX_EXTENSION_BYTES,
         }
     }
 
-    pub fn take(&mut self, length: usize) -> Result<&[u8], CodecError> {
+    // Caps the extension data of every extension list decoded through this cursor
+    pub fn with_extension_limit(mut self, max_bytes: usize) -> Cursor<'a> {
+        self.extension_limit = max_bytes;
+        self
+    }
+
+    pub fn extension_limit(&self) -> usize {
+        self.extension_limit
+    }
+
+    pub fn take(&mut self, length: usize) -> Result<&'a [u8], CodecError> {
         if self.unread_bytes() < length {
             return Err(CodecError::DecodingError);
         }
@@ -46,30 +85,38 @@ impl<'a> Cursor {
         Ok(&self.buffer[current..current + length])
     }
 
-    pub fn sub_cursor(&mut self, length: usize) -> Result<Cursor, CodecError> {
-        self.take(length).and_then(|buffer| Ok(Cursor::new(buffer)))
+    // Advances past length bytes and returns a cursor limited to them, so a nested
+    // struct can't read beyond its declared length
+    pub fn sub(&mut self, length: usize) -> Result<Cursor<'a>, CodecError> {
+        let extension_limit = self.extension_limit;
+        self.take(length)
+            .map(|bytes| Cursor::new(bytes).with_extension_limit(extension_limit))
+    }
+
+    pub fn sub_cursor(&mut self, length: usize) -> Result<Cursor<'a>, CodecError> {
+        self.sub(length)
     }
 
-    pub fn sub_cursor_u8(&mut self) -> Result<Cursor, CodecError> {
-        let buffer = decode_vec_u8(self)?;
-        Ok(Self::new(&buffer))
+    pub fn sub_cursor_u8(&mut self) -> Result<Cursor<'a>, CodecError> {
+        let length = u8::decode(self)?;
+        self.sub(length as usize)
     }
 
-    pub fn sub_cursor_u16(&mut self) -> Result<Cursor, CodecError> {
-        let buffer = decode_vec_u16(self)?;
-        Ok(Self::new(&buffer))
+    pub fn sub_cursor_u16(&mut self) -> Result<Cursor<'a>, CodecError> {
+        let length = u16::decode(self)?;
+        self.sub(length as usize)
     }
 
-    pub fn sub_cursor_u32(&mut self) -> Result<Cursor, CodecError> {
-        let buffer = decode_vec_u32(self)?;
-        Ok(Self::new(&buffer))
+    pub fn sub_cursor_u32(&mut self) -> Result<Cursor<'a>, CodecError> {
+        let length = u32::decode(self)?;
+        self.sub(length as usize)
     }
 
     pub fn unread_bytes(&self) -> usize {
         self.buffer.len() - self.offset
     }
 
-    pub fn read_to_end(&mut self) -> &[u8] {
+    pub fn read_to_end(&mut self) -> &'a [u8] {
         let ret = &self.buffer[self.offset..];
         self.offset = self.buffer.len();
         ret
@@ -86,6 +133,14 @@ impl<'a> Cursor {
     pub fn has_more(&self) -> bool {
         !self.is_empty()
     }
+
+    // Fails if a nested struct left bytes of its declared length unread
+    pub fn expect_end(&self) -> Result<(), CodecError> {
+        if self.has_more() {
+            return Err(CodecError::DecodingError);
+        }
+        Ok(())
+    }
 }
 
 pub trait Codec: Sized {
@@ -105,6 +160,8 @@ pub trait Codec: Sized {
     }
 }
 
+// Integers go on the wire in network byte order, like in TLS and MLS: most significant
+// byte first, whatever their width. Length prefixes of vectors are encoded the same way.
 impl Codec for u8 {
     fn encode(&self, buffer: &mut Vec<u8>) {
         buffer.push(*self as u8);
@@ -121,75 +178,60 @@ impl Codec for u8 {
 
 impl Codec for u16 {
     fn encode(&self, buffer: &mut Vec<u8>) {
-        let mut bytes = [0u8; 2];
-        bytes[0] = (*self >> 8) as u8;
-        bytes[1] = *self as u8;
-        buffer.extend_from_slice(&bytes[..]);
+        buffer.extend_from_slice(&self.to_be_bytes());
     }
 
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let bytes_option = cursor.take(2);
-        match bytes_option {
-            Ok(bytes) => Ok((u16::from(bytes[0]) << 8) | u16::from(bytes[1])),
-            Err(e) => Err(e),
-        }
+        Ok(u16::from_be_bytes(decode_fixed(cursor)?))
     }
 }
 
 impl Codec for u32 {
     fn encode(&self, buffer: &mut Vec<u8>) {
-        let mut bytes = [0u8; 4];
-        bytes[0] = (*self >> 24) as u8;
-        bytes[1] = (*self >> 16) as u8;
-        bytes[2] = (*self >> 8) as u8;
-        bytes[3] = *self as u8;
-        buffer.extend_from_slice(&bytes[..]);
+        buffer.extend_from_slice(&self.to_be_bytes());
     }
 
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let bytes_option = cursor.take(4);
-        match bytes_option {
-            Ok(bytes) => Ok((u32::from(bytes[0]) << 24)
-                | (u32::from(bytes[1]) << 16)
-                | (u32::from(bytes[2]) << 8)
-                | u32::from(bytes[3])),
-            Err(e) => Err(e),
-        }
+        Ok(u32::from_be_bytes(decode_fixed(cursor)?))
     }
 }
 
 impl Codec for u64 {
     fn encode(&self, buffer: &mut Vec<u8>) {
-        ((*self >> 32) as u32, *self as u32).encode(buffer);
+        buffer.extend_from_slice(&self.to_be_bytes());
     }
 
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let (hi, lo) = <(u32, u32)>::decode(cursor)?;
-        Ok((u64::from(hi) << 32) | u64::from(lo))
+        Ok(u64::from_be_bytes(decode_fixed(cursor)?))
+    }
+}
+
+// optional<T>: a presence byte of 0 or 1, followed by the value if present
+pub fn encode_option<T: Codec>(opt: &Option<T>, buffer: &mut Vec<u8>) {
+    match opt {
+        None => buffer.push(0),
+        Some(value) => {
+            buffer.push(1);
+            value.encode(buffer);
+        }
+    }
+}
+
+pub fn decode_option<T: Codec>(cursor: &mut Cursor) -> Result<Option<T>, CodecError> {
+    match u8::decode(cursor)? {
+        0 => Ok(None),
+        1 => Ok(Some(T::decode(cursor)?)),
+        _ => Err(CodecError::DecodingError),
     }
 }
 
 impl<T: Codec> Codec for Option<T> {
     fn encode(&self, buffer: &mut Vec<u8>) {
-        match self {
-            None => buffer.push(0),
-            Some(value) => {
-                buffer.push(1);
-                value.encode(buffer);
-            }
-        }
+        encode_option(self, buffer);
     }
 
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let tag = u8::decode(cursor)?;
-        match tag {
-            0 => Ok(None),
-            1 => match T::decode(cursor) {
-                Ok(value) => Ok(Some(value)),
-                Err(e) => Err(e),
-            },
-            _ => Err(CodecError::DecodingError),
-        }
+        decode_option(cursor)
     }
 }
 
@@ -204,6 +246,17 @@ impl<T1: Codec, T2: Codec> Codec for (T1, T2) {
     }
 }
 
+// Fixed length fields like keys, nonces and tags go on the wire without a length prefix
+pub fn encode_fixed(bytes: &[u8], buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(bytes);
+}
+
+pub fn decode_fixed<const N: usize>(cursor: &mut Cursor) -> Result<[u8; N], CodecError> {
+    let mut bytes = [0u8; N];
+    bytes.copy_from_slice(cursor.take(N)?);
+    Ok(bytes)
+}
+
 pub fn encode_vec_u8<T: Codec>(bytes: &mut Vec<u8>, slice: &[T]) {
     let mut sub_cursor: Vec<u8> = Vec::new();
     slice.iter().for_each(|e| e.encode(&mut sub_cursor));
@@ -240,6 +293,68 @@ pub fn encode_vec_u64<T: Codec>(bytes: &mut Vec<u8>, slice: &[T]) {
     bytes.append(&mut sub_cursor);
 }
 
+// MLS variable-length integer: the top two bits of the first byte give the length of
+// 1, 2 or 4 bytes, the rest is the value. Values take the shortest form.
+pub const VARINT_MAX: usize = (1 << 30) - 1;
+
+pub fn encode_varint(value: usize, buffer: &mut Vec<u8>) {
+    assert!(value <= VARINT_MAX);
+    if value < 1 << 6 {
+        (value as u8).encode(buffer);
+    } else if value < 1 << 14 {
+        (0x4000 | value as u16).encode(buffer);
+    } else {
+        (0x8000_0000 | value as u32).encode(buffer);
+    }
+}
+
+pub fn decode_varint(cursor: &mut Cursor) -> Result<usize, CodecError> {
+    let first = u8::decode(cursor)?;
+    let (value, min) = match first >> 6 {
+        0 => return Ok(usize::from(first)),
+        1 => (
+            usize::from(first & 0x3f) << 8 | usize::from(u8::decode(cursor)?),
+            1 << 6,
+        ),
+        2 => {
+            let mut value = usize::from(first & 0x3f);
+            for _ in 0..3 {
+                value = value << 8 | usize::from(u8::decode(cursor)?);
+            }
+            (value, 1 << 14)
+        }
+        _ => return Err(CodecError::DecodingError),
+    };
+    if value < min {
+        return Err(CodecError::DecodingError);
+    }
+    Ok(value)
+}
+
+// Vectors as MLS puts them on the wire: a varint byte length, then elements up to
+// exactly that many bytes. Elements can have different sizes.
+pub fn encode_vec_bytes<T: Codec>(bytes: &mut Vec<u8>, slice: &[T]) {
+    let mut sub_cursor: Vec<u8> = Vec::new();
+    slice.iter().for_each(|e| e.encode(&mut sub_cursor));
+
+    encode_varint(sub_cursor.len(), bytes);
+    bytes.append(&mut sub_cursor);
+}
+
+// An element running over the byte length fails to decode, as the sub-cursor ends
+// there. So does a partial element left at the end.
+pub fn decode_vec_bytes<T: Codec>(r: &mut Cursor) -> Result<Vec<T>, CodecError> {
+    let mut ret: Vec<T> = Vec::new();
+    let len = decode_varint(r)?;
+    let mut sub = r.sub_cursor(len)?;
+
+    while sub.has_more() {
+        ret.push(T::decode(&mut sub)?);
+    }
+
+    Ok(ret)
+}
+
 pub fn decode_vec_u8<T: Codec>(r: &mut Cursor) -> Result<Vec<T>, CodecError> {
     let mut ret: Vec<T> = Vec::new();
     let len = usize::from(u8::decode(r)?);
@@ -288,6 +403,26 @@ pub fn decode_vec_u64<T: Codec>(r: &mut Cursor) -> Result<Vec<T>, CodecError> {
     Ok(ret)
 }
 
+// Encodes, decodes and encodes again. The decoder has to take exactly the bytes the
+// encoder wrote, and both encodings have to be identical, so a field that is skipped or
+// read differently than it is written shows up here.
+#[cfg(test)]
+pub fn assert_encoding_roundtrip<T: Codec>(value: &T) -> T {
+    let encoded = value.encode_detached();
+    let mut cursor = Cursor::new(&encoded);
+    let decoded = T::decode(&mut cursor).unwrap();
+    cursor.expect_end().unwrap();
+    assert_eq!(decoded.encode_detached(), encoded);
+    decoded
+}
+
+// Same, for types that can be compared: the decoded value also has to equal the original
+#[cfg(test)]
+pub fn assert_codec_roundtrip<T: Codec + PartialEq + fmt::Debug>(value: T) {
+    let decoded = assert_encoding_roundtrip(&value);
+    assert_eq!(decoded, value);
+}
+
 #[test]
 fn test_primitives() {
     let uint8: u8 = 1;
@@ -311,6 +446,38 @@ fn test_primitives() {
     assert_eq!(buffer, vec![0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 1u8]);
 }
 
+#[test]
+fn test_big_endian() {
+    assert_eq!(1u16.encode_detached(), vec![0x00, 0x01]);
+    assert_eq!(0x0102u16.encode_detached(), vec![0x01, 0x02]);
+    assert_eq!(
+        0x0102_0304u32.encode_detached(),
+        vec![0x01, 0x02, 0x03, 0x04]
+    );
+    assert_eq!(
+        0x0102_0304_0506_0708u64.encode_detached(),
+        vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]
+    );
+    assert_eq!(u16::decode_detached(&[0x01, 0x02]).unwrap(), 0x0102);
+    assert_eq!(
+        u32::decode_detached(&[0x01, 0x02, 0x03, 0x04]).unwrap(),
+        0x0102_0304
+    );
+    assert_eq!(
+        u64::decode_detached(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]).unwrap(),
+        0x0102_0304_0506_0708
+    );
+    assert!(u32::decode_detached(&[0x01, 0x02, 0x03]).is_err());
+
+    // Length prefixes too
+    let mut buffer = Vec::new();
+    encode_vec_u16(&mut buffer, &[0u8; 0x0102]);
+    assert_eq!(&buffer[..2], &[0x01, 0x02]);
+    let mut buffer = Vec::new();
+    encode_vec_u32(&mut buffer, &[0u8; 0x0102]);
+    assert_eq!(&buffer[..4], &[0x00, 0x00, 0x01, 0x02]);
+}
+
 #[test]
 fn test_encode_vec_u8() {
     let v: Vec<u8> = vec![1, 2, 3];
@@ -351,3 +518,122 @@ fn test_encode_vec_u64() {
         ]
     );
 }
+
+#[test]
+fn test_option() {
+    let mut buffer = Vec::new();
+    encode_option(&Some(0x0102u16), &mut buffer);
+    encode_option::<u16>(&None, &mut buffer);
+    assert_eq!(buffer, vec![1u8, 1u8, 2u8, 0u8]);
+
+    let mut cursor = Cursor::new(&buffer);
+    assert_eq!(decode_option::<u16>(&mut cursor).unwrap(), Some(0x0102));
+    assert_eq!(decode_option::<u16>(&mut cursor).unwrap(), None);
+    assert!(cursor.is_empty());
+
+    // Presence flags other than 0 and 1 are rejected
+    let mut cursor = Cursor::new(&[2u8, 1u8, 2u8]);
+    assert!(decode_option::<u16>(&mut cursor).is_err());
+    // A present flag without its value is truncated
+    let mut cursor = Cursor::new(&[1u8]);
+    assert!(decode_option::<u16>(&mut cursor).is_err());
+}
+
+#[test]
+fn test_sub_cursor() {
+    // A u16 length followed by a nested (u8, u16)
+    let decode_nested = |bytes: &[u8]| -> Result<(u8, u16), CodecError> {
+        let mut cursor = Cursor::new(bytes);
+        let length = u16::decode(&mut cursor)? as usize;
+        let mut sub = cursor.sub(length)?;
+        let nested = <(u8, u16)>::decode(&mut sub)?;
+        sub.expect_end()?;
+        Ok(nested)
+    };
+    assert_eq!(decode_nested(&[0, 3, 1, 0, 2]).unwrap(), (1, 2));
+
+    // Longer than what is left in the parent
+    assert!(decode_nested(&[0, 4, 1, 0, 2]).is_err());
+    // Longer than the nested struct, with trailing bytes of the parent inside
+    assert!(decode_nested(&[0, 4, 1, 0, 2, 9]).is_err());
+    // Shorter than the nested struct, which can't read past it
+    assert!(decode_nested(&[0, 2, 1, 0, 2]).is_err());
+
+    let mut cursor = Cursor::new(&[1, 2, 3]);
+    let sub = cursor.sub(2).unwrap();
+    assert_eq!(sub.unread_bytes(), 2);
+    assert_eq!(cursor.position(), 2);
+
+    // Nested cursors read the input in place
+    let bytes = [2, 7, 8, 9];
+    let mut cursor = Cursor::new(&bytes);
+    let mut sub = cursor.sub_cursor_u8().unwrap();
+    assert_eq!(sub.take(2).unwrap().as_ptr(), bytes[1..].as_ptr());
+    assert_eq!(cursor.read_to_end().as_ptr(), bytes[3..].as_ptr());
+}
+
+#[test]
+fn test_fixed() {
+    let key = [7u8; 32];
+    let mut buffer = Vec::new();
+    encode_fixed(&key, &mut buffer);
+    encode_fixed(&[1, 2], &mut buffer);
+    assert_eq!(buffer.len(), 34);
+
+    let mut cursor = Cursor::new(&buffer);
+    assert_eq!(decode_fixed::<32>(&mut cursor).unwrap(), key);
+    assert_eq!(decode_fixed::<2>(&mut cursor).unwrap(), [1, 2]);
+    assert!(cursor.is_empty());
+
+    // Short buffer
+    let mut cursor = Cursor::new(&key[..31]);
+    assert!(decode_fixed::<32>(&mut cursor).is_err());
+}
+
+#[test]
+fn test_varint() {
+    for (value, encoded) in [
+        (0usize, vec![0x00]),
+        (37, vec![0x25]),
+        (63, vec![0x3f]),
+        (64, vec![0x40, 0x40]),
+        (15293, vec![0x7b, 0xbd]),
+        (16383, vec![0x7f, 0xff]),
+        (16384, vec![0x80, 0x00, 0x40, 0x00]),
+        (494878333, vec![0x9d, 0x7f, 0x3e, 0x7d]),
+        (VARINT_MAX, vec![0xbf, 0xff, 0xff, 0xff]),
+    ]
+    .iter()
+    {
+        let mut buffer = Vec::new();
+        encode_varint(*value, &mut buffer);
+        assert_eq!(&buffer, encoded);
+        let mut cursor = Cursor::new(&buffer);
+        assert_eq!(decode_varint(&mut cursor).unwrap(), *value);
+        assert!(cursor.is_empty());
+    }
+    // Longer than needed, 8 byte form, short
+    assert!(decode_varint(&mut Cursor::new(&[0x40, 0x25])).is_err());
+    assert!(decode_varint(&mut Cursor::new(&[0xc0, 0, 0, 0, 0, 0, 0, 1])).is_err());
+    assert!(decode_varint(&mut Cursor::new(&[0x80, 0x00, 0x40])).is_err());
+}
+
+#[test]
+fn test_vec_bytes() {
+    // Elements of 1 and 3 bytes
+    let v: Vec<(u8, Option<u16>)> = vec![(1, None), (2, Some(3)), (4, None)];
+    let mut buffer = Vec::new();
+    encode_vec_bytes(&mut buffer, &v);
+    assert_eq!(buffer, vec![8, 1, 0, 2, 1, 0, 3, 4, 0]);
+    let decoded: Vec<(u8, Option<u16>)> = decode_vec_bytes(&mut Cursor::new(&buffer)).unwrap();
+    assert_eq!(decoded, v);
+
+    // A byte length ending inside the third or the second element
+    buffer[0] = 7;
+    assert!(decode_vec_bytes::<(u8, Option<u16>)>(&mut Cursor::new(&buffer)).is_err());
+    buffer[0] = 5;
+    assert!(decode_vec_bytes::<(u8, Option<u16>)>(&mut Cursor::new(&buffer)).is_err());
+    // Longer than the buffer
+    buffer[0] = 9;
+    assert!(decode_vec_bytes::<(u8, Option<u16>)>(&mut Cursor::new(&buffer)).is_err());
+}
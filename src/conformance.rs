@@ -0,0 +1,377 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+// Wire format conformance checks. The tree math JSON vectors are checked in
+// test_vectors.rs, the resolution vectors next to the code in tree.rs.
+
+use codec::*;
+use crypto::hpke::*;
+use group::*;
+use keys::*;
+use messages::*;
+
+// Decodes a whole buffer and re-encodes it, which must give back the same bytes
+fn assert_canonical<T: Codec>(bytes: &[u8]) -> T {
+    let mut cursor = Cursor::new(bytes);
+    let value = T::decode(&mut cursor).unwrap();
+    cursor.expect_end().unwrap();
+    assert_eq!(value.encode_detached(), bytes.to_vec());
+    value
+}
+
+fn public_key_bytes(fill: u8) -> Vec<u8> {
+    let mut bytes = vec![0x00, X25519PUBLICKEYBYTES as u8];
+    bytes.extend_from_slice(&[fill; X25519PUBLICKEYBYTES]);
+    bytes
+}
+
+#[test]
+fn commit_wire_format() {
+    // group_id<0..255>
+    let mut expected = vec![0xff];
+    expected.extend_from_slice(&[0x44; GROUPIDBYTES]);
+    // epoch
+    expected.extend_from_slice(&[0x00, 0x00, 0x00, 0x02]);
+    // sender: member at leaf 1
+    expected.extend_from_slice(&[0x01, 0x00, 0x00, 0x00, 0x01]);
+    // proposals<0..2^32-1>: one Remove of leaf 1
+    expected.extend_from_slice(&[0x00, 0x00, 0x00, 0x06, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01]);
+    // optional<UpdatePath> path
+    expected.push(0x01);
+    // path.leaf_key
+    expected.extend_from_slice(&public_key_bytes(0x11));
+    // path.nodes<0..2^16-1>: one node with one ciphertext
+    expected.extend_from_slice(&[0x00, 0x4b]);
+    expected.extend_from_slice(&public_key_bytes(0x22));
+    expected.extend_from_slice(&[0x00, 0x27]);
+    expected.extend_from_slice(&public_key_bytes(0x33));
+    expected.extend_from_slice(&[0x04, 0xaa, 0xbb, 0xcc, 0xdd]);
+    // signature<0..2^16-1>
+    expected.extend_from_slice(&[0x00, 0x40]);
+    expected.extend_from_slice(&[0x55; 64]);
+    // confirmation_tag<0..255>
+    expected.extend_from_slice(&[0x03, 0x66, 0x66, 0x66]);
+
+    let commit = Commit {
+        group_id: GroupId([0x44; GROUPIDBYTES]),
+        epoch: 2,
+        sender: Sender::Member(1),
+        proposals: vec![Proposal::Remove(1)],
+        path: Some(UpdatePath {
+            leaf_key: X25519PublicKey::from_slice(&[0x11; X25519PUBLICKEYBYTES]).unwrap(),
+            nodes: vec![UpdatePathNode {
+                public_key: X25519PublicKey::from_slice(&[0x22; X25519PUBLICKEYBYTES]).unwrap(),
+                encrypted_path_secret: vec![HpkeCiphertext {
+                    ephemeral_public_key: X25519PublicKey::from_slice(
+                        &[0x33; X25519PUBLICKEYBYTES],
+                    )
+                    .unwrap(),
+                    content: vec![0xaa, 0xbb, 0xcc, 0xdd],
+                }],
+            }],
+        }),
+        signature: Signature::from_slice(&[0x55; 64]).unwrap(),
+        confirmation_tag: vec![0x66; 3],
+    };
+    assert_eq!(commit.encode_detached(), expected);
+    assert_canonical::<Commit>(&expected);
+
+    // Keys are fixed-size, a longer one must not be truncated silently
+    let mut long_key = expected.clone();
+    // Low byte of the path.leaf_key length, after group_id, epoch, sender, proposals
+    // and the option flag
+    let length = 1 + GROUPIDBYTES + 4 + 5 + 10 + 1 + 1;
+    long_key[length] = 0x21;
+    long_key.insert(length + 1, 0x11);
+    assert!(Commit::decode_detached(&long_key).is_err());
+}
+
+#[test]
+fn commit_encoding_is_canonical() {
+    let alice_identity = Identity::random();
+    let bob_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    let commit = assert_canonical::<Commit>(&commit.encode_detached());
+    assert_canonical::<CommitWelcome>(&welcome.unwrap().encode_detached());
+    group_alice.process_commit(&commit).unwrap();
+
+    let (commit, _) = group_alice.commit(&[Proposal::Remove(1)]).unwrap();
+    assert_canonical::<Commit>(&commit.encode_detached());
+}
+
+// Every Codec type survives encode, decode, encode. Values come from a real group where
+// that is easier than building them by hand.
+#[test]
+fn codec_roundtrip_all_types() {
+    use crypto::schedule::*;
+    use extensions::*;
+    use mp::*;
+    use tree::*;
+
+    // Primitives and keys
+    assert_codec_roundtrip(0xabu8);
+    assert_codec_roundtrip(0xabcdu16);
+    assert_codec_roundtrip(0xabcd_ef01u32);
+    assert_codec_roundtrip(0xabcd_ef01_2345_6789u64);
+    assert_codec_roundtrip(Some(7u32));
+    assert_codec_roundtrip(None::<u32>);
+    assert_codec_roundtrip((1u8, 2u16));
+    let key_pair = X25519KeyPair::new_random();
+    assert_codec_roundtrip(key_pair.public_key);
+    assert_codec_roundtrip(key_pair.private_key.clone());
+    let identity = Identity::random();
+    assert_codec_roundtrip(identity.public_key);
+    let private_key = SignaturePrivateKey::from_slice(&[7u8; 64]).unwrap();
+    assert!(assert_encoding_roundtrip(&private_key) == private_key);
+    let signature = identity.sign(b"payload");
+    assert_codec_roundtrip(signature);
+    assert_encoding_roundtrip(&identity);
+    let credential = BasicCredential {
+        identity: b"Alice".to_vec(),
+        public_key: identity.public_key,
+    };
+    assert_encoding_roundtrip(&credential);
+    assert_codec_roundtrip(CURRENT_VERSION);
+    let bundle = UserInitKeyBundle::new(&identity);
+    assert_encoding_roundtrip(&bundle.init_key);
+    assert_encoding_roundtrip(&bundle);
+
+    // Extensions
+    assert_codec_roundtrip(ExternalPubExtension {
+        external_pub: key_pair.public_key,
+    });
+    assert_codec_roundtrip(LifetimeExtension {
+        not_before: 1,
+        not_after: 2,
+    });
+    assert_encoding_roundtrip(&ExternalSendersExtension {
+        senders: vec![credential.clone()],
+    });
+    assert_codec_roundtrip(CapabilitiesExtension::supported());
+    assert_codec_roundtrip(RequiredCapabilitiesExtension {
+        extensions: vec![EXTENSION_TYPE_EXTERNAL_SENDERS],
+        proposals: vec![PROPOSAL_TYPE_REMOVE],
+        credentials: vec![CredentialType::Basic as u8],
+    });
+    assert_codec_roundtrip(
+        LifetimeExtension {
+            not_before: 1,
+            not_after: 2,
+        }
+        .to_extension(),
+    );
+
+    // Tree
+    let secret = NodeSecret::new_random();
+    assert_codec_roundtrip(secret);
+    let mut tree = Tree::from_leaves(vec![
+        Node::from_secret(&secret),
+        Node::new_blank(),
+        Node::new_from_public_key(&key_pair.public_key),
+    ])
+    .unwrap();
+    tree.nodes[3] = Node::new_from_public_key(&X25519KeyPair::new_random().public_key);
+    tree.nodes[3].unmerged_leaves = vec![2];
+    tree.nodes[4].parent_hash = vec![3u8; 32];
+    for node in tree.nodes.iter() {
+        assert_codec_roundtrip(node.clone());
+    }
+    assert_encoding_roundtrip(&tree);
+    assert_codec_roundtrip(AddPosition::Append);
+    assert_codec_roundtrip(UnmergedLeaves {
+        node: 3,
+        leaves: vec![2],
+    });
+    assert_codec_roundtrip(tree.tree_node(0).unwrap());
+    assert_codec_roundtrip(tree.tree_node(3).unwrap());
+    assert_codec_roundtrip(LeafNode {
+        public_key: key_pair.public_key,
+    });
+    let parent_node = tree.tree_node(3).unwrap().as_parent().cloned().unwrap();
+    assert_codec_roundtrip(parent_node.clone());
+    assert_encoding_roundtrip(&LeafNodeHashInput {
+        node_index: 4,
+        leaf_key: Some(key_pair.public_key),
+    });
+    assert_encoding_roundtrip(&ParentNodeHashInput {
+        node_index: 3,
+        parent_node: Some(parent_node),
+        left_hash: vec![1u8; 32],
+        right_hash: vec![2u8; 32],
+    });
+    assert_encoding_roundtrip(&ParentHashInput {
+        public_key: key_pair.public_key,
+        parent_hash: vec![3u8; 32],
+    });
+
+    // Key schedule and message protection
+    assert_codec_roundtrip(InitSecret::from_bytes(&[4u8; 32]));
+    assert_codec_roundtrip(ResumptionPskUsage::Branch);
+    assert_codec_roundtrip(PreSharedKeyID::external(b"psk", b"nonce"));
+    assert_encoding_roundtrip(&EpochSecrets::new(
+        &[1u8; 32], &[2u8; 32], &[3u8; 32], &[4u8; 32], &[5u8; 32], &[6u8; 32], &[7u8; 32],
+    ));
+    assert_encoding_roundtrip(&StageSecrets::new(&[1u8; 32], &[2u8; 32]));
+    assert_encoding_roundtrip(&SenderApplicationSecret::from_bytes_for_sender(
+        &[3u8; 32], 1,
+    ));
+    assert_encoding_roundtrip(&SenderRatchet::new(&[4u8; 32], 1));
+    assert_codec_roundtrip(Generation(7));
+    assert_codec_roundtrip(SenderData {
+        sender: 1,
+        generation: Generation(2),
+        reuse_guard: [3u8; REUSE_GUARD_BYTES],
+    });
+
+    // Messages of a group that adds a member, commits and sends
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: b"Alice".to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let group_id = GroupId::random();
+    assert!(assert_encoding_roundtrip(&group_id) == group_id);
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    let welcome = welcome.unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    assert_encoding_roundtrip(&commit);
+    assert_encoding_roundtrip(&welcome);
+    assert_encoding_roundtrip(&welcome.secrets[0]);
+    assert_encoding_roundtrip(&welcome.secrets[0].encrypted_group_secrets);
+    assert_encoding_roundtrip(&welcome.group_info);
+    assert_encoding_roundtrip(&GroupSecrets {
+        init_secret: InitSecret::from_bytes(&[4u8; 32]),
+        path_secret: Some(secret),
+    });
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let path = commit.path.clone().unwrap();
+    assert_encoding_roundtrip(&path);
+    assert_encoding_roundtrip(&path.nodes[0]);
+    let message = group_alice.encrypt_application_message(b"hello").unwrap();
+    assert_encoding_roundtrip(&message);
+    assert_encoding_roundtrip(&group_alice.context());
+    assert_encoding_roundtrip(&group_alice);
+
+    let group_info = group_alice.group_info().unwrap();
+    let (_, external_commit) =
+        Group::join_by_external_commit(Identity::random(), &group_info).unwrap();
+    assert_encoding_roundtrip(&external_commit);
+    assert_encoding_roundtrip(&external_commit.add);
+
+    let charlie_identity = Identity::random();
+    let proposals = [
+        Proposal::Add(bob_init_key_bundle.init_key.clone()),
+        Proposal::Update(UserInitKeyBundle::new(&charlie_identity).init_key),
+        Proposal::Remove(1),
+        Proposal::PreSharedKey(PreSharedKeyID::external(b"psk", b"nonce")),
+        Proposal::ReInit(ReInit {
+            group_id: GroupId::random(),
+            version: CURRENT_VERSION,
+            cipher_suite: AES128GCM_CURVE25519_SHA256,
+        }),
+        Proposal::ExternalInit(ExternalInit {
+            kem_output: key_pair.public_key,
+        }),
+        Proposal::CredentialUpdate(CredentialUpdate::new(
+            1,
+            credential.clone(),
+            &charlie_identity,
+            &identity,
+        )),
+    ];
+    for proposal in proposals.iter() {
+        assert_encoding_roundtrip(proposal);
+        assert_codec_roundtrip(ProposalRef::from_proposal(proposal));
+    }
+    for sender in [
+        Sender::Member(1),
+        Sender::External(0),
+        Sender::NewMemberProposal,
+        Sender::NewMemberCommit,
+    ]
+    .iter()
+    {
+        assert_codec_roundtrip(*sender);
+    }
+    let signed_proposal = SignedProposal::new(
+        &group_info.group_id,
+        group_info.epoch,
+        Sender::Member(0),
+        Proposal::Remove(1),
+        &identity,
+    );
+    assert_encoding_roundtrip(&signed_proposal);
+    for message in [
+        MlsMessage::Proposal(signed_proposal),
+        MlsMessage::Commit(commit),
+        MlsMessage::Ciphertext(message),
+        MlsMessage::Welcome(welcome),
+        MlsMessage::GroupInfo(group_info),
+    ] {
+        assert_encoding_roundtrip(&message);
+    }
+
+    // The older handshake messages
+    let mut group_alice = Group::new(identity, credential, GroupId::random());
+    let bob_credential = BasicCredential {
+        identity: b"Bob".to_vec(),
+        public_key: bob_identity.public_key,
+    };
+    let (welcome, add) = group_alice
+        .create_add(bob_credential, &bob_init_key_bundle.init_key)
+        .unwrap();
+    assert_encoding_roundtrip(&welcome);
+    assert_encoding_roundtrip(&add);
+    group_alice.process_add(&add).unwrap();
+    let update = group_alice.create_update();
+    assert_encoding_roundtrip(&update);
+    let reinit_commit = group_alice
+        .reinit(AES128GCM_CURVE25519_SHA256, CURRENT_VERSION)
+        .unwrap();
+    assert_encoding_roundtrip(&reinit_commit);
+    assert_encoding_roundtrip(&reinit_commit.reinit);
+    let operations = [
+        GroupOperationValue::Welcome(Box::new(welcome)),
+        GroupOperationValue::Add(add),
+        GroupOperationValue::Update(update.clone()),
+        GroupOperationValue::Remove(group_alice.create_remove(1)),
+        GroupOperationValue::ReInit(reinit_commit),
+    ];
+    for operation in operations.iter() {
+        assert_encoding_roundtrip(operation);
+    }
+    let handshake = group_alice.create_handshake(GroupOperation {
+        msg_type: GroupOperationType::Update,
+        group_operation: GroupOperationValue::Update(update),
+    });
+    assert_encoding_roundtrip(&handshake.operation);
+    assert_encoding_roundtrip(&handshake);
+}
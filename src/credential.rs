@@ -0,0 +1,68 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use group::GroupContext;
+use keys::BasicCredential;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CredentialError {
+    Rejected,
+    Unsupported,
+}
+
+impl fmt::Display for CredentialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CredentialError::Rejected => write!(f, "credential rejected"),
+            CredentialError::Unsupported => write!(f, "credential type not supported"),
+        }
+    }
+}
+
+impl Error for CredentialError {}
+
+// Deployment policy for the credentials of members. The group asks it about every
+// credential that enters the roster: with an Add, a credential update or an external join,
+// and about the whole roster when the validator is set. The group has already checked
+// that the credential's key signed what brought it in.
+pub trait CredentialValidator: Send + Sync {
+    fn validate(
+        &self,
+        credential: &BasicCredential,
+        context: &GroupContext,
+    ) -> Result<(), CredentialError>;
+}
+
+// A basic credential is bound to its member by the signature key alone, which the group
+// checks, so there is nothing left to decide
+pub struct BasicCredentialValidator;
+
+impl CredentialValidator for BasicCredentialValidator {
+    fn validate(&self, _: &BasicCredential, _: &GroupContext) -> Result<(), CredentialError> {
+        Ok(())
+    }
+}
+
+// Certificate chains aren't supported yet
+pub struct X509CredentialValidator;
+
+impl CredentialValidator for X509CredentialValidator {
+    fn validate(&self, _: &BasicCredential, _: &GroupContext) -> Result<(), CredentialError> {
+        Err(CredentialError::Unsupported)
+    }
+}
@@ -14,11 +14,11 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
+use crypto::rng::*;
 use libsodium_sys::{
     crypto_aead_aes256gcm_decrypt_detached, crypto_aead_aes256gcm_encrypt_detached,
 };
 use ring::aead as ring_aead;
-use sodiumoxide::randombytes;
 use std::*;
 use utils::*;
 
@@ -33,6 +33,25 @@ pub enum AesError {
     DecryptionError,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum AeadError {
+    TooShort,
+    AuthenticationFailed,
+    InvalidKeyLength,
+}
+
+impl fmt::Display for AeadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AeadError::TooShort => write!(f, "ciphertext too short"),
+            AeadError::AuthenticationFailed => write!(f, "authentication failed"),
+            AeadError::InvalidKeyLength => write!(f, "invalid key length"),
+        }
+    }
+}
+
+impl error::Error for AeadError {}
+
 pub const NONCEBYTES: usize = 12;
 pub const AES128KEYBYTES: usize = 16;
 pub const AES256KEYBYTES: usize = 32;
@@ -42,10 +61,14 @@ pub const TAGBYTES: usize = 16;
 pub struct Nonce(pub [u8; NONCEBYTES]);
 
 impl Nonce {
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
     pub fn new_random() -> Nonce {
-        let random_bytes = randombytes::randombytes(NONCEBYTES);
+        Nonce::new_with_rng(&mut SystemRng)
+    }
+
+    pub fn new_with_rng(rng: &mut dyn Rng) -> Nonce {
         let mut bytes: [u8; NONCEBYTES] = [0u8; NONCEBYTES];
-        bytes[..NONCEBYTES].clone_from_slice(&random_bytes[..NONCEBYTES]);
+        rng.fill(&mut bytes);
         Nonce(bytes)
     }
 
@@ -141,8 +164,17 @@ pub fn aes_128_open(
     }
 }
 
+#[cfg(any(test, not(feature = "explicit-entropy")))]
 pub fn aes_256_seal(payload: &[u8], key: &Aes256Key) -> Result<Vec<u8>, AesError> {
-    let nonce = Nonce::new_random();
+    aes_256_seal_with_rng(payload, key, &mut SystemRng)
+}
+
+pub fn aes_256_seal_with_rng(
+    payload: &[u8],
+    key: &Aes256Key,
+    rng: &mut dyn Rng,
+) -> Result<Vec<u8>, AesError> {
+    let nonce = Nonce::new_with_rng(rng);
     let mut ciphertext: Vec<u8> = vec![0; payload.len()];
     let mut tag: Vec<u8> = vec![0; TAGBYTES];
     let mut maclen: u64 = 0;
@@ -175,10 +207,10 @@ pub fn aes_256_seal(payload: &[u8], key: &Aes256Key) -> Result<Vec<u8>, AesError
 
 pub fn aes_256_open(sealed_box: &[u8], key: &Aes256Key) -> Result<Vec<u8>, AesError> {
     let sb_len = sealed_box.len();
-    let payload_len = sb_len - NONCEBYTES - TAGBYTES;
     if sb_len <= (NONCEBYTES + TAGBYTES) {
         return Err(AesError::DecryptionError);
     }
+    let payload_len = sb_len - NONCEBYTES - TAGBYTES;
     let (nonce, attached) = sealed_box.split_at(NONCEBYTES);
     let (ciphertext, tag) = attached.split_at(payload_len);
     let mut payload = vec![0; payload_len];
@@ -202,8 +234,172 @@ pub fn aes_256_open(sealed_box: &[u8], key: &Aes256Key) -> Result<Vec<u8>, AesEr
     Ok(payload)
 }
 
+fn algorithm(key: &[u8]) -> Result<&'static ring_aead::Algorithm, AeadError> {
+    match key.len() {
+        AES128KEYBYTES => Ok(&ring_aead::AES_128_GCM),
+        AES256KEYBYTES => Ok(&ring_aead::AES_256_GCM),
+        _ => Err(AeadError::InvalidKeyLength),
+    }
+}
+
+// Seals to ciphertext||tag with a raw key, picking AES-128 or AES-256 from its length
+pub fn seal(payload: &[u8], key: &[u8], nonce: &Nonce) -> Result<Vec<u8>, AeadError> {
+    let sealing_key = ring_aead::SealingKey::new(algorithm(key)?, key)
+        .map_err(|_| AeadError::InvalidKeyLength)?;
+    let mut buffer = payload.to_vec();
+    buffer.extend_from_slice(&[0u8; TAGBYTES]);
+    match ring_aead::seal_in_place(&sealing_key, &nonce.0, &[], &mut buffer, TAGBYTES) {
+        Ok(size) => {
+            buffer.truncate(size);
+            Ok(buffer)
+        }
+        Err(_) => Err(AeadError::AuthenticationFailed),
+    }
+}
+
+// Opens ciphertext||tag with a raw key, picking AES-128 or AES-256 from its length
+pub fn open(sealed_box: &[u8], key: &[u8], nonce: &Nonce) -> Result<Vec<u8>, AeadError> {
+    let algorithm = algorithm(key)?;
+    if sealed_box.len() < TAGBYTES {
+        return Err(AeadError::TooShort);
+    }
+    let opening_key =
+        ring_aead::OpeningKey::new(algorithm, key).map_err(|_| AeadError::InvalidKeyLength)?;
+    let mut buffer = sealed_box.to_vec();
+    match ring_aead::open_in_place(&opening_key, &nonce.0, &[], 0, &mut buffer) {
+        Ok(bytes) => Ok(bytes.to_vec()),
+        Err(_) => Err(AeadError::AuthenticationFailed),
+    }
+}
+
+// Chunked AEAD for payloads too large to hold twice in memory. Chunk i is sealed
+// under the base nonce XORed with i, and a flag in the associated data marks the last
+// chunk, so that reordered, dropped or truncated chunks fail to open.
+const STREAM_CHUNK: &[u8] = &[0];
+const STREAM_LAST_CHUNK: &[u8] = &[1];
+
+fn stream_nonce(base_nonce: &Nonce, counter: u64) -> [u8; NONCEBYTES] {
+    let mut nonce = base_nonce.0;
+    for (byte, c) in nonce[NONCEBYTES - 8..]
+        .iter_mut()
+        .zip(counter.to_be_bytes().iter())
+    {
+        *byte ^= c;
+    }
+    nonce
+}
+
+pub struct SealStream {
+    key: ring_aead::SealingKey,
+    base_nonce: Nonce,
+    chunk_size: usize,
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl SealStream {
+    pub fn new(key: &[u8], base_nonce: Nonce, chunk_size: usize) -> Result<Self, AeadError> {
+        assert!(chunk_size > 0);
+        let key = ring_aead::SealingKey::new(algorithm(key)?, key)
+            .map_err(|_| AeadError::InvalidKeyLength)?;
+        Ok(SealStream {
+            key,
+            base_nonce,
+            chunk_size,
+            counter: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    // Returns the chunks that are complete so far. A full chunk is held back until more
+    // data arrives, since it might turn out to be the last one.
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, AeadError> {
+        self.buffer.extend_from_slice(data);
+        let mut sealed = Vec::new();
+        while self.buffer.len() > self.chunk_size {
+            let rest = self.buffer.split_off(self.chunk_size);
+            let chunk = std::mem::replace(&mut self.buffer, rest);
+            sealed.append(&mut self.seal_chunk(chunk, STREAM_CHUNK)?);
+        }
+        Ok(sealed)
+    }
+
+    pub fn finalize(mut self) -> Result<Vec<u8>, AeadError> {
+        let chunk = std::mem::take(&mut self.buffer);
+        self.seal_chunk(chunk, STREAM_LAST_CHUNK)
+    }
+
+    fn seal_chunk(&mut self, mut chunk: Vec<u8>, ad: &[u8]) -> Result<Vec<u8>, AeadError> {
+        let nonce = stream_nonce(&self.base_nonce, self.counter);
+        self.counter += 1;
+        chunk.extend_from_slice(&[0u8; TAGBYTES]);
+        match ring_aead::seal_in_place(&self.key, &nonce, ad, &mut chunk, TAGBYTES) {
+            Ok(size) => {
+                chunk.truncate(size);
+                Ok(chunk)
+            }
+            Err(_) => Err(AeadError::AuthenticationFailed),
+        }
+    }
+}
+
+pub struct OpenStream {
+    key: ring_aead::OpeningKey,
+    base_nonce: Nonce,
+    chunk_size: usize,
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl OpenStream {
+    pub fn new(key: &[u8], base_nonce: Nonce, chunk_size: usize) -> Result<Self, AeadError> {
+        assert!(chunk_size > 0);
+        let key = ring_aead::OpeningKey::new(algorithm(key)?, key)
+            .map_err(|_| AeadError::InvalidKeyLength)?;
+        Ok(OpenStream {
+            key,
+            base_nonce,
+            chunk_size,
+            counter: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, AeadError> {
+        self.buffer.extend_from_slice(data);
+        let sealed_chunk_size = self.chunk_size + TAGBYTES;
+        let mut opened = Vec::new();
+        while self.buffer.len() > sealed_chunk_size {
+            let rest = self.buffer.split_off(sealed_chunk_size);
+            let chunk = std::mem::replace(&mut self.buffer, rest);
+            opened.append(&mut self.open_chunk(chunk, STREAM_CHUNK)?);
+        }
+        Ok(opened)
+    }
+
+    // Fails if the stream ended without its last chunk
+    pub fn finalize(mut self) -> Result<Vec<u8>, AeadError> {
+        if self.buffer.len() < TAGBYTES {
+            return Err(AeadError::TooShort);
+        }
+        let chunk = std::mem::take(&mut self.buffer);
+        self.open_chunk(chunk, STREAM_LAST_CHUNK)
+    }
+
+    fn open_chunk(&mut self, mut chunk: Vec<u8>, ad: &[u8]) -> Result<Vec<u8>, AeadError> {
+        let nonce = stream_nonce(&self.base_nonce, self.counter);
+        self.counter += 1;
+        match ring_aead::open_in_place(&self.key, &nonce, ad, 0, &mut chunk) {
+            Ok(plaintext) => Ok(plaintext.to_vec()),
+            Err(_) => Err(AeadError::AuthenticationFailed),
+        }
+    }
+}
+
 #[test]
 fn seal_open() {
+    use sodiumoxide::randombytes;
+
     let payload = vec![1, 2, 3];
 
     // AES128
@@ -218,4 +414,111 @@ fn seal_open() {
     let encrypted = aes_256_seal(&payload, &key).unwrap();
     let decrypted = aes_256_open(&encrypted, &key).unwrap();
     assert_eq!(decrypted, payload);
+
+    // Raw keys of either size
+    for key_size in [AES128KEYBYTES, AES256KEYBYTES].iter() {
+        let raw_key = randombytes::randombytes(*key_size);
+        let sealed = seal(&payload, &raw_key, &nonce).unwrap();
+        assert_eq!(sealed.len(), payload.len() + TAGBYTES);
+        assert_eq!(open(&sealed, &raw_key, &nonce), Ok(payload.clone()));
+    }
+    assert_eq!(
+        seal(&payload, &[0u8; 8], &nonce),
+        Err(AeadError::InvalidKeyLength)
+    );
+}
+
+#[test]
+fn open_errors() {
+    use sodiumoxide::randombytes;
+
+    let payload = vec![1, 2, 3];
+    let raw_key = randombytes::randombytes(AES128KEYBYTES);
+    let key = Aes128Key::from_slice(&raw_key);
+    let nonce = Nonce::new_random();
+    let mut sealed = aes_128_seal(&payload, &key, &nonce).unwrap();
+    assert_eq!(open(&sealed, &raw_key, &nonce), Ok(payload));
+
+    // Too short to even hold the tag
+    assert_eq!(
+        open(&sealed[..TAGBYTES - 1], &raw_key, &nonce),
+        Err(AeadError::TooShort)
+    );
+
+    // Tampered tag
+    let last = sealed.len() - 1;
+    sealed[last] ^= 0x01;
+    assert_eq!(
+        open(&sealed, &raw_key, &nonce),
+        Err(AeadError::AuthenticationFailed)
+    );
+
+    // Neither an AES-128 nor an AES-256 key
+    assert_eq!(
+        open(&sealed, &raw_key[..AES128KEYBYTES - 1], &nonce),
+        Err(AeadError::InvalidKeyLength)
+    );
+
+    // AES-256 keys go through the same entry point
+    let raw_key = randombytes::randombytes(AES256KEYBYTES);
+    let sealing_key = ring_aead::SealingKey::new(&ring_aead::AES_256_GCM, &raw_key).unwrap();
+    let mut buffer = vec![4, 5, 6];
+    buffer.extend_from_slice(&[0u8; TAGBYTES]);
+    let size =
+        ring_aead::seal_in_place(&sealing_key, &nonce.0, &[], &mut buffer, TAGBYTES).unwrap();
+    assert_eq!(open(&buffer[..size], &raw_key, &nonce), Ok(vec![4, 5, 6]));
+
+    // Truncated AES-256 boxes are rejected without reaching libsodium
+    assert!(aes_256_open(&[0u8; NONCEBYTES], &Aes256Key::from_slice(&raw_key)).is_err());
+}
+
+#[test]
+fn stream_seal_open() {
+    use sodiumoxide::randombytes;
+
+    let key = randombytes::randombytes(AES128KEYBYTES);
+    let nonce = Nonce::new_random();
+    let chunk_size = 16;
+    let payload = randombytes::randombytes(4 * chunk_size + 5);
+
+    // Five chunks, fed in uneven pieces
+    let mut seal = SealStream::new(&key, Nonce(nonce.0), chunk_size).unwrap();
+    let mut sealed = Vec::new();
+    for piece in payload.chunks(7) {
+        sealed.append(&mut seal.update(piece).unwrap());
+    }
+    sealed.append(&mut seal.finalize().unwrap());
+    assert_eq!(sealed.len(), payload.len() + 5 * TAGBYTES);
+
+    let mut open = OpenStream::new(&key, Nonce(nonce.0), chunk_size).unwrap();
+    let mut opened = Vec::new();
+    for piece in sealed.chunks(11) {
+        opened.append(&mut open.update(piece).unwrap());
+    }
+    opened.append(&mut open.finalize().unwrap());
+    assert_eq!(opened, payload);
+
+    // Cut after the fourth chunk, which wasn't sealed as the last one
+    let truncated = &sealed[..4 * (chunk_size + TAGBYTES)];
+    let mut open = OpenStream::new(&key, Nonce(nonce.0), chunk_size).unwrap();
+    let opened = open.update(truncated).unwrap();
+    assert_eq!(opened, payload[..3 * chunk_size].to_vec());
+    assert_eq!(open.finalize(), Err(AeadError::AuthenticationFailed));
+
+    // Swapped chunks
+    let mut reordered = sealed[chunk_size + TAGBYTES..2 * (chunk_size + TAGBYTES)].to_vec();
+    reordered.extend_from_slice(&sealed[..chunk_size + TAGBYTES]);
+    reordered.extend_from_slice(&sealed[2 * (chunk_size + TAGBYTES)..]);
+    let mut open = OpenStream::new(&key, Nonce(nonce.0), chunk_size).unwrap();
+    assert_eq!(
+        open.update(&reordered),
+        Err(AeadError::AuthenticationFailed)
+    );
+
+    // An empty payload is a single last chunk
+    let seal = SealStream::new(&key, Nonce(nonce.0), chunk_size).unwrap();
+    let sealed = seal.finalize().unwrap();
+    let mut open = OpenStream::new(&key, Nonce(nonce.0), chunk_size).unwrap();
+    open.update(&sealed).unwrap();
+    assert_eq!(open.finalize(), Ok(vec![]));
 }
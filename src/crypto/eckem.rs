@@ -17,6 +17,7 @@
 use codec::*;
 use crypto::aesgcm;
 use crypto::hkdf;
+use crypto::rng::*;
 use keys::*;
 use sodiumoxide::crypto::aead;
 use std::*;
@@ -64,7 +65,14 @@ impl X25519AES {
         public_key: &X25519PublicKey,
         payload: &[u8],
     ) -> Result<X25519AESCiphertext, EcKemError> {
-        let kp = X25519KeyPair::new_random();
+        X25519AES::encrypt_with_rng(public_key, payload, &mut SystemRng)
+    }
+    pub fn encrypt_with_rng(
+        public_key: &X25519PublicKey,
+        payload: &[u8],
+        rng: &mut dyn Rng,
+    ) -> Result<X25519AESCiphertext, EcKemError> {
+        let kp = X25519KeyPair::new_with_rng(rng);
         X25519AES::enc(public_key, payload, &kp)
     }
     pub fn encrypt_with_ephemeral(
@@ -221,17 +229,20 @@ fn test_eckem() {
     let alice_dh_private_key_hex =
         "5D43BE92D01AAD353B9B4B1DC32E6C828B00DD20B46BDEB98976E13D881DC39A";
     let alice_dh_private_key =
-        X25519PrivateKey::from_slice(&hex_to_bytes(alice_dh_private_key_hex));
+        X25519PrivateKey::from_slice(&hex_to_bytes(alice_dh_private_key_hex)).unwrap();
 
     let alice_dh_public_key_hex =
         "626848EAB66583E12FB94577D2399D32B1EA13D2E3B9EC07C9D54778F9E27910";
-    let alice_dh_public_key = X25519PublicKey::from_slice(&hex_to_bytes(alice_dh_public_key_hex));
+    let alice_dh_public_key =
+        X25519PublicKey::from_slice(&hex_to_bytes(alice_dh_public_key_hex)).unwrap();
 
     let bob_dh_private_key_hex = "FF629FC551E4B0657172E992AC543E89E0EB12EB11A8B413F140D88808B0EC40";
-    let _bob_dh_private_key = X25519PrivateKey::from_slice(&hex_to_bytes(bob_dh_private_key_hex));
+    let _bob_dh_private_key =
+        X25519PrivateKey::from_slice(&hex_to_bytes(bob_dh_private_key_hex)).unwrap();
 
     let bob_dh_public_key_hex = "A724AB6198B4D07A3E1E4FD788EF73BF8C0E8120AC7DA4C228948D408943D774";
-    let bob_dh_public_key = X25519PublicKey::from_slice(&hex_to_bytes(bob_dh_public_key_hex));
+    let bob_dh_public_key =
+        X25519PublicKey::from_slice(&hex_to_bytes(bob_dh_public_key_hex)).unwrap();
 
     let cleartext = hex_to_bytes("00010203040506070809");
 
@@ -0,0 +1,84 @@
+// Wire
+// Copyright (C) 2018 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use keys::*;
+use ring::digest;
+
+// The hash function of a cipher suite, for the tree, parent and transcript hashes.
+// Output lengths differ, so code handling these hashes reads output_len().
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    pub fn from_cipher_suite(cipher_suite: CipherSuite) -> Option<HashAlgorithm> {
+        match cipher_suite {
+            AES128GCM_P256_SHA256 | AES128GCM_CURVE25519_SHA256 => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+    fn digest_algorithm(self) -> &'static digest::Algorithm {
+        match self {
+            HashAlgorithm::Sha256 => &digest::SHA256,
+            HashAlgorithm::Sha384 => &digest::SHA384,
+            HashAlgorithm::Sha512 => &digest::SHA512,
+        }
+    }
+    pub fn output_len(self) -> usize {
+        self.digest_algorithm().output_len
+    }
+    pub fn hash(self, data: &[u8]) -> Vec<u8> {
+        digest::digest(self.digest_algorithm(), data)
+            .as_ref()
+            .to_vec()
+    }
+    // For hashing input that comes in several parts
+    pub fn context(self) -> digest::Context {
+        digest::Context::new(self.digest_algorithm())
+    }
+}
+
+#[test]
+fn hash_algorithms() {
+    for (algorithm, len) in [
+        (HashAlgorithm::Sha256, 32),
+        (HashAlgorithm::Sha384, 48),
+        (HashAlgorithm::Sha512, 64),
+    ]
+    .iter()
+    {
+        assert_eq!(algorithm.output_len(), *len);
+        assert_eq!(algorithm.hash(b"melissa").len(), *len);
+        let mut context = algorithm.context();
+        context.update(b"meli");
+        context.update(b"ssa");
+        assert_eq!(context.finish().as_ref(), &algorithm.hash(b"melissa")[..]);
+    }
+    assert_eq!(
+        HashAlgorithm::from_cipher_suite(AES128GCM_CURVE25519_SHA256),
+        Some(HashAlgorithm::Sha256)
+    );
+    assert_eq!(
+        HashAlgorithm::from_cipher_suite(AES128GCM_P256_SHA256),
+        Some(HashAlgorithm::Sha256)
+    );
+    assert_eq!(HashAlgorithm::from_cipher_suite(0xffff), None);
+}
@@ -17,7 +17,10 @@
 use codec::*;
 use crypto::aesgcm::*;
 use crypto::hkdf;
+use crypto::rng::*;
 use keys::*;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use std::*;
 use utils::*;
 
@@ -151,8 +154,10 @@ impl Codec for HpkeContext {
 }
 
 #[derive(Clone, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct HpkeCiphertext {
     pub ephemeral_public_key: X25519PublicKey,
+    #[cfg_attr(feature = "serde", serde(with = "::utils::serde_hex"))]
     pub content: Vec<u8>,
 }
 
@@ -177,7 +182,11 @@ impl HpkeCiphertext {
         payload: &[u8],
         ephemeral_key_pair: &X25519KeyPair,
     ) -> Result<HpkeCiphertext, HpkeError> {
-        let zz = ephemeral_key_pair.private_key.shared_secret(pkr).unwrap();
+        // A low-order recipient key gives an all-zero shared secret
+        let zz = ephemeral_key_pair
+            .private_key
+            .shared_secret(pkr)
+            .map_err(|_| AesError::EncryptionError)?;
         let enc = ephemeral_key_pair.public_key.to_slice();
         let (key, nonce) = setup_base_x25519_aes_128(pkr, &zz, &enc, &[]);
         let content = aes_128_seal(
@@ -193,11 +202,19 @@ impl HpkeCiphertext {
             content,
         })
     }
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
     pub fn encrypt(
         public_key: &X25519PublicKey,
         payload: &[u8],
     ) -> Result<HpkeCiphertext, HpkeError> {
-        let key_pair = X25519KeyPair::new_random();
+        HpkeCiphertext::encrypt_with_rng(public_key, payload, &mut SystemRng)
+    }
+    pub fn encrypt_with_rng(
+        public_key: &X25519PublicKey,
+        payload: &[u8],
+        rng: &mut dyn Rng,
+    ) -> Result<HpkeCiphertext, HpkeError> {
+        let key_pair = X25519KeyPair::new_with_rng(rng);
         HpkeCiphertext::enc_x25519_aes(public_key, payload, &key_pair)
     }
     pub fn encrypt_with_ephemeral(
@@ -207,6 +224,32 @@ impl HpkeCiphertext {
     ) -> Result<HpkeCiphertext, HpkeError> {
         HpkeCiphertext::enc_x25519_aes(public_key, payload, &key_pair)
     }
+    // Seals the same payload to every recipient, in recipient order. All ciphertexts share
+    // one ephemeral key pair, the key schedule still binds each of them to its recipient.
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn seal_to_many(
+        recipients: &[X25519PublicKey],
+        payload: &[u8],
+    ) -> Result<Vec<HpkeCiphertext>, HpkeError> {
+        HpkeCiphertext::seal_to_many_with_rng(recipients, payload, &mut SystemRng)
+    }
+    pub fn seal_to_many_with_rng(
+        recipients: &[X25519PublicKey],
+        payload: &[u8],
+        rng: &mut dyn Rng,
+    ) -> Result<Vec<HpkeCiphertext>, HpkeError> {
+        if recipients.is_empty() {
+            return Ok(Vec::new());
+        }
+        let key_pair = X25519KeyPair::new_with_rng(rng);
+        #[cfg(feature = "parallel")]
+        let recipients = recipients.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let recipients = recipients.iter();
+        recipients
+            .map(|public_key| HpkeCiphertext::enc_x25519_aes(public_key, payload, &key_pair))
+            .collect()
+    }
     pub fn decrypt(
         private_key: &X25519PrivateKey,
         ciphertext: &HpkeCiphertext,
@@ -214,7 +257,7 @@ impl HpkeCiphertext {
         let pkr = private_key.derive_public_key();
         let zz = private_key
             .shared_secret(&ciphertext.ephemeral_public_key)
-            .unwrap();
+            .map_err(|_| AesError::DecryptionError)?;
         let enc = ciphertext.ephemeral_public_key.to_slice();
         let (key, nonce) = setup_base_x25519_aes_128(&pkr, &zz, &enc, &[]);
         // println!("zz: {}", bytes_to_hex(&zz));
@@ -228,6 +271,16 @@ impl HpkeCiphertext {
     }
 }
 
+#[test]
+fn hpke_context_codec() {
+    assert_encoding_roundtrip(&HpkeContext {
+        ciphersuite: HpkeCipherSuite::X25519Sha256Aes128gcm as u16,
+        mode: 0,
+        kem_context: vec![1u8; 64],
+        info: b"info".to_vec(),
+    });
+}
+
 #[test]
 fn hpke_encrypt_decrypt_x25519_aes() {
     let kp = X25519KeyPair::new_random();
@@ -252,3 +305,45 @@ fn hpke_encrypt_decrypt_x25519_aes_random() {
         assert_eq!(cleartext, decrypted);
     }
 }
+
+#[test]
+fn hpke_encrypt_with_seeded_rng() {
+    let kp = X25519KeyPair::new_random();
+    let cleartext = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+    let first =
+        HpkeCiphertext::encrypt_with_rng(&kp.public_key, &cleartext, &mut SeededRng::new(b"seed"))
+            .unwrap();
+    let second =
+        HpkeCiphertext::encrypt_with_rng(&kp.public_key, &cleartext, &mut SeededRng::new(b"seed"))
+            .unwrap();
+    assert_eq!(first.encode_detached(), second.encode_detached());
+    assert_eq!(
+        HpkeCiphertext::decrypt(&kp.private_key, &first).unwrap(),
+        cleartext
+    );
+}
+
+#[test]
+fn hpke_seal_to_many() {
+    let key_pairs: Vec<X25519KeyPair> = (0..3).map(|_| X25519KeyPair::new_random()).collect();
+    let recipients: Vec<X25519PublicKey> = key_pairs.iter().map(|kp| kp.public_key).collect();
+    let cleartext = b"path secret".to_vec();
+
+    let sealed = HpkeCiphertext::seal_to_many(&recipients, &cleartext).unwrap();
+    assert_eq!(sealed.len(), 3);
+    for (i, kp) in key_pairs.iter().enumerate() {
+        assert_eq!(
+            HpkeCiphertext::decrypt(&kp.private_key, &sealed[i]).unwrap(),
+            cleartext
+        );
+        for (j, ciphertext) in sealed.iter().enumerate() {
+            if i != j {
+                assert!(HpkeCiphertext::decrypt(&kp.private_key, ciphertext).is_err());
+            }
+        }
+    }
+    assert!(HpkeCiphertext::seal_to_many(&[], &cleartext)
+        .unwrap()
+        .is_empty());
+}
@@ -15,6 +15,9 @@
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
 pub mod aesgcm;
+pub mod hash;
 pub mod hkdf;
 pub mod hpke;
+pub mod rng;
 pub mod schedule;
+pub mod transcript;
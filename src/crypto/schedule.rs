@@ -32,6 +32,143 @@ pub fn derive_secret(secret: hkdf::Prk, label: &str, context: &[u8]) -> Vec<u8>
     hkdf::expand(secret, info, HASH_LENGTH)
 }
 
+pub fn expand_with_label(secret: hkdf::Prk, label: &str, context: &[u8], length: usize) -> Vec<u8> {
+    let hkdf_label = HkdfLabel::new(context, label, length);
+    let state = &hkdf_label.serialize();
+    hkdf::expand(secret, hkdf::Info(state), length)
+}
+
+pub const PSK_TYPE_EXTERNAL: u8 = 1;
+pub const PSK_TYPE_RESUMPTION: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(u8)]
+pub enum ResumptionPskUsage {
+    Application = 1,
+    Reinit = 2,
+    Branch = 3,
+}
+
+impl Codec for ResumptionPskUsage {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        (*self as u8).encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        match u8::decode(cursor)? {
+            1 => Ok(ResumptionPskUsage::Application),
+            2 => Ok(ResumptionPskUsage::Reinit),
+            3 => Ok(ResumptionPskUsage::Branch),
+            _ => Err(CodecError::DecodingError),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Psk {
+    External(#[cfg_attr(feature = "serde", serde(with = "::utils::serde_hex"))] Vec<u8>), // opaque psk_id<0..255>;
+    Resumption {
+        usage: ResumptionPskUsage,
+        #[cfg_attr(feature = "serde", serde(with = "::utils::serde_hex"))]
+        group_id: Vec<u8>, // opaque psk_group_id<0..255>;
+        epoch: u64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PreSharedKeyID {
+    pub psk: Psk,
+    #[cfg_attr(feature = "serde", serde(with = "::utils::serde_hex"))]
+    pub psk_nonce: Vec<u8>, // opaque psk_nonce<0..255>;
+}
+
+impl PreSharedKeyID {
+    pub fn external(psk_id: &[u8], psk_nonce: &[u8]) -> Self {
+        PreSharedKeyID {
+            psk: Psk::External(psk_id.to_vec()),
+            psk_nonce: psk_nonce.to_vec(),
+        }
+    }
+}
+
+impl Codec for PreSharedKeyID {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match &self.psk {
+            Psk::External(psk_id) => {
+                PSK_TYPE_EXTERNAL.encode(buffer);
+                encode_vec_u8(buffer, psk_id);
+            }
+            Psk::Resumption {
+                usage,
+                group_id,
+                epoch,
+            } => {
+                PSK_TYPE_RESUMPTION.encode(buffer);
+                usage.encode(buffer);
+                encode_vec_u8(buffer, group_id);
+                epoch.encode(buffer);
+            }
+        }
+        encode_vec_u8(buffer, &self.psk_nonce);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let psk = match u8::decode(cursor)? {
+            PSK_TYPE_EXTERNAL => Psk::External(decode_vec_u8(cursor)?),
+            PSK_TYPE_RESUMPTION => {
+                let usage = ResumptionPskUsage::decode(cursor)?;
+                let group_id: Vec<u8> = decode_vec_u8(cursor)?;
+                // A resumption PSK has to name the group it comes from
+                if group_id.is_empty() {
+                    return Err(CodecError::DecodingError);
+                }
+                let epoch = u64::decode(cursor)?;
+                Psk::Resumption {
+                    usage,
+                    group_id,
+                    epoch,
+                }
+            }
+            _ => return Err(CodecError::DecodingError),
+        };
+        let psk_nonce = decode_vec_u8(cursor)?;
+        Ok(PreSharedKeyID { psk, psk_nonce })
+    }
+}
+
+// struct {
+//     PreSharedKeyID id;
+//     uint16 index;
+//     uint16 count;
+// } PSKLabel;
+fn psk_label(id: &PreSharedKeyID, index: u16, count: u16) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    id.encode(&mut buffer);
+    index.encode(&mut buffer);
+    count.encode(&mut buffer);
+    buffer
+}
+
+// psk_extracted_[i] = Extract(0, psk_[i])
+// psk_input_[i]     = ExpandWithLabel(psk_extracted_[i], "derived psk", PSKLabel, Nh)
+// psk_secret_[i]    = Extract(psk_input_[i], psk_secret_[i-1])
+// with psk_secret_[0] = 0
+pub fn psk_secret(psks: &[(PreSharedKeyID, Vec<u8>)]) -> Vec<u8> {
+    let zero = [0u8; HASH_LENGTH];
+    let count = psks.len() as u16;
+    let mut psk_secret = zero.to_vec();
+    for (index, (id, psk)) in psks.iter().enumerate() {
+        let psk_extracted = hkdf::extract(hkdf::Salt(&zero), hkdf::Input(psk));
+        let label = psk_label(id, index as u16, count);
+        let psk_input = expand_with_label(psk_extracted, "derived psk", &label, HASH_LENGTH);
+        psk_secret = hkdf::extract(hkdf::Salt(&psk_input), hkdf::Input(&psk_secret))
+            .0
+            .to_vec();
+    }
+    psk_secret
+}
+
 pub const INITSECRETBYTES: usize = 32;
 
 #[derive(Clone, PartialEq, Eq, Default, Debug)]
@@ -43,20 +180,22 @@ impl InitSecret {
         init_secret.0.clone_from_slice(bytes);
         init_secret
     }
-    pub fn update(&mut self, update_secret: &[u8], group_state: &[u8]) -> EpochSecrets {
-        let current_init_secret = self.0;
-        let salt = hkdf::Salt(&current_init_secret);
-        let ikm = hkdf::Input(update_secret);
-        let epoch_secret = hkdf::extract(salt, ikm);
-
-        // println!("Epoch secret {}", bytes_to_hex(&epoch_secret.0));
-
-        let application_secret = derive_secret(epoch_secret, "app", group_state);
-        let confirmation_key = derive_secret(epoch_secret, "confirm", group_state);
-        let init_secret = derive_secret(epoch_secret, "init", group_state);
-        let epoch_secrets = EpochSecrets::new(&application_secret, &confirmation_key);
-        self.0.copy_from_slice(&init_secret);
-
+    // Without PSKs the epoch secret is Extract(init_secret, update_secret) as before,
+    // otherwise the folded psk_secret is extracted into it as well.
+    pub fn update(
+        &mut self,
+        update_secret: &[u8],
+        psks: &[(PreSharedKeyID, Vec<u8>)],
+        group_state: &[u8],
+    ) -> EpochSecrets {
+        let psk_secret = if psks.is_empty() {
+            None
+        } else {
+            Some(psk_secret(psks))
+        };
+        let (epoch_secrets, init_secret) =
+            derive_epoch_secrets(self, update_secret, group_state, psk_secret.as_deref());
+        *self = init_secret;
         epoch_secrets
     }
 }
@@ -77,42 +216,156 @@ impl Drop for InitSecret {
     }
 }
 
+// init_secret = DeriveSecret(Extract(0, zz), "external init", kem_output)
+pub fn external_init_secret(zz: &[u8], kem_output: &[u8]) -> InitSecret {
+    let prk = hkdf::extract(hkdf::Salt(&[0u8; HASH_LENGTH]), hkdf::Input(zz));
+    InitSecret::from_bytes(&derive_secret(prk, "external init", kem_output))
+}
+
+// The whole key schedule of one epoch in a single pass:
+//   epoch_secret = Extract(init_secret, commit_secret)
+//   epoch_secret = Extract(epoch_secret, psk_secret), only with PSKs
+// and every secret of the epoch, the next init secret included, is
+// DeriveSecret(epoch_secret, label, group_context). The inputs are left untouched, so
+// members with the same inputs derive the same secrets.
+// This is the ladder of the draft this crate implements, not the one of RFC 9420: there
+// are no joiner, welcome, encryption or membership secrets, and the labels differ, so the
+// RFC's key schedule test vectors don't apply.
+pub fn derive_epoch_secrets(
+    init_secret: &InitSecret,
+    commit_secret: &[u8],
+    group_context: &[u8],
+    psk_secret: Option<&[u8]>,
+) -> (EpochSecrets, InitSecret) {
+    let mut epoch_secret = hkdf::extract(hkdf::Salt(&init_secret.0), hkdf::Input(commit_secret));
+    if let Some(psk_secret) = psk_secret {
+        epoch_secret = hkdf::extract(hkdf::Salt(&epoch_secret.0), hkdf::Input(psk_secret));
+    }
+    let derive = |label| derive_secret(epoch_secret, label, group_context);
+    let epoch_secrets = EpochSecrets::new(
+        &derive("app"),
+        &derive("confirm"),
+        &derive("external"),
+        &derive("resumption"),
+        &derive("authentication"),
+        &derive("exporter"),
+        &derive("sender data"),
+    );
+    let next_init_secret = InitSecret::from_bytes(&derive("init"));
+    erase(&mut epoch_secret.0);
+    (epoch_secrets, next_init_secret)
+}
+
 #[derive(Clone)]
 pub struct EpochSecrets {
     pub app_secret: [u8; 32],
     pub confirmation_key: [u8; 32],
+    pub external_secret: [u8; 32],
+    pub resumption_secret: [u8; 32],
+    pub epoch_authenticator: [u8; 32],
+    pub exporter_secret: [u8; 32],
+    pub sender_data_secret: [u8; 32],
 }
 
 impl Codec for EpochSecrets {
     fn encode(&self, buffer: &mut Vec<u8>) {
         encode_vec_u8(buffer, &self.app_secret);
         encode_vec_u8(buffer, &self.confirmation_key);
+        encode_vec_u8(buffer, &self.external_secret);
+        encode_vec_u8(buffer, &self.resumption_secret);
+        encode_vec_u8(buffer, &self.epoch_authenticator);
+        encode_vec_u8(buffer, &self.exporter_secret);
+        encode_vec_u8(buffer, &self.sender_data_secret);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
         let mut app_secret = <[u8; 32]>::default();
         let mut confirmation_key = <[u8; 32]>::default();
+        let mut external_secret = <[u8; 32]>::default();
+        let mut resumption_secret = <[u8; 32]>::default();
+        let mut epoch_authenticator = <[u8; 32]>::default();
+        let mut exporter_secret = <[u8; 32]>::default();
+        let mut sender_data_secret = <[u8; 32]>::default();
         app_secret.clone_from_slice(&decode_vec_u8(cursor)?);
         confirmation_key.clone_from_slice(&decode_vec_u8(cursor)?);
+        external_secret.clone_from_slice(&decode_vec_u8(cursor)?);
+        resumption_secret.clone_from_slice(&decode_vec_u8(cursor)?);
+        epoch_authenticator.clone_from_slice(&decode_vec_u8(cursor)?);
+        exporter_secret.clone_from_slice(&decode_vec_u8(cursor)?);
+        sender_data_secret.clone_from_slice(&decode_vec_u8(cursor)?);
         Ok(EpochSecrets {
             app_secret,
             confirmation_key,
+            external_secret,
+            resumption_secret,
+            epoch_authenticator,
+            exporter_secret,
+            sender_data_secret,
         })
     }
 }
 
+impl Drop for EpochSecrets {
+    fn drop(&mut self) {
+        erase(&mut self.app_secret);
+        erase(&mut self.confirmation_key);
+        erase(&mut self.external_secret);
+        erase(&mut self.resumption_secret);
+        erase(&mut self.epoch_authenticator);
+        erase(&mut self.exporter_secret);
+        erase(&mut self.sender_data_secret);
+    }
+}
+
 impl EpochSecrets {
-    pub fn new(app_secret_bytes: &[u8], confirmation_key_bytes: &[u8]) -> Self {
+    pub fn new(
+        app_secret_bytes: &[u8],
+        confirmation_key_bytes: &[u8],
+        external_secret_bytes: &[u8],
+        resumption_secret_bytes: &[u8],
+        epoch_authenticator_bytes: &[u8],
+        exporter_secret_bytes: &[u8],
+        sender_data_secret_bytes: &[u8],
+    ) -> Self {
         let mut app_secret = [0u8; 32];
         let mut confirmation_key = [0u8; 32];
+        let mut external_secret = [0u8; 32];
+        let mut resumption_secret = [0u8; 32];
+        let mut epoch_authenticator = [0u8; 32];
+        let mut exporter_secret = [0u8; 32];
+        let mut sender_data_secret = [0u8; 32];
         app_secret.clone_from_slice(&app_secret_bytes[..32]);
         confirmation_key.clone_from_slice(&confirmation_key_bytes[..32]);
+        external_secret.clone_from_slice(&external_secret_bytes[..32]);
+        resumption_secret.clone_from_slice(&resumption_secret_bytes[..32]);
+        epoch_authenticator.clone_from_slice(&epoch_authenticator_bytes[..32]);
+        exporter_secret.clone_from_slice(&exporter_secret_bytes[..32]);
+        sender_data_secret.clone_from_slice(&sender_data_secret_bytes[..32]);
         Self {
             app_secret,
             confirmation_key,
+            external_secret,
+            resumption_secret,
+            epoch_authenticator,
+            exporter_secret,
+            sender_data_secret,
         }
     }
 }
 
+// MLS-Exporter(label, context, length) =
+//     ExpandWithLabel(DeriveSecret(exporter_secret, label), "exported", Hash(context), length)
+pub fn export_secret(
+    exporter_secret: &[u8],
+    label: &str,
+    context: &[u8],
+    length: usize,
+) -> Vec<u8> {
+    let prk = hkdf::Prk::from_slice(exporter_secret).unwrap();
+    let secret = hkdf::Prk::from_slice(&derive_secret(prk, label, &[])).unwrap();
+    let context_hash = sha256::hash(context).0;
+    expand_with_label(secret, "exported", &context_hash, length)
+}
+
 pub struct HkdfLabel {
     length: u16,
     label: String,
@@ -138,3 +391,155 @@ impl HkdfLabel {
         buffer
     }
 }
+
+#[test]
+fn psk_secret_empty() {
+    assert_eq!(psk_secret(&[]), vec![0u8; HASH_LENGTH]);
+}
+
+#[test]
+fn psk_secret_fold() {
+    let zero = [0u8; HASH_LENGTH];
+    let id1 = PreSharedKeyID::external(b"psk 1", b"nonce 1");
+    let id2 = PreSharedKeyID {
+        psk: Psk::Resumption {
+            usage: ResumptionPskUsage::Application,
+            group_id: b"group".to_vec(),
+            epoch: 7,
+        },
+        psk_nonce: b"nonce 2".to_vec(),
+    };
+    let psk1 = vec![1u8; 32];
+    let psk2 = vec![2u8; 16];
+
+    let step = |id: &PreSharedKeyID, psk: &[u8], index: u16, count: u16, previous: &[u8]| {
+        let mut label = Vec::new();
+        id.encode(&mut label);
+        index.encode(&mut label);
+        count.encode(&mut label);
+        let extracted = hkdf::extract(hkdf::Salt(&zero), hkdf::Input(psk));
+        let info = HkdfLabel::new(&label, "derived psk", HASH_LENGTH).serialize();
+        let input = hkdf::expand(extracted, hkdf::Info(&info), HASH_LENGTH);
+        hkdf::extract(hkdf::Salt(&input), hkdf::Input(previous))
+            .0
+            .to_vec()
+    };
+
+    let one = step(&id1, &psk1, 0, 1, &zero);
+    assert_eq!(psk_secret(&[(id1.clone(), psk1.clone())]), one);
+
+    let first = step(&id1, &psk1, 0, 2, &zero);
+    let two = step(&id2, &psk2, 1, 2, &first);
+    assert_eq!(psk_secret(&[(id1, psk1), (id2, psk2)]), two);
+    assert_ne!(one, two);
+}
+
+#[test]
+fn psk_empty_matches_plain_schedule() {
+    let update_secret = [3u8; 32];
+    let group_state = b"group state".to_vec();
+    let mut init_secret = InitSecret::from_bytes(&[5u8; INITSECRETBYTES]);
+
+    let epoch_secret = hkdf::extract(hkdf::Salt(&[5u8; 32]), hkdf::Input(&update_secret));
+    let app_secret = derive_secret(epoch_secret, "app", &group_state);
+    let next_init_secret = derive_secret(epoch_secret, "init", &group_state);
+
+    let epoch_secrets = init_secret.update(&update_secret, &[], &group_state);
+    assert_eq!(&epoch_secrets.app_secret[..], &app_secret[..]);
+    assert_eq!(init_secret, InitSecret::from_bytes(&next_init_secret));
+
+    let mut psk_init_secret = InitSecret::from_bytes(&[5u8; INITSECRETBYTES]);
+    let psks = [(PreSharedKeyID::external(b"id", b"nonce"), vec![9u8; 32])];
+    let psk_epoch_secrets = psk_init_secret.update(&update_secret, &psks, &group_state);
+    assert_ne!(psk_epoch_secrets.app_secret, epoch_secrets.app_secret);
+    assert_ne!(psk_init_secret, init_secret);
+}
+
+#[test]
+fn psk_id_codec() {
+    let id = PreSharedKeyID {
+        psk: Psk::Resumption {
+            usage: ResumptionPskUsage::Reinit,
+            group_id: vec![1, 2, 3],
+            epoch: 42,
+        },
+        psk_nonce: vec![4, 5],
+    };
+    let decoded = PreSharedKeyID::decode_detached(&id.encode_detached()).unwrap();
+    assert_eq!(id, decoded);
+
+    let unnamed = PreSharedKeyID {
+        psk: Psk::Resumption {
+            usage: ResumptionPskUsage::Reinit,
+            group_id: Vec::new(),
+            epoch: 42,
+        },
+        psk_nonce: vec![4, 5],
+    };
+    assert!(PreSharedKeyID::decode_detached(&unnamed.encode_detached()).is_err());
+}
+
+#[test]
+fn epoch_secrets_vector() {
+    // Not the RFC 9420 vector, see derive_epoch_secrets(). Computed independently with
+    // HMAC-SHA256 from the labels above.
+    let init_secret = InitSecret::from_bytes(&[0x11u8; INITSECRETBYTES]);
+    let commit_secret = [0x22u8; 32];
+    let group_context = b"group context";
+    let check = |psk_secret: Option<&[u8]>, expected: [&str; 8]| {
+        let (epoch_secrets, next_init_secret) =
+            derive_epoch_secrets(&init_secret, &commit_secret, group_context, psk_secret);
+        let derived = [
+            &epoch_secrets.app_secret[..],
+            &epoch_secrets.confirmation_key[..],
+            &epoch_secrets.external_secret[..],
+            &epoch_secrets.resumption_secret[..],
+            &epoch_secrets.epoch_authenticator[..],
+            &epoch_secrets.exporter_secret[..],
+            &epoch_secrets.sender_data_secret[..],
+            &next_init_secret.0[..],
+        ];
+        for (secret, hex) in derived.iter().zip(expected.iter()) {
+            assert_eq!(secret.to_vec(), hex_to_bytes(hex));
+        }
+        // Same inputs, same secrets
+        let (again, again_init_secret) =
+            derive_epoch_secrets(&init_secret, &commit_secret, group_context, psk_secret);
+        assert_eq!(again.encode_detached(), epoch_secrets.encode_detached());
+        assert_eq!(again_init_secret, next_init_secret);
+    };
+    check(
+        None,
+        [
+            "9792d9c09e90af63650f49c252c6095959cc15a4257aa87c82875b6ea568c18d",
+            "b2be4c34cdf968637d9c5262048a15cca70ce2945c023b08c54ec9267a889045",
+            "94ffab0ff44818abc80d8271f04e6d50a8ec9f41e0f992d6eb69bd956a910801",
+            "e21b9e2fa3e05139fa8777d95dd4ecc6b5b7a26897eadbabb09738291b85e465",
+            "ceb359e5168abba6d3ed36c984aa0a3b165fc232a4be6e0f3a1567cb9b4acfae",
+            "b55e04d2a3f123633cbe32ff11337327dd80bba9d679db4ffde72bf37be11320",
+            "903a1e3619dbd34b2aecc9b5480fbba1d01a99dd8c0b69759132bdcb140a923b",
+            "f1531ac1db1c3356b534b716949630f78e84790e466dfdd3aff144cf0c8ab815",
+        ],
+    );
+    check(
+        Some(&[0x33u8; 32]),
+        [
+            "23aa87fc9cf8f99b9ac3674341b757b7182cacf316089bea82dbdce544dec6ec",
+            "df48bc623547b5af33c75d2b8adc52b64777b810c084132a41dcfff0e229cb6c",
+            "f12b5369c967da507f3437f8fba0d8c8f2fab973425a7c90a722329e455bdeed",
+            "dcd3cee5d69e4a5873f2c69ac681559496e9060809bda6d0c22dc0c50113682a",
+            "4f3a3695f552be65fbacf0ba2474d1bfe52f0d5408204629afbe8d69024251be",
+            "941d95b292b3e28b3b8c41cba2dabb6c0034c97b83f702454dca7bcc648d77bf",
+            "d440fd88349568cb2a12fa01cea7de47e056e219d281151629d403ab698f1d73",
+            "7f1724d0c19f9de58e845bdb63383f4d1c97280f8543c8d209b5a5ce6baea47e",
+        ],
+    );
+
+    // update() is the same pass
+    let mut updated = InitSecret::from_bytes(&[0x11u8; INITSECRETBYTES]);
+    let epoch_secrets = updated.update(&commit_secret, &[], group_context);
+    let (expected, next_init_secret) =
+        derive_epoch_secrets(&init_secret, &commit_secret, group_context, None);
+    assert_eq!(epoch_secrets.encode_detached(), expected.encode_detached());
+    assert_eq!(updated, next_init_secret);
+}
@@ -0,0 +1,73 @@
+// Wire
+// Copyright (C) 2018 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use crypto::hash::HashAlgorithm;
+
+// Both hashes chain onto the previous one, so each epoch only hashes its own commit and
+// no member has to keep the history
+
+// confirmed_transcript_hash_[n] = H(interim_transcript_hash_[n-1] || commit_content)
+pub fn confirmed_transcript_hash(
+    algorithm: HashAlgorithm,
+    interim_transcript_hash: &[u8],
+    commit_content: &[u8],
+) -> Vec<u8> {
+    let mut context = algorithm.context();
+    context.update(interim_transcript_hash);
+    context.update(commit_content);
+    context.finish().as_ref().to_vec()
+}
+
+// interim_transcript_hash_[n] = H(confirmed_transcript_hash_[n] || confirmation_tag_[n])
+pub fn interim_transcript_hash(
+    algorithm: HashAlgorithm,
+    confirmed_transcript_hash: &[u8],
+    confirmation_tag: &[u8],
+) -> Vec<u8> {
+    let mut context = algorithm.context();
+    context.update(confirmed_transcript_hash);
+    context.update(confirmation_tag);
+    context.finish().as_ref().to_vec()
+}
+
+#[test]
+fn transcript_hash_ordering() {
+    use ring::{digest, hmac};
+    let confirmation_key = hmac::SigningKey::new(&digest::SHA256, &[0x11; 32]);
+    let interim_before = [0x22; 32];
+    let commit_content = b"commit content";
+    let hash = |parts: &[&[u8]]| {
+        let mut context = digest::Context::new(&digest::SHA256);
+        for part in parts {
+            context.update(part);
+        }
+        context.finish().as_ref().to_vec()
+    };
+
+    // The previous interim hash comes before the commit content
+    let confirmed =
+        confirmed_transcript_hash(HashAlgorithm::Sha256, &interim_before, commit_content);
+    assert_eq!(confirmed, hash(&[&interim_before, commit_content]));
+    assert_ne!(confirmed, hash(&[commit_content, &interim_before]));
+
+    // The confirmed hash comes before the confirmation tag computed over it
+    let confirmation_tag = hmac::sign(&confirmation_key, &confirmed);
+    let interim =
+        interim_transcript_hash(HashAlgorithm::Sha256, &confirmed, confirmation_tag.as_ref());
+    assert_eq!(interim, hash(&[&confirmed, confirmation_tag.as_ref()]));
+    assert_ne!(interim, hash(&[confirmation_tag.as_ref(), &confirmed]));
+    assert_ne!(interim, confirmed);
+}
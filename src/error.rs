@@ -0,0 +1,147 @@
+// Wire
+// Copyright (C) 2018 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use codec::CodecError;
+use crypto::aesgcm::AeadError;
+use group::GroupError;
+use keys::{KeyError, KeyPackageError};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use storage::StorageError;
+use treemath::TreeMathError;
+
+// Error returned by the public entry points. Each variant wraps the error of the module
+// that failed, which is also available through source().
+#[derive(Debug)]
+pub enum MelissaError {
+    Codec(CodecError),
+    TreeMath(TreeMathError),
+    Aead(AeadError),
+    Group(GroupError),
+    KeyPackage(KeyPackageError),
+    Key(KeyError),
+    Storage(StorageError),
+    Io(io::Error),
+}
+
+impl fmt::Display for MelissaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MelissaError::Codec(e) => write!(f, "codec: {}", e),
+            MelissaError::TreeMath(e) => write!(f, "tree math: {}", e),
+            MelissaError::Aead(e) => write!(f, "AEAD: {}", e),
+            MelissaError::Group(e) => write!(f, "group: {}", e),
+            MelissaError::KeyPackage(e) => write!(f, "key package: {}", e),
+            MelissaError::Key(e) => write!(f, "key: {}", e),
+            MelissaError::Storage(e) => write!(f, "storage: {}", e),
+            MelissaError::Io(e) => write!(f, "I/O: {}", e),
+        }
+    }
+}
+
+impl Error for MelissaError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MelissaError::Codec(e) => Some(e),
+            MelissaError::TreeMath(e) => Some(e),
+            MelissaError::Aead(e) => Some(e),
+            MelissaError::Group(e) => Some(e),
+            MelissaError::KeyPackage(e) => Some(e),
+            MelissaError::Key(e) => Some(e),
+            MelissaError::Storage(e) => Some(e),
+            MelissaError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<CodecError> for MelissaError {
+    fn from(e: CodecError) -> Self {
+        MelissaError::Codec(e)
+    }
+}
+
+impl From<TreeMathError> for MelissaError {
+    fn from(e: TreeMathError) -> Self {
+        MelissaError::TreeMath(e)
+    }
+}
+
+impl From<AeadError> for MelissaError {
+    fn from(e: AeadError) -> Self {
+        MelissaError::Aead(e)
+    }
+}
+
+impl From<GroupError> for MelissaError {
+    fn from(e: GroupError) -> Self {
+        MelissaError::Group(e)
+    }
+}
+
+impl From<KeyPackageError> for MelissaError {
+    fn from(e: KeyPackageError) -> Self {
+        MelissaError::KeyPackage(e)
+    }
+}
+
+impl From<KeyError> for MelissaError {
+    fn from(e: KeyError) -> Self {
+        MelissaError::Key(e)
+    }
+}
+
+impl From<StorageError> for MelissaError {
+    fn from(e: StorageError) -> Self {
+        MelissaError::Storage(e)
+    }
+}
+
+impl From<io::Error> for MelissaError {
+    fn from(e: io::Error) -> Self {
+        MelissaError::Io(e)
+    }
+}
+
+#[test]
+fn source_chain() {
+    use mp::StageError;
+    let error = MelissaError::from(GroupError::InvalidGeneration(StageError::TooFarInThePast));
+    let group_error = error.source().unwrap();
+    assert!(group_error.downcast_ref::<GroupError>().is_some());
+    let stage_error = group_error.source().unwrap();
+    assert_eq!(
+        stage_error.downcast_ref::<StageError>(),
+        Some(&StageError::TooFarInThePast)
+    );
+    assert_eq!(error.to_string(), "group: invalid generation".to_string());
+
+    // Each variant is named after the error it wraps
+    let error = MelissaError::from(KeyError::UnsupportedCipherSuite);
+    assert!(matches!(error, MelissaError::Key(_)));
+    assert!(error.source().unwrap().downcast_ref::<KeyError>().is_some());
+    assert_eq!(
+        error.to_string(),
+        "key: unsupported cipher suite".to_string()
+    );
+    let error = MelissaError::from(KeyPackageError::Expired);
+    assert!(matches!(error, MelissaError::KeyPackage(_)));
+    assert!(error
+        .source()
+        .unwrap()
+        .downcast_ref::<KeyPackageError>()
+        .is_some());
+}
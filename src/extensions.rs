@@ -0,0 +1,342 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use codec::*;
+use keys::*;
+use messages::*;
+
+pub type ExtensionType = u16;
+
+pub const EXTENSION_TYPE_CAPABILITIES: ExtensionType = 0x0001;
+pub const EXTENSION_TYPE_LIFETIME: ExtensionType = 0x0002;
+pub const EXTENSION_TYPE_REQUIRED_CAPABILITIES: ExtensionType = 0x0003;
+pub const EXTENSION_TYPE_EXTERNAL_PUB: ExtensionType = 0x0004;
+pub const EXTENSION_TYPE_EXTERNAL_SENDERS: ExtensionType = 0x0005;
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Extension {
+    pub extension_type: ExtensionType,
+    #[cfg_attr(feature = "serde", serde(with = "::utils::serde_hex"))]
+    pub extension_data: Vec<u8>, // opaque extension_data<0..2^32-1>;
+}
+
+impl Codec for Extension {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.extension_type.encode(buffer);
+        encode_vec_u32(buffer, &self.extension_data);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let extension_type = ExtensionType::decode(cursor)?;
+        // The length is checked before anything is read, whatever the peer claims
+        let length = u32::decode(cursor)? as usize;
+        if length > cursor.extension_limit() {
+            return Err(CodecError::ExtensionTooLarge);
+        }
+        let extension_data = cursor.take(length)?.to_vec();
+        Ok(Extension {
+            extension_type,
+            extension_data,
+        })
+    }
+}
+
+// An extension list whose extension data adds up to at most the cursor's extension limit
+pub fn decode_extensions(cursor: &mut Cursor) -> Result<Vec<Extension>, CodecError> {
+    let extensions: Vec<Extension> = decode_vec_u16(cursor)?;
+    let total = extensions
+        .iter()
+        .map(|extension| extension.extension_data.len())
+        .sum::<usize>();
+    if total > cursor.extension_limit() {
+        return Err(CodecError::ExtensionTooLarge);
+    }
+    Ok(extensions)
+}
+
+pub trait ExtensionBody: Codec {
+    fn extension_type() -> ExtensionType;
+
+    fn to_extension(&self) -> Extension {
+        Extension {
+            extension_type: Self::extension_type(),
+            extension_data: self.encode_detached(),
+        }
+    }
+
+    // Returns Ok(None) if the extension is absent
+    fn from_extensions(extensions: &[Extension]) -> Result<Option<Self>, CodecError> {
+        match extensions
+            .iter()
+            .find(|e| e.extension_type == Self::extension_type())
+        {
+            Some(extension) => {
+                let mut cursor = Cursor::new(&extension.extension_data);
+                let body = Self::decode(&mut cursor)?;
+                if cursor.has_more() {
+                    return Err(CodecError::DecodingError);
+                }
+                Ok(Some(body))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExternalPubExtension {
+    pub external_pub: X25519PublicKey,
+}
+
+impl Codec for ExternalPubExtension {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.external_pub.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let external_pub = X25519PublicKey::decode(cursor)?;
+        Ok(ExternalPubExtension { external_pub })
+    }
+}
+
+impl ExtensionBody for ExternalPubExtension {
+    fn extension_type() -> ExtensionType {
+        EXTENSION_TYPE_EXTERNAL_PUB
+    }
+}
+
+// Validity period of a UserInitKey, in seconds since the Unix epoch
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LifetimeExtension {
+    pub not_before: u64,
+    pub not_after: u64,
+}
+
+impl Codec for LifetimeExtension {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.not_before.encode(buffer);
+        self.not_after.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let not_before = u64::decode(cursor)?;
+        let not_after = u64::decode(cursor)?;
+        Ok(LifetimeExtension {
+            not_before,
+            not_after,
+        })
+    }
+}
+
+impl ExtensionBody for LifetimeExtension {
+    fn extension_type() -> ExtensionType {
+        EXTENSION_TYPE_LIFETIME
+    }
+}
+
+// Signature keys of non-members, such as a delivery server, that may send proposals.
+// Proposals from an external sender refer to it by its position in the list.
+#[derive(Clone, Debug)]
+pub struct ExternalSendersExtension {
+    pub senders: Vec<BasicCredential>,
+}
+
+impl Codec for ExternalSendersExtension {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u16(buffer, &self.senders);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let senders = decode_vec_u16(cursor)?;
+        Ok(ExternalSendersExtension { senders })
+    }
+}
+
+impl ExtensionBody for ExternalSendersExtension {
+    fn extension_type() -> ExtensionType {
+        EXTENSION_TYPE_EXTERNAL_SENDERS
+    }
+}
+
+// What the owner of a UserInitKey can handle beyond its versions and cipher suites.
+// Credential types are the values of CredentialType.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CapabilitiesExtension {
+    pub extensions: Vec<ExtensionType>,
+    pub proposals: Vec<ProposalType>,
+    pub credentials: Vec<u8>,
+}
+
+impl CapabilitiesExtension {
+    // Everything this implementation supports
+    pub fn supported() -> Self {
+        CapabilitiesExtension {
+            extensions: vec![
+                EXTENSION_TYPE_CAPABILITIES,
+                EXTENSION_TYPE_LIFETIME,
+                EXTENSION_TYPE_REQUIRED_CAPABILITIES,
+                EXTENSION_TYPE_EXTERNAL_PUB,
+                EXTENSION_TYPE_EXTERNAL_SENDERS,
+            ],
+            proposals: vec![
+                PROPOSAL_TYPE_ADD,
+                PROPOSAL_TYPE_UPDATE,
+                PROPOSAL_TYPE_REMOVE,
+                PROPOSAL_TYPE_PSK,
+                PROPOSAL_TYPE_REINIT,
+                PROPOSAL_TYPE_EXTERNAL_INIT,
+                PROPOSAL_TYPE_GROUP_CONTEXT_EXTENSIONS,
+                PROPOSAL_TYPE_CREDENTIAL_UPDATE,
+            ],
+            credentials: vec![CredentialType::Basic as u8],
+        }
+    }
+    pub fn satisfies(&self, required: &RequiredCapabilitiesExtension) -> bool {
+        required
+            .extensions
+            .iter()
+            .all(|e| self.extensions.contains(e))
+            && required
+                .proposals
+                .iter()
+                .all(|p| self.proposals.contains(p))
+            && required
+                .credentials
+                .iter()
+                .all(|c| self.credentials.contains(c))
+    }
+}
+
+impl Codec for CapabilitiesExtension {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u8(buffer, &self.extensions);
+        encode_vec_u8(buffer, &self.proposals);
+        encode_vec_u8(buffer, &self.credentials);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let extensions = decode_vec_u8(cursor)?;
+        let proposals = decode_vec_u8(cursor)?;
+        let credentials = decode_vec_u8(cursor)?;
+        Ok(CapabilitiesExtension {
+            extensions,
+            proposals,
+            credentials,
+        })
+    }
+}
+
+impl ExtensionBody for CapabilitiesExtension {
+    fn extension_type() -> ExtensionType {
+        EXTENSION_TYPE_CAPABILITIES
+    }
+}
+
+// What every member of a group has to support, a group context extension
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RequiredCapabilitiesExtension {
+    pub extensions: Vec<ExtensionType>,
+    pub proposals: Vec<ProposalType>,
+    pub credentials: Vec<u8>,
+}
+
+impl Codec for RequiredCapabilitiesExtension {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u8(buffer, &self.extensions);
+        encode_vec_u8(buffer, &self.proposals);
+        encode_vec_u8(buffer, &self.credentials);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let extensions = decode_vec_u8(cursor)?;
+        let proposals = decode_vec_u8(cursor)?;
+        let credentials = decode_vec_u8(cursor)?;
+        Ok(RequiredCapabilitiesExtension {
+            extensions,
+            proposals,
+            credentials,
+        })
+    }
+}
+
+impl ExtensionBody for RequiredCapabilitiesExtension {
+    fn extension_type() -> ExtensionType {
+        EXTENSION_TYPE_REQUIRED_CAPABILITIES
+    }
+}
+
+#[test]
+fn external_pub_extension() {
+    let kp = X25519KeyPair::new_random();
+    let body = ExternalPubExtension {
+        external_pub: kp.public_key,
+    };
+    let extensions = [body.to_extension()];
+    let encoded = extensions[0].encode_detached();
+    let decoded = [Extension::decode_detached(&encoded).unwrap()];
+    assert_eq!(
+        ExternalPubExtension::from_extensions(&decoded).unwrap(),
+        Some(body)
+    );
+    assert_eq!(ExternalPubExtension::from_extensions(&[]).unwrap(), None);
+}
+
+#[test]
+fn extension_size_limit() {
+    let extension = |length: usize| Extension {
+        extension_type: EXTENSION_TYPE_EXTERNAL_SENDERS,
+        extension_data: vec![7u8; length],
+    };
+    let largest = extension(MAX_EXTENSION_BYTES).encode_detached();
+    assert_eq!(
+        Extension::decode_detached(&largest)
+            .unwrap()
+            .extension_data
+            .len(),
+        MAX_EXTENSION_BYTES
+    );
+    match Extension::decode_detached(&extension(MAX_EXTENSION_BYTES + 1).encode_detached()) {
+        Err(CodecError::ExtensionTooLarge) => (),
+        _ => panic!("oversized extension accepted"),
+    }
+    // Rejected on the claimed length alone
+    let mut claimed = Vec::new();
+    EXTENSION_TYPE_EXTERNAL_SENDERS.encode(&mut claimed);
+    u32::MAX.encode(&mut claimed);
+    match Extension::decode_detached(&claimed) {
+        Err(CodecError::ExtensionTooLarge) => (),
+        _ => panic!("oversized extension accepted"),
+    }
+
+    // The limit of a list covers all of its extensions
+    let mut list = Vec::new();
+    encode_vec_u16(&mut list, &[extension(300), extension(300)]);
+    assert_eq!(decode_extensions(&mut Cursor::new(&list)).unwrap().len(), 2);
+    assert!(decode_extensions(&mut Cursor::new(&list).with_extension_limit(600)).is_ok());
+    match decode_extensions(&mut Cursor::new(&list).with_extension_limit(599)) {
+        Err(CodecError::ExtensionTooLarge) => (),
+        _ => panic!("oversized extension list accepted"),
+    }
+    let mut list = Vec::new();
+    let half = MAX_EXTENSION_BYTES / 2 + 1;
+    encode_vec_u16(&mut list, &[extension(half), extension(half)]);
+    match decode_extensions(&mut Cursor::new(&list)) {
+        Err(CodecError::ExtensionTooLarge) => (),
+        _ => panic!("oversized extension list accepted"),
+    }
+
+    // Nested cursors keep the limit
+    let mut nested = Vec::new();
+    encode_vec_u32(&mut nested, &list);
+    let mut cursor = Cursor::new(&nested).with_extension_limit(MAX_EXTENSION_BYTES * 2);
+    let mut sub = cursor.sub_cursor_u32().unwrap();
+    assert_eq!(decode_extensions(&mut sub).unwrap().len(), 2);
+}
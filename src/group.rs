@@ -14,28 +14,155 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
+use clock::*;
 use codec::*;
+use credential::*;
+use crypto::hash::*;
+use crypto::hpke::*;
+use crypto::rng::*;
 use crypto::schedule::*;
+use crypto::transcript::*;
+use error::*;
+use extensions::*;
 use keys::*;
 use messages::*;
-use sodiumoxide::randombytes;
+use mp::*;
+use sodiumoxide::crypto::auth::hmacsha256;
+use sodiumoxide::crypto::sign::ed25519;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
 use std::hash::*;
+use std::path::Path;
+use std::sync::Arc;
+use storage::*;
 use tree::*;
 use treemath;
+use utils::*;
 
 #[derive(Clone)]
 pub struct Member {}
 
+// A commit with only adds doesn't need a path, as the existing members don't have to
+// be re-keyed. Forcing one re-keys the committer's direct path anyway, so that members
+// who join together don't share secrets from before their join.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CommitOptions {
+    pub force_path: bool,
+}
+
+#[derive(Debug)]
+pub enum GroupError {
+    MissingEpochSecrets,
+    MissingExtension(ExtensionType),
+    InvalidExtension,
+    InvalidKemOutput,
+    UnsupportedCipherSuite,
+    UnsupportedVersion,
+    MissingReInit,
+    InvalidProposal,
+    ConflictingProposals,
+    ReInitNotAlone,
+    InvalidCommit,
+    InvalidWelcome,
+    Removed,
+    InvalidGroupInfo,
+    DuplicateKey,
+    GroupTooLarge,
+    InvalidMessage,
+    InvalidGeneration(StageError),
+    InvalidCredential(CredentialError),
+    UnsupportedCapability,
+    RatchetExhausted,
+    EpochTooOld,
+    UnknownProposalRef,
+    UnauthorizedSender,
+    InvalidTree,
+    InvalidLeaf,
+    InvalidParentHash,
+    InvalidTranscriptHash,
+    InvalidConfirmationTag,
+    InvalidUpdatePath,
+    WrongEpoch {
+        expected: GroupEpoch,
+        got: GroupEpoch,
+    },
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GroupError::MissingEpochSecrets => write!(f, "missing epoch secrets"),
+            GroupError::MissingExtension(extension_type) => {
+                write!(f, "missing extension {}", extension_type)
+            }
+            GroupError::InvalidExtension => write!(f, "invalid extension"),
+            GroupError::InvalidKemOutput => write!(f, "invalid KEM output"),
+            GroupError::UnsupportedCipherSuite => write!(f, "unsupported cipher suite"),
+            GroupError::UnsupportedVersion => write!(f, "unsupported version"),
+            GroupError::MissingReInit => write!(f, "missing reinit"),
+            GroupError::InvalidProposal => write!(f, "invalid proposal"),
+            GroupError::ConflictingProposals => write!(f, "conflicting proposals"),
+            GroupError::ReInitNotAlone => write!(f, "reinit with other proposals"),
+            GroupError::InvalidCommit => write!(f, "invalid commit"),
+            GroupError::InvalidWelcome => write!(f, "invalid welcome"),
+            GroupError::Removed => write!(f, "removed from the group"),
+            GroupError::InvalidGroupInfo => write!(f, "invalid group info"),
+            GroupError::DuplicateKey => write!(f, "duplicate key"),
+            GroupError::GroupTooLarge => write!(f, "group too large"),
+            GroupError::InvalidMessage => write!(f, "invalid message"),
+            GroupError::InvalidGeneration(_) => write!(f, "invalid generation"),
+            GroupError::InvalidCredential(_) => write!(f, "invalid credential"),
+            GroupError::UnsupportedCapability => write!(f, "unsupported capability"),
+            GroupError::RatchetExhausted => write!(f, "ratchet exhausted, a commit is needed"),
+            GroupError::EpochTooOld => write!(f, "epoch too old"),
+            GroupError::UnknownProposalRef => write!(f, "unknown proposal reference"),
+            GroupError::UnauthorizedSender => write!(f, "unauthorized sender"),
+            GroupError::InvalidTree => write!(f, "invalid tree"),
+            GroupError::InvalidLeaf => write!(f, "invalid own leaf"),
+            GroupError::InvalidParentHash => write!(f, "invalid parent hash"),
+            GroupError::InvalidTranscriptHash => write!(f, "invalid transcript hash"),
+            GroupError::InvalidConfirmationTag => write!(f, "invalid confirmation tag"),
+            GroupError::InvalidUpdatePath => write!(f, "invalid update path"),
+            GroupError::WrongEpoch { expected, got } => {
+                write!(f, "wrong epoch: expected {}, got {}", expected, got)
+            }
+        }
+    }
+}
+
+impl Error for GroupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GroupError::InvalidGeneration(e) => Some(e),
+            GroupError::InvalidCredential(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 pub const GROUPSECRETBYTES: usize = 32;
+// Upper bound on the number of leaves, so that adds can't grow the tree without limit
+pub const DEFAULT_MAX_MEMBERS: usize = 1 << 20;
 pub const GROUPIDBYTES: usize = 255;
+// Earlier epochs whose application messages can still be opened after a commit
+pub const PAST_EPOCHS_KEPT: usize = 2;
 
-#[derive(Clone)]
-pub struct GroupId(pub [u8; GROUPIDBYTES]);
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct GroupId(
+    #[cfg_attr(feature = "serde", serde(with = "::utils::serde_hex"))] pub [u8; GROUPIDBYTES],
+);
 
 impl GroupId {
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
     pub fn random() -> Self {
-        Self::from_bytes(&randombytes::randombytes(GROUPIDBYTES))
+        Self::random_with_rng(&mut SystemRng)
+    }
+    pub fn random_with_rng(rng: &mut dyn Rng) -> Self {
+        Self::from_bytes(&rng.random_bytes(GROUPIDBYTES))
     }
     pub fn from_bytes(bytes: &[u8]) -> Self {
         let mut group_id = [0u8; GROUPIDBYTES];
@@ -49,13 +176,113 @@ impl Codec for GroupId {
         encode_vec_u8(buffer, &self.0);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let bytes = decode_vec_u8(cursor)?;
+        let bytes: Vec<u8> = decode_vec_u8(cursor)?;
+        // Empty group ids are rejected with any other length
+        if bytes.len() != GROUPIDBYTES {
+            return Err(CodecError::DecodingError);
+        }
         Ok(GroupId::from_bytes(&bytes))
     }
 }
 
 pub type GroupEpoch = u32;
 
+// What is left of an earlier epoch: enough to open the application messages that were
+// still in flight when it ended
+#[derive(Clone)]
+struct PastEpoch {
+    epoch: GroupEpoch,
+    sender_data_secret: [u8; 32],
+    app_secret: [u8; 32],
+    ratchets: Vec<SenderRatchet>,
+}
+
+impl Drop for PastEpoch {
+    fn drop(&mut self) {
+        erase(&mut self.sender_data_secret);
+        erase(&mut self.app_secret);
+    }
+}
+
+impl Codec for PastEpoch {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.epoch.encode(buffer);
+        encode_vec_u8(buffer, &self.sender_data_secret);
+        encode_vec_u8(buffer, &self.app_secret);
+        encode_vec_u32(buffer, &self.ratchets);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let epoch = GroupEpoch::decode(cursor)?;
+        let mut sender_data_secret = [0u8; 32];
+        let mut app_secret = [0u8; 32];
+        let bytes = decode_vec_u8(cursor)?;
+        if bytes.len() != 32 {
+            return Err(CodecError::DecodingError);
+        }
+        sender_data_secret.copy_from_slice(&bytes);
+        let bytes = decode_vec_u8(cursor)?;
+        if bytes.len() != 32 {
+            return Err(CodecError::DecodingError);
+        }
+        app_secret.copy_from_slice(&bytes);
+        let ratchets = decode_vec_u32(cursor)?;
+        Ok(PastEpoch {
+            epoch,
+            sender_data_secret,
+            app_secret,
+            ratchets,
+        })
+    }
+}
+
+// The state an epoch's key schedule is bound to. Members at the same epoch of the same
+// group encode the same bytes.
+#[derive(Clone)]
+pub struct GroupContext {
+    pub version: ProtocolVersion,
+    pub cipher_suite: CipherSuite,
+    pub group_id: GroupId,
+    pub epoch: GroupEpoch,
+    pub tree_hash: Vec<u8>,
+    // The tree hash only covers HPKE keys, the member identities are bound here
+    pub roster_hash: Vec<u8>,
+    pub confirmed_transcript_hash: Vec<u8>,
+    pub extensions: Vec<Extension>,
+}
+
+impl Codec for GroupContext {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.version.encode(buffer);
+        self.cipher_suite.encode(buffer);
+        self.group_id.encode(buffer);
+        self.epoch.encode(buffer);
+        encode_vec_u8(buffer, &self.tree_hash);
+        encode_vec_u8(buffer, &self.roster_hash);
+        encode_vec_u8(buffer, &self.confirmed_transcript_hash);
+        encode_vec_u16(buffer, &self.extensions);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let version = ProtocolVersion::decode(cursor)?;
+        let cipher_suite = CipherSuite::decode(cursor)?;
+        let group_id = GroupId::decode(cursor)?;
+        let epoch = GroupEpoch::decode(cursor)?;
+        let tree_hash = decode_vec_u8(cursor)?;
+        let roster_hash = decode_vec_u8(cursor)?;
+        let confirmed_transcript_hash = decode_vec_u8(cursor)?;
+        let extensions = decode_extensions(cursor)?;
+        Ok(GroupContext {
+            version,
+            cipher_suite,
+            group_id,
+            epoch,
+            tree_hash,
+            roster_hash,
+            confirmed_transcript_hash,
+            extensions,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct Group {
     id: Identity,
@@ -63,10 +290,28 @@ pub struct Group {
     group_epoch: GroupEpoch,
     init_secret: InitSecret,
     epoch_secrets: Option<EpochSecrets>,
-    roster: Vec<BasicCredential>,
+    roster: Vec<Option<BasicCredential>>,
+    // What each member's key package claimed, aligned with the roster
+    capabilities: Vec<Option<CapabilitiesExtension>>,
     tree: Tree,
     update_secret: Option<(u64, NodeSecret)>,
-    transcript: Vec<GroupOperationValue>,
+    proposed_leaf_secret: Option<NodeSecret>,
+    proposed_identity: Option<Identity>,
+    confirmed_transcript_hash: Vec<u8>,
+    interim_transcript_hash: Vec<u8>,
+    pending_psks: Vec<(PreSharedKeyID, Vec<u8>)>,
+    pending_proposals: Vec<(ProposalRef, Proposal)>,
+    cipher_suite: CipherSuite,
+    version: ProtocolVersion,
+    reinit: Option<ReInit>,
+    add_position: AddPosition,
+    max_members: usize,
+    ratchets: Vec<SenderRatchet>,
+    past_epochs: Vec<PastEpoch>,
+    extensions: Vec<Extension>,
+    // Local policy, so it isn't encoded with the group. A decoded group starts out with
+    // the basic validator.
+    credential_validator: Arc<dyn CredentialValidator>,
 }
 
 impl Codec for Group {
@@ -77,9 +322,31 @@ impl Codec for Group {
         self.init_secret.encode(buffer);
         self.epoch_secrets.encode(buffer);
         encode_vec_u32(buffer, &self.roster);
+        encode_vec_u32(buffer, &self.capabilities);
         self.tree.encode(buffer);
         self.update_secret.encode(buffer);
-        encode_vec_u32(buffer, &self.transcript);
+        self.proposed_leaf_secret.encode(buffer);
+        self.proposed_identity.encode(buffer);
+        encode_vec_u8(buffer, &self.confirmed_transcript_hash);
+        encode_vec_u8(buffer, &self.interim_transcript_hash);
+        (self.pending_psks.len() as u32).encode(buffer);
+        for (id, psk) in self.pending_psks.iter() {
+            id.encode(buffer);
+            encode_vec_u8(buffer, psk);
+        }
+        (self.pending_proposals.len() as u32).encode(buffer);
+        for (proposal_ref, proposal) in self.pending_proposals.iter() {
+            proposal_ref.encode(buffer);
+            proposal.encode(buffer);
+        }
+        self.cipher_suite.encode(buffer);
+        self.version.encode(buffer);
+        self.reinit.encode(buffer);
+        self.add_position.encode(buffer);
+        (self.max_members as u32).encode(buffer);
+        encode_vec_u32(buffer, &self.ratchets);
+        encode_vec_u32(buffer, &self.past_epochs);
+        encode_vec_u16(buffer, &self.extensions);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
         let id = Identity::decode(cursor)?;
@@ -88,9 +355,38 @@ impl Codec for Group {
         let init_secret = InitSecret::decode(cursor)?;
         let epoch_secrets = Option::<EpochSecrets>::decode(cursor)?;
         let roster = decode_vec_u32(cursor)?;
-        let tree = Tree::decode(cursor)?;
+        let capabilities = decode_vec_u32(cursor)?;
+        let mut tree = Tree::decode(cursor)?;
         let update_secret = Option::<(u64, NodeSecret)>::decode(cursor)?;
-        let transcript = decode_vec_u32(cursor)?;
+        let proposed_leaf_secret = Option::<NodeSecret>::decode(cursor)?;
+        let proposed_identity = Option::<Identity>::decode(cursor)?;
+        let confirmed_transcript_hash = decode_vec_u8(cursor)?;
+        let interim_transcript_hash = decode_vec_u8(cursor)?;
+        let psk_count = u32::decode(cursor)?;
+        let mut pending_psks = Vec::new();
+        for _ in 0..psk_count {
+            let id = PreSharedKeyID::decode(cursor)?;
+            let psk = decode_vec_u8(cursor)?;
+            pending_psks.push((id, psk));
+        }
+        let proposal_count = u32::decode(cursor)?;
+        let mut pending_proposals = Vec::new();
+        for _ in 0..proposal_count {
+            let proposal_ref = ProposalRef::decode(cursor)?;
+            let proposal = Proposal::decode(cursor)?;
+            pending_proposals.push((proposal_ref, proposal));
+        }
+        let cipher_suite = CipherSuite::decode(cursor)?;
+        let hash_algorithm =
+            HashAlgorithm::from_cipher_suite(cipher_suite).ok_or(CodecError::DecodingError)?;
+        tree.hash_algorithm = hash_algorithm;
+        let version = ProtocolVersion::decode(cursor)?;
+        let reinit = Option::<ReInit>::decode(cursor)?;
+        let add_position = AddPosition::decode(cursor)?;
+        let max_members = u32::decode(cursor)? as usize;
+        let ratchets = decode_vec_u32(cursor)?;
+        let past_epochs = decode_vec_u32(cursor)?;
+        let extensions = decode_vec_u16(cursor)?;
         Ok(Group {
             id,
             group_id,
@@ -98,16 +394,40 @@ impl Codec for Group {
             init_secret,
             epoch_secrets,
             roster,
+            capabilities,
             tree,
             update_secret,
-            transcript,
+            proposed_leaf_secret,
+            proposed_identity,
+            confirmed_transcript_hash,
+            interim_transcript_hash,
+            pending_psks,
+            pending_proposals,
+            cipher_suite,
+            version,
+            reinit,
+            add_position,
+            max_members,
+            ratchets,
+            past_epochs,
+            extensions,
+            credential_validator: Arc::new(BasicCredentialValidator),
         })
     }
 }
 
 impl Group {
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
     pub fn new(id: Identity, credential: BasicCredential, group_id: GroupId) -> Self {
-        let secret = NodeSecret::new_random();
+        Group::new_with_rng(id, credential, group_id, &mut SystemRng)
+    }
+    pub fn new_with_rng(
+        id: Identity,
+        credential: BasicCredential,
+        group_id: GroupId,
+        rng: &mut dyn Rng,
+    ) -> Self {
+        let secret = NodeSecret::new_with_rng(rng);
         let own_leaf = Node::from_secret(&secret);
         let init_secret = InitSecret::from_bytes(&[0u8; 32]);
         let tree = Tree::new_from_leaf(&own_leaf);
@@ -117,17 +437,85 @@ impl Group {
             group_epoch: 0,
             init_secret,
             epoch_secrets: None,
-            roster: vec![credential],
+            roster: vec![Some(credential)],
+            capabilities: vec![Some(CapabilitiesExtension::supported())],
             tree,
             update_secret: None,
-            transcript: vec![],
+            proposed_leaf_secret: None,
+            proposed_identity: None,
+            confirmed_transcript_hash: Vec::new(),
+            interim_transcript_hash: Vec::new(),
+            pending_psks: Vec::new(),
+            pending_proposals: Vec::new(),
+            cipher_suite: AES128GCM_CURVE25519_SHA256,
+            version: CURRENT_VERSION,
+            reinit: None,
+            add_position: AddPosition::default(),
+            max_members: DEFAULT_MAX_MEMBERS,
+            ratchets: Vec::new(),
+            past_epochs: Vec::new(),
+            credential_validator: Arc::new(BasicCredentialValidator),
+            extensions: Vec::new(),
+        }
+    }
+    // Founds a group at epoch 0 with the creator at the only leaf, using the key package
+    // for the leaf key. Unlike new(), the key schedule starts from a random init secret
+    // right away, so the group has epoch secrets before the first commit.
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn create(
+        id: Identity,
+        credential: BasicCredential,
+        group_id: GroupId,
+        cipher_suite: CipherSuite,
+        init_key_bundle: &UserInitKeyBundle,
+    ) -> Result<Self, MelissaError> {
+        Group::create_with_rng(
+            id,
+            credential,
+            group_id,
+            cipher_suite,
+            init_key_bundle,
+            &mut SystemRng,
+        )
+    }
+    pub fn create_with_rng(
+        id: Identity,
+        credential: BasicCredential,
+        group_id: GroupId,
+        cipher_suite: CipherSuite,
+        init_key_bundle: &UserInitKeyBundle,
+        rng: &mut dyn Rng,
+    ) -> Result<Self, MelissaError> {
+        let init_key = &init_key_bundle.init_key;
+        if cipher_suite != AES128GCM_CURVE25519_SHA256
+            || !init_key.cipher_suites.contains(&cipher_suite)
+        {
+            return Err(GroupError::UnsupportedCipherSuite.into());
         }
+        if init_key.verify_keys().is_err()
+            || init_key.identity_key != id.public_key
+            || credential.public_key != id.public_key
+        {
+            return Err(GroupError::InvalidLeaf.into());
+        }
+        let mut own_leaf = Node::new_from_public_key(&init_key.init_keys[0]);
+        own_leaf.dh_private_key = Some(init_key_bundle.private_key().clone());
+        let mut group = Group::new_with_rng(id, credential, group_id, rng);
+        group.cipher_suite = cipher_suite;
+        group.tree = Tree::from_leaves(vec![own_leaf]).unwrap();
+        group.tree.hash_algorithm = supported_hash_algorithm(cipher_suite)?;
+        group.init_secret = InitSecret::from_bytes(&rng.random_bytes(INITSECRETBYTES));
+        group.derive_epoch_secrets(&[0u8; NODESECRETBYTES]);
+        Ok(group)
     }
     pub fn new_from_welcome(id: Identity, welcome: &Welcome) -> Self {
         let tree_size = welcome.tree.len();
         assert!(tree_size > 0);
         let roster = welcome.roster.clone();
-        let own_slot = roster.iter().position(|k| k.public_key == id.public_key);
+        let own_slot = roster.iter().position(|k| match k {
+            Some(k) => k.public_key == id.public_key,
+            None => false,
+        });
         assert!(own_slot.is_some());
         let tree =
             Tree::new_from_public_keys(&welcome.tree, own_slot.unwrap() * 2, &welcome.leaf_secret);
@@ -137,19 +525,48 @@ impl Group {
             group_epoch: welcome.epoch,
             init_secret: welcome.init_secret.clone(),
             epoch_secrets: None,
+            // The legacy Welcome carries no capabilities
+            capabilities: vec![None; roster.len()],
             roster,
             tree,
             update_secret: None,
-            transcript: welcome.transcript.clone(),
+            proposed_leaf_secret: None,
+            proposed_identity: None,
+            confirmed_transcript_hash: Vec::new(),
+            interim_transcript_hash: welcome.interim_transcript_hash.clone(),
+            pending_psks: Vec::new(),
+            pending_proposals: Vec::new(),
+            cipher_suite: AES128GCM_CURVE25519_SHA256,
+            version: welcome.version,
+            reinit: None,
+            add_position: AddPosition::default(),
+            max_members: DEFAULT_MAX_MEMBERS,
+            ratchets: Vec::new(),
+            past_epochs: Vec::new(),
+            credential_validator: Arc::new(BasicCredentialValidator),
+            extensions: Vec::new(),
         }
     }
-    pub fn create_add(&mut self, id: BasicCredential, init_key: &UserInitKey) -> (Welcome, Add) {
-        assert!(init_key.self_verify());
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn create_add(
+        &mut self,
+        id: BasicCredential,
+        init_key: &UserInitKey,
+    ) -> Result<(Welcome, Add), MelissaError> {
+        self.create_add_with_rng(id, init_key, &mut SystemRng)
+    }
+    pub fn create_add_with_rng(
+        &mut self,
+        id: BasicCredential,
+        init_key: &UserInitKey,
+        rng: &mut dyn Rng,
+    ) -> Result<(Welcome, Add), MelissaError> {
+        init_key.verify_keys()?;
         let size = self.tree.get_leaf_count() + 1;
         let index = self.tree.get_leaf_count() * 2;
 
-        let leaf_secret = NodeSecret::new_random();
-        let (public_nodes, ciphertexts) = self.tree.encrypt(index, size, leaf_secret);
+        let leaf_secret = NodeSecret::new_with_rng(rng);
+        let (public_nodes, ciphertexts) = self.tree.encrypt(index, size, leaf_secret, rng);
         let public_path = treemath::dirpath(index, size);
         assert_eq!(public_path.len(), public_nodes.len());
 
@@ -161,44 +578,49 @@ impl Group {
         };
 
         let mut welcome_group = self.clone();
-        welcome_group.process_add(&add);
+        welcome_group.process_add(&add)?;
 
         let mut welcome_roster = self.roster.clone();
-        welcome_roster.push(id);
+        welcome_roster.push(Some(id));
 
         let welcome = Welcome {
             group_id: welcome_group.group_id.clone(),
             epoch: welcome_group.group_epoch,
             roster: welcome_group.roster.clone(),
             tree: welcome_group.tree.get_public_key_tree(),
-            transcript: welcome_group.transcript.clone(),
+            interim_transcript_hash: welcome_group.interim_transcript_hash.clone(),
             init_secret: welcome_group.get_init_secret(),
             leaf_secret,
-            version: CURRENT_VERSION,
+            version: self.version,
         };
-        (welcome, add)
+        Ok((welcome, add))
     }
-    pub fn process_add(&mut self, add: &Add) {
-        assert!(add.init_key.self_verify());
+    pub fn process_add(&mut self, add: &Add) -> Result<(), MelissaError> {
+        add.init_key.verify_keys()?;
         let size = self.tree.get_leaf_count() + 1;
         let index = self.tree.get_leaf_count() * 2;
         let kem_path = treemath::copath(index, size);
-        assert_eq!(kem_path.len(), add.path.len());
+        if kem_path.len() != add.path.len() {
+            return Err(GroupError::InvalidUpdatePath.into());
+        }
         self.tree
-            .apply_kem_path(index, size, &kem_path, &add.path, &add.nodes);
-        let bc = BasicCredential {
-            identity: vec![],
-            public_key: add.init_key.identity_key,
-        };
-        self.roster.push(bc);
-        self.transcript.push(GroupOperationValue::Add(add.clone()));
+            .apply_kem_path(index, size, &kem_path, &add.path, &add.nodes)
+            .map_err(|_| GroupError::InvalidUpdatePath)?;
+        self.roster.push(Some(add.init_key.credential()));
+        self.capabilities.push(add.init_key.capabilities().ok());
+        self.push_transcript(GroupOperationValue::Add(add.clone()));
         self.rotate_epoch_secret();
+        Ok(())
     }
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
     pub fn create_update(&mut self) -> Update {
+        self.create_update_with_rng(&mut SystemRng)
+    }
+    pub fn create_update_with_rng(&mut self, rng: &mut dyn Rng) -> Update {
         let own_leaf_index = self.tree.get_own_leaf_index();
         let size = self.tree.get_leaf_count();
-        let leaf_secret = NodeSecret::new_random();
-        let (nodes, ciphertexts) = self.tree.encrypt(own_leaf_index, size, leaf_secret);
+        let leaf_secret = NodeSecret::new_with_rng(rng);
+        let (nodes, ciphertexts) = self.tree.encrypt(own_leaf_index, size, leaf_secret, rng);
         let update = Update {
             nodes,
             path: ciphertexts,
@@ -209,7 +631,13 @@ impl Group {
         self.update_secret = Some((hash, leaf_secret));
         update
     }
-    pub fn process_update(&mut self, sender: usize, update: &Update) {
+    pub fn process_update(&mut self, sender: usize, update: &Update) -> Result<(), MelissaError> {
+        self.apply_update(sender, update)?;
+        self.push_transcript(GroupOperationValue::Update(update.clone()));
+        self.rotate_epoch_secret();
+        Ok(())
+    }
+    fn apply_update(&mut self, sender: usize, update: &Update) -> Result<(), GroupError> {
         let size = self.tree.get_leaf_count();
         let index = sender * 2;
         let kem_path = treemath::copath(index, size);
@@ -221,52 +649,1079 @@ impl Group {
                 let nodes = Tree::hash_up(index, size, &node_secret);
                 let mut merge_path = treemath::dirpath(index, size);
                 merge_path.push(treemath::root(size));
-                self.tree.merge(merge_path, &nodes);
+                self.tree
+                    .merge(merge_path, &nodes)
+                    .map_err(|_| GroupError::InvalidUpdatePath)?;
             } else {
                 self.tree
-                    .apply_kem_path(index, size, &kem_path, &update.path, &update.nodes);
+                    .apply_kem_path(index, size, &kem_path, &update.path, &update.nodes)
+                    .map_err(|_| GroupError::InvalidUpdatePath)?;
             }
         } else {
             self.tree
-                .apply_kem_path(index, size, &kem_path, &update.path, &update.nodes);
+                .apply_kem_path(index, size, &kem_path, &update.path, &update.nodes)
+                .map_err(|_| GroupError::InvalidUpdatePath)?;
         }
         self.update_secret = None;
-        self.transcript
-            .push(GroupOperationValue::Update(update.clone()));
+        Ok(())
+    }
+    // Ends the group. Once the ReInit is processed no further operations or application
+    // messages should be sent in this group; members continue in the successor created
+    // with from_reinit().
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn reinit(
+        &mut self,
+        cipher_suite: CipherSuite,
+        version: ProtocolVersion,
+    ) -> Result<ReInitCommit, MelissaError> {
+        self.reinit_with_rng(cipher_suite, version, &mut SystemRng)
+    }
+    pub fn reinit_with_rng(
+        &mut self,
+        cipher_suite: CipherSuite,
+        version: ProtocolVersion,
+        rng: &mut dyn Rng,
+    ) -> Result<ReInitCommit, MelissaError> {
+        if cipher_suite != AES128GCM_CURVE25519_SHA256 {
+            return Err(GroupError::UnsupportedCipherSuite.into());
+        }
+        if version != CURRENT_VERSION {
+            return Err(GroupError::UnsupportedVersion.into());
+        }
+        let reinit = ReInit {
+            group_id: GroupId::random_with_rng(rng),
+            version,
+            cipher_suite,
+        };
+        Ok(ReInitCommit {
+            reinit,
+            update: self.create_update_with_rng(rng),
+        })
+    }
+    pub fn process_reinit(
+        &mut self,
+        sender: usize,
+        reinit_commit: &ReInitCommit,
+    ) -> Result<(), MelissaError> {
+        self.apply_update(sender, &reinit_commit.update)?;
+        self.push_transcript(GroupOperationValue::ReInit(reinit_commit.clone()));
+        self.reinit = Some(reinit_commit.reinit.clone());
         self.rotate_epoch_secret();
+        Ok(())
+    }
+    // The successor keeps the tree and roster of the old group. It is bound to the final
+    // epoch of the old group by a resumption PSK that is mixed into its first epoch.
+    pub fn from_reinit(group: &Group) -> Result<Group, MelissaError> {
+        let reinit = match group.reinit {
+            Some(ref reinit) => reinit.clone(),
+            None => return Err(GroupError::MissingReInit.into()),
+        };
+        let resumption_secret = match group.epoch_secrets {
+            Some(ref epoch_secrets) => epoch_secrets.resumption_secret.to_vec(),
+            None => return Err(GroupError::MissingEpochSecrets.into()),
+        };
+        let psk_id = PreSharedKeyID {
+            psk: Psk::Resumption {
+                usage: ResumptionPskUsage::Reinit,
+                group_id: group.group_id.0.to_vec(),
+                epoch: u64::from(group.group_epoch),
+            },
+            psk_nonce: vec![],
+        };
+        let mut tree = group.tree.clone();
+        tree.hash_algorithm = supported_hash_algorithm(reinit.cipher_suite)?;
+        Ok(Group {
+            id: group.id.clone(),
+            group_id: reinit.group_id,
+            group_epoch: 0,
+            init_secret: InitSecret::from_bytes(&[0u8; 32]),
+            epoch_secrets: None,
+            roster: group.roster.clone(),
+            capabilities: group.capabilities.clone(),
+            tree,
+            update_secret: None,
+            proposed_leaf_secret: None,
+            proposed_identity: None,
+            confirmed_transcript_hash: Vec::new(),
+            interim_transcript_hash: Vec::new(),
+            pending_psks: vec![(psk_id, resumption_secret)],
+            pending_proposals: Vec::new(),
+            cipher_suite: reinit.cipher_suite,
+            version: reinit.version,
+            reinit: None,
+            add_position: group.add_position,
+            max_members: group.max_members,
+            ratchets: Vec::new(),
+            past_epochs: Vec::new(),
+            credential_validator: Arc::new(BasicCredentialValidator),
+            extensions: Vec::new(),
+        })
     }
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
     pub fn create_remove(&self, participant: usize) -> Remove {
+        self.create_remove_with_rng(participant, &mut SystemRng)
+    }
+    pub fn create_remove_with_rng(&self, participant: usize, rng: &mut dyn Rng) -> Remove {
         assert!(participant <= self.tree.get_leaf_count());
         let index = participant * 2;
         assert!(index != self.tree.get_own_leaf_index());
         let size = self.tree.get_leaf_count();
-        let leaf_secret = NodeSecret::new_random();
-        let (nodes, ciphertexts) = self.tree.encrypt(index, size, leaf_secret);
+        let leaf_secret = NodeSecret::new_with_rng(rng);
+        let (nodes, ciphertexts) = self.tree.encrypt(index, size, leaf_secret, rng);
         Remove {
             removed: participant,
             nodes,
             path: ciphertexts,
         }
     }
-    pub fn process_remove(&mut self, remove: &Remove) {
+    pub fn process_remove(&mut self, remove: &Remove) -> Result<(), MelissaError> {
         let index = remove.removed * 2; // FIXME should be checked against the roster
         if index != self.tree.own_leaf_index {
             let size = self.tree.get_leaf_count();
+            if !treemath::in_range(index, size) {
+                return Err(GroupError::InvalidProposal.into());
+            }
             let kem_path = treemath::copath(index, size);
-            assert_eq!(kem_path.len(), remove.path.len());
+            if kem_path.len() != remove.path.len() {
+                return Err(GroupError::InvalidUpdatePath.into());
+            }
             self.tree
-                .apply_kem_path(index, size, &kem_path, &remove.path, &remove.nodes);
-            self.transcript
-                .push(GroupOperationValue::Remove(remove.clone()));
-            self.roster.remove(index);
+                .apply_kem_path(index, size, &kem_path, &remove.path, &remove.nodes)
+                .map_err(|_| GroupError::InvalidUpdatePath)?;
+            self.push_transcript(GroupOperationValue::Remove(remove.clone()));
+            self.roster[remove.removed] = None;
+            self.capabilities[remove.removed] = None;
             self.rotate_epoch_secret();
         }
+        Ok(())
+    }
+    // Where committed Adds place new members. Every member has to use the same policy,
+    // otherwise their trees diverge.
+    pub fn set_add_position(&mut self, position: AddPosition) {
+        self.add_position = position;
+    }
+    // Part of the group context, all members have to set the same external senders
+    pub fn set_external_senders(&mut self, external_senders: &ExternalSendersExtension) {
+        self.extensions
+            .retain(|e| e.extension_type != EXTENSION_TYPE_EXTERNAL_SENDERS);
+        self.extensions.push(external_senders.to_extension());
+    }
+    // The current group context extensions with required_capabilities replaced. Every
+    // member has to support them by the time a commit applies the proposal.
+    pub fn propose_required_capabilities(
+        &self,
+        required: &RequiredCapabilitiesExtension,
+    ) -> Proposal {
+        let mut extensions = self.extensions.clone();
+        extensions.retain(|e| e.extension_type != EXTENSION_TYPE_REQUIRED_CAPABILITIES);
+        extensions.push(required.to_extension());
+        Proposal::GroupContextExtensions(extensions)
+    }
+    pub fn set_max_members(&mut self, max_members: usize) {
+        self.max_members = max_members;
+    }
+    // The new leaf key is installed once a commit covering the proposal is processed
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn propose_update(&mut self) -> Proposal {
+        self.propose_update_with_rng(&mut SystemRng)
+    }
+    pub fn propose_update_with_rng(&mut self, rng: &mut dyn Rng) -> Proposal {
+        let leaf_secret = NodeSecret::new_with_rng(rng);
+        let public_key = X25519KeyPair::new_from_secret(&leaf_secret).public_key;
+        self.proposed_leaf_secret = Some(leaf_secret);
+        Proposal::Update(UserInitKey::new(&[public_key], &self.id))
+    }
+    // Rotates the signature key of the own leaf without touching its HPKE key. The new
+    // identity signs from the commit that applies the proposal on.
+    pub fn update_leaf_credential(
+        &mut self,
+        credential: BasicCredential,
+        id: Identity,
+    ) -> Result<Proposal, MelissaError> {
+        if credential.public_key != id.public_key {
+            return Err(GroupError::InvalidProposal.into());
+        }
+        if self.roster.iter().any(|member| match member {
+            Some(member) => member.public_key == credential.public_key,
+            None => false,
+        }) {
+            return Err(GroupError::DuplicateKey.into());
+        }
+        let leaf = self.tree.get_own_leaf_index() / 2;
+        let proposal = Proposal::CredentialUpdate(CredentialUpdate::new(
+            leaf as u32,
+            credential,
+            &self.id,
+            &id,
+        ));
+        self.proposed_identity = Some(id);
+        Ok(proposal)
+    }
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn commit(
+        &mut self,
+        proposals: &[Proposal],
+    ) -> Result<(Commit, Option<CommitWelcome>), MelissaError> {
+        self.commit_with_rng(proposals, CommitOptions::default(), &mut SystemRng)
+    }
+    // Keeps a proposal that was sent on its own until a commit refers to it. Pending
+    // proposals only hold for the current epoch.
+    pub fn store_proposal(&mut self, proposal: &Proposal) -> ProposalRef {
+        let proposal_ref = ProposalRef::from_proposal(proposal);
+        if !self
+            .pending_proposals
+            .iter()
+            .any(|(r, _)| *r == proposal_ref)
+        {
+            self.pending_proposals
+                .push((proposal_ref, proposal.clone()));
+        }
+        proposal_ref
+    }
+    // Members may propose anything, external senders listed in the external_senders
+    // extension only adds and removes. A new member can only propose its own add, signed
+    // with the key of its key package. Valid proposals are stored for a later commit.
+    pub fn process_proposal(
+        &mut self,
+        signed_proposal: &SignedProposal,
+    ) -> Result<ProposalRef, MelissaError> {
+        if signed_proposal.group_id != self.group_id || signed_proposal.epoch != self.group_epoch {
+            return Err(GroupError::InvalidProposal.into());
+        }
+        let signer = match signed_proposal.sender {
+            Sender::Member(leaf) => match self.roster.get(leaf as usize) {
+                Some(Some(credential)) => credential.clone(),
+                _ => return Err(GroupError::UnauthorizedSender.into()),
+            },
+            Sender::External(index) => {
+                match signed_proposal.proposal {
+                    Proposal::Add(_) | Proposal::Remove(_) => (),
+                    _ => return Err(GroupError::InvalidProposal.into()),
+                }
+                let external_senders =
+                    match ExternalSendersExtension::from_extensions(&self.extensions) {
+                        Ok(Some(extension)) => extension.senders,
+                        Ok(None) => return Err(GroupError::UnauthorizedSender.into()),
+                        Err(_) => return Err(GroupError::InvalidExtension.into()),
+                    };
+                match external_senders.get(index as usize) {
+                    Some(credential) => credential.clone(),
+                    None => return Err(GroupError::UnauthorizedSender.into()),
+                }
+            }
+            Sender::NewMemberProposal => match signed_proposal.proposal {
+                Proposal::Add(ref init_key) if init_key.verify_keys().is_ok() => BasicCredential {
+                    identity: Vec::new(),
+                    public_key: init_key.identity_key,
+                },
+                _ => return Err(GroupError::InvalidProposal.into()),
+            },
+            // Only external commits are sent as a new member
+            Sender::NewMemberCommit => return Err(GroupError::UnauthorizedSender.into()),
+        };
+        if !signer.verify(&signed_proposal.tbs_bytes(), &signed_proposal.signature) {
+            return Err(GroupError::UnauthorizedSender.into());
+        }
+        if let Proposal::Add(ref init_key) = signed_proposal.proposal {
+            self.check_capabilities(init_key)?;
+        }
+        Ok(self.store_proposal(&signed_proposal.proposal))
+    }
+    // Commits the stored proposals in the order of the references
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn commit_by_reference(
+        &mut self,
+        refs: &[ProposalRef],
+    ) -> Result<(Commit, Option<CommitWelcome>), MelissaError> {
+        self.commit_by_reference_with_rng(refs, &mut SystemRng)
+    }
+    pub fn commit_by_reference_with_rng(
+        &mut self,
+        refs: &[ProposalRef],
+        rng: &mut dyn Rng,
+    ) -> Result<(Commit, Option<CommitWelcome>), MelissaError> {
+        let mut proposals = Vec::new();
+        for proposal_ref in refs {
+            match self
+                .pending_proposals
+                .iter()
+                .find(|(r, _)| r == proposal_ref)
+            {
+                Some((_, proposal)) => proposals.push(proposal.clone()),
+                None => return Err(GroupError::UnknownProposalRef.into()),
+            }
+        }
+        self.commit_with_rng(&proposals, CommitOptions::default(), rng)
+    }
+    // Applies all proposals in one epoch change and sends a fresh path for the committer
+    // unless it can be left out. New members get the next init secret in the Welcome, and
+    // the path secret of their lowest common ancestor with the committer if there's a path.
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn commit_with_options(
+        &mut self,
+        proposals: &[Proposal],
+        options: CommitOptions,
+    ) -> Result<(Commit, Option<CommitWelcome>), MelissaError> {
+        self.commit_with_rng(proposals, options, &mut SystemRng)
+    }
+    pub fn commit_with_rng(
+        &mut self,
+        proposals: &[Proposal],
+        options: CommitOptions,
+        rng: &mut dyn Rng,
+    ) -> Result<(Commit, Option<CommitWelcome>), MelissaError> {
+        let committer = self.tree.get_own_leaf_index() / 2;
+        let mut provisional = self.clone();
+        let added = provisional.apply_proposals(committer, proposals)?;
+
+        let size = provisional.tree.get_leaf_count();
+        let index = self.tree.get_own_leaf_index();
+        let (path, leaf_secret) = if options.force_path || path_required(proposals) {
+            let leaf_secret = NodeSecret::new_with_rng(rng);
+            let path_nodes = Tree::hash_up(index, size, &leaf_secret);
+            let path_secrets: Vec<NodeSecret> = path_nodes
+                .iter()
+                .skip(1)
+                .map(|node| node.secret.unwrap())
+                .collect();
+            let path = UpdatePath {
+                leaf_key: path_nodes[0].dh_public_key.unwrap(),
+                nodes: encrypt_path_secrets(
+                    &provisional.tree,
+                    committer,
+                    &path_secrets,
+                    &added,
+                    rng,
+                )?,
+            };
+            (Some(path), Some(leaf_secret))
+        } else {
+            (None, None)
+        };
+        let mut commit = Commit {
+            group_id: self.group_id.clone(),
+            epoch: self.group_epoch + 1,
+            sender: Sender::Member(committer as u32),
+            proposals: proposals.to_vec(),
+            path,
+            signature: Signature::from_slice(&[0u8; ed25519::SIGNATUREBYTES]).unwrap(),
+            confirmation_tag: Vec::new(),
+        };
+        commit.sign(&self.id);
+        if let Some(leaf_secret) = leaf_secret {
+            self.update_secret = Some((commit_hash(&commit), leaf_secret));
+        }
+        // The tag is over the transcript of the new epoch, so we apply the commit first
+        let group = self.apply_commit(&commit)?;
+        commit.confirmation_tag = group.confirmation_tag()?;
+        if added.is_empty() {
+            return Ok((commit, None));
+        }
+
+        let init_keys: Vec<UserInitKey> = proposals
+            .iter()
+            .filter_map(|proposal| match proposal {
+                Proposal::Add(init_key) => Some(init_key.clone()),
+                _ => None,
+            })
+            .collect();
+        let welcome = group.welcomes_for_with_rng(&commit, &init_keys, rng)?;
+        Ok((commit, Some(welcome)))
+    }
+    // A commit without proposals that re-keys the whole direct path of the committer, so
+    // none of its nodes stay blank and their resolutions shrink back to one node. Blanks
+    // in other subtrees can only be filled by a commit from a member below them.
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn full_rekey(&mut self) -> Result<Commit, MelissaError> {
+        self.full_rekey_with_rng(&mut SystemRng)
+    }
+    pub fn full_rekey_with_rng(&mut self, rng: &mut dyn Rng) -> Result<Commit, MelissaError> {
+        let options = CommitOptions { force_path: true };
+        let (commit, _) = self.commit_with_rng(&[], options, rng)?;
+        Ok(commit)
+    }
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn welcomes_for(
+        &self,
+        commit: &Commit,
+        added: &[UserInitKey],
+    ) -> Result<CommitWelcome, MelissaError> {
+        self.welcomes_for_with_rng(commit, added, &mut SystemRng)
+    }
+    // One Welcome for all members added by our own commit, once we processed it. They
+    // share the group info, each gets the next init secret encrypted to its key package,
+    // and the path secret of its lowest common ancestor with us if the commit had a path.
+    pub fn welcomes_for_with_rng(
+        &self,
+        commit: &Commit,
+        added: &[UserInitKey],
+        rng: &mut dyn Rng,
+    ) -> Result<CommitWelcome, MelissaError> {
+        if commit.epoch != self.group_epoch {
+            return Err(GroupError::InvalidCommit.into());
+        }
+        let size = self.tree.get_leaf_count();
+        let index = self.tree.get_own_leaf_index();
+        let mut merge_path = treemath::dirpath(index, size);
+        merge_path.push(treemath::root(size));
+        let mut secrets = Vec::new();
+        for init_key in added {
+            let leaf = match self
+                .tree
+                .occupied_leaves()
+                .find(|(_, _, node)| node.dh_public_key == Some(init_key.init_keys[0]))
+            {
+                Some((_, leaf, _)) => leaf,
+                None => return Err(GroupError::InvalidWelcome.into()),
+            };
+            let mut joiner_path = treemath::dirpath(leaf, size);
+            joiner_path.push(treemath::root(size));
+            let ancestor = match joiner_path.into_iter().find(|x| merge_path.contains(x)) {
+                Some(ancestor) => ancestor,
+                None => return Err(GroupError::InvalidTree.into()),
+            };
+            let group_secrets = GroupSecrets {
+                init_secret: self.get_init_secret(),
+                path_secret: match commit.path {
+                    Some(_) => {
+                        self.tree
+                            .node(ancestor)
+                            .map_err(|_| GroupError::InvalidTree)?
+                            .secret
+                    }
+                    None => None,
+                },
+            };
+            let encrypted_group_secrets = HpkeCiphertext::encrypt_with_rng(
+                &init_key.init_keys[0],
+                &group_secrets.encode_detached(),
+                rng,
+            )
+            .map_err(|_| GroupError::InvalidWelcome)?;
+            secrets.push(EncryptedGroupSecrets {
+                key_package_hash: init_key.hash(),
+                encrypted_group_secrets,
+            });
+        }
+        Ok(CommitWelcome {
+            secrets,
+            group_info: self.group_info()?,
+        })
+    }
+    // The leaf of the member that signed the commit, checked against the roster of the
+    // epoch the commit is sent in
+    fn commit_sender(&self, commit: &Commit) -> Result<usize, MelissaError> {
+        if commit.group_id != self.group_id {
+            return Err(GroupError::InvalidCommit.into());
+        }
+        let leaf = match commit.sender {
+            Sender::Member(leaf) => leaf as usize,
+            // External senders may only propose, and new members join with an
+            // ExternalCommit instead
+            Sender::External(_) | Sender::NewMemberProposal | Sender::NewMemberCommit => {
+                return Err(GroupError::UnauthorizedSender.into())
+            }
+        };
+        match self.roster.get(leaf) {
+            Some(Some(credential)) if credential.verify(&commit.tbs_bytes(), &commit.signature) => {
+                Ok(leaf)
+            }
+            _ => Err(GroupError::UnauthorizedSender.into()),
+        }
+    }
+    // Every member computes the confirmation tag of the new epoch on its own, so a tag
+    // that differs from the committer's means they ended up in different states
+    pub fn process_commit(&mut self, commit: &Commit) -> Result<(), MelissaError> {
+        let group = self.apply_commit(commit)?;
+        if group.confirmation_tag()? != commit.confirmation_tag {
+            return Err(GroupError::InvalidConfirmationTag.into());
+        }
+        *self = group;
+        Ok(())
+    }
+    // The group as it is after the commit, with self left untouched
+    fn apply_commit(&self, commit: &Commit) -> Result<Group, MelissaError> {
+        // A replayed commit must not roll the group back
+        if commit.epoch != self.group_epoch + 1 {
+            return Err(GroupError::WrongEpoch {
+                expected: self.group_epoch + 1,
+                got: commit.epoch,
+            }
+            .into());
+        }
+        let sender = self.commit_sender(commit)?;
+        let mut group = self.clone();
+        let added = group.apply_proposals(sender, &commit.proposals)?;
+        let own_leaf = group.tree.get_own_leaf_index() as u32 / 2;
+        if commit.proposals.iter().any(|proposal| match proposal {
+            Proposal::Remove(removed) => *removed == own_leaf,
+            _ => false,
+        }) {
+            return Err(GroupError::Removed.into());
+        }
+
+        let path = match commit.path {
+            Some(ref path) => path,
+            None if path_required(&commit.proposals) => {
+                return Err(GroupError::InvalidCommit.into())
+            }
+            None => {
+                group.update_secret = None;
+                group.push_transcript(GroupOperationValue::Commit(commit.unconfirmed()));
+                group.rotate_epoch_secret_without_path();
+                return Ok(group);
+            }
+        };
+        let size = group.tree.get_leaf_count();
+        let index = sender * 2;
+        if path.nodes.len() != treemath::dirpath_len(index, size) {
+            return Err(GroupError::InvalidCommit.into());
+        }
+        let mut merge_path = treemath::dirpath(index, size);
+        merge_path.push(treemath::root(size));
+        match group.update_secret {
+            Some((stored_hash, leaf_secret)) if stored_hash == commit_hash(commit) => {
+                group
+                    .tree
+                    .merge(merge_path, &Tree::hash_up(index, size, &leaf_secret))
+                    .map_err(|_| GroupError::InvalidCommit)?;
+            }
+            _ => {
+                let (node, path_secret) = decrypt_path_secret(&group.tree, sender, &added, path)?;
+                let mut secret_path = treemath::dirpath(node, size);
+                secret_path.push(treemath::root(size));
+                let secret_nodes = Tree::hash_up(node, size, &path_secret);
+                let mut public_nodes = vec![Node::new_from_public_key(&path.leaf_key)];
+                for node in path.nodes.iter() {
+                    public_nodes.push(Node::new_from_public_key(&node.public_key));
+                }
+                // The sender must have put the keys of its path secrets in the path
+                for (x, secret_node) in secret_path.iter().zip(secret_nodes.iter()) {
+                    let position = merge_path.iter().position(|y| y == x).unwrap();
+                    if public_nodes[position].dh_public_key != secret_node.dh_public_key {
+                        return Err(GroupError::InvalidCommit.into());
+                    }
+                }
+                group
+                    .tree
+                    .merge(merge_path, &public_nodes)
+                    .map_err(|_| GroupError::InvalidCommit)?;
+                group
+                    .tree
+                    .merge(secret_path, &secret_nodes)
+                    .map_err(|_| GroupError::InvalidCommit)?;
+            }
+        }
+        group.update_secret = None;
+        group.push_transcript(GroupOperationValue::Commit(commit.unconfirmed()));
+        group.rotate_epoch_secret();
+        Ok(group)
+    }
+    pub fn from_welcome(
+        id: Identity,
+        init_key_bundle: &UserInitKeyBundle,
+        welcome: &CommitWelcome,
+    ) -> Result<Group, MelissaError> {
+        Group::from_welcome_with_validator(
+            id,
+            init_key_bundle,
+            welcome,
+            Arc::new(BasicCredentialValidator),
+        )
+    }
+    // Every credential in the Welcome's roster has to pass the validator, which the
+    // group then keeps for later Adds
+    pub fn from_welcome_with_validator(
+        id: Identity,
+        init_key_bundle: &UserInitKeyBundle,
+        welcome: &CommitWelcome,
+        credential_validator: Arc<dyn CredentialValidator>,
+    ) -> Result<Group, MelissaError> {
+        init_key_bundle.init_key.verify_keys()?;
+        let key_package_hash = init_key_bundle.init_key.hash();
+        let encrypted_group_secrets = match welcome
+            .secrets
+            .iter()
+            .find(|s| s.key_package_hash == key_package_hash)
+        {
+            Some(secrets) => &secrets.encrypted_group_secrets,
+            None => return Err(GroupError::InvalidWelcome.into()),
+        };
+        let group_secrets =
+            HpkeCiphertext::decrypt(init_key_bundle.private_key(), encrypted_group_secrets)
+                .map_err(|_| GroupError::InvalidWelcome)?;
+        let group_secrets = GroupSecrets::decode_detached(&group_secrets)
+            .map_err(|_| GroupError::InvalidWelcome)?;
+
+        let group_info = &welcome.group_info;
+        if !group_info.verify(&group_info.roster)
+            || !capabilities_match_roster(&group_info.roster, &group_info.capabilities)
+        {
+            return Err(GroupError::InvalidWelcome.into());
+        }
+        if !init_key_bundle
+            .init_key
+            .supported_versions
+            .contains(&group_info.version)
+        {
+            return Err(GroupError::UnsupportedVersion.into());
+        }
+        if !init_key_bundle
+            .init_key
+            .cipher_suites
+            .contains(&group_info.cipher_suite)
+        {
+            return Err(GroupError::UnsupportedCipherSuite.into());
+        }
+        let hash_algorithm = supported_hash_algorithm(group_info.cipher_suite)?;
+        if group_info.tree.len() > treemath::node_width(DEFAULT_MAX_MEMBERS) {
+            return Err(GroupError::GroupTooLarge.into());
+        }
+        let public_key = init_key_bundle.init_key.init_keys[0];
+        let own_leaf_index = match (0..group_info.tree.len())
+            .step_by(2)
+            .find(|x| group_info.tree[*x] == Some(public_key))
+        {
+            Some(index) => index,
+            None => return Err(GroupError::InvalidWelcome.into()),
+        };
+        let nodes = group_info_nodes(group_info).ok_or(GroupError::InvalidWelcome)?;
+        let context = group_info_context(group_info, hash_algorithm);
+        let extensions = context.extensions.clone();
+        let mut tree = Tree::import_and_validate(
+            nodes,
+            own_leaf_index,
+            &group_info.roster,
+            &*credential_validator,
+            &context,
+        )
+        .map_err(|error| match error {
+            TreeError::InvalidCredential(error) => GroupError::InvalidCredential(error),
+            _ => GroupError::InvalidWelcome,
+        })?;
+        tree.node_mut(own_leaf_index)
+            .map_err(|_| GroupError::InvalidWelcome)?
+            .dh_private_key = Some(init_key_bundle.private_key().clone());
+
+        // The path secret belongs to the ancestor whose public key it derives
+        if let Some(path_secret) = group_secrets.path_secret {
+            let size = tree.get_leaf_count();
+            let public_key = X25519KeyPair::new_from_secret(&path_secret).public_key;
+            let mut own_path = treemath::dirpath(own_leaf_index, size);
+            own_path.push(treemath::root(size));
+            let ancestor = match own_path.into_iter().find(|x| {
+                tree.node(*x).ok().and_then(|node| node.dh_public_key) == Some(public_key)
+            }) {
+                Some(ancestor) => ancestor,
+                None => return Err(GroupError::InvalidWelcome.into()),
+            };
+            let mut merge_path = treemath::dirpath(ancestor, size);
+            merge_path.push(treemath::root(size));
+            tree.merge(merge_path, &Tree::hash_up(ancestor, size, &path_secret))
+                .map_err(|_| GroupError::InvalidWelcome)?;
+        }
+
+        check_duplicate_keys(&group_info.roster, &tree)?;
+        Ok(Group {
+            id,
+            group_id: group_info.group_id.clone(),
+            group_epoch: group_info.epoch,
+            init_secret: group_secrets.init_secret,
+            epoch_secrets: None,
+            roster: group_info.roster.clone(),
+            capabilities: group_info.capabilities.clone(),
+            tree,
+            update_secret: None,
+            proposed_leaf_secret: None,
+            proposed_identity: None,
+            confirmed_transcript_hash: group_info.confirmed_transcript_hash.clone(),
+            interim_transcript_hash: interim_transcript_hash(
+                hash_algorithm,
+                &group_info.confirmed_transcript_hash,
+                &group_info.confirmation_tag,
+            ),
+            pending_psks: Vec::new(),
+            pending_proposals: Vec::new(),
+            cipher_suite: group_info.cipher_suite,
+            version: group_info.version,
+            reinit: None,
+            add_position: AddPosition::default(),
+            max_members: DEFAULT_MAX_MEMBERS,
+            ratchets: Vec::new(),
+            past_epochs: Vec::new(),
+            credential_validator,
+            extensions,
+        })
+    }
+    // Proposals are applied removes first, then updates, then adds. Removes and updates
+    // touch distinct leaves, so only the order of the adds matters for the resulting tree.
+    // Returns the node indices of the added leaves.
+    fn apply_proposals(
+        &mut self,
+        committer: usize,
+        proposals: &[Proposal],
+    ) -> Result<Vec<usize>, GroupError> {
+        validate_proposal_list(proposals, &self.roster, &self.tree, committer)?;
+        let mut removes = Vec::new();
+        let mut updates = Vec::new();
+        let mut adds = Vec::new();
+        let mut credential_updates = Vec::new();
+        let mut reinit = None;
+        let mut extensions = None;
+        for proposal in proposals {
+            match proposal {
+                Proposal::Remove(removed) => removes.push(*removed as usize),
+                Proposal::Update(init_key) => {
+                    if init_key.verify(&SystemClock).is_err() {
+                        return Err(GroupError::InvalidProposal);
+                    }
+                    let leaf = match self.roster.iter().position(|k| match k {
+                        Some(k) => k.public_key == init_key.identity_key,
+                        None => false,
+                    }) {
+                        Some(leaf) => leaf,
+                        None => return Err(GroupError::InvalidProposal),
+                    };
+                    self.check_capabilities(init_key)?;
+                    updates.push((leaf, init_key));
+                }
+                Proposal::Add(init_key) => {
+                    if init_key.verify(&SystemClock).is_err() {
+                        return Err(GroupError::InvalidProposal);
+                    }
+                    self.check_capabilities(init_key)?;
+                    adds.push(init_key);
+                }
+                // The PSK itself has to be known to every member through add_psk()
+                Proposal::PreSharedKey(psk_id) => {
+                    if !self.pending_psks.iter().any(|(id, _)| id == psk_id) {
+                        return Err(GroupError::InvalidProposal);
+                    }
+                }
+                Proposal::ReInit(proposed) => {
+                    if proposed.cipher_suite != AES128GCM_CURVE25519_SHA256
+                        || proposed.version != CURRENT_VERSION
+                    {
+                        return Err(GroupError::InvalidProposal);
+                    }
+                    reinit = Some(proposed.clone());
+                }
+                Proposal::ExternalInit(_) => (),
+                Proposal::CredentialUpdate(credential_update) => {
+                    let leaf = credential_update.leaf as usize;
+                    match self.roster.get(leaf) {
+                        Some(Some(credential)) if credential_update.verify(credential) => (),
+                        _ => return Err(GroupError::InvalidProposal),
+                    }
+                    credential_updates.push((leaf, credential_update.credential.clone()));
+                }
+                Proposal::GroupContextExtensions(proposed) => extensions = Some(proposed),
+            }
+        }
+        if !adds.is_empty() || !credential_updates.is_empty() {
+            let context = self.context();
+            for init_key in &adds {
+                self.credential_validator
+                    .validate(&init_key.credential(), &context)
+                    .map_err(GroupError::InvalidCredential)?;
+            }
+            for (_, credential) in &credential_updates {
+                self.credential_validator
+                    .validate(credential, &context)
+                    .map_err(GroupError::InvalidCredential)?;
+            }
+        }
+        removes.sort();
+        updates.sort_by_key(|(leaf, _)| *leaf);
+
+        for leaf in removes {
+            self.tree
+                .blank_up(leaf * 2)
+                .map_err(|_| GroupError::InvalidProposal)?;
+            self.roster[leaf] = None;
+            self.capabilities[leaf] = None;
+        }
+        self.tree.truncate();
+        let size = self.tree.get_leaf_count();
+        self.roster.truncate(size);
+        self.capabilities.truncate(size);
+
+        for (leaf, init_key) in updates {
+            let index = leaf * 2;
+            let public_key = init_key.init_keys[0];
+            let node = if index == self.tree.get_own_leaf_index() {
+                match self.proposed_leaf_secret.take() {
+                    Some(ref secret)
+                        if X25519KeyPair::new_from_secret(secret).public_key == public_key =>
+                    {
+                        Node::from_secret(secret)
+                    }
+                    _ => return Err(GroupError::InvalidProposal),
+                }
+            } else {
+                Node::new_from_public_key(&public_key)
+            };
+            self.tree
+                .blank_up(index)
+                .map_err(|_| GroupError::InvalidProposal)?;
+            *self
+                .tree
+                .node_mut(index)
+                .map_err(|_| GroupError::InvalidProposal)? = node;
+            self.capabilities[leaf] = init_key.capabilities().ok();
+        }
+
+        for (leaf, credential) in credential_updates {
+            if leaf * 2 == self.tree.get_own_leaf_index() {
+                match self.proposed_identity.take() {
+                    Some(id) if id.public_key == credential.public_key => self.id = id,
+                    _ => return Err(GroupError::InvalidProposal),
+                }
+            }
+            self.roster[leaf] = Some(credential);
+        }
+
+        let mut added = Vec::new();
+        for init_key in adds {
+            let node = Node::new_from_public_key(&init_key.init_keys[0]);
+            let leaf = self
+                .tree
+                .add_leaf(node, self.add_position)
+                .map_err(|_| GroupError::InvalidTree)?;
+            if self.tree.get_leaf_count() > self.max_members {
+                return Err(GroupError::GroupTooLarge);
+            }
+            if leaf == self.roster.len() {
+                self.roster.push(None);
+                self.capabilities.push(None);
+            }
+            let index = leaf * 2;
+            self.roster[leaf] = Some(init_key.credential());
+            self.capabilities[leaf] = init_key.capabilities().ok();
+            added.push(index);
+        }
+        check_duplicate_keys(&self.roster, &self.tree)?;
+        // New requirements hold for the members added in the same commit as well
+        if let Some(extensions) = extensions {
+            self.check_member_capabilities(extensions)?;
+            self.extensions = extensions.clone();
+        }
+        if reinit.is_some() {
+            self.reinit = reinit;
+        }
+        Ok(added)
+    }
+    pub fn group_info(&self) -> Result<GroupInfo, MelissaError> {
+        let external_pub = ExternalPubExtension {
+            external_pub: self.external_key_pair()?.public_key,
+        };
+        let mut group_info = GroupInfo {
+            version: self.version,
+            cipher_suite: self.cipher_suite,
+            group_id: self.group_id.clone(),
+            epoch: self.group_epoch,
+            tree_hash: self.tree.tree_hash(),
+            roster: self.roster.clone(),
+            capabilities: self.capabilities.clone(),
+            tree: self.tree.get_public_key_tree(),
+            unmerged_leaves: self.tree.get_unmerged_leaves(),
+            extensions: self.group_info_extensions(external_pub.to_extension()),
+            confirmed_transcript_hash: self.confirmed_transcript_hash.clone(),
+            confirmation_tag: self.confirmation_tag()?,
+            signer_index: self.tree.get_own_leaf_index() as u32 / 2,
+            signature: Signature::from_slice(&[0u8; ed25519::SIGNATUREBYTES]).unwrap(),
+        };
+        group_info.sign(&self.id);
+        Ok(group_info)
+    }
+    fn group_info_extensions(&self, external_pub: Extension) -> Vec<Extension> {
+        let mut extensions = vec![external_pub];
+        extensions.extend(self.extensions.iter().cloned());
+        extensions
+    }
+    // Checks a GroupInfo against our own view of the current epoch
+    pub fn verify_group_info(&self, group_info: &GroupInfo) -> Result<(), MelissaError> {
+        if group_info.version != self.version
+            || group_info.epoch != self.group_epoch
+            || !group_info.verify(&self.roster)
+            || group_info.confirmation_tag != self.confirmation_tag()?
+        {
+            return Err(GroupError::InvalidGroupInfo.into());
+        }
+        Ok(())
+    }
+    // MAC over the confirmed transcript hash under the confirmation key of the current epoch
+    fn confirmation_tag(&self) -> Result<Vec<u8>, GroupError> {
+        let confirmation_key = match self.epoch_secrets {
+            Some(ref epoch_secrets) => hmacsha256::Key(epoch_secrets.confirmation_key),
+            None => return Err(GroupError::MissingEpochSecrets),
+        };
+        Ok(
+            hmacsha256::authenticate(&self.confirmed_transcript_hash, &confirmation_key)
+                .0
+                .to_vec(),
+        )
+    }
+    // Joins without a Welcome: the joiner adds itself at the right edge of the tree and
+    // derives the init secret from a KEM to the group's external_pub.
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn join_by_external_commit(
+        id: Identity,
+        group_info: &GroupInfo,
+    ) -> Result<(Group, ExternalCommit), MelissaError> {
+        Group::join_by_external_commit_with_rng(id, group_info, &mut SystemRng)
+    }
+    pub fn join_by_external_commit_with_rng(
+        id: Identity,
+        group_info: &GroupInfo,
+        rng: &mut dyn Rng,
+    ) -> Result<(Group, ExternalCommit), MelissaError> {
+        let external_pub = match ExternalPubExtension::from_extensions(&group_info.extensions) {
+            Ok(Some(extension)) => extension.external_pub,
+            Ok(None) => {
+                return Err(GroupError::MissingExtension(EXTENSION_TYPE_EXTERNAL_PUB).into())
+            }
+            Err(_) => return Err(GroupError::InvalidExtension.into()),
+        };
+        if !group_info.verify(&group_info.roster)
+            || !capabilities_match_roster(&group_info.roster, &group_info.capabilities)
+        {
+            return Err(GroupError::InvalidGroupInfo.into());
+        }
+        let hash_algorithm = supported_hash_algorithm(group_info.cipher_suite)?;
+        let ephemeral_key_pair = X25519KeyPair::new_with_rng(rng);
+        let zz = ephemeral_key_pair
+            .private_key
+            .shared_secret(&external_pub)
+            .map_err(|_| GroupError::InvalidKemOutput)?;
+        let kem_output = ephemeral_key_pair.public_key;
+
+        let size = group_info.roster.len() + 1;
+        if size > DEFAULT_MAX_MEMBERS
+            || group_info.tree.len() > treemath::node_width(DEFAULT_MAX_MEMBERS)
+        {
+            return Err(GroupError::GroupTooLarge.into());
+        }
+        let index = group_info.roster.len() * 2;
+        let nodes = group_info_nodes(group_info).ok_or(GroupError::InvalidGroupInfo)?;
+        let mut tree = Tree::import_and_validate_public(
+            nodes,
+            &group_info.roster,
+            &BasicCredentialValidator,
+            &group_info_context(group_info, hash_algorithm),
+        )
+        .map_err(|error| match error {
+            TreeError::InvalidCredential(error) => GroupError::InvalidCredential(error),
+            _ => GroupError::InvalidGroupInfo,
+        })?;
+        tree.own_leaf_index = index;
+        let leaf_secret = NodeSecret::new_with_rng(rng);
+        let (public_nodes, ciphertexts) = tree.encrypt(index, size, leaf_secret, rng);
+        let mut merge_path = treemath::dirpath(index, size);
+        merge_path.push(treemath::root(size));
+        tree.merge(merge_path, &Tree::hash_up(index, size, &leaf_secret))
+            .map_err(|_| GroupError::InvalidTree)?;
+
+        let own_leaf = tree.get_own_leaf().map_err(|_| GroupError::InvalidTree)?;
+        let init_key = UserInitKey::new(&[own_leaf.dh_public_key.unwrap()], &id);
+        let external_commit = ExternalCommit {
+            kem_output,
+            add: Add {
+                nodes: public_nodes,
+                path: ciphertexts,
+                init_key: init_key.clone(),
+                index: 0,
+            },
+        };
+
+        let mut roster = group_info.roster.clone();
+        roster.push(Some(init_key.credential()));
+        let mut capabilities = group_info.capabilities.clone();
+        capabilities.push(init_key.capabilities().ok());
+        let confirmed_transcript_hash = confirmed_transcript_hash(
+            hash_algorithm,
+            &interim_transcript_hash(
+                hash_algorithm,
+                &group_info.confirmed_transcript_hash,
+                &group_info.confirmation_tag,
+            ),
+            &GroupOperationValue::ExternalCommit(external_commit.clone()).encode_detached(),
+        );
+        check_duplicate_keys(&roster, &tree)?;
+        let mut group = Group {
+            id,
+            group_id: group_info.group_id.clone(),
+            group_epoch: group_info.epoch,
+            init_secret: external_init_secret(&zz, &kem_output.to_slice()),
+            epoch_secrets: None,
+            roster,
+            capabilities,
+            tree,
+            update_secret: None,
+            proposed_leaf_secret: None,
+            proposed_identity: None,
+            confirmed_transcript_hash,
+            interim_transcript_hash: Vec::new(),
+            pending_psks: Vec::new(),
+            pending_proposals: Vec::new(),
+            cipher_suite: group_info.cipher_suite,
+            version: group_info.version,
+            reinit: None,
+            add_position: AddPosition::default(),
+            max_members: DEFAULT_MAX_MEMBERS,
+            ratchets: Vec::new(),
+            past_epochs: Vec::new(),
+            credential_validator: Arc::new(BasicCredentialValidator),
+            extensions: group_context_extensions(&group_info.extensions),
+        };
+        group.rotate_epoch_secret();
+        Ok((group, external_commit))
+    }
+    pub fn process_external_commit(
+        &mut self,
+        external_commit: &ExternalCommit,
+    ) -> Result<(), MelissaError> {
+        let zz = self
+            .external_key_pair()?
+            .private_key
+            .shared_secret(&external_commit.kem_output)
+            .map_err(|_| GroupError::InvalidKemOutput)?;
+        let add = &external_commit.add;
+        add.init_key
+            .verify_keys()
+            .map_err(|_| GroupError::InvalidProposal)?;
+        self.check_capabilities(&add.init_key)?;
+        let credential = add.init_key.credential();
+        self.credential_validator
+            .validate(&credential, &self.context())
+            .map_err(GroupError::InvalidCredential)?;
+        let size = self.tree.get_leaf_count() + 1;
+        if size > self.max_members {
+            return Err(GroupError::GroupTooLarge.into());
+        }
+        let index = self.tree.get_leaf_count() * 2;
+        let kem_path = treemath::copath(index, size);
+        // Work on a copy so that a rejected commit leaves the group untouched
+        let mut group = self.clone();
+        group
+            .tree
+            .apply_kem_path(index, size, &kem_path, &add.path, &add.nodes)
+            .map_err(|_| GroupError::InvalidUpdatePath)?;
+        group.roster.push(Some(credential));
+        group.capabilities.push(add.init_key.capabilities().ok());
+        check_duplicate_keys(&group.roster, &group.tree)?;
+        group.push_transcript(GroupOperationValue::ExternalCommit(external_commit.clone()));
+        group.init_secret = external_init_secret(&zz, &external_commit.kem_output.to_slice());
+        group.rotate_epoch_secret();
+        *self = group;
+        Ok(())
     }
     pub fn create_handshake(&self, group_operation: GroupOperation) -> Handshake {
         let signer_index = self.tree.get_own_leaf_index() as u32 / 2;
         let prior_epoch = self.group_epoch;
         let algorithm = ED25519;
         let mut hs = Handshake {
+            version: self.version,
             prior_epoch,
             operation: group_operation,
             signer_index,
@@ -276,14 +1731,25 @@ impl Group {
         hs.signature = Some(hs.sign(&self.id));
         hs
     }
-    pub fn process_handshake(&mut self, hs: Handshake) {
+    pub fn process_handshake(&mut self, hs: Handshake) -> Result<(), MelissaError> {
         let sender = hs.signer_index as usize;
-        assert_eq!(hs.prior_epoch, self.group_epoch);
-        assert_eq!(hs.algorithm, ED25519);
-        assert!(sender < self.roster.len());
-        {
-            let signer = &self.roster[sender];
-            assert!(signer.verify(&hs.unsigned_payload(), &hs.signature.unwrap()));
+        if hs.version != self.version {
+            return Err(GroupError::UnsupportedVersion.into());
+        }
+        if hs.prior_epoch != self.group_epoch {
+            return Err(GroupError::WrongEpoch {
+                expected: self.group_epoch,
+                got: hs.prior_epoch,
+            }
+            .into());
+        }
+        if hs.algorithm != ED25519 {
+            return Err(GroupError::InvalidMessage.into());
+        }
+        match (self.roster.get(sender), hs.signature) {
+            (Some(Some(signer)), Some(signature)) if signer.verify(&hs.tbs_bytes(), &signature) => {
+            }
+            _ => return Err(GroupError::UnauthorizedSender.into()),
         }
 
         let group_operation_value = hs.operation.group_operation;
@@ -291,39 +1757,797 @@ impl Group {
             GroupOperationValue::Add(add) => self.process_add(&add),
             GroupOperationValue::Update(update) => self.process_update(sender, &update),
             GroupOperationValue::Remove(remove) => self.process_remove(&remove),
-            _ => (),
+            _ => Ok(()),
         }
     }
+    // The tree in Graphviz format, with leaves labeled by the identity of their member,
+    // or the start of its signature key if the credential carries no identity
+    pub fn tree_to_dot(&self) -> String {
+        let labels: Vec<String> = self
+            .roster
+            .iter()
+            .map(|credential| match credential {
+                Some(credential) if !credential.identity.is_empty() => {
+                    String::from_utf8_lossy(&credential.identity).into_owned()
+                }
+                Some(credential) => bytes_to_hex(&credential.public_key.as_slice()[..4]),
+                None => String::new(),
+            })
+            .collect();
+        self.tree.to_dot_with_labels(&labels)
+    }
+    // Occupied leaves in leaf order, with their leaf index
+    pub fn members(&self) -> Vec<(u32, BasicCredential)> {
+        self.tree
+            .occupied_leaves()
+            .filter_map(|(leaf, _, _)| match self.roster.get(leaf) {
+                Some(Some(credential)) => Some((leaf as u32, credential.clone())),
+                _ => None,
+            })
+            .collect()
+    }
     pub fn get_members(&self) -> Vec<BasicCredential> {
-        self.roster.clone()
+        self.roster.iter().filter_map(|k| k.clone()).collect()
     }
-    pub fn get_init_secret(&self) -> InitSecret {
-        self.init_secret.clone()
+    pub fn confirmed_transcript_hash(&self) -> &[u8] {
+        &self.confirmed_transcript_hash
     }
-    fn rotate_epoch_secret(&mut self) {
-        let root = self.tree.get_root();
-        let update_secret = &root.secret.unwrap().0;
-        let mut group_state = Vec::new();
-        self.encode_group_state(&mut group_state);
-        self.epoch_secrets = Some(self.init_secret.update(update_secret, &group_state));
-        self.group_epoch += 1;
+    pub fn interim_transcript_hash(&self) -> &[u8] {
+        &self.interim_transcript_hash
     }
-    fn encode_group_state(&self, buffer: &mut Vec<u8>) {
-        self.group_id.encode(buffer);
-        self.group_epoch.encode(buffer);
-        encode_vec_u16(buffer, &self.roster);
-        encode_vec_u16(buffer, &self.tree.get_public_key_tree());
-        encode_vec_u16(buffer, &self.transcript); // FIXME
+    pub fn epoch(&self) -> GroupEpoch {
+        self.group_epoch
     }
-}
-
-#[test]
-fn alice_bob_charlie_walk_into_a_group() {
-    // Define identities
-    let alice_identity = Identity::random();
-    let bob_identity = Identity::random();
-    let charlie_identity = Identity::random();
-
+    pub fn context(&self) -> GroupContext {
+        GroupContext {
+            version: self.version,
+            cipher_suite: self.cipher_suite,
+            group_id: self.group_id.clone(),
+            epoch: self.group_epoch,
+            tree_hash: self.tree.tree_hash(),
+            roster_hash: roster_hash(self.hash_algorithm(), &self.roster),
+            confirmed_transcript_hash: self.confirmed_transcript_hash.clone(),
+            extensions: self.extensions.clone(),
+        }
+    }
+    // The current roster has to pass the new validator too, so a stricter policy can't
+    // leave members in the group that it would have kept out. This is also how a joiner
+    // checks the tree it imported from a Welcome or a GroupInfo.
+    pub fn set_credential_validator(
+        &mut self,
+        validator: Arc<dyn CredentialValidator>,
+    ) -> Result<(), GroupError> {
+        self.validate_roster_credentials(&*validator)?;
+        self.credential_validator = validator;
+        Ok(())
+    }
+    // A new member has to support everything in the group's required_capabilities
+    fn check_capabilities(&self, init_key: &UserInitKey) -> Result<(), GroupError> {
+        let required = match RequiredCapabilitiesExtension::from_extensions(&self.extensions) {
+            Ok(Some(required)) => required,
+            Ok(None) => return Ok(()),
+            Err(_) => return Err(GroupError::InvalidExtension),
+        };
+        match init_key.capabilities() {
+            Ok(capabilities) if capabilities.satisfies(&required) => Ok(()),
+            Ok(_) => Err(GroupError::UnsupportedCapability),
+            Err(_) => Err(GroupError::InvalidExtension),
+        }
+    }
+    // Every current member has to support the required_capabilities in a proposed set of
+    // group context extensions
+    fn check_member_capabilities(&self, extensions: &[Extension]) -> Result<(), GroupError> {
+        let required = match RequiredCapabilitiesExtension::from_extensions(extensions) {
+            Ok(Some(required)) => required,
+            Ok(None) => return Ok(()),
+            Err(_) => return Err(GroupError::InvalidExtension),
+        };
+        for (credential, capabilities) in self.roster.iter().zip(self.capabilities.iter()) {
+            match (credential, capabilities) {
+                (None, _) => (),
+                (Some(_), Some(capabilities)) if capabilities.satisfies(&required) => (),
+                (Some(_), _) => return Err(GroupError::UnsupportedCapability),
+            }
+        }
+        Ok(())
+    }
+    fn validate_roster_credentials(
+        &self,
+        validator: &dyn CredentialValidator,
+    ) -> Result<(), GroupError> {
+        let context = self.context();
+        for credential in self.roster.iter().flatten() {
+            validator
+                .validate(credential, &context)
+                .map_err(GroupError::InvalidCredential)?;
+        }
+        Ok(())
+    }
+    // Checks the invariants that a loaded or long-lived group must still satisfy
+    pub fn validate(&self) -> Result<(), GroupError> {
+        let leaf_count = match check_tree_size(self.tree.nodes.len()) {
+            Ok(leaf_count) => leaf_count,
+            Err(_) => return Err(GroupError::InvalidTree),
+        };
+        if self.roster.len() != leaf_count {
+            return Err(GroupError::InvalidTree);
+        }
+        for (leaf, credential) in self.roster.iter().enumerate() {
+            let node = self
+                .tree
+                .node(leaf * 2)
+                .map_err(|_| GroupError::InvalidTree)?;
+            if credential.is_some() == node.is_blank() {
+                return Err(GroupError::InvalidTree);
+            }
+        }
+        let own_leaf_index = self.tree.get_own_leaf_index();
+        let own_node = self
+            .tree
+            .node(own_leaf_index)
+            .map_err(|_| GroupError::InvalidLeaf)?;
+        match (own_node.dh_public_key, &own_node.dh_private_key) {
+            (Some(public_key), Some(private_key))
+                if private_key.derive_public_key() == public_key => {}
+            _ => return Err(GroupError::InvalidLeaf),
+        }
+        match self.roster[own_leaf_index / 2] {
+            Some(ref credential) if credential.public_key == self.id.public_key => {}
+            _ => return Err(GroupError::InvalidLeaf),
+        }
+        check_duplicate_keys(&self.roster, &self.tree)?;
+        if !self.tree.verify_parent_hashes() {
+            return Err(GroupError::InvalidParentHash);
+        }
+        if self.epoch_secrets.is_some() {
+            let expected = interim_transcript_hash(
+                self.hash_algorithm(),
+                &self.confirmed_transcript_hash,
+                &self.confirmation_tag()?,
+            );
+            if self.interim_transcript_hash != expected {
+                return Err(GroupError::InvalidTranscriptHash);
+            }
+        }
+        Ok(())
+    }
+    // Members at the same epoch of the same group share it and can compare it out of band
+    pub fn epoch_authenticator(&self) -> Result<Vec<u8>, MelissaError> {
+        match self.epoch_secrets {
+            Some(ref epoch_secrets) => Ok(epoch_secrets.epoch_authenticator.to_vec()),
+            None => Err(GroupError::MissingEpochSecrets.into()),
+        }
+    }
+    // Keys for the application, bound to the current epoch
+    pub fn export_secret(
+        &self,
+        label: &str,
+        context: &[u8],
+        length: usize,
+    ) -> Result<Vec<u8>, MelissaError> {
+        match self.epoch_secrets {
+            Some(ref epoch_secrets) => Ok(export_secret(
+                &epoch_secrets.exporter_secret,
+                label,
+                context,
+                length,
+            )),
+            None => Err(GroupError::MissingEpochSecrets.into()),
+        }
+    }
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn encrypt_application_message(
+        &mut self,
+        content: &[u8],
+    ) -> Result<EncryptedMessage, MelissaError> {
+        self.encrypt_application_message_with_rng(content, &mut SystemRng)
+    }
+    pub fn encrypt_application_message_with_rng(
+        &mut self,
+        content: &[u8],
+        rng: &mut dyn Rng,
+    ) -> Result<EncryptedMessage, MelissaError> {
+        let (sender_data_secret, _) = self.epoch_keys(self.group_epoch)?;
+        let sender = self.tree.get_own_leaf_index() as u32 / 2;
+        let (generation, stage_secrets) = self
+            .ratchet(sender)?
+            .advance()
+            .map_err(|_| GroupError::RatchetExhausted)?;
+        Ok(protect_with_rng(
+            content,
+            self.group_epoch,
+            sender,
+            generation,
+            &stage_secrets,
+            &sender_data_secret,
+            rng,
+        ))
+    }
+    // Messages may arrive out of order within the epoch. The keys of skipped generations
+    // are kept so that they can still be opened later, but only once. Messages of the
+    // last PAST_EPOCHS_KEPT epochs are opened with the keys of their epoch, as long as
+    // their sender is still a member.
+    pub fn decrypt_application_message(
+        &mut self,
+        message: &EncryptedMessage,
+    ) -> Result<DecryptedMessage, MelissaError> {
+        let epoch = message.epoch;
+        if epoch > self.group_epoch {
+            return Err(GroupError::WrongEpoch {
+                expected: self.group_epoch,
+                got: epoch,
+            }
+            .into());
+        }
+        let (sender_data_secret, app_secret) = self.epoch_keys(epoch)?;
+        let sender_data = unprotect_sender_data(message, &sender_data_secret)
+            .map_err(|_| GroupError::InvalidMessage)?;
+        let sender = sender_data.sender;
+        let own_leaf = self.tree.get_own_leaf_index() as u32 / 2;
+        match self.roster.get(sender as usize) {
+            Some(Some(_)) if sender != own_leaf => (),
+            _ => return Err(GroupError::InvalidMessage.into()),
+        }
+        // The ratchet only moves on once the content opened
+        let mut ratchet = sender_ratchet(self.epoch_ratchets(epoch), &app_secret, sender).clone();
+        let stage_secrets = ratchet
+            .get_secret_for_generation(sender_data.generation)
+            .map_err(GroupError::InvalidGeneration)?;
+        let content = unprotect(message, &sender_data, &stage_secrets)
+            .map_err(|_| GroupError::InvalidMessage)?;
+        *sender_ratchet(self.epoch_ratchets(epoch), &app_secret, sender) = ratchet;
+        Ok(DecryptedMessage {
+            sender,
+            generation: sender_data.generation,
+            content,
+        })
+    }
+    // Encrypts for the current epoch and frames the result as it goes on the wire
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn create_application_message(
+        &mut self,
+        content: &[u8],
+    ) -> Result<MlsMessage, MelissaError> {
+        self.create_application_message_with_rng(content, &mut SystemRng)
+    }
+    pub fn create_application_message_with_rng(
+        &mut self,
+        content: &[u8],
+        rng: &mut dyn Rng,
+    ) -> Result<MlsMessage, MelissaError> {
+        let message = self.encrypt_application_message_with_rng(content, rng)?;
+        Ok(MlsMessage::Ciphertext(message))
+    }
+    // Receives any message a member can get: application messages are decrypted and
+    // proposals are checked and stored. Welcomes and group infos are for joiners only.
+    pub fn process_message(
+        &mut self,
+        message: &MlsMessage,
+    ) -> Result<ProcessedMessage, MelissaError> {
+        match message {
+            MlsMessage::Ciphertext(message) => Ok(ProcessedMessage::Application(
+                self.decrypt_application_message(message)?,
+            )),
+            MlsMessage::Proposal(signed_proposal) => Ok(ProcessedMessage::Proposal(
+                self.process_proposal(signed_proposal)?,
+            )),
+            MlsMessage::Commit(commit) => {
+                self.commit_sender(commit)?;
+                Ok(ProcessedMessage::Commit(Box::new(commit.clone())))
+            }
+            MlsMessage::Welcome(_) | MlsMessage::GroupInfo(_) => {
+                Err(GroupError::InvalidMessage.into())
+            }
+        }
+    }
+    // Same as decrypt_application_message, for a message as it came off the wire
+    pub fn decrypt_application_message_bytes(
+        &mut self,
+        bytes: &[u8],
+    ) -> Result<DecryptedMessage, MelissaError> {
+        let message = EncryptedMessage::decode_detached(bytes)?;
+        self.decrypt_application_message(&message)
+    }
+    // Decrypts a backlog of messages, for instance after being offline. A message that
+    // fails doesn't keep the others from being decrypted.
+    pub fn decrypt_pending(
+        &mut self,
+        messages: &[EncryptedMessage],
+    ) -> Vec<Result<DecryptedMessage, MelissaError>> {
+        messages
+            .iter()
+            .map(|message| self.decrypt_application_message(message))
+            .collect()
+    }
+    // The secrets of epochs older than PAST_EPOCHS_KEPT are erased as soon as the epoch
+    // changes. In the kept epochs, this erases the keys kept for skipped generations that
+    // are more than keep_window generations old, and returns how many were erased.
+    pub fn forget_epoch_secrets(&mut self, keep_window: u32) -> usize {
+        self.ratchets
+            .iter_mut()
+            .chain(
+                self.past_epochs
+                    .iter_mut()
+                    .flat_map(|past| past.ratchets.iter_mut()),
+            )
+            .map(|ratchet| ratchet.forget_skipped(keep_window))
+            .sum()
+    }
+    // The sender data secret and application secret of the current or a kept epoch
+    fn epoch_keys(&self, epoch: GroupEpoch) -> Result<([u8; 32], [u8; 32]), GroupError> {
+        if epoch == self.group_epoch {
+            return match self.epoch_secrets {
+                Some(ref epoch_secrets) => {
+                    Ok((epoch_secrets.sender_data_secret, epoch_secrets.app_secret))
+                }
+                None => Err(GroupError::MissingEpochSecrets),
+            };
+        }
+        match self.past_epochs.iter().find(|past| past.epoch == epoch) {
+            Some(past) => Ok((past.sender_data_secret, past.app_secret)),
+            None => Err(GroupError::EpochTooOld),
+        }
+    }
+    // Only called after epoch_keys() found the epoch
+    fn epoch_ratchets(&mut self, epoch: GroupEpoch) -> &mut Vec<SenderRatchet> {
+        if epoch == self.group_epoch {
+            return &mut self.ratchets;
+        }
+        let past = self.past_epochs.iter_mut().find(|past| past.epoch == epoch);
+        &mut past.unwrap().ratchets
+    }
+    fn ratchet(&mut self, sender: u32) -> Result<&mut SenderRatchet, GroupError> {
+        let (_, app_secret) = self.epoch_keys(self.group_epoch)?;
+        Ok(sender_ratchet(&mut self.ratchets, &app_secret, sender))
+    }
+    pub fn epoch_authenticator_code(&self) -> Result<String, MelissaError> {
+        Ok(bytes_to_decimal_blocks(&self.epoch_authenticator()?))
+    }
+    pub fn get_init_secret(&self) -> InitSecret {
+        self.init_secret.clone()
+    }
+    // PSKs are mixed into the key schedule at the next epoch change, in the order
+    // they were added. All members have to inject the same PSKs to stay in sync.
+    pub fn add_psk(&mut self, id: PreSharedKeyID, psk: &[u8]) {
+        self.pending_psks.push((id, psk.to_vec()));
+    }
+    // The state holds the private keys and epoch secrets, so it is only written to
+    // disk sealed under a key derived from the passphrase
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
+    pub fn save_encrypted<P: AsRef<Path>>(
+        &self,
+        path: P,
+        passphrase: &[u8],
+    ) -> Result<(), MelissaError> {
+        self.save_encrypted_with_rng(path, passphrase, &mut SystemRng)
+    }
+    pub fn save_encrypted_with_rng<P: AsRef<Path>>(
+        &self,
+        path: P,
+        passphrase: &[u8],
+        rng: &mut dyn Rng,
+    ) -> Result<(), MelissaError> {
+        let mut plaintext = self.encode_detached();
+        let sealed = seal_with_passphrase_with_rng(&plaintext, passphrase, rng);
+        erase(&mut plaintext);
+        fs::write(path, sealed?)?;
+        Ok(())
+    }
+    pub fn load_encrypted<P: AsRef<Path>>(
+        path: P,
+        passphrase: &[u8],
+    ) -> Result<Group, MelissaError> {
+        let sealed = fs::read(path)?;
+        let mut plaintext = open_with_passphrase(&sealed, passphrase)?;
+        let group = Group::decode_detached(&plaintext);
+        erase(&mut plaintext);
+        Ok(group?)
+    }
+    fn external_key_pair(&self) -> Result<X25519KeyPair, GroupError> {
+        match self.epoch_secrets {
+            Some(ref epoch_secrets) => Ok(X25519KeyPair::new_from_secret(&NodeSecret::from_bytes(
+                &epoch_secrets.external_secret,
+            ))),
+            None => Err(GroupError::MissingEpochSecrets),
+        }
+    }
+    fn rotate_epoch_secret(&mut self) {
+        let update_secret = self.tree.get_root().unwrap().secret.unwrap().0;
+        self.rotate_epoch(&update_secret);
+    }
+    // Nothing was re-keyed, the new epoch only depends on the init secret
+    fn rotate_epoch_secret_without_path(&mut self) {
+        self.rotate_epoch(&[0u8; NODESECRETBYTES]);
+    }
+    fn rotate_epoch(&mut self, update_secret: &[u8]) {
+        self.retire_epoch();
+        self.group_epoch += 1;
+        self.derive_epoch_secrets(update_secret);
+    }
+    // Keeps what is needed to open the messages of the ending epoch that are still in
+    // flight, and drops the oldest kept epoch beyond PAST_EPOCHS_KEPT
+    fn retire_epoch(&mut self) {
+        if let Some(ref epoch_secrets) = self.epoch_secrets {
+            self.past_epochs.push(PastEpoch {
+                epoch: self.group_epoch,
+                sender_data_secret: epoch_secrets.sender_data_secret,
+                app_secret: epoch_secrets.app_secret,
+                ratchets: self.ratchets.drain(..).collect(),
+            });
+        }
+        let excess = self.past_epochs.len().saturating_sub(PAST_EPOCHS_KEPT);
+        self.past_epochs.drain(..excess);
+    }
+    fn derive_epoch_secrets(&mut self, update_secret: &[u8]) {
+        let group_context = self.context().encode_detached();
+        let psks: Vec<_> = self.pending_psks.drain(..).collect();
+        self.pending_proposals.clear();
+        self.epoch_secrets = Some(
+            self.init_secret
+                .update(update_secret, &psks, &group_context),
+        );
+        // The next commit chains onto this epoch's confirmation tag
+        let confirmation_tag = self.confirmation_tag().unwrap();
+        self.interim_transcript_hash = interim_transcript_hash(
+            self.hash_algorithm(),
+            &self.confirmed_transcript_hash,
+            &confirmation_tag,
+        );
+        self.ratchets.clear();
+    }
+    // Derived from the cipher suite when the group is constructed or decoded
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.tree.hash_algorithm
+    }
+    // Only the running hash is kept, not the operations themselves
+    fn push_transcript(&mut self, operation: GroupOperationValue) {
+        let content = operation.encode_detached();
+        self.confirmed_transcript_hash = confirmed_transcript_hash(
+            self.hash_algorithm(),
+            &self.interim_transcript_hash,
+            &content,
+        );
+    }
+}
+
+// The extensions of a GroupInfo that are part of the group context. external_pub is
+// derived anew in every epoch.
+fn group_context_extensions(extensions: &[Extension]) -> Vec<Extension> {
+    extensions
+        .iter()
+        .filter(|e| e.extension_type != EXTENSION_TYPE_EXTERNAL_PUB)
+        .cloned()
+        .collect()
+}
+
+// The public tree of a GroupInfo with its unmerged leaves in place, None if one of them
+// names a node outside the tree
+fn group_info_nodes(group_info: &GroupInfo) -> Option<Vec<Node>> {
+    let mut nodes: Vec<Node> = group_info
+        .tree
+        .iter()
+        .map(|key| match key {
+            Some(key) => Node::new_from_public_key(key),
+            None => Node::new_blank(),
+        })
+        .collect();
+    for entry in group_info.unmerged_leaves.iter() {
+        nodes.get_mut(entry.node as usize)?.unmerged_leaves = entry.leaves.clone();
+    }
+    Some(nodes)
+}
+
+// The context a GroupInfo claims, checked against the imported tree and roster
+fn group_info_context(group_info: &GroupInfo, hash_algorithm: HashAlgorithm) -> GroupContext {
+    GroupContext {
+        version: group_info.version,
+        cipher_suite: group_info.cipher_suite,
+        group_id: group_info.group_id.clone(),
+        epoch: group_info.epoch,
+        tree_hash: group_info.tree_hash.clone(),
+        roster_hash: roster_hash(hash_algorithm, &group_info.roster),
+        confirmed_transcript_hash: group_info.confirmed_transcript_hash.clone(),
+        extensions: group_context_extensions(&group_info.extensions),
+    }
+}
+
+// Blank leaves claim nothing
+fn capabilities_match_roster(
+    roster: &[Option<BasicCredential>],
+    capabilities: &[Option<CapabilitiesExtension>],
+) -> bool {
+    roster.len() == capabilities.len()
+        && roster
+            .iter()
+            .zip(capabilities.iter())
+            .all(|(credential, capabilities)| credential.is_some() || capabilities.is_none())
+}
+
+fn roster_hash(hash_algorithm: HashAlgorithm, roster: &[Option<BasicCredential>]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    encode_vec_u16(&mut buffer, roster);
+    hash_algorithm.hash(&buffer)
+}
+
+// Groups only run with X25519, so that is the only suite whose hash is usable
+fn supported_hash_algorithm(cipher_suite: CipherSuite) -> Result<HashAlgorithm, GroupError> {
+    if cipher_suite != AES128GCM_CURVE25519_SHA256 {
+        return Err(GroupError::UnsupportedCipherSuite);
+    }
+    HashAlgorithm::from_cipher_suite(cipher_suite).ok_or(GroupError::UnsupportedCipherSuite)
+}
+
+// Ratchets are started on first use in an epoch
+fn sender_ratchet<'a>(
+    ratchets: &'a mut Vec<SenderRatchet>,
+    app_secret: &[u8],
+    sender: u32,
+) -> &'a mut SenderRatchet {
+    let position = match ratchets.iter().position(|r| r.sender() == sender) {
+        Some(position) => position,
+        None => {
+            ratchets.push(SenderRatchet::new(app_secret, sender));
+            ratchets.len() - 1
+        }
+    };
+    &mut ratchets[position]
+}
+
+// No two occupied leaves may share a signature key or an encryption key
+pub fn check_duplicate_keys(
+    roster: &[Option<BasicCredential>],
+    tree: &Tree,
+) -> Result<(), GroupError> {
+    let mut signature_keys = HashSet::new();
+    let mut encryption_keys = HashSet::new();
+    for leaf in 0..tree.get_leaf_count() {
+        if let Some(Some(credential)) = roster.get(leaf) {
+            if !signature_keys.insert(credential.public_key) {
+                return Err(GroupError::DuplicateKey);
+            }
+        }
+        let node = tree.node(leaf * 2).map_err(|_| GroupError::InvalidTree)?;
+        if let Some(public_key) = node.dh_public_key {
+            if !encryption_keys.insert(public_key) {
+                return Err(GroupError::DuplicateKey);
+            }
+        }
+    }
+    Ok(())
+}
+
+// The rules on which proposals can go together in a commit, checked before anything is
+// applied so that a malformed commit is rejected instead of panicking in the tree code.
+// Proposals on the same leaf conflict, a ReInit has to be alone, the committer re-keys
+// its own leaf with the path instead of proposals, and adds can't bring in keys that are
+// already in the group or in another add.
+// map_or instead of is_some_and keeps the crate building on older compilers.
+#[allow(clippy::unnecessary_map_or)]
+pub fn validate_proposal_list(
+    proposals: &[Proposal],
+    roster: &[Option<BasicCredential>],
+    tree: &Tree,
+    committer: usize,
+) -> Result<(), GroupError> {
+    let size = tree.get_leaf_count();
+    let is_member = |leaf: usize| tree.node(leaf * 2).map_or(false, |node| !node.is_blank());
+    if !is_member(committer) {
+        return Err(GroupError::InvalidProposal);
+    }
+    if proposals.len() > 1
+        && proposals
+            .iter()
+            .any(|proposal| matches!(proposal, Proposal::ReInit(_)))
+    {
+        return Err(GroupError::ReInitNotAlone);
+    }
+    let mut removed = Vec::new();
+    let mut updated = Vec::new();
+    let mut credential_updated = Vec::new();
+    let mut group_context_extensions_proposed = false;
+    let mut signature_keys = HashSet::new();
+    let mut encryption_keys = HashSet::new();
+    for proposal in proposals {
+        match proposal {
+            Proposal::Remove(leaf) => {
+                let leaf = *leaf as usize;
+                if leaf == committer || !is_member(leaf) {
+                    return Err(GroupError::InvalidProposal);
+                }
+                if removed.contains(&leaf) {
+                    return Err(GroupError::ConflictingProposals);
+                }
+                removed.push(leaf);
+            }
+            Proposal::Update(init_key) => {
+                let leaf = match roster.iter().position(|credential| match credential {
+                    Some(credential) => credential.public_key == init_key.identity_key,
+                    None => false,
+                }) {
+                    Some(leaf) => leaf,
+                    None => return Err(GroupError::InvalidProposal),
+                };
+                if leaf == committer {
+                    return Err(GroupError::InvalidProposal);
+                }
+                if updated.contains(&leaf) {
+                    return Err(GroupError::ConflictingProposals);
+                }
+                updated.push(leaf);
+            }
+            Proposal::CredentialUpdate(credential_update) => {
+                let leaf = credential_update.leaf as usize;
+                if !is_member(leaf) {
+                    return Err(GroupError::InvalidProposal);
+                }
+                if credential_updated.contains(&leaf) {
+                    return Err(GroupError::ConflictingProposals);
+                }
+                credential_updated.push(leaf);
+            }
+            Proposal::Add(init_key) => {
+                if !signature_keys.insert(init_key.identity_key)
+                    || init_key
+                        .init_keys
+                        .first()
+                        .map_or(false, |key| !encryption_keys.insert(*key))
+                {
+                    return Err(GroupError::DuplicateKey);
+                }
+            }
+            // Only valid in an external commit
+            Proposal::ExternalInit(_) => return Err(GroupError::InvalidProposal),
+            Proposal::GroupContextExtensions(extensions) => {
+                if group_context_extensions_proposed {
+                    return Err(GroupError::ConflictingProposals);
+                }
+                // The external public key is per epoch, not part of the context
+                if extensions
+                    .iter()
+                    .any(|e| e.extension_type == EXTENSION_TYPE_EXTERNAL_PUB)
+                {
+                    return Err(GroupError::InvalidProposal);
+                }
+                group_context_extensions_proposed = true;
+            }
+            Proposal::PreSharedKey(_) | Proposal::ReInit(_) => (),
+        }
+    }
+    if updated
+        .iter()
+        .chain(credential_updated.iter())
+        .any(|leaf| removed.contains(leaf))
+    {
+        return Err(GroupError::ConflictingProposals);
+    }
+    // Adds against the members that stay
+    for leaf in (0..size).filter(|leaf| !removed.contains(leaf)) {
+        if let Some(Some(credential)) = roster.get(leaf) {
+            if signature_keys.contains(&credential.public_key) {
+                return Err(GroupError::DuplicateKey);
+            }
+        }
+        let node = tree.node(leaf * 2).map_err(|_| GroupError::InvalidTree)?;
+        if let Some(public_key) = node.dh_public_key {
+            if encryption_keys.contains(&public_key) {
+                return Err(GroupError::DuplicateKey);
+            }
+        }
+    }
+    Ok(())
+}
+
+// For each parent on the sender's path, encrypts its path secret to every node in the
+// resolution of the copath child below it. Path secrets run from the first parent up to
+// the root. Excluded nodes are new members that get their path secret from the Welcome.
+pub fn encrypt_path_secrets(
+    tree: &Tree,
+    from_leaf: usize,
+    path_secrets: &[NodeSecret],
+    exclude: &[usize],
+    rng: &mut dyn Rng,
+) -> Result<Vec<UpdatePathNode>, GroupError> {
+    let recipients = tree
+        .path_secret_recipients(from_leaf)
+        .map_err(|_| GroupError::InvalidTree)?;
+    assert_eq!(recipients.len(), path_secrets.len());
+    let mut nodes = Vec::new();
+    for (resolution, path_secret) in recipients.into_iter().zip(path_secrets.iter()) {
+        let public_keys = tree
+            .get_public_keys_from_path(
+                resolution
+                    .into_iter()
+                    .filter(|x| !exclude.contains(x))
+                    .collect(),
+            )
+            .map_err(|_| GroupError::InvalidTree)?;
+        let encrypted_path_secret =
+            HpkeCiphertext::seal_to_many_with_rng(&public_keys, &path_secret.0, rng).unwrap();
+        nodes.push(UpdatePathNode {
+            public_key: X25519KeyPair::new_from_secret(path_secret).public_key,
+            encrypted_path_secret,
+        });
+    }
+    Ok(nodes)
+}
+
+// Opens the one ciphertext of the path that is addressed to a node we hold the private
+// key of. Returns the node index the decrypted path secret belongs to.
+pub fn decrypt_path_secret(
+    tree: &Tree,
+    from_leaf: usize,
+    exclude: &[usize],
+    path: &UpdatePath,
+) -> Result<(usize, NodeSecret), GroupError> {
+    let size = tree.get_leaf_count();
+    let own_path = treemath::dirpath(tree.get_own_leaf_index(), size);
+    let dirpath = treemath::dirpath(from_leaf * 2, size);
+    let copath = treemath::copath(from_leaf * 2, size);
+    if path.nodes.len() != copath.len() {
+        return Err(GroupError::InvalidCommit);
+    }
+    let own_leaf = tree.get_own_leaf_index();
+    let position = match treemath::is_in_copath_subtree(own_leaf, from_leaf * 2, size) {
+        Some(node) => copath.iter().position(|&x| x == node).unwrap(),
+        None => return Err(GroupError::InvalidCommit),
+    };
+    let resolution: Vec<usize> = tree
+        .resolve(copath[position])
+        .map_err(|_| GroupError::InvalidCommit)?
+        .into_iter()
+        .filter(|x| !exclude.contains(x))
+        .collect();
+    let ciphertexts = &path.nodes[position].encrypted_path_secret;
+    if ciphertexts.len() != resolution.len() {
+        return Err(GroupError::InvalidCommit);
+    }
+    let mut found = None;
+    for (x, ciphertext) in resolution.iter().zip(ciphertexts.iter()) {
+        if !own_path.contains(x) {
+            continue;
+        }
+        let node = tree.node(*x).map_err(|_| GroupError::InvalidCommit)?;
+        if let Some(ref private_key) = node.dh_private_key {
+            found = Some((ciphertext, private_key.clone()));
+            break;
+        }
+    }
+    let (ciphertext, private_key) = match found {
+        Some(found) => found,
+        None => return Err(GroupError::InvalidCommit),
+    };
+    let path_secret =
+        HpkeCiphertext::decrypt(&private_key, ciphertext).map_err(|_| GroupError::InvalidCommit)?;
+    if path_secret.len() != NODESECRETBYTES {
+        return Err(GroupError::InvalidCommit);
+    }
+    Ok((
+        treemath::parent(dirpath[position], size),
+        NodeSecret::from_bytes(&path_secret),
+    ))
+}
+
+// Adds and credential updates leave the existing members' HPKE keys untouched
+fn path_required(proposals: &[Proposal]) -> bool {
+    proposals.is_empty()
+        || proposals
+            .iter()
+            .any(|proposal| !matches!(proposal, Proposal::Add(_) | Proposal::CredentialUpdate(_)))
+}
+
+fn commit_hash(commit: &Commit) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    commit.tbs_bytes().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[test]
+fn alice_bob_charlie_walk_into_a_group() {
+    // Define identities
+    let alice_identity = Identity::random();
+    let bob_identity = Identity::random();
+    let charlie_identity = Identity::random();
+
     let alice_credential = BasicCredential {
         identity: "Alice".as_bytes().to_vec(),
         public_key: alice_identity.public_key,
@@ -348,49 +2572,52 @@ fn alice_bob_charlie_walk_into_a_group() {
     let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
 
     // Alice adds Bob
-    let (welcome_alice_bob, add_alice_bob) = group_alice.create_add(bob_credential, &bob_init_key);
-    group_alice.process_add(&add_alice_bob);
+    let (welcome_alice_bob, add_alice_bob) = group_alice
+        .create_add(bob_credential, &bob_init_key)
+        .unwrap();
+    group_alice.process_add(&add_alice_bob).unwrap();
 
     let mut group_bob = Group::new_from_welcome(bob_identity, &welcome_alice_bob);
     assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
 
     // Bob updates
     let update_bob = group_bob.create_update();
-    group_bob.process_update(1, &update_bob);
-    group_alice.process_update(1, &update_bob);
+    group_bob.process_update(1, &update_bob).unwrap();
+    group_alice.process_update(1, &update_bob).unwrap();
     assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
 
     // Alice updates
     let update_alice = group_alice.create_update();
-    group_alice.process_update(0, &update_alice);
-    group_bob.process_update(0, &update_alice);
+    group_alice.process_update(0, &update_alice).unwrap();
+    group_bob.process_update(0, &update_alice).unwrap();
 
     // Bob adds Charlie
-    let (welcome_bob_charlie, add_bob_charlie) =
-        group_bob.create_add(charlie_credential, &charlie_init_key);
+    let (welcome_bob_charlie, add_bob_charlie) = group_bob
+        .create_add(charlie_credential, &charlie_init_key)
+        .unwrap();
     let mut group_charlie = Group::new_from_welcome(charlie_identity, &welcome_bob_charlie);
 
-    group_alice.process_add(&add_bob_charlie);
+    group_alice.process_add(&add_bob_charlie).unwrap();
     assert_eq!(
         group_alice.get_init_secret(),
         group_charlie.get_init_secret()
     );
 
-    group_bob.process_add(&add_bob_charlie);
+    group_bob.process_add(&add_bob_charlie).unwrap();
     assert_eq!(group_bob.get_init_secret(), group_charlie.get_init_secret());
     assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
 
     // Charlie updates
     let update_charlie = group_charlie.create_update();
-    group_alice.process_update(2, &update_charlie);
-    group_bob.process_update(2, &update_charlie);
-    group_charlie.process_update(2, &update_charlie);
+    group_alice.process_update(2, &update_charlie).unwrap();
+    group_bob.process_update(2, &update_charlie).unwrap();
+    group_charlie.process_update(2, &update_charlie).unwrap();
 
     // Alice updates
     let update_alice = group_alice.create_update();
-    group_alice.process_update(0, &update_alice);
-    group_bob.process_update(0, &update_alice);
-    group_charlie.process_update(0, &update_alice);
+    group_alice.process_update(0, &update_alice).unwrap();
+    group_bob.process_update(0, &update_alice).unwrap();
+    group_charlie.process_update(0, &update_alice).unwrap();
     assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
     assert_eq!(
         group_alice.get_init_secret(),
@@ -399,14 +2626,2929 @@ fn alice_bob_charlie_walk_into_a_group() {
 
     // Charlie removes Bob
     let remove_charlie_bob = group_charlie.create_remove(1);
-    group_alice.process_remove(&remove_charlie_bob);
-    group_bob.process_remove(&remove_charlie_bob);
-    group_charlie.process_remove(&remove_charlie_bob);
+    group_alice.process_remove(&remove_charlie_bob).unwrap();
+    group_bob.process_remove(&remove_charlie_bob).unwrap();
+    group_charlie.process_remove(&remove_charlie_bob).unwrap();
 
     assert_eq!(
         group_alice.get_init_secret(),
         group_charlie.get_init_secret()
     );
+    // Bob's slot is emptied, the others keep their leaf
+    for group in [&group_alice, &group_charlie].iter() {
+        assert_eq!(group.roster.len(), 3);
+        assert!(group.roster[1].is_none());
+        assert!(group.capabilities[1].is_none());
+        let leaves: Vec<u32> = group.members().into_iter().map(|(leaf, _)| leaf).collect();
+        assert_eq!(leaves, vec![0, 2]);
+    }
+
+    assert_ne!(group_alice.get_init_secret(), group_bob.get_init_secret());
+}
+
+#[test]
+fn psk_injection() {
+    let alice_identity = Identity::random();
+    let bob_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_credential = BasicCredential {
+        identity: "Bob".as_bytes().to_vec(),
+        public_key: bob_identity.public_key,
+    };
+    let bob_init_key = UserInitKeyBundle::new(&bob_identity).init_key;
+
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (welcome, add) = group_alice
+        .create_add(bob_credential, &bob_init_key)
+        .unwrap();
+    group_alice.process_add(&add).unwrap();
+    let mut group_bob = Group::new_from_welcome(bob_identity, &welcome);
+
+    // Both members inject the same PSK
+    let psk_id = PreSharedKeyID::external(b"external psk", b"nonce");
+    let psk = vec![7u8; 32];
+    group_alice.add_psk(psk_id.clone(), &psk);
+    group_bob.add_psk(psk_id.clone(), &psk);
+    let update = group_bob.create_update();
+    group_bob.process_update(1, &update).unwrap();
+    group_alice.process_update(1, &update).unwrap();
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
 
+    // Only Alice injects a PSK, the groups diverge
+    group_alice.add_psk(psk_id, &psk);
+    let update = group_alice.create_update();
+    group_alice.process_update(0, &update).unwrap();
+    group_bob.process_update(0, &update).unwrap();
     assert_ne!(group_alice.get_init_secret(), group_bob.get_init_secret());
 }
+
+#[test]
+fn legacy_operation_errors() {
+    let alice_identity = Identity::random();
+    let bob_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_credential = BasicCredential {
+        identity: "Bob".as_bytes().to_vec(),
+        public_key: bob_identity.public_key,
+    };
+    let mut bob_init_key = UserInitKeyBundle::new(&bob_identity).init_key;
+
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let mut forged_init_key = bob_init_key.clone();
+    forged_init_key.identity_key = Identity::random().public_key;
+    match group_alice.create_add(bob_credential.clone(), &forged_init_key) {
+        Err(MelissaError::KeyPackage(_)) => (),
+        _ => panic!("add with a forged key package created"),
+    }
+    let (welcome, mut add) = group_alice
+        .create_add(bob_credential, &bob_init_key)
+        .unwrap();
+    let mut truncated = add.clone();
+    truncated.path.clear();
+    match group_alice.clone().process_add(&truncated) {
+        Err(MelissaError::Group(GroupError::InvalidUpdatePath)) => (),
+        _ => panic!("add with a truncated path accepted"),
+    }
+    group_alice.process_add(&add).unwrap();
+    let mut group_bob = Group::new_from_welcome(bob_identity, &welcome);
+
+    // Handshakes are checked against the epoch and the signer's credential
+    let update = GroupOperation {
+        msg_type: GroupOperationType::Update,
+        group_operation: GroupOperationValue::Update(group_bob.create_update()),
+    };
+    let handshake = group_bob.create_handshake(update);
+    let mut unsigned = handshake.clone();
+    unsigned.signature = None;
+    match group_alice.process_handshake(unsigned) {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("unsigned handshake accepted"),
+    }
+    let mut impostor = handshake.clone();
+    impostor.signer_index = 0;
+    match group_alice.process_handshake(impostor) {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("handshake signed by another member accepted"),
+    }
+    group_alice.process_handshake(handshake.clone()).unwrap();
+    match group_alice.process_handshake(handshake) {
+        Err(MelissaError::Group(GroupError::WrongEpoch { .. })) => (),
+        _ => panic!("replayed handshake accepted"),
+    }
+
+    let mut update = group_alice.create_update();
+    update.path.pop();
+    match group_bob.process_update(0, &update) {
+        Err(MelissaError::Group(GroupError::InvalidUpdatePath)) => (),
+        _ => panic!("update with a truncated path accepted"),
+    }
+    let remove = Remove {
+        removed: 10,
+        nodes: Vec::new(),
+        path: Vec::new(),
+    };
+    match group_bob.process_remove(&remove) {
+        Err(MelissaError::Group(GroupError::InvalidProposal)) => (),
+        _ => panic!("remove outside the tree accepted"),
+    }
+    bob_init_key.init_keys.clear();
+    add.init_key = bob_init_key;
+    match group_bob.process_add(&add) {
+        Err(MelissaError::KeyPackage(_)) => (),
+        _ => panic!("add with an invalid key package accepted"),
+    }
+}
+
+#[test]
+fn external_commit() {
+    let alice_identity = Identity::random();
+    let bob_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_credential = BasicCredential {
+        identity: "Bob".as_bytes().to_vec(),
+        public_key: bob_identity.public_key,
+    };
+    let bob_init_key = UserInitKeyBundle::new(&bob_identity).init_key;
+
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    assert!(group_alice.group_info().is_err());
+    let (welcome, add) = group_alice
+        .create_add(bob_credential, &bob_init_key)
+        .unwrap();
+    group_alice.process_add(&add).unwrap();
+    let mut group_bob = Group::new_from_welcome(bob_identity, &welcome);
+    let update = group_bob.create_update();
+    group_bob.process_update(1, &update).unwrap();
+    group_alice.process_update(1, &update).unwrap();
+
+    // Charlie joins using only the public group info
+    let group_info =
+        GroupInfo::decode_detached(&group_alice.group_info().unwrap().encode_detached()).unwrap();
+    let (mut group_charlie, external_commit) =
+        Group::join_by_external_commit(Identity::random(), &group_info).unwrap();
+    group_alice
+        .process_external_commit(&external_commit)
+        .unwrap();
+    group_bob.process_external_commit(&external_commit).unwrap();
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    assert_eq!(
+        group_alice.get_init_secret(),
+        group_charlie.get_init_secret()
+    );
+
+    // The resulting tree is usable by all three
+    let update = group_charlie.create_update();
+    group_alice.process_update(2, &update).unwrap();
+    group_bob.process_update(2, &update).unwrap();
+    group_charlie.process_update(2, &update).unwrap();
+    assert_eq!(
+        group_alice.get_init_secret(),
+        group_charlie.get_init_secret()
+    );
+    assert_eq!(group_bob.get_init_secret(), group_charlie.get_init_secret());
+}
+
+#[test]
+fn external_commit_unmerged_leaves() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, _) = group_alice
+        .commit(&[
+            Proposal::Add(UserInitKeyBundle::new(&Identity::random()).init_key),
+            Proposal::Add(UserInitKeyBundle::new(&Identity::random()).init_key),
+        ])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let (commit, _) = group_alice.commit(&[Proposal::Remove(1)]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    // An Add without a path leaves the new member unmerged at its ancestors
+    let (commit, _) = group_alice
+        .commit(&[Proposal::Add(
+            UserInitKeyBundle::new(&Identity::random()).init_key,
+        )])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let group_info = group_alice.group_info().unwrap();
+    assert!(!group_info.unmerged_leaves.is_empty());
+
+    let (group_dave, external_commit) =
+        Group::join_by_external_commit(Identity::random(), &group_info).unwrap();
+    group_alice
+        .process_external_commit(&external_commit)
+        .unwrap();
+    assert_eq!(group_dave.tree.tree_hash(), group_alice.tree.tree_hash());
+    assert_eq!(
+        group_dave.epoch_authenticator().unwrap(),
+        group_alice.epoch_authenticator().unwrap()
+    );
+
+    // Unmerged leaves outside the tree are refused
+    let mut group_info = group_alice.group_info().unwrap();
+    group_info.unmerged_leaves.push(UnmergedLeaves {
+        node: 1,
+        leaves: vec![100],
+    });
+    group_info.sign(&group_alice.id);
+    match Group::join_by_external_commit(Identity::random(), &group_info) {
+        Err(MelissaError::Group(GroupError::InvalidGroupInfo)) => (),
+        _ => panic!("invalid unmerged leaves accepted"),
+    }
+}
+
+#[test]
+fn external_commit_invalid_group_info() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, _) = group_alice
+        .commit(&[Proposal::Add(
+            UserInitKeyBundle::new(&Identity::random()).init_key,
+        )])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let group_info = group_alice.group_info().unwrap();
+    let check = |group_info: GroupInfo| {
+        let mut group_info = group_info;
+        group_info.sign(&group_alice.id);
+        match Group::join_by_external_commit(Identity::random(), &group_info) {
+            Err(MelissaError::Group(GroupError::InvalidGroupInfo)) => (),
+            _ => panic!("invalid group info accepted"),
+        }
+    };
+
+    // A roster longer than the tree
+    let mut longer_roster = group_info.clone();
+    longer_roster.roster.push(None);
+    longer_roster.capabilities.push(None);
+    check(longer_roster);
+
+    // A tree that is not the one the tree hash covers
+    let mut other_tree = group_info.clone();
+    other_tree.tree[0] = Some(X25519KeyPair::new_random().public_key);
+    check(other_tree);
+    let mut other_hash = group_info.clone();
+    other_hash.tree_hash = vec![0u8; 32];
+    check(other_hash);
+
+    // A tree of impossible size
+    let mut odd_size = group_info;
+    odd_size.tree.push(None);
+    check(odd_size);
+}
+
+#[test]
+fn external_commit_requires_external_pub() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let update = group_alice.create_update();
+    group_alice.process_update(0, &update).unwrap();
+
+    let mut group_info = group_alice.group_info().unwrap();
+    group_info.extensions.clear();
+    match Group::join_by_external_commit(Identity::random(), &group_info) {
+        Err(MelissaError::Group(GroupError::MissingExtension(EXTENSION_TYPE_EXTERNAL_PUB))) => (),
+        _ => panic!("external commit without external_pub"),
+    }
+}
+
+#[test]
+fn external_commit_unsupported_cipher_suite() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let update = group_alice.create_update();
+    group_alice.process_update(0, &update).unwrap();
+
+    let mut group_info = group_alice.group_info().unwrap();
+    assert_eq!(group_info.cipher_suite, AES128GCM_CURVE25519_SHA256);
+    group_info.cipher_suite = AES128GCM_P256_SHA256;
+    group_info.sign(&group_alice.id);
+    match Group::join_by_external_commit(Identity::random(), &group_info) {
+        Err(MelissaError::Group(GroupError::UnsupportedCipherSuite)) => (),
+        _ => panic!("external commit with unsupported cipher suite"),
+    }
+}
+
+#[test]
+fn external_commit_init_key_reuse() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let update = group_alice.create_update();
+    group_alice.process_update(0, &update).unwrap();
+    let group_info = group_alice.group_info().unwrap();
+    let (_, mut external_commit) =
+        Group::join_by_external_commit(Identity::random(), &group_info).unwrap();
+
+    // Correctly signed, but with the signature key as init key
+    let charlie_identity = Identity::random();
+    let reused = X25519PublicKey::from_slice(charlie_identity.public_key.as_slice()).unwrap();
+    external_commit.add.init_key = UserInitKey::new(&[reused], &charlie_identity);
+    match group_alice.process_external_commit(&external_commit) {
+        Err(MelissaError::Group(GroupError::InvalidProposal)) => (),
+        _ => panic!("init key reusing the signature key accepted"),
+    }
+
+    // A joiner that brings the signature key of a member
+    let init_key = X25519KeyPair::new_random().public_key;
+    external_commit.add.init_key = UserInitKey::new(&[init_key], &group_alice.id);
+    match group_alice.process_external_commit(&external_commit) {
+        Err(MelissaError::Group(GroupError::DuplicateKey)) => (),
+        _ => panic!("duplicate signature key accepted"),
+    }
+    assert_eq!(group_alice.members().len(), 1);
+}
+
+#[test]
+fn external_commit_malformed() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let update = group_alice.create_update();
+    group_alice.process_update(0, &update).unwrap();
+    let group_info = group_alice.group_info().unwrap();
+    let (_, external_commit) =
+        Group::join_by_external_commit(Identity::random(), &group_info).unwrap();
+
+    // Truncated path
+    let mut truncated = external_commit.clone();
+    truncated.add.path.pop();
+    match group_alice.process_external_commit(&truncated) {
+        Err(MelissaError::Group(GroupError::InvalidUpdatePath)) => (),
+        _ => panic!("truncated path accepted"),
+    }
+    let mut truncated = external_commit.clone();
+    truncated.add.nodes.pop();
+    match group_alice.process_external_commit(&truncated) {
+        Err(MelissaError::Group(GroupError::InvalidUpdatePath)) => (),
+        _ => panic!("truncated public keys accepted"),
+    }
+
+    // All-zero keys give no shared secret
+    let zero = X25519PublicKey::from_slice(&[0u8; 32]).unwrap();
+    let mut zero_path_key = external_commit.clone();
+    for ciphertext in zero_path_key.add.path.iter_mut() {
+        ciphertext.ephemeral_public_key = zero;
+    }
+    match group_alice.process_external_commit(&zero_path_key) {
+        Err(MelissaError::Group(GroupError::InvalidUpdatePath)) => (),
+        _ => panic!("all-zero ephemeral key accepted"),
+    }
+    let mut zero_kem_output = external_commit.clone();
+    zero_kem_output.kem_output = zero;
+    match group_alice.process_external_commit(&zero_kem_output) {
+        Err(MelissaError::Group(GroupError::InvalidKemOutput)) => (),
+        _ => panic!("all-zero kem output accepted"),
+    }
+
+    // Signature that does not verify under the advertised key
+    let mut bad_signature = external_commit.clone();
+    bad_signature.add.init_key.identity_key = Identity::random().public_key;
+    match group_alice.process_external_commit(&bad_signature) {
+        Err(MelissaError::Group(GroupError::InvalidProposal)) => (),
+        _ => panic!("bad signature accepted"),
+    }
+
+    // The rejected commits left the group untouched
+    group_alice
+        .process_external_commit(&external_commit)
+        .unwrap();
+}
+
+#[test]
+fn reinit() {
+    let alice_identity = Identity::random();
+    let bob_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_credential = BasicCredential {
+        identity: "Bob".as_bytes().to_vec(),
+        public_key: bob_identity.public_key,
+    };
+    let bob_init_key = UserInitKeyBundle::new(&bob_identity).init_key;
+
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (welcome, add) = group_alice
+        .create_add(bob_credential, &bob_init_key)
+        .unwrap();
+    group_alice.process_add(&add).unwrap();
+    let mut group_bob = Group::new_from_welcome(bob_identity, &welcome);
+    assert!(Group::from_reinit(&group_bob).is_err());
+
+    assert!(group_alice
+        .reinit(AES128GCM_P256_SHA256, CURRENT_VERSION)
+        .is_err());
+    let reinit_commit = group_alice
+        .reinit(AES128GCM_CURVE25519_SHA256, CURRENT_VERSION)
+        .unwrap();
+    group_alice.process_reinit(0, &reinit_commit).unwrap();
+    group_bob.process_reinit(0, &reinit_commit).unwrap();
+
+    let mut new_group_alice = Group::from_reinit(&group_alice).unwrap();
+    let mut new_group_bob = Group::from_reinit(&group_bob).unwrap();
+    let resumption_secret = group_alice.epoch_secrets.clone().unwrap().resumption_secret;
+    assert_eq!(
+        new_group_alice.pending_psks[0].1,
+        resumption_secret.to_vec()
+    );
+    let mut unlinked_group_alice = new_group_alice.clone();
+    unlinked_group_alice.pending_psks.clear();
+
+    let update = new_group_bob.create_update();
+    new_group_bob.process_update(1, &update).unwrap();
+    new_group_alice.process_update(1, &update).unwrap();
+    unlinked_group_alice.process_update(1, &update).unwrap();
+    assert_eq!(
+        new_group_alice.get_init_secret(),
+        new_group_bob.get_init_secret()
+    );
+    assert_ne!(
+        new_group_alice.get_init_secret(),
+        group_alice.get_init_secret()
+    );
+    assert_ne!(
+        new_group_alice.get_init_secret(),
+        unlinked_group_alice.get_init_secret()
+    );
+}
+
+#[test]
+fn batched_commit() {
+    let alice_identity = Identity::random();
+    let bob_identity = Identity::random();
+    let charlie_identity = Identity::random();
+    let dave_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let charlie_init_key_bundle = UserInitKeyBundle::new(&charlie_identity);
+    let dave_init_key_bundle = UserInitKeyBundle::new(&dave_identity);
+
+    // Alice adds Bob and Charlie in one commit
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[
+            Proposal::Add(bob_init_key_bundle.init_key.clone()),
+            Proposal::Add(charlie_init_key_bundle.init_key.clone()),
+        ])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let welcome = CommitWelcome::decode_detached(&welcome.unwrap().encode_detached()).unwrap();
+    let mut group_bob = Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome).unwrap();
+    let mut group_charlie =
+        Group::from_welcome(charlie_identity, &charlie_init_key_bundle, &welcome).unwrap();
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    assert_eq!(
+        group_alice.get_init_secret(),
+        group_charlie.get_init_secret()
+    );
+
+    // Alice adds Dave, removes Charlie and commits Bob's update at once
+    let update_bob = group_bob.propose_update();
+    let proposals = [
+        Proposal::Add(dave_init_key_bundle.init_key.clone()),
+        Proposal::Remove(2),
+        update_bob,
+    ];
+    let (commit, welcome) = group_alice.commit(&proposals).unwrap();
+    let commit = Commit::decode_detached(&commit.encode_detached()).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    match group_charlie.process_commit(&commit) {
+        Err(MelissaError::Group(GroupError::Removed)) => (),
+        _ => panic!("Charlie should be removed"),
+    }
+    let mut group_dave =
+        Group::from_welcome(dave_identity, &dave_init_key_bundle, &welcome.unwrap()).unwrap();
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    assert_eq!(group_alice.get_init_secret(), group_dave.get_init_secret());
+
+    // Dave took Charlie's leaf
+    assert_eq!(group_dave.tree.get_own_leaf_index(), 4);
+    assert_eq!(group_alice.get_members().len(), 3);
+    assert_eq!(
+        group_alice.tree.get_public_key_tree(),
+        group_dave.tree.get_public_key_tree()
+    );
+
+    // The resulting tree is usable by everyone
+    let (commit, welcome) = group_dave.commit(&[]).unwrap();
+    assert!(welcome.is_none());
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    group_dave.process_commit(&commit).unwrap();
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    assert_eq!(group_alice.get_init_secret(), group_dave.get_init_secret());
+}
+
+#[test]
+fn batched_commit_conflicts() {
+    let alice_identity = Identity::random();
+    let bob_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+
+    let update_bob = group_bob.propose_update();
+    match group_alice.commit(&[update_bob, Proposal::Remove(1)]) {
+        Err(MelissaError::Group(GroupError::ConflictingProposals)) => (),
+        _ => panic!("update and remove of the same leaf"),
+    }
+    match group_alice.commit(&[Proposal::Remove(1), Proposal::Remove(1)]) {
+        Err(MelissaError::Group(GroupError::ConflictingProposals)) => (),
+        _ => panic!("double remove"),
+    }
+    match group_alice.commit(&[Proposal::Remove(0)]) {
+        Err(MelissaError::Group(GroupError::InvalidProposal)) => (),
+        _ => panic!("committer removes itself"),
+    }
+}
+
+#[test]
+fn batched_commit_order() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bundles: Vec<UserInitKeyBundle> = (0..3)
+        .map(|_| UserInitKeyBundle::new(&Identity::random()))
+        .collect();
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let proposals: Vec<Proposal> = bundles
+        .iter()
+        .map(|bundle| Proposal::Add(bundle.init_key.clone()))
+        .collect();
+    let (commit, _) = group_alice.commit(&proposals).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+
+    let new_member = UserInitKeyBundle::new(&Identity::random());
+    let first = [
+        Proposal::Remove(1),
+        Proposal::Add(new_member.init_key.clone()),
+        Proposal::Remove(3),
+    ];
+    let second = [
+        Proposal::Add(new_member.init_key.clone()),
+        Proposal::Remove(3),
+        Proposal::Remove(1),
+    ];
+    let mut group_first = group_alice.clone();
+    let mut group_second = group_alice.clone();
+    group_first.apply_proposals(0, &first).unwrap();
+    group_second.apply_proposals(0, &second).unwrap();
+    assert_eq!(
+        group_first.tree.get_public_key_tree(),
+        group_second.tree.get_public_key_tree()
+    );
+    // Leaf 3 is truncated and the new member fills the leftmost blank leaf
+    assert_eq!(group_first.tree.get_leaf_count(), 3);
+    assert_eq!(
+        group_first.tree.nodes[2].dh_public_key,
+        Some(new_member.init_key.init_keys[0])
+    );
+}
+
+#[test]
+fn commit_with_invalid_leaves() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let charlie_init_key_bundle = UserInitKeyBundle::new(&Identity::random());
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[
+            Proposal::Add(bob_init_key_bundle.init_key.clone()),
+            Proposal::Add(charlie_init_key_bundle.init_key.clone()),
+        ])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+
+    // Out of range
+    match group_alice.commit(&[Proposal::Remove(10)]) {
+        Err(MelissaError::Group(GroupError::InvalidProposal)) => (),
+        _ => panic!("out of range remove"),
+    }
+    let (mut commit, _) = group_alice.commit(&[]).unwrap();
+    commit.proposals.push(Proposal::Remove(10));
+    commit.sign(&group_alice.id);
+    match group_bob.process_commit(&commit) {
+        Err(MelissaError::Group(GroupError::InvalidProposal)) => (),
+        _ => panic!("out of range remove"),
+    }
+    commit.sender = Sender::Member(7);
+    commit.sign(&group_alice.id);
+    match group_bob.process_commit(&commit) {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("out of range sender"),
+    }
+    assert_eq!(group_bob.group_epoch, group_alice.group_epoch);
+
+    // A root key that doesn't match the path secret Bob derives
+    let (mut commit, _) = group_alice.commit(&[]).unwrap();
+    commit.path.as_mut().unwrap().nodes[1].public_key = X25519KeyPair::new_random().public_key;
+    commit.sign(&group_alice.id);
+    match group_bob.process_commit(&commit) {
+        Err(MelissaError::Group(GroupError::InvalidCommit)) => (),
+        _ => panic!("inconsistent path"),
+    }
+
+    // Removing a leaf that is already blank
+    let (commit, _) = group_alice.commit(&[Proposal::Remove(1)]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    match group_alice.commit(&[Proposal::Remove(1)]) {
+        Err(MelissaError::Group(GroupError::InvalidProposal)) => (),
+        _ => panic!("double blank"),
+    }
+    let (mut commit, _) = group_alice.commit(&[]).unwrap();
+    commit.proposals.push(Proposal::Remove(1));
+    commit.sign(&group_alice.id);
+    match group_alice.process_commit(&commit) {
+        Err(MelissaError::Group(GroupError::InvalidProposal)) => (),
+        _ => panic!("double blank"),
+    }
+}
+
+#[test]
+fn path_secrets_to_resolution() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let dave_identity = Identity::random();
+    let dave_init_key_bundle = UserInitKeyBundle::new(&dave_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[
+            Proposal::Add(UserInitKeyBundle::new(&Identity::random()).init_key),
+            Proposal::Add(UserInitKeyBundle::new(&Identity::random()).init_key),
+            Proposal::Add(dave_init_key_bundle.init_key.clone()),
+        ])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let group_dave =
+        Group::from_welcome(dave_identity, &dave_init_key_bundle, &welcome.unwrap()).unwrap();
+
+    // Node 5 was not on Alice's path and is still blank
+    let tree = &group_alice.tree;
+    assert!(tree.nodes[5].is_blank());
+    let path_secrets = [NodeSecret::new_random(), NodeSecret::new_random()];
+    let nodes = encrypt_path_secrets(tree, 0, &path_secrets, &[], &mut SystemRng).unwrap();
+    for (node, copath_node) in nodes.iter().zip(treemath::copath(0, 4)) {
+        assert_eq!(
+            node.encrypted_path_secret.len(),
+            tree.resolve(copath_node).unwrap().len()
+        );
+    }
+    assert_eq!(nodes[1].encrypted_path_secret.len(), 2);
+
+    // Dave opens the secret of the root
+    let path = UpdatePath {
+        leaf_key: X25519KeyPair::new_random().public_key,
+        nodes,
+    };
+    let (node, path_secret) = decrypt_path_secret(&group_dave.tree, 0, &[], &path).unwrap();
+    assert_eq!(node, 3);
+    assert_eq!(path_secret, path_secrets[1]);
+}
+
+#[test]
+fn commit_psk_and_reinit_proposals() {
+    let alice_identity = Identity::random();
+    let bob_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+
+    // A PSK proposal needs the PSK to be known
+    let psk_id = PreSharedKeyID::external(b"external psk", b"nonce");
+    let proposals = [Proposal::PreSharedKey(psk_id.clone())];
+    match group_alice.commit(&proposals) {
+        Err(MelissaError::Group(GroupError::InvalidProposal)) => (),
+        _ => panic!("unknown PSK"),
+    }
+    group_alice.add_psk(psk_id.clone(), &[7u8; 32]);
+    group_bob.add_psk(psk_id, &[7u8; 32]);
+    let (commit, _) = group_alice.commit(&proposals).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+
+    // ReInit
+    let reinit = ReInit {
+        group_id: GroupId::random(),
+        version: CURRENT_VERSION,
+        cipher_suite: AES128GCM_CURVE25519_SHA256,
+    };
+    let (commit, _) = group_bob
+        .commit(&[Proposal::ReInit(reinit.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    assert!(Group::from_reinit(&group_alice).is_ok());
+    assert!(Group::from_reinit(&group_bob).is_ok());
+
+    match group_alice.commit(&[Proposal::ExternalInit(ExternalInit {
+        kem_output: X25519KeyPair::new_random().public_key,
+    })]) {
+        Err(MelissaError::Group(GroupError::InvalidProposal)) => (),
+        _ => panic!("external init in a regular commit"),
+    }
+}
+
+#[test]
+fn signed_group_info() {
+    let alice_identity = Identity::random();
+    let bob_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    let (commit, _) = group_bob.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+
+    let group_info =
+        GroupInfo::decode_detached(&group_alice.group_info().unwrap().encode_detached()).unwrap();
+    assert!(group_info.verify(&group_info.roster));
+    group_bob.verify_group_info(&group_info).unwrap();
+
+    // Wrong signer
+    let mut wrong_signer = group_info.clone();
+    wrong_signer.signer_index = 1;
+    assert!(!wrong_signer.verify(&wrong_signer.roster));
+    wrong_signer.signer_index = 5;
+    assert!(!wrong_signer.verify(&wrong_signer.roster));
+
+    // The confirmation tag is bound to the epoch
+    let (commit, _) = group_bob.commit(&[]).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    assert!(group_bob.verify_group_info(&group_info).is_err());
+    let mut stale_tag = group_bob.group_info().unwrap();
+    stale_tag.confirmation_tag = group_info.confirmation_tag.clone();
+    stale_tag.sign(&group_bob.id);
+    assert!(stale_tag.verify(&stale_tag.roster));
+    assert!(group_bob.verify_group_info(&stale_tag).is_err());
+}
+
+#[test]
+fn unmerged_leaves_after_add() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+
+    // Alice fills a tree of eight leaves
+    let identities: Vec<Identity> = (0..7).map(|_| Identity::random()).collect();
+    let bundles: Vec<UserInitKeyBundle> = identities.iter().map(UserInitKeyBundle::new).collect();
+    let adds: Vec<Proposal> = bundles
+        .iter()
+        .map(|bundle| Proposal::Add(bundle.init_key.clone()))
+        .collect();
+    let (commit, welcome) = group_alice.commit(&adds).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let welcome = welcome.unwrap();
+    let mut groups = vec![group_alice];
+    for (identity, bundle) in identities.into_iter().zip(bundles.iter()) {
+        groups.push(Group::from_welcome(identity, bundle, &welcome).unwrap());
+    }
+
+    // Alice removes leaf 1, which re-keys nodes 1, 3 and 7 on her path
+    let (commit, _) = groups[0].commit(&[Proposal::Remove(1)]).unwrap();
+    for group in groups
+        .iter_mut()
+        .filter(|g| g.tree.get_own_leaf_index() != 2)
+    {
+        group.process_commit(&commit).unwrap();
+    }
+    groups.remove(1);
+
+    // Leaf 7 adds Eve into the free leaf below nodes 1 and 3, which it can't re-key
+    let eve_identity = Identity::random();
+    let eve_bundle = UserInitKeyBundle::new(&eve_identity);
+    let (commit, welcome) = groups[6]
+        .commit_with_options(
+            &[Proposal::Add(eve_bundle.init_key.clone())],
+            CommitOptions { force_path: true },
+        )
+        .unwrap();
+    for group in groups.iter_mut() {
+        group.process_commit(&commit).unwrap();
+    }
+    let group_eve = Group::from_welcome(eve_identity, &eve_bundle, &welcome.unwrap()).unwrap();
+    assert_eq!(group_eve.tree.get_own_leaf_index(), 2);
+    for group in groups.iter() {
+        assert_eq!(group.tree.nodes[1].unmerged_leaves, vec![1]);
+        assert_eq!(group.tree.nodes[3].unmerged_leaves, vec![1]);
+        assert!(group.tree.nodes[7].unmerged_leaves.is_empty());
+        assert_eq!(
+            group.tree.get_unmerged_leaves(),
+            group_eve.tree.get_unmerged_leaves()
+        );
+    }
+    groups.insert(1, group_eve);
+
+    // Leaf 4 reaches Eve through the unmerged leaf in the resolution of node 3
+    assert_eq!(groups[4].tree.resolve(3).unwrap(), vec![3, 2]);
+    let (commit, _) = groups[4].commit(&[]).unwrap();
+    for group in groups.iter_mut() {
+        group.process_commit(&commit).unwrap();
+    }
+    for group in groups.iter() {
+        assert_eq!(group.get_init_secret(), groups[0].get_init_secret());
+    }
+
+    // Alice's path covers both nodes and merges Eve
+    let (commit, _) = groups[0].commit(&[]).unwrap();
+    for group in groups.iter_mut() {
+        group.process_commit(&commit).unwrap();
+    }
+    for group in groups.iter() {
+        assert_eq!(group.get_init_secret(), groups[0].get_init_secret());
+        assert!(group.tree.get_unmerged_leaves().is_empty());
+    }
+}
+
+#[test]
+fn epoch_authenticator() {
+    let alice_identity = Identity::random();
+    let bob_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    match group_bob.epoch_authenticator() {
+        Err(MelissaError::Group(GroupError::MissingEpochSecrets)) => (),
+        _ => panic!("A joiner has no epoch secrets yet"),
+    }
+
+    let (commit, _) = group_bob.commit(&[]).unwrap();
+    let stale_alice = group_alice.clone();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    assert_eq!(
+        group_alice.epoch_authenticator().unwrap(),
+        group_bob.epoch_authenticator().unwrap()
+    );
+    assert_ne!(
+        group_alice.epoch_authenticator().unwrap(),
+        stale_alice.epoch_authenticator().unwrap()
+    );
+
+    let code = group_alice.epoch_authenticator_code().unwrap();
+    assert_eq!(code, group_bob.epoch_authenticator_code().unwrap());
+    assert_eq!(code.len(), 6 * 5 + 5);
+    assert!(code.split(' ').all(|block| block.len() == 5));
+}
+
+#[test]
+fn protocol_version() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+
+    // The version leads the GroupInfo and the Handshake, an unknown one doesn't decode
+    let group_info = group_alice.group_info().unwrap();
+    let mut encoded = group_info.encode_detached();
+    assert_eq!(
+        ProtocolVersion::decode_detached(&encoded[..2]).unwrap(),
+        CURRENT_VERSION
+    );
+    encoded[1] = 2;
+    match GroupInfo::decode_detached(&encoded) {
+        Err(CodecError::UnsupportedVersion) => (),
+        _ => panic!("An unknown version must be rejected"),
+    }
+
+    let update = GroupOperation {
+        msg_type: GroupOperationType::Update,
+        group_operation: GroupOperationValue::Update(group_alice.create_update()),
+    };
+    let mut encoded = group_alice.create_handshake(update).encode_detached();
+    encoded[0] = 0xff;
+    match Handshake::decode_detached(&encoded) {
+        Err(CodecError::UnsupportedVersion) => (),
+        _ => panic!("An unknown version must be rejected"),
+    }
+}
+
+#[test]
+fn exported_secrets() {
+    let alice_identity = Identity::random();
+    let bob_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    let (commit, _) = group_bob.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+
+    let exported = group_alice
+        .export_secret("side channel", b"ctx", 48)
+        .unwrap();
+    assert_eq!(exported.len(), 48);
+    assert_eq!(
+        exported,
+        group_bob.export_secret("side channel", b"ctx", 48).unwrap()
+    );
+    assert_ne!(
+        exported,
+        group_alice.export_secret("other", b"ctx", 48).unwrap()
+    );
+    assert_ne!(
+        exported,
+        group_alice
+            .export_secret("side channel", b"other", 48)
+            .unwrap()
+    );
+    assert_eq!(
+        group_alice
+            .export_secret("side channel", b"ctx", 16)
+            .unwrap()
+            .len(),
+        16
+    );
+}
+
+#[test]
+fn members_skip_blank_leaves() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let charlie_identity = Identity::random();
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, _) = group_alice
+        .commit(&[
+            Proposal::Add(UserInitKeyBundle::new(&bob_identity).init_key),
+            Proposal::Add(UserInitKeyBundle::new(&charlie_identity).init_key),
+        ])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let members = group_alice.members();
+    assert_eq!(
+        members.iter().map(|(leaf, _)| *leaf).collect::<Vec<u32>>(),
+        vec![0, 1, 2]
+    );
+
+    // Bob's leaf in the middle is blank after his removal
+    let (commit, _) = group_alice.commit(&[Proposal::Remove(1)]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let members = group_alice.members();
+    assert_eq!(members.len(), 2);
+    assert_eq!(members[0].0, 0);
+    assert_eq!(members[0].1.identity, b"Alice".to_vec());
+    assert_eq!(members[1].0, 2);
+    assert_eq!(members[1].1.public_key, charlie_identity.public_key);
+}
+
+#[test]
+fn duplicate_keys() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity.clone(), alice_credential, GroupId::random());
+
+    // Adding a second leaf with Alice's signature key
+    match group_alice.commit(&[Proposal::Add(
+        UserInitKeyBundle::new(&alice_identity).init_key,
+    )]) {
+        Err(MelissaError::Group(GroupError::DuplicateKey)) => (),
+        _ => panic!("A duplicate signature key must be rejected"),
+    }
+    // Adding the same init key twice
+    match group_alice.commit(&[
+        Proposal::Add(bob_init_key_bundle.init_key.clone()),
+        Proposal::Add(bob_init_key_bundle.init_key.clone()),
+    ]) {
+        Err(MelissaError::Group(GroupError::DuplicateKey)) => (),
+        _ => panic!("A duplicate encryption key must be rejected"),
+    }
+
+    // Importing a tree with two identical signature keys
+    let (commit, welcome) = group_alice
+        .commit(&[
+            Proposal::Add(bob_init_key_bundle.init_key.clone()),
+            Proposal::Add(UserInitKeyBundle::new(&Identity::random()).init_key),
+        ])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let roster = welcome.unwrap().group_info.roster;
+    assert!(check_duplicate_keys(&roster, &group_alice.tree).is_ok());
+    let mut forged = roster.clone();
+    forged[2] = forged[0].clone();
+    match check_duplicate_keys(&forged, &group_alice.tree) {
+        Err(GroupError::DuplicateKey) => (),
+        _ => panic!("A duplicate signature key must be rejected"),
+    }
+}
+
+#[test]
+fn max_members() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_init_key = UserInitKeyBundle::new(&Identity::random()).init_key;
+    let charlie_init_key = UserInitKeyBundle::new(&Identity::random()).init_key;
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    group_alice.set_max_members(2);
+
+    let (commit, _) = group_alice.commit(&[Proposal::Add(bob_init_key)]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    match group_alice.commit(&[Proposal::Add(charlie_init_key.clone())]) {
+        Err(MelissaError::Group(GroupError::GroupTooLarge)) => {}
+        _ => panic!("commit past max_members accepted"),
+    }
+    assert_eq!(group_alice.members().len(), 2);
+
+    // A removed member's leaf can be reused
+    let (commit, _) = group_alice
+        .commit(&[Proposal::Remove(1), Proposal::Add(charlie_init_key)])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    assert_eq!(group_alice.members().len(), 2);
+
+    // External joiners count as well
+    let group_info = group_alice.group_info().unwrap();
+    let (_, external_commit) =
+        Group::join_by_external_commit(Identity::random(), &group_info).unwrap();
+    match group_alice.process_external_commit(&external_commit) {
+        Err(MelissaError::Group(GroupError::GroupTooLarge)) => {}
+        _ => panic!("external commit past max_members accepted"),
+    }
+    assert_eq!(group_alice.members().len(), 2);
+}
+
+#[test]
+fn decrypt_pending_messages() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+
+    // Bob was offline for 50 generations and gets the messages in reverse order
+    let messages: Vec<EncryptedMessage> = (0..50u8)
+        .map(|i| group_alice.encrypt_application_message(&[i]).unwrap())
+        .collect();
+    let reversed: Vec<EncryptedMessage> = messages.iter().rev().cloned().collect();
+    let decrypted = group_bob.decrypt_pending(&reversed);
+    for (message, i) in decrypted.into_iter().zip((0..50u8).rev()) {
+        let message = message.unwrap();
+        assert_eq!(
+            (message.sender, message.generation),
+            (0, Generation(u32::from(i)))
+        );
+        assert_eq!(message.content, vec![i]);
+    }
+    // Keys are used only once
+    match group_bob.decrypt_application_message(&messages[10]) {
+        Err(MelissaError::Group(GroupError::InvalidGeneration(StageError::TooFarInThePast))) => (),
+        _ => panic!("replayed message accepted"),
+    }
+
+    // Too far ahead of the last generation Bob has seen
+    let skipped: Vec<EncryptedMessage> = (0..=MAX_SKIPPED_GENERATIONS + 1)
+        .map(|_| group_alice.encrypt_application_message(b"later").unwrap())
+        .collect();
+    let last = MAX_SKIPPED_GENERATIONS as usize;
+    let results = group_bob.decrypt_pending(&[skipped[last + 1].clone(), skipped[last].clone()]);
+    match results[0] {
+        Err(MelissaError::Group(GroupError::InvalidGeneration(StageError::TooFarInTheFuture))) => {}
+        _ => panic!("skip limit exceeded"),
+    }
+    assert!(results[1].is_ok());
+
+    // A corrupted message doesn't advance the ratchet
+    let message = group_alice.encrypt_application_message(b"hello").unwrap();
+    let mut corrupted = message.clone();
+    let last = corrupted.ciphertext.len() - 1;
+    corrupted.ciphertext[last] ^= 0x01;
+    let results = group_bob.decrypt_pending(&[corrupted, message]);
+    assert!(results[0].is_err());
+    assert_eq!(results[1].as_ref().unwrap().content, b"hello".to_vec());
+
+    // A new epoch starts new ratchets
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    let message = group_bob.encrypt_application_message(b"hi").unwrap();
+    let decrypted = group_alice.decrypt_application_message(&message).unwrap();
+    assert_eq!((decrypted.sender, decrypted.generation), (1, Generation(0)));
+}
+
+#[test]
+fn commit_path_on_add() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit_with_options(
+            &[Proposal::Add(bob_init_key_bundle.init_key.clone())],
+            CommitOptions { force_path: true },
+        )
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+
+    // Adds alone need no path, the keys of the tree stay as they were
+    let charlie_identity = Identity::random();
+    let charlie_init_key_bundle = UserInitKeyBundle::new(&charlie_identity);
+    let root_key = group_alice.tree.nodes[1].dh_public_key;
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(charlie_init_key_bundle.init_key.clone())])
+        .unwrap();
+    assert!(commit.path.is_none());
+    let commit = Commit::decode_detached(&commit.encode_detached()).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    let mut group_charlie = Group::from_welcome(
+        charlie_identity,
+        &charlie_init_key_bundle,
+        &welcome.unwrap(),
+    )
+    .unwrap();
+    assert_eq!(group_alice.tree.nodes[1].dh_public_key, root_key);
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    assert_eq!(
+        group_alice.get_init_secret(),
+        group_charlie.get_init_secret()
+    );
+
+    // A forced path re-keys Alice's direct path
+    let dave_identity = Identity::random();
+    let dave_init_key_bundle = UserInitKeyBundle::new(&dave_identity);
+    let old_keys = [
+        group_alice.tree.nodes[1].dh_public_key,
+        group_alice.tree.nodes[3].dh_public_key,
+    ];
+    let (commit, welcome) = group_alice
+        .commit_with_options(
+            &[Proposal::Add(dave_init_key_bundle.init_key.clone())],
+            CommitOptions { force_path: true },
+        )
+        .unwrap();
+    assert!(commit.path.is_some());
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    group_charlie.process_commit(&commit).unwrap();
+    let group_dave =
+        Group::from_welcome(dave_identity, &dave_init_key_bundle, &welcome.unwrap()).unwrap();
+    for (x, old_key) in [1, 3].iter().zip(old_keys.iter()) {
+        let new_key = group_alice.tree.nodes[*x].dh_public_key;
+        assert!(new_key.is_some());
+        assert_ne!(new_key, *old_key);
+        assert_eq!(group_bob.tree.nodes[*x].dh_public_key, new_key);
+        assert_eq!(group_dave.tree.nodes[*x].dh_public_key, new_key);
+    }
+    assert!(group_alice.tree.get_unmerged_leaves().is_empty());
+    for group in [&group_bob, &group_charlie, &group_dave].iter() {
+        assert_eq!(group.get_init_secret(), group_alice.get_init_secret());
+    }
+
+    // Commits that remove or update always carry a path
+    let (mut commit, _) = group_alice.commit(&[Proposal::Remove(1)]).unwrap();
+    assert!(commit.path.is_some());
+    commit.path = None;
+    commit.sign(&group_alice.id);
+    match group_charlie.process_commit(&commit) {
+        Err(MelissaError::Group(GroupError::InvalidCommit)) => (),
+        _ => panic!("remove without path"),
+    }
+}
+
+#[test]
+fn transcript_hashes() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    assert_eq!(
+        group_alice.interim_transcript_hash(),
+        group_bob.interim_transcript_hash()
+    );
+
+    // The commit is folded into the interim hash of the previous epoch, the confirmation
+    // tag of the new epoch into the confirmed hash
+    let interim_before = group_alice.interim_transcript_hash().to_vec();
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    let confirmed = confirmed_transcript_hash(
+        HashAlgorithm::Sha256,
+        &interim_before,
+        &GroupOperationValue::Commit(commit.unconfirmed()).encode_detached(),
+    );
+    assert_eq!(group_alice.confirmed_transcript_hash(), &confirmed[..]);
+    assert_eq!(group_bob.confirmed_transcript_hash(), &confirmed[..]);
+    let confirmation_tag = group_alice.confirmation_tag().unwrap();
+    assert_eq!(confirmation_tag, group_bob.confirmation_tag().unwrap());
+    assert_eq!(confirmation_tag, commit.confirmation_tag);
+    assert_eq!(
+        group_alice.interim_transcript_hash(),
+        &interim_transcript_hash(HashAlgorithm::Sha256, &confirmed, &confirmation_tag)[..]
+    );
+    assert_eq!(
+        group_alice.interim_transcript_hash(),
+        group_bob.interim_transcript_hash()
+    );
+
+    // The tag isn't signed, but Bob recomputes it and rejects one that doesn't match
+    let (mut commit, _) = group_alice.commit(&[]).unwrap();
+    commit.confirmation_tag[0] ^= 1;
+    match group_bob.process_commit(&commit) {
+        Err(MelissaError::Group(GroupError::InvalidConfirmationTag)) => (),
+        _ => panic!("wrong confirmation tag accepted"),
+    }
+    assert_eq!(group_bob.epoch(), group_alice.epoch());
+    commit.confirmation_tag.clear();
+    match group_bob.process_commit(&commit) {
+        Err(MelissaError::Group(GroupError::InvalidConfirmationTag)) => (),
+        _ => panic!("missing confirmation tag accepted"),
+    }
+}
+
+#[test]
+fn codec_error_surfaces() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+
+    let bytes = group_alice
+        .encrypt_application_message(b"hello")
+        .unwrap()
+        .encode_detached();
+    let decrypted = group_bob.decrypt_application_message_bytes(&bytes).unwrap();
+    assert_eq!(decrypted.content, b"hello".to_vec());
+
+    // A truncated ciphertext fails in the codec, not in the group
+    let error = group_bob
+        .decrypt_application_message_bytes(&bytes[..bytes.len() - 1])
+        .unwrap_err();
+    match error {
+        MelissaError::Codec(CodecError::DecodingError) => (),
+        _ => panic!("expected a codec error, got {:?}", error),
+    }
+    let source = error.source().unwrap();
+    match source.downcast_ref::<CodecError>() {
+        Some(CodecError::DecodingError) => (),
+        _ => panic!("the source must be the codec error"),
+    }
+}
+
+#[test]
+fn commit_by_reference() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+
+    // Bob proposes to add Charlie, Alice stores the proposal and commits it later
+    let charlie_init_key = UserInitKeyBundle::new(&Identity::random()).init_key;
+    let proposal = Proposal::Add(charlie_init_key);
+    let proposal_ref = group_bob.store_proposal(&proposal);
+    let proposal = Proposal::decode_detached(&proposal.encode_detached()).unwrap();
+    assert_eq!(group_alice.store_proposal(&proposal), proposal_ref);
+
+    let mut group_inline = group_alice.clone();
+    let (inline_commit, _) = group_inline.commit(&[proposal]).unwrap();
+    group_inline.process_commit(&inline_commit).unwrap();
+    let (commit, welcome) = group_alice.commit_by_reference(&[proposal_ref]).unwrap();
+    assert!(welcome.is_some());
+    assert_eq!(
+        commit.proposals[0].encode_detached(),
+        inline_commit.proposals[0].encode_detached()
+    );
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    assert_eq!(
+        group_alice.tree.get_public_key_tree().len(),
+        group_inline.tree.get_public_key_tree().len()
+    );
+    let keys = |group: &Group| -> Vec<_> {
+        group
+            .members()
+            .into_iter()
+            .map(|(leaf, credential)| (leaf, credential.public_key))
+            .collect()
+    };
+    assert_eq!(keys(&group_alice), keys(&group_inline));
+
+    // Pending proposals don't carry over to the next epoch
+    match group_alice.commit_by_reference(&[proposal_ref]) {
+        Err(MelissaError::Group(GroupError::UnknownProposalRef)) => (),
+        _ => panic!("unknown proposal reference accepted"),
+    }
+}
+
+#[test]
+fn external_sender_proposals() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let server_identity = Identity::random();
+    let server_credential = BasicCredential {
+        identity: "Server".as_bytes().to_vec(),
+        public_key: server_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let charlie_init_key_bundle = UserInitKeyBundle::new(&Identity::random());
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    group_alice.set_external_senders(&ExternalSendersExtension {
+        senders: vec![server_credential],
+    });
+    let (commit, welcome) = group_alice
+        .commit(&[
+            Proposal::Add(bob_init_key_bundle.init_key.clone()),
+            Proposal::Add(charlie_init_key_bundle.init_key.clone()),
+        ])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    // Bob learns about the external senders from the Welcome
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+
+    // The server removes Charlie
+    let remove = SignedProposal::new(
+        &group_alice.group_id,
+        group_alice.group_epoch,
+        Sender::External(0),
+        Proposal::Remove(2),
+        &server_identity,
+    );
+    let remove = SignedProposal::decode_detached(&remove.encode_detached()).unwrap();
+    let proposal_ref = group_alice.process_proposal(&remove).unwrap();
+    assert_eq!(group_bob.process_proposal(&remove).unwrap(), proposal_ref);
+    let (commit, _) = group_alice.commit_by_reference(&[proposal_ref]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    assert_eq!(group_alice.members().len(), 2);
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+
+    // Keys that aren't listed, or listed at another position, are rejected
+    let rogue = SignedProposal::new(
+        &group_alice.group_id,
+        group_alice.group_epoch,
+        Sender::External(0),
+        Proposal::Remove(1),
+        &Identity::random(),
+    );
+    match group_alice.process_proposal(&rogue) {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("unlisted external sender accepted"),
+    }
+    let unknown_index = SignedProposal::new(
+        &group_alice.group_id,
+        group_alice.group_epoch,
+        Sender::External(1),
+        Proposal::Remove(1),
+        &server_identity,
+    );
+    match group_alice.process_proposal(&unknown_index) {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("unknown external sender accepted"),
+    }
+    // External senders can only add and remove
+    let reinit = SignedProposal::new(
+        &group_alice.group_id,
+        group_alice.group_epoch,
+        Sender::External(0),
+        Proposal::ReInit(ReInit {
+            group_id: GroupId::random(),
+            version: CURRENT_VERSION,
+            cipher_suite: AES128GCM_CURVE25519_SHA256,
+        }),
+        &server_identity,
+    );
+    match group_alice.process_proposal(&reinit) {
+        Err(MelissaError::Group(GroupError::InvalidProposal)) => (),
+        _ => panic!("external reinit accepted"),
+    }
+}
+
+#[test]
+fn epoch_rollback() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let stale_commit = commit;
+    let welcome = welcome.unwrap();
+    let mut group_bob = Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome).unwrap();
+    assert_eq!(group_bob.epoch(), welcome.group_info.epoch);
+    assert_eq!(group_bob.epoch(), group_alice.epoch());
+
+    // The first commit after joining follows the epoch of the group info
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    assert_eq!(commit.epoch, welcome.group_info.epoch + 1);
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+
+    // Replaying an older commit is rejected and leaves the group as it was
+    let epoch = group_bob.epoch();
+    match group_bob.process_commit(&stale_commit) {
+        Err(MelissaError::Group(GroupError::WrongEpoch { expected, got })) => {
+            assert_eq!(expected, epoch + 1);
+            assert_eq!(got, stale_commit.epoch);
+        }
+        _ => panic!("stale commit accepted"),
+    }
+    match group_bob.process_commit(&commit) {
+        Err(MelissaError::Group(GroupError::WrongEpoch { .. })) => (),
+        _ => panic!("replayed commit accepted"),
+    }
+    assert_eq!(group_bob.epoch(), epoch);
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+}
+
+#[test]
+fn encrypted_at_rest() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_init_key = UserInitKeyBundle::new(&Identity::random()).init_key;
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, _) = group_alice.commit(&[Proposal::Add(bob_init_key)]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+
+    let path = std::env::temp_dir().join(format!(
+        "melissa-{}.state",
+        sodiumoxide::randombytes::randombytes(8)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    ));
+    group_alice.save_encrypted(&path, b"passphrase").unwrap();
+    let loaded = Group::load_encrypted(&path, b"passphrase").unwrap();
+    assert_eq!(loaded.encode_detached(), group_alice.encode_detached());
+    match Group::load_encrypted(&path, b"not the passphrase") {
+        Err(MelissaError::Aead(_)) => {}
+        _ => panic!("wrong passphrase accepted"),
+    }
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn rotate_leaf_credential() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity.clone(), alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob = Group::from_welcome(
+        bob_identity.clone(),
+        &bob_init_key_bundle,
+        &welcome.unwrap(),
+    )
+    .unwrap();
+
+    // Bob's key is already in the group
+    let bob_credential = group_bob.members()[1].1.clone();
+    match group_alice.update_leaf_credential(bob_credential, bob_identity) {
+        Err(MelissaError::Group(GroupError::DuplicateKey)) => (),
+        _ => panic!("duplicate signature key accepted"),
+    }
+
+    let new_identity = Identity::random();
+    let new_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: new_identity.public_key,
+    };
+    let leaf_key_before = group_alice.tree.nodes[0].dh_public_key;
+    let proposal = group_alice
+        .update_leaf_credential(new_credential, new_identity.clone())
+        .unwrap();
+    let proposal = Proposal::decode_detached(&proposal.encode_detached()).unwrap();
+    let (commit, _) = group_bob.commit(&[proposal]).unwrap();
+    assert!(commit.path.is_none());
+    group_bob.process_commit(&commit).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    assert_eq!(group_alice.tree.nodes[0].dh_public_key, leaf_key_before);
+    assert_eq!(group_bob.members()[0].1.public_key, new_identity.public_key);
+
+    // Alice signs with the new key, the old one is no longer accepted
+    let proposal = Proposal::Remove(1);
+    let signed = SignedProposal::new(
+        &group_alice.group_id,
+        group_alice.epoch(),
+        Sender::Member(0),
+        proposal.clone(),
+        &group_alice.id,
+    );
+    assert!(group_bob.process_proposal(&signed).is_ok());
+    let stale = SignedProposal::new(
+        &group_alice.group_id,
+        group_alice.epoch(),
+        Sender::Member(0),
+        proposal,
+        &alice_identity,
+    );
+    match group_bob.process_proposal(&stale) {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("rotated key still accepted"),
+    }
+}
+
+#[test]
+fn validate_invariants() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    assert!(group_alice.validate().is_ok());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    assert!(group_bob.validate().is_ok());
+    let (commit, _) = group_bob.commit(&[]).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    assert!(group_alice.validate().is_ok());
+    assert!(group_bob.validate().is_ok());
+
+    let mut group = group_alice.clone();
+    group.roster.push(None);
+    match group.validate() {
+        Err(GroupError::InvalidTree) => (),
+        _ => panic!("roster and tree sizes differ"),
+    }
+    let mut group = group_alice.clone();
+    group.roster[1] = None;
+    match group.validate() {
+        Err(GroupError::InvalidTree) => (),
+        _ => panic!("occupied leaf without a credential"),
+    }
+    let mut group = group_alice.clone();
+    group.tree.nodes[0].dh_private_key = group_bob.tree.nodes[2].dh_private_key.clone();
+    match group.validate() {
+        Err(GroupError::InvalidLeaf) => (),
+        _ => panic!("own private key doesn't match"),
+    }
+    let mut group = group_alice.clone();
+    group.id = Identity::random();
+    match group.validate() {
+        Err(GroupError::InvalidLeaf) => (),
+        _ => panic!("own signature key doesn't match"),
+    }
+    let mut group = group_alice.clone();
+    group.roster[1] = group.roster[0].clone();
+    match group.validate() {
+        Err(GroupError::DuplicateKey) => (),
+        _ => panic!("duplicate signature key"),
+    }
+    let mut group = group_alice.clone();
+    group.tree.nodes[2].parent_hash = vec![0u8; 32];
+    match group.validate() {
+        Err(GroupError::InvalidParentHash) => (),
+        _ => panic!("broken parent hash chain"),
+    }
+    let mut group = group_alice.clone();
+    group.interim_transcript_hash = vec![0u8; 32];
+    match group.validate() {
+        Err(GroupError::InvalidTranscriptHash) => (),
+        _ => panic!("inconsistent transcript hashes"),
+    }
+}
+
+#[test]
+fn authorized_leaves_after_remove() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, _) = group_alice
+        .commit_with_options(
+            &[
+                Proposal::Add(UserInitKeyBundle::new(&Identity::random()).init_key),
+                Proposal::Add(UserInitKeyBundle::new(&Identity::random()).init_key),
+            ],
+            CommitOptions { force_path: true },
+        )
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let root = treemath::root(group_alice.tree.get_leaf_count());
+    assert_eq!(
+        group_alice.tree.authorized_leaves(root).unwrap(),
+        vec![0, 2, 4]
+    );
+
+    // A leaf added without a path is unmerged at the root until the next commit
+    let mut group = group_alice.clone();
+    let (commit, _) = group
+        .commit(&[Proposal::Add(
+            UserInitKeyBundle::new(&Identity::random()).init_key,
+        )])
+        .unwrap();
+    group.process_commit(&commit).unwrap();
+    assert_eq!(group.tree.get_leaf_count(), 4);
+    assert_eq!(group.tree.authorized_leaves(root).unwrap(), vec![0, 2, 4]);
+    assert_eq!(group.tree.authorized_leaves(6).unwrap(), vec![6]);
+
+    let (commit, _) = group_alice.commit(&[Proposal::Remove(1)]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let authorized = group_alice.tree.authorized_leaves(root).unwrap();
+    assert_eq!(authorized, vec![0, 4]);
+    assert!(!authorized.contains(&2));
+}
+
+#[test]
+fn seeded_group_operations() {
+    let run = |seed: &[u8]| {
+        let mut rng = SeededRng::new(seed);
+        let alice_identity = Identity::random_with_rng(&mut rng);
+        let alice_credential = BasicCredential {
+            identity: "Alice".as_bytes().to_vec(),
+            public_key: alice_identity.public_key,
+        };
+        let bob_init_key =
+            UserInitKeyBundle::new_with_rng(&Identity::random_with_rng(&mut rng), &mut rng)
+                .init_key;
+        let group_id = GroupId::random_with_rng(&mut rng);
+        let mut group = Group::new_with_rng(alice_identity, alice_credential, group_id, &mut rng);
+        let (commit, welcome) = group
+            .commit_with_rng(
+                &[Proposal::Add(bob_init_key)],
+                CommitOptions { force_path: true },
+                &mut rng,
+            )
+            .unwrap();
+        group.process_commit(&commit).unwrap();
+        let message = group
+            .encrypt_application_message_with_rng(b"hello", &mut rng)
+            .unwrap();
+        (
+            commit.encode_detached(),
+            welcome.unwrap().encode_detached(),
+            message.encode_detached(),
+        )
+    };
+    // All randomness comes from the generator, so equal seeds give equal messages
+    assert_eq!(run(b"seed"), run(b"seed"));
+    assert_ne!(run(b"seed"), run(b"other seed"));
+}
+
+#[test]
+fn proposal_sender_types() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity.clone(), alice_credential, GroupId::random());
+    let (commit, _) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let signed = |sender: Sender, proposal: Proposal, id: &Identity| {
+        let signed_proposal = SignedProposal::new(
+            &group_alice.group_id,
+            group_alice.epoch(),
+            sender,
+            proposal,
+            id,
+        );
+        SignedProposal::decode_detached(&signed_proposal.encode_detached()).unwrap()
+    };
+
+    // Member senders are checked against the credential at their leaf
+    let update = Proposal::Update(UserInitKeyBundle::new(&bob_identity).init_key);
+    let from_bob = signed(Sender::Member(1), update.clone(), &bob_identity);
+    assert!(group_alice.clone().process_proposal(&from_bob).is_ok());
+    let impostor = signed(Sender::Member(1), update.clone(), &alice_identity);
+    match group_alice.clone().process_proposal(&impostor) {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("member signature not checked against the tree"),
+    }
+    let blank_leaf = signed(Sender::Member(2), update, &bob_identity);
+    match group_alice.clone().process_proposal(&blank_leaf) {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("sender outside the tree accepted"),
+    }
+
+    // A new member proposes its own add, signed with its key package's key
+    let charlie_identity = Identity::random();
+    let charlie_init_key = UserInitKeyBundle::new(&charlie_identity).init_key;
+    let self_add = signed(
+        Sender::NewMemberProposal,
+        Proposal::Add(charlie_init_key.clone()),
+        &charlie_identity,
+    );
+    assert!(group_alice.clone().process_proposal(&self_add).is_ok());
+    let forged = signed(
+        Sender::NewMemberProposal,
+        Proposal::Add(charlie_init_key.clone()),
+        &Identity::random(),
+    );
+    match group_alice.clone().process_proposal(&forged) {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("self-add signed with another key accepted"),
+    }
+    let remove = signed(
+        Sender::NewMemberProposal,
+        Proposal::Remove(1),
+        &charlie_identity,
+    );
+    match group_alice.clone().process_proposal(&remove) {
+        Err(MelissaError::Group(GroupError::InvalidProposal)) => (),
+        _ => panic!("new member proposed a remove"),
+    }
+    let commit_sender = signed(
+        Sender::NewMemberCommit,
+        Proposal::Add(charlie_init_key),
+        &charlie_identity,
+    );
+    match group_alice.process_proposal(&commit_sender) {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("proposal with a commit sender accepted"),
+    }
+}
+
+#[test]
+fn commit_sender_types() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity.clone(), alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob = Group::from_welcome(
+        bob_identity.clone(),
+        &bob_init_key_bundle,
+        &welcome.unwrap(),
+    )
+    .unwrap();
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    let commit = Commit::decode_detached(&commit.encode_detached()).unwrap();
+    match commit.sender {
+        Sender::Member(0) => (),
+        _ => panic!("commit not sent from the committer's leaf"),
+    }
+
+    // Alice's commit claimed by Bob, or signed by Bob in her name
+    let mut impostor = commit.clone();
+    impostor.sender = Sender::Member(1);
+    match group_bob.clone().process_commit(&impostor) {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("member signature not checked against the roster"),
+    }
+    impostor.sender = Sender::Member(0);
+    impostor.sign(&bob_identity);
+    match group_bob.clone().process_commit(&impostor) {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("commit signed with another member's key accepted"),
+    }
+    let mut tampered = commit.clone();
+    tampered.proposals.push(Proposal::Remove(1));
+    match group_bob
+        .clone()
+        .process_message(&MlsMessage::Commit(tampered))
+    {
+        Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+        _ => panic!("altered commit handed back"),
+    }
+
+    // Only members commit by reference
+    for sender in [
+        Sender::External(0),
+        Sender::NewMemberProposal,
+        Sender::NewMemberCommit,
+    ]
+    .iter()
+    {
+        let mut non_member = commit.clone();
+        non_member.sender = *sender;
+        non_member.sign(&alice_identity);
+        match group_bob.clone().process_commit(&non_member) {
+            Err(MelissaError::Group(GroupError::UnauthorizedSender)) => (),
+            _ => panic!("commit from a non-member accepted"),
+        }
+    }
+    let mut other_group = commit.clone();
+    other_group.group_id = GroupId::random();
+    other_group.sign(&alice_identity);
+    match group_bob.clone().process_commit(&other_group) {
+        Err(MelissaError::Group(GroupError::InvalidCommit)) => (),
+        _ => panic!("commit for another group accepted"),
+    }
+
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    assert_eq!(
+        group_bob.epoch_authenticator().unwrap(),
+        group_alice.epoch_authenticator().unwrap()
+    );
+}
+
+#[test]
+fn welcome_several_members() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let charlie_identity = Identity::random();
+    let charlie_init_key_bundle = UserInitKeyBundle::new(&charlie_identity);
+    let dave_init_key_bundle = UserInitKeyBundle::new(&Identity::random());
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let added = vec![
+        bob_init_key_bundle.init_key.clone(),
+        charlie_init_key_bundle.init_key.clone(),
+    ];
+    let proposals: Vec<Proposal> = added.iter().cloned().map(Proposal::Add).collect();
+    let (commit, _) = group_alice
+        .commit_with_options(&proposals, CommitOptions { force_path: true })
+        .unwrap();
+    match group_alice.welcomes_for(&commit, &added) {
+        Err(MelissaError::Group(GroupError::InvalidCommit)) => (),
+        _ => panic!("welcome built before processing the commit"),
+    }
+    group_alice.process_commit(&commit).unwrap();
+    let welcome = group_alice.welcomes_for(&commit, &added).unwrap();
+    assert_eq!(welcome.secrets.len(), 2);
+    let welcome = CommitWelcome::decode_detached(&welcome.encode_detached()).unwrap();
+
+    let group_bob = Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome).unwrap();
+    let group_charlie =
+        Group::from_welcome(charlie_identity, &charlie_init_key_bundle, &welcome).unwrap();
+    assert_eq!(group_bob.get_init_secret(), group_alice.get_init_secret());
+    assert_eq!(
+        group_charlie.get_init_secret(),
+        group_alice.get_init_secret()
+    );
+    assert_eq!(group_bob.tree.get_own_leaf_index(), 2);
+    assert_eq!(group_charlie.tree.get_own_leaf_index(), 4);
+    // Each entry only opens with its own key package
+    assert!(group_bob.tree.nodes[1].secret.is_some());
+    assert!(group_charlie.tree.nodes[1].secret.is_none());
+    assert!(group_charlie.tree.nodes[3].secret.is_some());
+    match Group::from_welcome(Identity::random(), &dave_init_key_bundle, &welcome) {
+        Err(MelissaError::Group(GroupError::InvalidWelcome)) => (),
+        _ => panic!("joined from a welcome for other members"),
+    }
+    match group_alice.welcomes_for(&commit, &[dave_init_key_bundle.init_key]) {
+        Err(MelissaError::Group(GroupError::InvalidWelcome)) => (),
+        _ => panic!("welcome for a member that wasn't added"),
+    }
+
+    // Nothing can be encrypted to an all-zero init key
+    let zero = X25519PublicKey::from_slice(&[0u8; 32]).unwrap();
+    let zero_init_key = UserInitKey::new(&[zero], &Identity::random());
+    match group_alice.commit(&[Proposal::Add(zero_init_key)]) {
+        Err(MelissaError::Group(GroupError::InvalidWelcome)) => (),
+        _ => panic!("welcome encrypted to an all-zero init key"),
+    }
+}
+
+#[test]
+fn single_member_group() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    assert_eq!(group_alice.tree.nodes.len(), 1);
+    assert_eq!(group_alice.tree.get_leaf_count(), 1);
+    assert_eq!(group_alice.tree.get_own_leaf_index(), 0);
+    assert_eq!(group_alice.members().len(), 1);
+    assert!(group_alice.validate().is_ok());
+    assert_eq!(group_alice.tree.authorized_leaves(0).unwrap(), vec![0]);
+    assert_eq!(group_alice.tree.update_path_cost(0).unwrap(), (0, 0));
+    assert!(group_alice
+        .tree
+        .path_secret_recipients(0)
+        .unwrap()
+        .is_empty());
+    assert!(group_alice.tree.verify_parent_hashes());
+    let group = Group::decode_detached(&group_alice.encode_detached()).unwrap();
+    assert_eq!(group.tree.tree_hash(), group_alice.tree.tree_hash());
+
+    // A commit with nothing to apply re-keys the only leaf, its path is empty
+    let leaf_key = group_alice.tree.nodes[0].dh_public_key;
+    let (commit, welcome) = group_alice.commit(&[]).unwrap();
+    assert!(welcome.is_none());
+    assert!(commit.path.as_ref().unwrap().nodes.is_empty());
+    group_alice.process_commit(&commit).unwrap();
+    assert_ne!(group_alice.tree.nodes[0].dh_public_key, leaf_key);
+    assert_eq!(group_alice.tree.nodes.len(), 1);
+    assert!(group_alice.validate().is_ok());
+
+    // The first add grows the tree to two leaves under a new root
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    assert_eq!(group_alice.tree.nodes.len(), 3);
+    assert_eq!(group_bob.tree.get_own_leaf_index(), 2);
+    assert_eq!(group_alice.tree.tree_hash(), group_bob.tree.tree_hash());
+    assert!(group_alice.validate().is_ok());
+
+    let (commit, _) = group_bob.commit(&[]).unwrap();
+    assert_eq!(commit.path.as_ref().unwrap().nodes.len(), 1);
+    group_bob.process_commit(&commit).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    assert_eq!(group_alice.tree.authorized_leaves(1).unwrap(), vec![0, 2]);
+    assert!(group_alice.validate().is_ok());
+    assert!(group_bob.validate().is_ok());
+}
+
+#[test]
+fn proposal_validation_matrix() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity.clone(), alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+
+    let update_bob = group_bob.propose_update();
+    let reinit = Proposal::ReInit(ReInit {
+        group_id: GroupId::random(),
+        version: CURRENT_VERSION,
+        cipher_suite: AES128GCM_CURVE25519_SHA256,
+    });
+    let charlie_init_key = UserInitKeyBundle::new(&Identity::random()).init_key;
+    let add_charlie = Proposal::Add(charlie_init_key.clone());
+    let cases = vec![
+        (
+            vec![update_bob.clone(), Proposal::Remove(1)],
+            GroupError::ConflictingProposals,
+        ),
+        (
+            vec![Proposal::Remove(1), Proposal::Remove(1)],
+            GroupError::ConflictingProposals,
+        ),
+        (
+            vec![update_bob.clone(), update_bob.clone()],
+            GroupError::ConflictingProposals,
+        ),
+        (
+            vec![reinit.clone(), add_charlie.clone()],
+            GroupError::ReInitNotAlone,
+        ),
+        (
+            vec![reinit.clone(), reinit.clone()],
+            GroupError::ReInitNotAlone,
+        ),
+        (
+            vec![add_charlie.clone(), add_charlie.clone()],
+            GroupError::DuplicateKey,
+        ),
+        (
+            vec![Proposal::Add(
+                UserInitKeyBundle::new(&alice_identity).init_key,
+            )],
+            GroupError::DuplicateKey,
+        ),
+        (
+            vec![Proposal::Add(UserInitKey::new(
+                &[group_alice.tree.nodes[2].dh_public_key.unwrap()],
+                &Identity::random(),
+            ))],
+            GroupError::DuplicateKey,
+        ),
+        (vec![Proposal::Remove(0)], GroupError::InvalidProposal),
+        (vec![Proposal::Remove(2)], GroupError::InvalidProposal),
+        (
+            vec![Proposal::ExternalInit(ExternalInit {
+                kem_output: X25519KeyPair::new_random().public_key,
+            })],
+            GroupError::InvalidProposal,
+        ),
+    ];
+    let same = |e: &GroupError, expected: &GroupError| {
+        std::mem::discriminant(e) == std::mem::discriminant(expected)
+    };
+    for (proposals, expected) in cases {
+        match validate_proposal_list(&proposals, &group_alice.roster, &group_alice.tree, 0) {
+            Err(ref e) if same(e, &expected) => (),
+            _ => panic!("{} not detected", expected),
+        }
+        match group_alice.commit(&proposals) {
+            Err(MelissaError::Group(ref e)) if same(e, &expected) => (),
+            _ => panic!("{} not detected in commit", expected),
+        }
+    }
+
+    // Legal combinations pass
+    for proposals in [
+        vec![update_bob.clone(), add_charlie.clone()],
+        vec![Proposal::Remove(1), add_charlie],
+        vec![reinit],
+    ]
+    .iter()
+    {
+        assert!(
+            validate_proposal_list(proposals, &group_alice.roster, &group_alice.tree, 0).is_ok()
+        );
+    }
+    // A removed member's key can come back in the same commit
+    let mut group = group_alice.clone();
+    let (commit, _) = group
+        .commit(&[
+            Proposal::Remove(1),
+            Proposal::Add(UserInitKeyBundle::new(&group_bob.id).init_key),
+        ])
+        .unwrap();
+    group.process_commit(&commit).unwrap();
+}
+
+#[test]
+fn create_group() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let alice_init_key_bundle = UserInitKeyBundle::new(&alice_identity);
+    let group_id = GroupId::random();
+    let create = || {
+        Group::create(
+            alice_identity.clone(),
+            alice_credential.clone(),
+            group_id.clone(),
+            AES128GCM_CURVE25519_SHA256,
+            &alice_init_key_bundle,
+        )
+        .unwrap()
+    };
+    let mut group_alice = create();
+    let other = create();
+    assert_eq!(group_alice.epoch(), 0);
+    assert!(group_alice.group_id == other.group_id);
+    assert_eq!(group_alice.tree.tree_hash(), other.tree.tree_hash());
+    assert_ne!(group_alice.get_init_secret(), other.get_init_secret());
+    assert_ne!(
+        group_alice.epoch_authenticator().unwrap(),
+        other.epoch_authenticator().unwrap()
+    );
+    assert!(group_alice.confirmed_transcript_hash().is_empty());
+    assert!(!group_alice.interim_transcript_hash().is_empty());
+    assert_eq!(
+        group_alice.tree.nodes[0].dh_public_key,
+        Some(alice_init_key_bundle.init_key.init_keys[0])
+    );
+    assert!(group_alice.validate().is_ok());
+    assert!(group_alice.encrypt_application_message(b"hello").is_ok());
+
+    // The founding epoch leads into the next one like any other
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    assert_eq!(group_alice.epoch(), 1);
+    assert_eq!(group_alice.get_init_secret(), group_bob.get_init_secret());
+    assert!(group_alice.validate().is_ok());
+
+    match Group::create(
+        alice_identity.clone(),
+        alice_credential.clone(),
+        group_id.clone(),
+        AES128GCM_P256_SHA256,
+        &alice_init_key_bundle,
+    ) {
+        Err(MelissaError::Group(GroupError::UnsupportedCipherSuite)) => (),
+        _ => panic!("unsupported cipher suite accepted"),
+    }
+    match Group::create(
+        alice_identity,
+        alice_credential,
+        group_id,
+        AES128GCM_CURVE25519_SHA256,
+        &UserInitKeyBundle::new(&Identity::random()),
+    ) {
+        Err(MelissaError::Group(GroupError::InvalidLeaf)) => (),
+        _ => panic!("key package of someone else accepted"),
+    }
+}
+
+#[test]
+fn forget_epoch_secrets() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    for epoch in 0..3 {
+        let (commit, _) = group_alice.commit(&[]).unwrap();
+        group_alice.process_commit(&commit).unwrap();
+        group_bob.process_commit(&commit).unwrap();
+        // Earlier epochs left nothing behind
+        assert!(group_bob.ratchets.is_empty());
+        assert_eq!(group_bob.forget_epoch_secrets(0), 0);
+
+        let messages: Vec<EncryptedMessage> = (0..10u8)
+            .map(|i| {
+                group_alice
+                    .encrypt_application_message(&[epoch, i])
+                    .unwrap()
+            })
+            .collect();
+        // Bob skips generations 0 to 8, keeping their keys
+        group_bob.decrypt_application_message(&messages[9]).unwrap();
+        assert_eq!(group_bob.forget_epoch_secrets(3), 7);
+        assert_eq!(group_bob.forget_epoch_secrets(3), 0);
+
+        // Generations 7 and 8 are within the window
+        for (i, message) in messages.iter().enumerate().take(9).skip(7) {
+            let decrypted = group_bob.decrypt_application_message(message).unwrap();
+            assert_eq!(decrypted.content, vec![epoch, i as u8]);
+        }
+        match group_bob.decrypt_application_message(&messages[6]) {
+            Err(MelissaError::Group(GroupError::InvalidGeneration(
+                StageError::TooFarInThePast,
+            ))) => {}
+            _ => panic!("forgotten key still available"),
+        }
+    }
+}
+
+#[test]
+fn group_context() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    let (commit, _) = group_bob.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+
+    let context = group_alice.context();
+    assert_eq!(context.epoch, 2);
+    assert_eq!(context.tree_hash, group_alice.tree.tree_hash());
+    assert_eq!(
+        context.roster_hash,
+        roster_hash(group_alice.hash_algorithm(), &group_alice.roster)
+    );
+    assert_eq!(
+        context.confirmed_transcript_hash,
+        group_alice.confirmed_transcript_hash()
+    );
+    // Both members encode the same bytes, and they decode back to them
+    let encoded = context.encode_detached();
+    assert_eq!(group_bob.context().encode_detached(), encoded);
+    assert_eq!(
+        GroupContext::decode_detached(&encoded)
+            .unwrap()
+            .encode_detached(),
+        encoded
+    );
+
+    // Same tree, but Bob's copy has a different identity for Alice
+    let mut group_bob_diverged = group_bob.clone();
+    group_bob_diverged.roster[0] = Some(BasicCredential {
+        identity: "Mallory".as_bytes().to_vec(),
+        public_key: group_alice.id.public_key,
+    });
+    assert_eq!(
+        group_bob_diverged.tree.tree_hash(),
+        group_bob.tree.tree_hash()
+    );
+
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    assert_ne!(group_alice.context().encode_detached(), encoded);
+    group_bob.process_commit(&commit).unwrap();
+    assert_eq!(
+        group_bob.epoch_authenticator().unwrap(),
+        group_alice.epoch_authenticator().unwrap()
+    );
+    // The diverged copy derives another epoch and so another confirmation tag
+    match group_bob_diverged.process_commit(&commit) {
+        Err(MelissaError::Group(GroupError::InvalidConfirmationTag)) => (),
+        _ => panic!("diverged group accepted the commit"),
+    }
+}
+
+#[test]
+fn past_epoch_messages() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+
+    // Alice sends while Bob already commits the next epoch
+    let epoch = group_alice.epoch();
+    let in_flight: Vec<EncryptedMessage> = (0..3u8)
+        .map(|i| group_alice.encrypt_application_message(&[i]).unwrap())
+        .collect();
+    assert!(in_flight.iter().all(|message| message.epoch == epoch));
+    let (commit, _) = group_bob.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+
+    // Out of order and interleaved with messages of the new epoch
+    let current = group_alice.encrypt_application_message(b"new").unwrap();
+    assert_eq!(current.epoch, epoch + 1);
+    let results = group_bob.decrypt_pending(&[in_flight[1].clone(), current, in_flight[0].clone()]);
+    assert_eq!(results[0].as_ref().unwrap().content, vec![1]);
+    assert_eq!(results[1].as_ref().unwrap().content, b"new".to_vec());
+    assert_eq!(results[2].as_ref().unwrap().content, vec![0]);
+    // Keys of past epochs are used only once as well
+    match group_bob.decrypt_application_message(&in_flight[0]) {
+        Err(MelissaError::Group(GroupError::InvalidGeneration(StageError::TooFarInThePast))) => (),
+        _ => panic!("replayed message accepted"),
+    }
+
+    // The epoch is kept for PAST_EPOCHS_KEPT commits, and the state survives encoding
+    for _ in 1..PAST_EPOCHS_KEPT {
+        let (commit, _) = group_alice.commit(&[]).unwrap();
+        group_alice.process_commit(&commit).unwrap();
+        group_bob.process_commit(&commit).unwrap();
+    }
+    let mut group_bob = Group::decode_detached(&group_bob.encode_detached()).unwrap();
+    assert_eq!(
+        group_bob
+            .decrypt_application_message(&in_flight[2])
+            .unwrap()
+            .content,
+        vec![2]
+    );
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    assert_eq!(group_bob.past_epochs.len(), PAST_EPOCHS_KEPT);
+    match group_bob.decrypt_application_message(&in_flight[1]) {
+        Err(MelissaError::Group(GroupError::EpochTooOld)) => (),
+        _ => panic!("message of a dropped epoch accepted"),
+    }
+
+    // Nothing can be sent in an epoch that didn't start yet
+    let mut future = group_alice.encrypt_application_message(b"later").unwrap();
+    future.epoch += 1;
+    match group_bob.decrypt_application_message(&future) {
+        Err(MelissaError::Group(GroupError::WrongEpoch { .. })) => (),
+        _ => panic!("message of a future epoch accepted"),
+    }
+}
+
+#[test]
+fn credential_validator() {
+    struct RejectKey(SignaturePublicKey);
+    impl CredentialValidator for RejectKey {
+        fn validate(
+            &self,
+            credential: &BasicCredential,
+            _: &GroupContext,
+        ) -> Result<(), CredentialError> {
+            if credential.public_key == self.0 {
+                Err(CredentialError::Rejected)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let alice_public_key = alice_identity.public_key;
+    let mut bob_identity = Identity::random();
+    bob_identity.id = b"Bob".to_vec();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut mallory_identity = Identity::random();
+    mallory_identity.id = b"Mallory".to_vec();
+    let mallory_init_key = UserInitKeyBundle::new(&mallory_identity).init_key;
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    group_alice
+        .set_credential_validator(Arc::new(RejectKey(mallory_identity.public_key)))
+        .unwrap();
+
+    // An Add of the rejected key fails, others go through
+    match group_alice.commit(&[Proposal::Add(mallory_init_key)]) {
+        Err(MelissaError::Group(GroupError::InvalidCredential(CredentialError::Rejected))) => (),
+        _ => panic!("rejected credential added"),
+    }
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    // The roster holds the identity signed in the key package
+    assert_eq!(group_alice.roster[1].as_ref().unwrap().identity, b"Bob");
+    let welcome = welcome.unwrap();
+
+    // A joiner checks the Welcome's roster with its own validator
+    match Group::from_welcome_with_validator(
+        bob_identity.clone(),
+        &bob_init_key_bundle,
+        &welcome,
+        Arc::new(RejectKey(alice_public_key)),
+    ) {
+        Err(MelissaError::Group(GroupError::InvalidCredential(CredentialError::Rejected))) => (),
+        _ => panic!("rejected credential imported"),
+    }
+    let mut group_bob = Group::from_welcome_with_validator(
+        bob_identity,
+        &bob_init_key_bundle,
+        &welcome,
+        Arc::new(RejectKey(mallory_identity.public_key)),
+    )
+    .unwrap();
+    assert_eq!(group_bob.roster[1].as_ref().unwrap().identity, b"Bob");
+
+    // The imported roster is checked when the validator is set
+    match group_bob.set_credential_validator(Arc::new(RejectKey(alice_public_key))) {
+        Err(GroupError::InvalidCredential(CredentialError::Rejected)) => (),
+        _ => panic!("rejected credential kept"),
+    }
+    group_bob
+        .set_credential_validator(Arc::new(RejectKey(mallory_identity.public_key)))
+        .unwrap();
+
+    // So is an external join
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    let group_info = group_alice.group_info().unwrap();
+    let (group_mallory, external_commit) =
+        Group::join_by_external_commit(mallory_identity, &group_info).unwrap();
+    assert_eq!(
+        group_mallory.roster[2].as_ref().unwrap().identity,
+        b"Mallory"
+    );
+    match group_bob.process_external_commit(&external_commit) {
+        Err(MelissaError::Group(GroupError::InvalidCredential(CredentialError::Rejected))) => (),
+        _ => panic!("rejected credential joined"),
+    }
+    assert_eq!(group_bob.get_members().len(), 2);
+
+    // X.509 isn't supported yet
+    match group_bob.set_credential_validator(Arc::new(X509CredentialValidator)) {
+        Err(GroupError::InvalidCredential(CredentialError::Unsupported)) => (),
+        _ => panic!("unsupported credential type accepted"),
+    }
+}
+
+#[test]
+fn required_capabilities() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let required = RequiredCapabilitiesExtension {
+        extensions: vec![EXTENSION_TYPE_EXTERNAL_SENDERS],
+        proposals: vec![PROPOSAL_TYPE_CREDENTIAL_UPDATE],
+        credentials: vec![CredentialType::Basic as u8],
+    };
+    let with_capabilities = |identity: &Identity, capabilities: Option<CapabilitiesExtension>| {
+        let mut init_key = UserInitKeyBundle::new(identity).init_key;
+        init_key
+            .extensions
+            .retain(|e| e.extension_type != EXTENSION_TYPE_CAPABILITIES);
+        if let Some(capabilities) = capabilities {
+            init_key.extensions.push(capabilities.to_extension());
+        }
+        init_key.signature = identity.sign(&init_key.tbs_bytes());
+        init_key
+    };
+    let charlie_identity = Identity::random();
+    let mut capabilities = CapabilitiesExtension::supported();
+    capabilities
+        .extensions
+        .retain(|e| *e != EXTENSION_TYPE_EXTERNAL_SENDERS);
+
+    // Nothing is required yet, so Charlie gets in
+    let (commit, _) = group_alice
+        .commit(&[Proposal::Add(with_capabilities(
+            &charlie_identity,
+            Some(capabilities.clone()),
+        ))])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+
+    // Requirements that a current member lacks can't be committed
+    let proposal = group_alice.propose_required_capabilities(&required);
+    match group_alice.commit(std::slice::from_ref(&proposal)) {
+        Err(MelissaError::Group(GroupError::UnsupportedCapability)) => (),
+        _ => panic!("requirement committed that a member lacks"),
+    }
+    assert_eq!(
+        RequiredCapabilitiesExtension::from_extensions(&group_alice.extensions).unwrap(),
+        None
+    );
+    // Unless that member is removed in the same commit
+    let (commit, _) = group_alice
+        .commit(&[Proposal::Remove(1), proposal.clone()])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    assert_eq!(
+        RequiredCapabilitiesExtension::from_extensions(&group_alice.extensions).unwrap(),
+        Some(required.clone())
+    );
+    match group_alice.commit(&[proposal.clone(), proposal]) {
+        Err(MelissaError::Group(GroupError::ConflictingProposals)) => (),
+        _ => panic!("two context extension proposals committed"),
+    }
+
+    // Lacking a required extension, or claiming no capabilities at all
+    for init_key in [
+        with_capabilities(&charlie_identity, Some(capabilities)),
+        with_capabilities(&charlie_identity, None),
+    ] {
+        match group_alice.commit(&[Proposal::Add(init_key)]) {
+            Err(MelissaError::Group(GroupError::UnsupportedCapability)) => (),
+            _ => panic!("member without required capabilities added"),
+        }
+    }
+
+    // The default key package supports everything the group can require
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    assert_eq!(group_bob.get_members().len(), 2);
+    assert_eq!(
+        RequiredCapabilitiesExtension::from_extensions(&group_bob.extensions).unwrap(),
+        Some(required)
+    );
+
+    // Dropping the requirement is a committed change too, that every member applies
+    let proposal = Proposal::GroupContextExtensions(vec![]);
+    let (commit, _) = group_alice.commit(&[proposal]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    assert!(group_bob.extensions.is_empty());
+    assert_eq!(
+        group_alice.epoch_authenticator().unwrap(),
+        group_bob.epoch_authenticator().unwrap()
+    );
+}
+
+#[test]
+fn send_and_process_messages() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let welcome = MlsMessage::Welcome(welcome.unwrap());
+    let mut group_bob = match parse_message(&welcome.encode_detached()).unwrap() {
+        MlsMessage::Welcome(welcome) => {
+            Group::from_welcome(bob_identity.clone(), &bob_init_key_bundle, &welcome).unwrap()
+        }
+        _ => panic!("not a welcome"),
+    };
+    assert!(group_bob.process_message(&welcome).is_err());
+
+    // A commit is handed back to be applied
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let message = parse_message(&MlsMessage::Commit(commit).encode_detached()).unwrap();
+    match group_bob.process_message(&message).unwrap() {
+        ProcessedMessage::Commit(commit) => group_bob.process_commit(&commit).unwrap(),
+        _ => panic!("commit not handed back"),
+    }
+
+    // Application messages both ways, each advancing the sender's ratchet
+    for (i, content) in [&b"hello"[..], b"", &[0xffu8; 100][..]].iter().enumerate() {
+        let message = group_alice.create_application_message(content).unwrap();
+        let encoded = message.encode_detached();
+        match group_bob
+            .process_message(&parse_message(&encoded).unwrap())
+            .unwrap()
+        {
+            ProcessedMessage::Application(decrypted) => {
+                assert_eq!(decrypted.sender, 0);
+                assert_eq!(decrypted.generation, Generation(i as u32));
+                assert_eq!(decrypted.content, content.to_vec());
+            }
+            _ => panic!("not an application message"),
+        }
+        assert!(group_bob.process_message(&message).is_err());
+    }
+    let message = group_bob.create_application_message(b"hi").unwrap();
+    match group_alice.process_message(&message).unwrap() {
+        ProcessedMessage::Application(decrypted) => {
+            assert_eq!((decrypted.sender, decrypted.content), (1, b"hi".to_vec()))
+        }
+        _ => panic!("not an application message"),
+    }
+
+    // Proposals are stored for a later commit
+    let proposal = Proposal::Remove(0);
+    let message = MlsMessage::Proposal(SignedProposal::new(
+        &group_bob.group_id,
+        group_bob.epoch(),
+        Sender::Member(1),
+        proposal.clone(),
+        &bob_identity,
+    ));
+    match group_alice.process_message(&message).unwrap() {
+        ProcessedMessage::Proposal(proposal_ref) => {
+            assert_eq!(proposal_ref, ProposalRef::from_proposal(&proposal))
+        }
+        _ => panic!("proposal not stored"),
+    }
+}
+
+#[test]
+fn ratchet_exhausted() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_identity = Identity::random();
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let mut group_bob =
+        Group::from_welcome(bob_identity, &bob_init_key_bundle, &welcome.unwrap()).unwrap();
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+
+    // The last generation that can be sent is u32::MAX - 1
+    group_alice
+        .ratchet(0)
+        .unwrap()
+        .set_next_generation(Generation(u32::MAX - 1));
+    let message = group_alice.encrypt_application_message(b"last").unwrap();
+    match group_alice.encrypt_application_message(b"one too many") {
+        Err(MelissaError::Group(GroupError::RatchetExhausted)) => (),
+        _ => panic!("generation wrapped"),
+    }
+    group_bob
+        .ratchet(0)
+        .unwrap()
+        .set_next_generation(Generation(u32::MAX - 1));
+    let decrypted = group_bob.decrypt_application_message(&message).unwrap();
+    assert_eq!(decrypted.generation, Generation(u32::MAX - 1));
+
+    // A commit starts the generations over
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_bob.process_commit(&commit).unwrap();
+    let message = group_alice.encrypt_application_message(b"again").unwrap();
+    assert_eq!(
+        group_bob
+            .decrypt_application_message(&message)
+            .unwrap()
+            .generation,
+        Generation(0)
+    );
+}
+
+#[test]
+fn tree_to_dot() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut bob_identity = Identity::random();
+    bob_identity.id = vec![];
+    let bob_init_key_bundle = UserInitKeyBundle::new(&bob_identity);
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, _) = group_alice
+        .commit(&[Proposal::Add(bob_init_key_bundle.init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let dot = group_alice.tree_to_dot();
+    assert!(dot.contains("0 (level 0)\\nAlice"));
+    // Without an identity the label falls back to the key
+    let bob_key = bytes_to_hex(&bob_identity.public_key.as_slice()[..4]);
+    assert!(dot.contains(&format!("2 (level 0)\\n{}", bob_key)));
+}
+
+#[test]
+fn full_rekey() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let bundles: Vec<(Identity, UserInitKeyBundle)> = (0..5)
+        .map(|_| {
+            let identity = Identity::random();
+            let bundle = UserInitKeyBundle::new(&identity);
+            (identity, bundle)
+        })
+        .collect();
+    let adds: Vec<Proposal> = bundles
+        .iter()
+        .map(|(_, bundle)| Proposal::Add(bundle.init_key.clone()))
+        .collect();
+    let (commit, welcome) = group_alice.commit(&adds).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let (identity, bundle) = &bundles[4];
+    let mut group_frank = Group::from_welcome(identity.clone(), bundle, &welcome.unwrap()).unwrap();
+
+    // Removing leaves blanks their direct paths, some of them shared with Frank's
+    let (commit, _) = group_alice
+        .commit(&[Proposal::Remove(3), Proposal::Remove(4)])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    group_frank.process_commit(&commit).unwrap();
+    let frank_leaf = group_frank.tree.get_own_leaf_index();
+    let size = group_frank.tree.get_leaf_count();
+    let mut frank_path = treemath::dirpath(frank_leaf, size);
+    frank_path.push(treemath::root(size));
+    let blanks_on = |group: &Group, path: &[usize]| {
+        path.iter()
+            .filter(|x| group.tree.nodes[**x].is_blank())
+            .count()
+    };
+    assert!(blanks_on(&group_frank, &frank_path) > 0);
+
+    let commit = group_frank.full_rekey().unwrap();
+    assert!(commit.proposals.is_empty() && commit.path.is_some());
+    group_frank.process_commit(&commit).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    assert_eq!(blanks_on(&group_frank, &frank_path), 0);
+    assert_eq!(blanks_on(&group_alice, &frank_path), 0);
+    assert_eq!(group_alice.tree.tree_hash(), group_frank.tree.tree_hash());
+    assert_eq!(
+        group_alice.epoch_authenticator().unwrap(),
+        group_frank.epoch_authenticator().unwrap()
+    );
+}
+
+#[test]
+fn group_context_group_id() {
+    let identity = Identity::random();
+    let credential = BasicCredential {
+        identity: b"Alice".to_vec(),
+        public_key: identity.public_key,
+    };
+    let group = Group::new(identity, credential, GroupId::random());
+    let context = group.context();
+    let encoded = context.encode_detached();
+    assert!(GroupContext::decode_detached(&encoded).is_ok());
+
+    // The same context with a zero-length group id
+    let offset =
+        context.version.encode_detached().len() + context.cipher_suite.encode_detached().len();
+    let mut empty = encoded[..offset].to_vec();
+    encode_vec_u8::<u8>(&mut empty, &[]);
+    empty.extend_from_slice(&encoded[offset + 1 + GROUPIDBYTES..]);
+    match GroupContext::decode_detached(&empty) {
+        Err(CodecError::DecodingError) => (),
+        _ => panic!("empty group id accepted"),
+    }
+}
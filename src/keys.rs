@@ -14,10 +14,16 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
+use clock::*;
 use codec::*;
+use crypto::rng::*;
+pub use crypto::schedule::{derive_epoch_secrets, EpochSecrets};
+use extensions::*;
+use sodiumoxide::crypto::hash::sha256;
 use sodiumoxide::crypto::scalarmult;
 use sodiumoxide::crypto::sign::ed25519;
-use sodiumoxide::randombytes;
+use std::error::Error;
+use std::fmt;
 use tree::*;
 use utils::*;
 
@@ -29,14 +35,117 @@ pub const P256PUBLICKEYBYTES: usize = 32;
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Zero {}
 
-#[derive(Hash, PartialEq, Clone, Copy, Debug)]
-pub struct X25519PublicKey([u8; X25519PUBLICKEYBYTES]);
+#[derive(Debug, PartialEq)]
+pub enum KeyError {
+    InvalidLength { expected: usize, got: usize },
+    UnsupportedCipherSuite,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyError::InvalidLength { expected, got } => {
+                write!(f, "invalid key length: expected {}, got {}", expected, got)
+            }
+            KeyError::UnsupportedCipherSuite => write!(f, "unsupported cipher suite"),
+        }
+    }
+}
+
+impl Error for KeyError {}
+
+fn check_length(bytes: &[u8], expected: usize) -> Result<(), KeyError> {
+    if bytes.len() != expected {
+        return Err(KeyError::InvalidLength {
+            expected,
+            got: bytes.len(),
+        });
+    }
+    Ok(())
+}
+
+// HPKE keys of the cipher suite's KEM. X25519 is the only KEM implemented, so new()
+// rejects the other suites and the key inside is always an X25519 key.
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HpkePublicKey(X25519PublicKey);
+
+impl HpkePublicKey {
+    pub fn new(cipher_suite: CipherSuite, bytes: &[u8]) -> Result<HpkePublicKey, KeyError> {
+        if cipher_suite != AES128GCM_CURVE25519_SHA256 {
+            return Err(KeyError::UnsupportedCipherSuite);
+        }
+        X25519PublicKey::from_slice(bytes).map(HpkePublicKey)
+    }
+    pub fn as_x25519(&self) -> &X25519PublicKey {
+        &self.0
+    }
+    pub fn to_slice(&self) -> Vec<u8> {
+        self.0.to_slice()
+    }
+}
+
+impl From<X25519PublicKey> for HpkePublicKey {
+    fn from(key: X25519PublicKey) -> Self {
+        HpkePublicKey(key)
+    }
+}
+
+impl Codec for HpkePublicKey {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.0.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        X25519PublicKey::decode(cursor).map(HpkePublicKey)
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HpkePrivateKey(X25519PrivateKey);
+
+impl HpkePrivateKey {
+    pub fn new(cipher_suite: CipherSuite, bytes: &[u8]) -> Result<HpkePrivateKey, KeyError> {
+        if cipher_suite != AES128GCM_CURVE25519_SHA256 {
+            return Err(KeyError::UnsupportedCipherSuite);
+        }
+        X25519PrivateKey::from_slice(bytes).map(HpkePrivateKey)
+    }
+    pub fn as_x25519(&self) -> &X25519PrivateKey {
+        &self.0
+    }
+    pub fn public_key(&self) -> HpkePublicKey {
+        HpkePublicKey(self.0.derive_public_key())
+    }
+}
+
+impl From<X25519PrivateKey> for HpkePrivateKey {
+    fn from(key: X25519PrivateKey) -> Self {
+        HpkePrivateKey(key)
+    }
+}
+
+impl Codec for HpkePrivateKey {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.0.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        X25519PrivateKey::decode(cursor).map(HpkePrivateKey)
+    }
+}
+
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct X25519PublicKey(
+    #[cfg_attr(feature = "serde", serde(with = "::utils::serde_hex"))] [u8; X25519PUBLICKEYBYTES],
+);
 
 impl X25519PublicKey {
-    pub fn from_slice(bytes: &[u8]) -> X25519PublicKey {
+    pub fn from_slice(bytes: &[u8]) -> Result<X25519PublicKey, KeyError> {
+        check_length(bytes, X25519PUBLICKEYBYTES)?;
         let mut inner = <[u8; X25519PUBLICKEYBYTES]>::default();
-        inner.copy_from_slice(&bytes[..X25519PUBLICKEYBYTES]);
-        X25519PublicKey(inner)
+        inner.copy_from_slice(bytes);
+        Ok(X25519PublicKey(inner))
     }
     pub fn to_slice(&self) -> Vec<u8> {
         self.0.to_vec()
@@ -48,14 +157,16 @@ impl Codec for X25519PublicKey {
         encode_vec_u16(buffer, &self.0);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let mut value = [0u8; X25519PUBLICKEYBYTES];
-        value.clone_from_slice(&decode_vec_u16(cursor)?[..X25519PUBLICKEYBYTES]);
-        Ok(X25519PublicKey(value))
+        let bytes: Vec<u8> = decode_vec_u16(cursor)?;
+        X25519PublicKey::from_slice(&bytes).map_err(|_| CodecError::DecodingError)
     }
 }
 
 #[derive(PartialEq, Clone, Debug)]
-pub struct X25519PrivateKey([u8; X25519PRIVATEKEYBYTES]);
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct X25519PrivateKey(
+    #[cfg_attr(feature = "serde", serde(with = "::utils::serde_hex"))] [u8; X25519PRIVATEKEYBYTES],
+);
 
 impl X25519PrivateKey {
     pub fn shared_secret(&self, p: &X25519PublicKey) -> Result<[u8; 32], Zero> {
@@ -69,10 +180,11 @@ impl X25519PrivateKey {
         let scalar = scalarmult::curve25519::Scalar::from_slice(&self.0).unwrap();
         X25519PublicKey(scalarmult::curve25519::scalarmult_base(&scalar).0)
     }
-    pub fn from_slice(bytes: &[u8]) -> X25519PrivateKey {
+    pub fn from_slice(bytes: &[u8]) -> Result<X25519PrivateKey, KeyError> {
+        check_length(bytes, X25519PRIVATEKEYBYTES)?;
         let mut inner = <[u8; X25519PRIVATEKEYBYTES]>::default();
-        inner.copy_from_slice(&bytes[..X25519PRIVATEKEYBYTES]);
-        X25519PrivateKey(inner)
+        inner.copy_from_slice(bytes);
+        Ok(X25519PrivateKey(inner))
     }
     pub fn to_bytes(&self) -> [u8; X25519PRIVATEKEYBYTES] {
         self.0
@@ -90,9 +202,10 @@ impl Codec for X25519PrivateKey {
         encode_vec_u16(buffer, &self.0);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let mut value = [0u8; X25519PRIVATEKEYBYTES];
-        value.clone_from_slice(&decode_vec_u16(cursor)?[..X25519PRIVATEKEYBYTES]);
-        Ok(X25519PrivateKey(value))
+        let mut bytes: Vec<u8> = decode_vec_u16(cursor)?;
+        let private_key = X25519PrivateKey::from_slice(&bytes);
+        erase(&mut bytes);
+        private_key.map_err(|_| CodecError::DecodingError)
     }
 }
 
@@ -102,8 +215,12 @@ pub struct X25519KeyPair {
 }
 
 impl X25519KeyPair {
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
     pub fn new_random() -> X25519KeyPair {
-        let random_bytes = randombytes::randombytes(scalarmult::curve25519::SCALARBYTES);
+        X25519KeyPair::new_with_rng(&mut SystemRng)
+    }
+    pub fn new_with_rng(rng: &mut dyn Rng) -> X25519KeyPair {
+        let random_bytes = rng.random_bytes(scalarmult::curve25519::SCALARBYTES);
         let mut private_key: scalarmult::curve25519::Scalar =
             scalarmult::curve25519::Scalar([0u8; scalarmult::curve25519::SCALARBYTES]);
         private_key.0[..scalarmult::curve25519::SCALARBYTES]
@@ -141,27 +258,63 @@ pub struct LeafKey {
     pub name: String,
 }
 
-pub type SignaturePublicKey = ed25519::PublicKey;
+#[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SignaturePublicKey(ed25519::PublicKey);
+
+impl SignaturePublicKey {
+    pub fn from_slice(bytes: &[u8]) -> Result<SignaturePublicKey, KeyError> {
+        check_length(bytes, ed25519::PUBLICKEYBYTES)?;
+        Ok(SignaturePublicKey(
+            ed25519::PublicKey::from_slice(bytes).unwrap(),
+        ))
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        &(self.0).0
+    }
+    pub fn verify(&self, payload: &[u8], signature: &Signature) -> bool {
+        ed25519::verify_detached(signature, payload, &self.0)
+    }
+}
 
 impl Codec for SignaturePublicKey {
     fn encode(&self, buffer: &mut Vec<u8>) {
-        encode_vec_u16(buffer, &self.0);
+        encode_vec_u16(buffer, self.as_slice());
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let bytes = decode_vec_u16(cursor)?;
-        Ok(SignaturePublicKey::from_slice(&bytes).unwrap())
+        let bytes: Vec<u8> = decode_vec_u16(cursor)?;
+        SignaturePublicKey::from_slice(&bytes).map_err(|_| CodecError::DecodingError)
     }
 }
 
-pub type SignaturePrivateKey = ed25519::SecretKey;
+// The inner key is zeroed by sodiumoxide when dropped
+#[derive(PartialEq, Eq, Clone)]
+pub struct SignaturePrivateKey(ed25519::SecretKey);
+
+impl SignaturePrivateKey {
+    pub fn from_slice(bytes: &[u8]) -> Result<SignaturePrivateKey, KeyError> {
+        check_length(bytes, ed25519::SECRETKEYBYTES)?;
+        Ok(SignaturePrivateKey(
+            ed25519::SecretKey::from_slice(bytes).unwrap(),
+        ))
+    }
+    pub fn as_slice(&self) -> &[u8] {
+        &(self.0).0
+    }
+    pub fn sign(&self, payload: &[u8]) -> Signature {
+        ed25519::sign_detached(payload, &self.0)
+    }
+}
 
 impl Codec for SignaturePrivateKey {
     fn encode(&self, buffer: &mut Vec<u8>) {
-        encode_vec_u16(buffer, &self.0);
+        encode_vec_u16(buffer, self.as_slice());
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let bytes = decode_vec_u16(cursor)?;
-        Ok(SignaturePrivateKey::from_slice(&bytes).unwrap())
+        let mut bytes: Vec<u8> = decode_vec_u16(cursor)?;
+        let private_key = SignaturePrivateKey::from_slice(&bytes);
+        erase(&mut bytes);
+        private_key.map_err(|_| CodecError::DecodingError)
     }
 }
 
@@ -172,8 +325,8 @@ impl Codec for Signature {
         encode_vec_u16(buffer, &self.0);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let bytes = decode_vec_u16(cursor)?;
-        Ok(Signature::from_slice(&bytes).unwrap())
+        let bytes: Vec<u8> = decode_vec_u16(cursor)?;
+        Signature::from_slice(&bytes).ok_or(CodecError::DecodingError)
     }
 }
 
@@ -208,40 +361,45 @@ impl Codec for Identity {
 }
 
 impl Identity {
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
     pub fn random() -> Self {
-        let id = randombytes::randombytes(4).to_vec();
-        let (public_key, private_key) = ed25519::gen_keypair();
+        Identity::random_with_rng(&mut SystemRng)
+    }
+    pub fn random_with_rng(rng: &mut dyn Rng) -> Self {
+        let id = rng.random_bytes(4);
+        let seed = ed25519::Seed::from_slice(&rng.random_bytes(ed25519::SEEDBYTES)).unwrap();
+        let (public_key, private_key) = ed25519::keypair_from_seed(&seed);
         Self {
             id,
-            public_key,
-            private_key,
+            public_key: SignaturePublicKey(public_key),
+            private_key: SignaturePrivateKey(private_key),
         }
     }
 
     pub fn sign(&self, payload: &[u8]) -> Signature {
-        ed25519::sign_detached(payload, &self.private_key)
+        self.private_key.sign(payload)
     }
     pub fn verify(&self, payload: &[u8], signature: &Signature) -> bool {
-        ed25519::verify_detached(signature, payload, &self.public_key)
+        self.public_key.verify(payload, signature)
     }
 }
 
 impl Drop for Identity {
     fn drop(&mut self) {
-        erase(&mut self.private_key.0);
-        erase(&mut self.public_key.0);
         erase(&mut self.id);
     }
 }
 
+// Signed types encode as tbs_bytes() followed by the signature, so that signing,
+// verifying and encoding all use the one to-be-signed view of the fields
 pub trait Signable: Sized {
-    fn unsigned_payload(&self) -> Vec<u8>;
+    fn tbs_bytes(&self) -> Vec<u8>;
 
     fn sign(&mut self, id: &Identity) -> Signature {
-        id.sign(&self.unsigned_payload())
+        id.sign(&self.tbs_bytes())
     }
     fn verify(&self, id: &Identity, signature: &Signature) -> bool {
-        id.verify(&self.unsigned_payload(), signature)
+        id.verify(&self.tbs_bytes(), signature)
     }
 }
 
@@ -253,14 +411,16 @@ pub enum CredentialType {
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BasicCredential {
+    #[cfg_attr(feature = "serde", serde(with = "::utils::serde_hex"))]
     pub identity: Vec<u8>, // <0..2^16-1>;
     pub public_key: SignaturePublicKey,
 }
 
 impl BasicCredential {
     pub fn verify(&self, payload: &[u8], signature: &Signature) -> bool {
-        ed25519::verify_detached(signature, payload, &self.public_key)
+        self.public_key.verify(payload, signature)
     }
 }
 
@@ -280,60 +440,170 @@ impl Codec for BasicCredential {
 }
 
 pub type CipherSuite = u16;
-pub type ProtocolVersion = u16;
 
 pub const AES128GCM_P256_SHA256: CipherSuite = 0;
 pub const AES128GCM_CURVE25519_SHA256: CipherSuite = 1;
 
-pub const CURRENT_VERSION: u16 = 1;
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(u16)]
+pub enum ProtocolVersion {
+    Mls10 = 1,
+}
+
+impl Codec for ProtocolVersion {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        (*self as u16).encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        match u16::decode(cursor)? {
+            1 => Ok(ProtocolVersion::Mls10),
+            _ => Err(CodecError::UnsupportedVersion),
+        }
+    }
+}
+
+pub const CURRENT_VERSION: ProtocolVersion = ProtocolVersion::Mls10;
+
+// Lifetime given to new UserInitKeys. It starts an hour early to allow for clock skew.
+pub const DEFAULT_LIFETIME_SECONDS: u64 = 90 * 24 * 3600;
+pub const CLOCK_SKEW_SECONDS: u64 = 3600;
+
+#[derive(Debug, PartialEq)]
+pub enum KeyPackageError {
+    InvalidSignature,
+    InitKeyReusesSignatureKey,
+    MissingLifetime,
+    Expired,
+    NotYetValid,
+}
+
+impl fmt::Display for KeyPackageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyPackageError::InvalidSignature => write!(f, "invalid signature"),
+            KeyPackageError::InitKeyReusesSignatureKey => {
+                write!(f, "init key reuses the signature key")
+            }
+            KeyPackageError::MissingLifetime => write!(f, "missing lifetime"),
+            KeyPackageError::Expired => write!(f, "expired"),
+            KeyPackageError::NotYetValid => write!(f, "not yet valid"),
+        }
+    }
+}
+
+impl Error for KeyPackageError {}
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct UserInitKey {
     pub cipher_suites: Vec<CipherSuite>,
     pub init_keys: Vec<X25519PublicKey>, /* [2^16-1] */
     pub algorithm: SignatureScheme,
     pub identity_key: SignaturePublicKey,
+    // Signed along with the key, so an Add cannot swap in someone else's identity
+    #[cfg_attr(feature = "serde", serde(with = "::utils::serde_hex"))]
+    pub identity: Vec<u8>,
     pub signature: Signature,
     pub supported_versions: Vec<ProtocolVersion>,
+    pub extensions: Vec<Extension>,
 }
 
 impl UserInitKey {
     pub fn new(init_keys: &[X25519PublicKey], identity: &Identity) -> Self {
+        let now = SystemClock.now();
+        let lifetime = LifetimeExtension {
+            not_before: now.saturating_sub(CLOCK_SKEW_SECONDS),
+            not_after: now.saturating_add(DEFAULT_LIFETIME_SECONDS),
+        };
+        UserInitKey::new_with_lifetime(init_keys, identity, lifetime)
+    }
+    pub fn new_with_lifetime(
+        init_keys: &[X25519PublicKey],
+        identity: &Identity,
+        lifetime: LifetimeExtension,
+    ) -> Self {
         let mut init_key = Self {
             cipher_suites: vec![AES128GCM_CURVE25519_SHA256],
             init_keys: init_keys.to_owned(),
             algorithm: ED25519,
             identity_key: identity.public_key,
+            identity: identity.id.clone(),
             signature: Signature::from_slice(&[0u8; ed25519::SIGNATUREBYTES]).unwrap(),
             supported_versions: vec![CURRENT_VERSION],
+            extensions: vec![
+                lifetime.to_extension(),
+                CapabilitiesExtension::supported().to_extension(),
+            ],
         };
-        init_key.signature = identity.sign(&init_key.unsigned_payload());
+        init_key.signature = identity.sign(&init_key.tbs_bytes());
         init_key
     }
+    // The credential the new member joins the roster with
+    pub fn credential(&self) -> BasicCredential {
+        BasicCredential {
+            identity: self.identity.clone(),
+            public_key: self.identity_key,
+        }
+    }
+    // A key package without the extension claims no capabilities
+    pub fn capabilities(&self) -> Result<CapabilitiesExtension, CodecError> {
+        Ok(CapabilitiesExtension::from_extensions(&self.extensions)?.unwrap_or_default())
+    }
+    // Checks the signature under the credential's own key and that no init key reuses
+    // that key, but not the lifetime
+    pub fn verify_keys(&self) -> Result<(), KeyPackageError> {
+        if !self.self_verify() {
+            return Err(KeyPackageError::InvalidSignature);
+        }
+        let signature_key = self.identity_key.as_slice();
+        if self.init_keys.iter().any(|k| k.0[..] == *signature_key) {
+            return Err(KeyPackageError::InitKeyReusesSignatureKey);
+        }
+        Ok(())
+    }
+    // verify_keys(), and that the clock is within the lifetime, bounds included
+    pub fn verify(&self, clock: &dyn Clock) -> Result<(), KeyPackageError> {
+        self.verify_keys()?;
+        let lifetime = match LifetimeExtension::from_extensions(&self.extensions) {
+            Ok(Some(lifetime)) => lifetime,
+            _ => return Err(KeyPackageError::MissingLifetime),
+        };
+        let now = clock.now();
+        if now < lifetime.not_before {
+            return Err(KeyPackageError::NotYetValid);
+        }
+        if now > lifetime.not_after {
+            return Err(KeyPackageError::Expired);
+        }
+        Ok(())
+    }
+    // Identifies the init key in a Welcome
+    pub fn hash(&self) -> Vec<u8> {
+        sha256::hash(&self.encode_detached()).0.to_vec()
+    }
     pub fn self_verify(&self) -> bool {
-        ed25519::verify_detached(
-            &self.signature,
-            &self.unsigned_payload(),
-            &self.identity_key,
-        )
+        self.identity_key.verify(&self.tbs_bytes(), &self.signature)
     }
 }
 
 impl Signable for UserInitKey {
-    fn unsigned_payload(&self) -> Vec<u8> {
+    fn tbs_bytes(&self) -> Vec<u8> {
         let buffer = &mut Vec::new();
         encode_vec_u8(buffer, &self.cipher_suites);
         encode_vec_u16(buffer, &self.init_keys);
         self.algorithm.encode(buffer);
         self.identity_key.encode(buffer);
+        encode_vec_u8(buffer, &self.identity);
         encode_vec_u8(buffer, &self.supported_versions);
+        encode_vec_u16(buffer, &self.extensions);
         buffer.to_vec()
     }
 }
 
 impl Codec for UserInitKey {
     fn encode(&self, buffer: &mut Vec<u8>) {
-        buffer.append(&mut self.unsigned_payload());
+        buffer.append(&mut self.tbs_bytes());
         self.signature.encode(buffer);
     }
 
@@ -374,51 +644,62 @@ impl Codec for UserInitKey {
         }
 
         let identity_key = SignaturePublicKey::decode(cursor)?;
+        let identity = decode_vec_u8(cursor)?;
 
         let supported_versions: Vec<ProtocolVersion> = decode_vec_u8(cursor)?;
+        let extensions = decode_extensions(cursor)?;
 
         let signature = Signature::decode(cursor)?;
         Ok(UserInitKey {
             cipher_suites,
             init_keys,
             identity_key,
+            identity,
             algorithm,
             signature,
             supported_versions,
+            extensions,
         })
     }
 }
 
 pub struct UserInitKeyBundle {
     pub init_key: UserInitKey,
-    _private_keys: Vec<X25519PrivateKey>,
+    private_keys: Vec<X25519PrivateKey>,
 }
 
 impl UserInitKeyBundle {
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
     pub fn new(identity: &Identity) -> Self {
-        let kp = X25519KeyPair::new_random();
+        UserInitKeyBundle::new_with_rng(identity, &mut SystemRng)
+    }
+    pub fn new_with_rng(identity: &Identity, rng: &mut dyn Rng) -> Self {
+        let kp = X25519KeyPair::new_with_rng(rng);
         let private_keys = vec![kp.private_key];
         let public_keys = [kp.public_key];
         let init_key = UserInitKey::new(&public_keys, identity);
         UserInitKeyBundle {
             init_key,
-            _private_keys: private_keys,
+            private_keys,
         }
     }
+    pub fn private_key(&self) -> &X25519PrivateKey {
+        &self.private_keys[0]
+    }
 }
 
 impl Codec for UserInitKeyBundle {
     fn encode(&self, buffer: &mut Vec<u8>) {
         self.init_key.encode(buffer);
-        encode_vec_u16(buffer, &self._private_keys);
+        encode_vec_u16(buffer, &self.private_keys);
     }
 
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
         let init_key: UserInitKey = UserInitKey::decode(cursor)?;
-        let _private_keys: Vec<X25519PrivateKey> = decode_vec_u16(cursor)?;
+        let private_keys: Vec<X25519PrivateKey> = decode_vec_u16(cursor)?;
         Ok(UserInitKeyBundle {
             init_key,
-            _private_keys,
+            private_keys,
         })
     }
 }
@@ -498,7 +779,7 @@ fn verify_binary_test_vector_crypto() {
 
     let node_secret = NodeSecret::from_bytes(&derive_key_pair_seed);
     let key_pair = X25519KeyPair::new_from_secret(&node_secret);
-    let derive_key_pair_pub = X25519PublicKey::from_slice(&derive_key_pair_pub_x25519);
+    let derive_key_pair_pub = X25519PublicKey::from_slice(&derive_key_pair_pub_x25519).unwrap();
     assert_eq!(key_pair.public_key, derive_key_pair_pub);
 
     let mut seed_buffer: Vec<u8> = Vec::new();
@@ -514,7 +795,7 @@ fn verify_binary_test_vector_crypto() {
 
     println!("pkE: {}", bytes_to_hex(&key_pair.public_key.0));
     assert_eq!(
-        X25519PublicKey::from_slice(&ephemeral_key_x25519),
+        X25519PublicKey::from_slice(&ephemeral_key_x25519).unwrap(),
         ephemeral_public_key
     );
 
@@ -541,7 +822,7 @@ fn test_signature() {
     ))
     .unwrap();
     let sig = Signature::from_slice(&hex_to_bytes("4d51569eb56fc808cad8d8707110bcbf5c3daae9d394af77d48e840b2750ab15ea04c0fd30658625a20d0446fbd8ae09c6cc67f1004ed8c79818b74bef4fa107")).unwrap();
-    assert!(ed25519::verify_detached(&sig, &payload, &pk));
+    assert!(pk.verify(&payload, &sig));
 }
 
 #[test]
@@ -559,3 +840,176 @@ fn generate_user_init_key() {
         bytes_to_hex(&dh_kp.public_key.0)
     );
 }
+
+#[test]
+fn user_init_key_lifetime() {
+    let identity = Identity::random();
+    let lifetime = LifetimeExtension {
+        not_before: 1000,
+        not_after: 2000,
+    };
+    let public_key = X25519KeyPair::new_random().public_key;
+    let init_key = UserInitKey::new_with_lifetime(&[public_key], &identity, lifetime);
+    let init_key = UserInitKey::decode_detached(&init_key.encode_detached()).unwrap();
+
+    assert_eq!(
+        init_key.verify(&FixedClock(999)),
+        Err(KeyPackageError::NotYetValid)
+    );
+    assert_eq!(init_key.verify(&FixedClock(1000)), Ok(()));
+    assert_eq!(init_key.verify(&FixedClock(2000)), Ok(()));
+    assert_eq!(
+        init_key.verify(&FixedClock(2001)),
+        Err(KeyPackageError::Expired)
+    );
+
+    // The lifetime is covered by the signature
+    let mut extended = init_key.clone();
+    extended.extensions = vec![LifetimeExtension {
+        not_before: 1000,
+        not_after: 3000,
+    }
+    .to_extension()];
+    assert_eq!(
+        extended.verify(&FixedClock(2500)),
+        Err(KeyPackageError::InvalidSignature)
+    );
+
+    // New keys are valid now
+    let init_key = UserInitKey::new(&[public_key], &identity);
+    assert_eq!(init_key.verify(&SystemClock), Ok(()));
+}
+
+#[test]
+fn user_init_key_signature_key() {
+    let identity = Identity::random();
+    let other = Identity::random();
+    let public_key = X25519KeyPair::new_random().public_key;
+
+    // Signed by another key than the one it carries
+    let mut init_key = UserInitKey::new(&[public_key], &identity);
+    init_key.signature = other.sign(&init_key.tbs_bytes());
+    assert_eq!(
+        init_key.verify(&SystemClock),
+        Err(KeyPackageError::InvalidSignature)
+    );
+
+    // Correctly signed, but the init key is the signature key
+    let reused = X25519PublicKey::from_slice(identity.public_key.as_slice()).unwrap();
+    let init_key = UserInitKey::new(&[public_key, reused], &identity);
+    assert!(init_key.self_verify());
+    assert_eq!(
+        init_key.verify(&SystemClock),
+        Err(KeyPackageError::InitKeyReusesSignatureKey)
+    );
+    assert_eq!(
+        init_key.verify_keys(),
+        Err(KeyPackageError::InitKeyReusesSignatureKey)
+    );
+}
+
+#[test]
+fn user_init_key_tbs_bytes() {
+    let identity = Identity::random();
+    let init_key = UserInitKey::new(&[X25519KeyPair::new_random().public_key], &identity);
+    let encoded = init_key.encode_detached();
+    let decoded = UserInitKey::decode_detached(&encoded).unwrap();
+    assert!(decoded.self_verify());
+    assert_eq!(decoded.encode_detached(), encoded);
+
+    // The encoding is the to-be-signed view followed by the signature
+    let tbs = init_key.tbs_bytes();
+    assert_eq!(decoded.tbs_bytes(), tbs);
+    assert_eq!(&encoded[..tbs.len()], &tbs[..]);
+    let mut resigned = init_key.clone();
+    resigned.signature = resigned.sign(&Identity::random());
+    assert_eq!(resigned.tbs_bytes(), tbs);
+    assert!(!resigned.self_verify());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn user_init_key_serde() {
+    extern crate serde_json;
+
+    let identity = Identity::random();
+    let public_key = X25519KeyPair::new_random().public_key;
+    let init_key = UserInitKey::new(&[public_key], &identity);
+
+    let json = serde_json::to_string(&init_key).unwrap();
+    assert!(json.contains(&bytes_to_hex(&public_key.0)));
+    let from_json: UserInitKey = serde_json::from_str(&json).unwrap();
+    let from_codec = UserInitKey::decode_detached(&init_key.encode_detached()).unwrap();
+    assert_eq!(from_json.encode_detached(), from_codec.encode_detached());
+    assert!(from_json.self_verify());
+
+    // Keys have a fixed length
+    let short_key = format!("\"{}\"", bytes_to_hex(&public_key.0[1..]));
+    assert!(serde_json::from_str::<X25519PublicKey>(&short_key).is_err());
+}
+
+#[test]
+fn key_lengths() {
+    assert_eq!(
+        HpkePublicKey::new(AES128GCM_CURVE25519_SHA256, &[0x42; 31]),
+        Err(KeyError::InvalidLength {
+            expected: X25519PUBLICKEYBYTES,
+            got: 31
+        })
+    );
+    let public_key = HpkePublicKey::new(AES128GCM_CURVE25519_SHA256, &[0x42; 32]).unwrap();
+    assert_eq!(public_key.to_slice(), vec![0x42; 32]);
+    assert!(HpkePublicKey::new(AES128GCM_P256_SHA256, &[0x42; 32]).is_err());
+    assert!(HpkePrivateKey::new(AES128GCM_CURVE25519_SHA256, &[0x42; 33]).is_err());
+    let private_key = X25519KeyPair::new_random().private_key;
+    let hpke_private_key =
+        HpkePrivateKey::new(AES128GCM_CURVE25519_SHA256, &private_key.to_bytes()).unwrap();
+    assert_eq!(
+        hpke_private_key.public_key(),
+        HpkePublicKey::from(private_key.derive_public_key())
+    );
+    assert_eq!(
+        HpkePublicKey::decode_detached(&public_key.encode_detached()).unwrap(),
+        public_key
+    );
+    assert!(SignaturePublicKey::from_slice(&[0x42; 31]).is_err());
+
+    // Decoding a short key is an error rather than a panic
+    let mut short = Vec::new();
+    encode_vec_u16(&mut short, &[0x42u8; 31]);
+    assert!(X25519PrivateKey::decode_detached(&short).is_err());
+    assert!(SignaturePublicKey::decode_detached(&short).is_err());
+}
+
+#[test]
+fn user_init_keys_vec_bytes() {
+    let identity = Identity::random();
+    // Elements of different sizes through extensions of different lengths
+    let init_keys: Vec<UserInitKey> = (0..3)
+        .map(|n| {
+            let mut init_key =
+                UserInitKey::new(&[X25519KeyPair::new_random().public_key], &identity);
+            init_key.extensions.push(Extension {
+                extension_type: 0xff00,
+                extension_data: vec![n; n as usize * 100],
+            });
+            init_key.signature = init_key.sign(&identity);
+            init_key
+        })
+        .collect();
+    let mut buffer = Vec::new();
+    encode_vec_bytes(&mut buffer, &init_keys);
+    let decoded: Vec<UserInitKey> = decode_vec_bytes(&mut Cursor::new(&buffer)).unwrap();
+    assert_eq!(decoded.len(), 3);
+    for (decoded, init_key) in decoded.iter().zip(init_keys.iter()) {
+        assert_eq!(decoded.encode_detached(), init_key.encode_detached());
+        assert!(decoded.self_verify());
+    }
+
+    // One byte short of the last key
+    let mut short = Vec::new();
+    let length = buffer.len() - 2 - 1;
+    encode_varint(length, &mut short);
+    short.extend_from_slice(&buffer[2..buffer.len() - 1]);
+    assert!(decode_vec_bytes::<UserInitKey>(&mut Cursor::new(&short)).is_err());
+}
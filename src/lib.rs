@@ -14,17 +14,56 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
+// Only the tree math and the codec are available without std
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "std")]
 extern crate libsodium_sys;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+#[cfg(feature = "std")]
 extern crate ring;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "std")]
 extern crate sodiumoxide;
+#[cfg(all(not(feature = "std"), test))]
+#[macro_use]
+extern crate std;
 
+#[cfg(feature = "std")]
+pub mod clock;
 pub mod codec;
+#[cfg(all(test, feature = "std"))]
+mod conformance;
+#[cfg(feature = "std")]
+pub mod credential;
+#[cfg(feature = "std")]
 pub mod crypto;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
+pub mod extensions;
+#[cfg(feature = "std")]
 pub mod group;
+#[cfg(feature = "std")]
 pub mod keys;
+#[cfg(feature = "std")]
 pub mod messages;
+#[cfg(feature = "std")]
 pub mod mp;
+#[cfg(feature = "std")]
 pub mod roster;
+#[cfg(feature = "std")]
+pub mod storage;
+#[cfg(all(test, feature = "std"))]
+mod test_vectors;
+#[cfg(feature = "std")]
 pub mod tree;
 pub mod treemath;
+#[cfg(feature = "std")]
 pub mod utils;
@@ -15,21 +15,35 @@
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
 use codec::*;
+use crypto::aesgcm::*;
+use crypto::hkdf;
 use crypto::hpke::*;
-use crypto::schedule::InitSecret;
+use crypto::rng::*;
+use crypto::schedule::{expand_with_label, InitSecret, PreSharedKeyID};
+use error::MelissaError;
+use extensions::*;
 use group::*;
 use keys::*;
+use mp::{Generation, StageSecrets};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::sign::ed25519;
 use std::convert::From;
 use tree::*;
 
+#[derive(Debug)]
 pub enum MessageError {
     UnknownOperation,
+    DecryptionError,
+    DecodingError,
 }
 
 pub const HANDSHAKE_WELCOME: u8 = 1;
 pub const HANDSHAKE_UPDATE: u8 = 2;
 pub const HANDSHAKE_ADD: u8 = 3;
 pub const HANDSHAKE_REMOVE: u8 = 4;
+pub const HANDSHAKE_EXTERNAL_COMMIT: u8 = 5;
+pub const HANDSHAKE_REINIT: u8 = 6;
+pub const HANDSHAKE_COMMIT: u8 = 7;
 
 #[derive(Clone)]
 pub enum GroupOperationValue {
@@ -37,6 +51,9 @@ pub enum GroupOperationValue {
     Update(Update),
     Add(Add),
     Remove(Remove),
+    ExternalCommit(ExternalCommit),
+    ReInit(ReInitCommit),
+    Commit(Commit),
 }
 
 impl Codec for GroupOperationValue {
@@ -58,6 +75,18 @@ impl Codec for GroupOperationValue {
                 HANDSHAKE_REMOVE.encode(buffer);
                 remove.encode(buffer);
             }
+            GroupOperationValue::ExternalCommit(external_commit) => {
+                HANDSHAKE_EXTERNAL_COMMIT.encode(buffer);
+                external_commit.encode(buffer);
+            }
+            GroupOperationValue::ReInit(reinit_commit) => {
+                HANDSHAKE_REINIT.encode(buffer);
+                reinit_commit.encode(buffer);
+            }
+            GroupOperationValue::Commit(commit) => {
+                HANDSHAKE_COMMIT.encode(buffer);
+                commit.encode(buffer);
+            }
         }
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
@@ -69,6 +98,11 @@ impl Codec for GroupOperationValue {
             HANDSHAKE_UPDATE => Ok(GroupOperationValue::Update(Update::decode(cursor)?)),
             HANDSHAKE_ADD => Ok(GroupOperationValue::Add(Add::decode(cursor)?)),
             HANDSHAKE_REMOVE => Ok(GroupOperationValue::Remove(Remove::decode(cursor)?)),
+            HANDSHAKE_EXTERNAL_COMMIT => Ok(GroupOperationValue::ExternalCommit(
+                ExternalCommit::decode(cursor)?,
+            )),
+            HANDSHAKE_REINIT => Ok(GroupOperationValue::ReInit(ReInitCommit::decode(cursor)?)),
+            HANDSHAKE_COMMIT => Ok(GroupOperationValue::Commit(Commit::decode(cursor)?)),
             _ => Err(CodecError::DecodingError),
         }
     }
@@ -128,6 +162,7 @@ impl Codec for GroupOperation {
 
 #[derive(Clone)]
 pub struct Handshake {
+    pub version: ProtocolVersion,
     pub prior_epoch: GroupEpoch,
     pub operation: GroupOperation,
     pub signer_index: u32,
@@ -136,8 +171,9 @@ pub struct Handshake {
 }
 
 impl Signable for Handshake {
-    fn unsigned_payload(&self) -> Vec<u8> {
+    fn tbs_bytes(&self) -> Vec<u8> {
         let buffer = &mut Vec::new();
+        self.version.encode(buffer);
         self.prior_epoch.encode(buffer);
         self.operation.encode(buffer);
         self.signer_index.encode(buffer);
@@ -148,6 +184,7 @@ impl Signable for Handshake {
 
 impl Codec for Handshake {
     fn encode(&self, buffer: &mut Vec<u8>) {
+        self.version.encode(buffer);
         self.prior_epoch.encode(buffer);
         self.operation.encode(buffer);
         self.signer_index.encode(buffer);
@@ -155,12 +192,14 @@ impl Codec for Handshake {
         self.signature.unwrap().encode(buffer);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let version = ProtocolVersion::decode(cursor)?;
         let prior_epoch = GroupEpoch::decode(cursor)?;
         let operation = GroupOperation::decode(cursor)?;
         let signer_index = u32::decode(cursor)?;
         let algorithm = SignatureScheme::decode(cursor)?;
         let signature = Some(Signature::decode(cursor)?);
         Ok(Handshake {
+            version,
             prior_epoch,
             operation,
             signer_index,
@@ -174,9 +213,9 @@ impl Codec for Handshake {
 pub struct Welcome {
     pub group_id: GroupId,
     pub epoch: GroupEpoch,
-    pub roster: Vec<BasicCredential>,
-    pub tree: Vec<X25519PublicKey>,
-    pub transcript: Vec<GroupOperationValue>,
+    pub roster: Vec<Option<BasicCredential>>,
+    pub tree: Vec<Option<X25519PublicKey>>,
+    pub interim_transcript_hash: Vec<u8>,
     pub init_secret: InitSecret,
     pub leaf_secret: NodeSecret,
     pub version: ProtocolVersion,
@@ -188,7 +227,7 @@ impl Codec for Welcome {
         self.epoch.encode(buffer);
         encode_vec_u16(buffer, &self.roster);
         encode_vec_u16(buffer, &self.tree);
-        encode_vec_u16(buffer, &self.transcript);
+        encode_vec_u8(buffer, &self.interim_transcript_hash);
         self.init_secret.encode(buffer);
         self.leaf_secret.encode(buffer);
         self.version.encode(buffer);
@@ -198,7 +237,7 @@ impl Codec for Welcome {
         let epoch = GroupEpoch::decode(cursor)?;
         let roster = decode_vec_u16(cursor)?;
         let tree = decode_vec_u16(cursor)?;
-        let transcript = decode_vec_u16(cursor)?;
+        let interim_transcript_hash = decode_vec_u8(cursor)?;
         let init_secret = InitSecret::decode(cursor)?;
         let leaf_secret = NodeSecret::decode(cursor)?;
         let version = ProtocolVersion::decode(cursor)?;
@@ -207,7 +246,7 @@ impl Codec for Welcome {
             epoch,
             roster,
             tree,
-            transcript,
+            interim_transcript_hash,
             init_secret,
             leaf_secret,
             version,
@@ -257,7 +296,7 @@ impl Codec for Add {
             nodes,
             path,
             init_key,
-            index
+            index,
         })
     }
 }
@@ -286,3 +325,1281 @@ impl Codec for Remove {
         })
     }
 }
+
+// Public group state that lets a new member join by external commit or from a Welcome.
+// It is signed by the member at signer_index.
+#[derive(Clone)]
+pub struct GroupInfo {
+    pub version: ProtocolVersion,
+    pub cipher_suite: CipherSuite,
+    pub group_id: GroupId,
+    pub epoch: GroupEpoch,
+    pub tree_hash: Vec<u8>,
+    pub roster: Vec<Option<BasicCredential>>,
+    // What each member's key package claimed, aligned with the roster
+    pub capabilities: Vec<Option<CapabilitiesExtension>>,
+    pub tree: Vec<Option<X25519PublicKey>>,
+    pub unmerged_leaves: Vec<UnmergedLeaves>,
+    pub extensions: Vec<Extension>,
+    pub confirmed_transcript_hash: Vec<u8>,
+    pub confirmation_tag: Vec<u8>,
+    pub signer_index: u32,
+    pub signature: Signature,
+}
+
+impl GroupInfo {
+    pub fn sign(&mut self, id: &Identity) {
+        self.signature = id.sign(&self.tbs_bytes());
+    }
+    // Checks that the signer is a member of the given roster and signed the body
+    pub fn verify(&self, roster: &[Option<BasicCredential>]) -> bool {
+        match roster.get(self.signer_index as usize) {
+            Some(Some(signer)) => signer.verify(&self.tbs_bytes(), &self.signature),
+            _ => false,
+        }
+    }
+}
+
+impl Signable for GroupInfo {
+    fn tbs_bytes(&self) -> Vec<u8> {
+        let buffer = &mut Vec::new();
+        self.version.encode(buffer);
+        self.cipher_suite.encode(buffer);
+        self.group_id.encode(buffer);
+        self.epoch.encode(buffer);
+        encode_vec_u8(buffer, &self.tree_hash);
+        encode_vec_u16(buffer, &self.roster);
+        encode_vec_u16(buffer, &self.capabilities);
+        encode_vec_u16(buffer, &self.tree);
+        encode_vec_u16(buffer, &self.unmerged_leaves);
+        encode_vec_u16(buffer, &self.extensions);
+        encode_vec_u8(buffer, &self.confirmed_transcript_hash);
+        encode_vec_u8(buffer, &self.confirmation_tag);
+        self.signer_index.encode(buffer);
+        buffer.to_vec()
+    }
+}
+
+impl Codec for GroupInfo {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.append(&mut self.tbs_bytes());
+        self.signature.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let version = ProtocolVersion::decode(cursor)?;
+        let cipher_suite = CipherSuite::decode(cursor)?;
+        let group_id = GroupId::decode(cursor)?;
+        let epoch = GroupEpoch::decode(cursor)?;
+        let tree_hash = decode_vec_u8(cursor)?;
+        let roster = decode_vec_u16(cursor)?;
+        let capabilities = decode_vec_u16(cursor)?;
+        let tree = TreeDecoder::new(cursor)?.decode_tree(DEFAULT_MAX_MEMBERS)?;
+        let unmerged_leaves = decode_vec_u16(cursor)?;
+        let extensions = decode_extensions(cursor)?;
+        let confirmed_transcript_hash = decode_vec_u8(cursor)?;
+        let confirmation_tag = decode_vec_u8(cursor)?;
+        let signer_index = u32::decode(cursor)?;
+        let signature = Signature::decode(cursor)?;
+        Ok(GroupInfo {
+            version,
+            cipher_suite,
+            group_id,
+            epoch,
+            tree_hash,
+            roster,
+            capabilities,
+            tree,
+            unmerged_leaves,
+            extensions,
+            confirmed_transcript_hash,
+            confirmation_tag,
+            signer_index,
+            signature,
+        })
+    }
+}
+
+// An Add of the sender itself, keyed to the group's external_pub
+#[derive(Clone)]
+pub struct ExternalCommit {
+    pub kem_output: X25519PublicKey,
+    pub add: Add,
+}
+
+impl Codec for ExternalCommit {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.kem_output.encode(buffer);
+        self.add.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let kem_output = X25519PublicKey::decode(cursor)?;
+        let add = Add::decode(cursor)?;
+        Ok(ExternalCommit { kem_output, add })
+    }
+}
+
+// Parameters of the group that succeeds a reinitialized one
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ReInit {
+    pub group_id: GroupId,
+    pub version: ProtocolVersion,
+    pub cipher_suite: CipherSuite,
+}
+
+impl Codec for ReInit {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.group_id.encode(buffer);
+        self.version.encode(buffer);
+        self.cipher_suite.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let group_id = GroupId::decode(cursor)?;
+        let version = ProtocolVersion::decode(cursor)?;
+        let cipher_suite = CipherSuite::decode(cursor)?;
+        Ok(ReInit {
+            group_id,
+            version,
+            cipher_suite,
+        })
+    }
+}
+
+// The update finalizes the last epoch of the old group
+#[derive(Clone)]
+pub struct ReInitCommit {
+    pub reinit: ReInit,
+    pub update: Update,
+}
+
+impl Codec for ReInitCommit {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.reinit.encode(buffer);
+        self.update.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let reinit = ReInit::decode(cursor)?;
+        let update = Update::decode(cursor)?;
+        Ok(ReInitCommit { reinit, update })
+    }
+}
+
+pub type ProposalType = u16;
+
+pub const PROPOSAL_TYPE_ADD: ProposalType = 1;
+pub const PROPOSAL_TYPE_UPDATE: ProposalType = 2;
+pub const PROPOSAL_TYPE_REMOVE: ProposalType = 3;
+pub const PROPOSAL_TYPE_PSK: ProposalType = 4;
+pub const PROPOSAL_TYPE_REINIT: ProposalType = 5;
+pub const PROPOSAL_TYPE_EXTERNAL_INIT: ProposalType = 6;
+pub const PROPOSAL_TYPE_GROUP_CONTEXT_EXTENSIONS: ProposalType = 7;
+// Private use range
+pub const PROPOSAL_TYPE_CREDENTIAL_UPDATE: ProposalType = 0xf000;
+
+// An Update carries the new leaf key of the member that signed the UserInitKey
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Proposal {
+    Add(UserInitKey),
+    Update(UserInitKey),
+    Remove(u32),
+    PreSharedKey(PreSharedKeyID),
+    ReInit(ReInit),
+    ExternalInit(ExternalInit),
+    CredentialUpdate(CredentialUpdate),
+    // Replaces all extensions of the group context
+    GroupContextExtensions(Vec<Extension>),
+}
+
+impl Codec for Proposal {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Proposal::Add(init_key) => {
+                PROPOSAL_TYPE_ADD.encode(buffer);
+                init_key.encode(buffer);
+            }
+            Proposal::Update(init_key) => {
+                PROPOSAL_TYPE_UPDATE.encode(buffer);
+                init_key.encode(buffer);
+            }
+            Proposal::Remove(removed) => {
+                PROPOSAL_TYPE_REMOVE.encode(buffer);
+                removed.encode(buffer);
+            }
+            Proposal::PreSharedKey(psk_id) => {
+                PROPOSAL_TYPE_PSK.encode(buffer);
+                psk_id.encode(buffer);
+            }
+            Proposal::ReInit(reinit) => {
+                PROPOSAL_TYPE_REINIT.encode(buffer);
+                reinit.encode(buffer);
+            }
+            Proposal::ExternalInit(external_init) => {
+                PROPOSAL_TYPE_EXTERNAL_INIT.encode(buffer);
+                external_init.encode(buffer);
+            }
+            Proposal::CredentialUpdate(credential_update) => {
+                PROPOSAL_TYPE_CREDENTIAL_UPDATE.encode(buffer);
+                credential_update.encode(buffer);
+            }
+            Proposal::GroupContextExtensions(extensions) => {
+                PROPOSAL_TYPE_GROUP_CONTEXT_EXTENSIONS.encode(buffer);
+                encode_vec_u16(buffer, extensions);
+            }
+        }
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let proposal_type = ProposalType::decode(cursor)?;
+        match proposal_type {
+            PROPOSAL_TYPE_ADD => Ok(Proposal::Add(UserInitKey::decode(cursor)?)),
+            PROPOSAL_TYPE_UPDATE => Ok(Proposal::Update(UserInitKey::decode(cursor)?)),
+            PROPOSAL_TYPE_REMOVE => Ok(Proposal::Remove(u32::decode(cursor)?)),
+            PROPOSAL_TYPE_PSK => Ok(Proposal::PreSharedKey(PreSharedKeyID::decode(cursor)?)),
+            PROPOSAL_TYPE_REINIT => Ok(Proposal::ReInit(ReInit::decode(cursor)?)),
+            PROPOSAL_TYPE_EXTERNAL_INIT => {
+                Ok(Proposal::ExternalInit(ExternalInit::decode(cursor)?))
+            }
+            PROPOSAL_TYPE_CREDENTIAL_UPDATE => Ok(Proposal::CredentialUpdate(
+                CredentialUpdate::decode(cursor)?,
+            )),
+            PROPOSAL_TYPE_GROUP_CONTEXT_EXTENSIONS => {
+                Ok(Proposal::GroupContextExtensions(decode_extensions(cursor)?))
+            }
+            _ => Err(CodecError::DecodingError),
+        }
+    }
+}
+
+// Replaces the credential and signature key of a leaf and keeps its HPKE key, so no
+// path secrets change. Signed by the old key to authorize the rotation and by the new
+// one to prove possession.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CredentialUpdate {
+    pub leaf: u32,
+    pub credential: BasicCredential,
+    pub old_key_signature: Signature,
+    pub new_key_signature: Signature,
+}
+
+impl CredentialUpdate {
+    pub fn new(
+        leaf: u32,
+        credential: BasicCredential,
+        old_id: &Identity,
+        new_id: &Identity,
+    ) -> Self {
+        let mut credential_update = CredentialUpdate {
+            leaf,
+            credential,
+            old_key_signature: Signature::from_slice(&[0u8; ed25519::SIGNATUREBYTES]).unwrap(),
+            new_key_signature: Signature::from_slice(&[0u8; ed25519::SIGNATUREBYTES]).unwrap(),
+        };
+        let tbs = credential_update.tbs_bytes();
+        credential_update.old_key_signature = old_id.sign(&tbs);
+        credential_update.new_key_signature = new_id.sign(&tbs);
+        credential_update
+    }
+    pub fn verify(&self, old_credential: &BasicCredential) -> bool {
+        let tbs = self.tbs_bytes();
+        old_credential.verify(&tbs, &self.old_key_signature)
+            && self.credential.verify(&tbs, &self.new_key_signature)
+    }
+}
+
+impl Signable for CredentialUpdate {
+    fn tbs_bytes(&self) -> Vec<u8> {
+        let buffer = &mut Vec::new();
+        self.leaf.encode(buffer);
+        self.credential.encode(buffer);
+        buffer.to_vec()
+    }
+}
+
+impl Codec for CredentialUpdate {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.append(&mut self.tbs_bytes());
+        self.old_key_signature.encode(buffer);
+        self.new_key_signature.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let leaf = u32::decode(cursor)?;
+        let credential = BasicCredential::decode(cursor)?;
+        let old_key_signature = Signature::decode(cursor)?;
+        let new_key_signature = Signature::decode(cursor)?;
+        Ok(CredentialUpdate {
+            leaf,
+            credential,
+            old_key_signature,
+            new_key_signature,
+        })
+    }
+}
+
+pub const PROPOSALREFBYTES: usize = 32;
+
+// Hash of a proposal that was sent on its own, so that a commit can refer to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ProposalRef(pub [u8; PROPOSALREFBYTES]);
+
+impl ProposalRef {
+    pub fn from_proposal(proposal: &Proposal) -> Self {
+        ProposalRef(sha256::hash(&proposal.encode_detached()).0)
+    }
+}
+
+impl Codec for ProposalRef {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u8(buffer, &self.0);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let bytes = decode_vec_u8(cursor)?;
+        if bytes.len() != PROPOSALREFBYTES {
+            return Err(CodecError::DecodingError);
+        }
+        let mut proposal_ref = [0u8; PROPOSALREFBYTES];
+        proposal_ref.clone_from_slice(&bytes);
+        Ok(ProposalRef(proposal_ref))
+    }
+}
+
+pub type SenderType = u8;
+
+pub const SENDER_TYPE_MEMBER: SenderType = 1;
+pub const SENDER_TYPE_EXTERNAL: SenderType = 2;
+pub const SENDER_TYPE_NEW_MEMBER_PROPOSAL: SenderType = 3;
+pub const SENDER_TYPE_NEW_MEMBER_COMMIT: SenderType = 4;
+
+// Members are identified by their leaf, external senders by their position in the
+// external_senders extension. New members aren't in the tree yet and carry no index.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Sender {
+    Member(u32),
+    External(u32),
+    NewMemberProposal,
+    NewMemberCommit,
+}
+
+impl Codec for Sender {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Sender::Member(leaf) => {
+                SENDER_TYPE_MEMBER.encode(buffer);
+                leaf.encode(buffer);
+            }
+            Sender::External(index) => {
+                SENDER_TYPE_EXTERNAL.encode(buffer);
+                index.encode(buffer);
+            }
+            Sender::NewMemberProposal => SENDER_TYPE_NEW_MEMBER_PROPOSAL.encode(buffer),
+            Sender::NewMemberCommit => SENDER_TYPE_NEW_MEMBER_COMMIT.encode(buffer),
+        }
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        match SenderType::decode(cursor)? {
+            SENDER_TYPE_MEMBER => Ok(Sender::Member(u32::decode(cursor)?)),
+            SENDER_TYPE_EXTERNAL => Ok(Sender::External(u32::decode(cursor)?)),
+            SENDER_TYPE_NEW_MEMBER_PROPOSAL => Ok(Sender::NewMemberProposal),
+            SENDER_TYPE_NEW_MEMBER_COMMIT => Ok(Sender::NewMemberCommit),
+            _ => Err(CodecError::DecodingError),
+        }
+    }
+}
+
+// A proposal sent on its own, to be committed later by reference
+#[derive(Clone)]
+pub struct SignedProposal {
+    pub group_id: GroupId,
+    pub epoch: GroupEpoch,
+    pub sender: Sender,
+    pub proposal: Proposal,
+    pub signature: Signature,
+}
+
+impl SignedProposal {
+    pub fn new(
+        group_id: &GroupId,
+        epoch: GroupEpoch,
+        sender: Sender,
+        proposal: Proposal,
+        id: &Identity,
+    ) -> Self {
+        let mut signed_proposal = SignedProposal {
+            group_id: group_id.clone(),
+            epoch,
+            sender,
+            proposal,
+            signature: Signature::from_slice(&[0u8; ed25519::SIGNATUREBYTES]).unwrap(),
+        };
+        signed_proposal.signature = id.sign(&signed_proposal.tbs_bytes());
+        signed_proposal
+    }
+}
+
+impl Signable for SignedProposal {
+    fn tbs_bytes(&self) -> Vec<u8> {
+        let buffer = &mut Vec::new();
+        self.group_id.encode(buffer);
+        self.epoch.encode(buffer);
+        self.sender.encode(buffer);
+        self.proposal.encode(buffer);
+        buffer.to_vec()
+    }
+}
+
+impl Codec for SignedProposal {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.append(&mut self.tbs_bytes());
+        self.signature.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let group_id = GroupId::decode(cursor)?;
+        let epoch = GroupEpoch::decode(cursor)?;
+        let sender = Sender::decode(cursor)?;
+        let proposal = Proposal::decode(cursor)?;
+        let signature = Signature::decode(cursor)?;
+        Ok(SignedProposal {
+            group_id,
+            epoch,
+            sender,
+            proposal,
+            signature,
+        })
+    }
+}
+
+// KEM output to the group's external_pub, sent by a new member joining by external commit
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExternalInit {
+    pub kem_output: X25519PublicKey,
+}
+
+impl Codec for ExternalInit {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.kem_output.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let kem_output = X25519PublicKey::decode(cursor)?;
+        Ok(ExternalInit { kem_output })
+    }
+}
+
+// The path secret of a parent node, encrypted to the resolution of its copath child
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UpdatePathNode {
+    pub public_key: X25519PublicKey,
+    pub encrypted_path_secret: Vec<HpkeCiphertext>,
+}
+
+impl Codec for UpdatePathNode {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.public_key.encode(buffer);
+        encode_vec_u16(buffer, &self.encrypted_path_secret);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let public_key = X25519PublicKey::decode(cursor)?;
+        let encrypted_path_secret = decode_vec_u16(cursor)?;
+        Ok(UpdatePathNode {
+            public_key,
+            encrypted_path_secret,
+        })
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UpdatePath {
+    pub leaf_key: X25519PublicKey,
+    pub nodes: Vec<UpdatePathNode>,
+}
+
+impl Codec for UpdatePath {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.leaf_key.encode(buffer);
+        encode_vec_u16(buffer, &self.nodes);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let leaf_key = X25519PublicKey::decode(cursor)?;
+        let nodes = decode_vec_u16(cursor)?;
+        Ok(UpdatePath { leaf_key, nodes })
+    }
+}
+
+// Signed by the committer, which is always a member
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Commit {
+    pub group_id: GroupId,
+    // The epoch the commit moves the group to
+    pub epoch: GroupEpoch,
+    pub sender: Sender,
+    pub proposals: Vec<Proposal>,
+    // Only optional when all proposals are adds
+    pub path: Option<UpdatePath>,
+    pub signature: Signature,
+    // MAC over the confirmed transcript hash of the epoch the commit leads to
+    pub confirmation_tag: Vec<u8>,
+}
+
+impl Commit {
+    pub fn sign(&mut self, id: &Identity) {
+        self.signature = id.sign(&self.tbs_bytes());
+    }
+    // The commit as it enters the confirmed transcript hash, which the tag is computed
+    // over
+    pub fn unconfirmed(&self) -> Commit {
+        Commit {
+            confirmation_tag: Vec::new(),
+            ..self.clone()
+        }
+    }
+}
+
+impl Signable for Commit {
+    fn tbs_bytes(&self) -> Vec<u8> {
+        let buffer = &mut Vec::new();
+        self.group_id.encode(buffer);
+        self.epoch.encode(buffer);
+        self.sender.encode(buffer);
+        encode_vec_u32(buffer, &self.proposals);
+        encode_option(&self.path, buffer);
+        buffer.to_vec()
+    }
+}
+
+impl Codec for Commit {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.append(&mut self.tbs_bytes());
+        self.signature.encode(buffer);
+        encode_vec_u8(buffer, &self.confirmation_tag);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let group_id = GroupId::decode(cursor)?;
+        let epoch = GroupEpoch::decode(cursor)?;
+        let sender = Sender::decode(cursor)?;
+        let proposals = decode_vec_u32(cursor)?;
+        let path = decode_option(cursor)?;
+        let signature = Signature::decode(cursor)?;
+        let confirmation_tag = decode_vec_u8(cursor)?;
+        Ok(Commit {
+            group_id,
+            epoch,
+            sender,
+            proposals,
+            path,
+            signature,
+            confirmation_tag,
+        })
+    }
+}
+
+// The path secret is the one of the lowest node shared by the new member and the committer
+#[derive(Clone)]
+pub struct GroupSecrets {
+    pub init_secret: InitSecret,
+    pub path_secret: Option<NodeSecret>,
+}
+
+impl Codec for GroupSecrets {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.init_secret.encode(buffer);
+        self.path_secret.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let init_secret = InitSecret::decode(cursor)?;
+        let path_secret = Option::<NodeSecret>::decode(cursor)?;
+        Ok(GroupSecrets {
+            init_secret,
+            path_secret,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct EncryptedGroupSecrets {
+    pub key_package_hash: Vec<u8>,
+    pub encrypted_group_secrets: HpkeCiphertext,
+}
+
+impl Codec for EncryptedGroupSecrets {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u8(buffer, &self.key_package_hash);
+        self.encrypted_group_secrets.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let key_package_hash = decode_vec_u8(cursor)?;
+        let encrypted_group_secrets = HpkeCiphertext::decode(cursor)?;
+        Ok(EncryptedGroupSecrets {
+            key_package_hash,
+            encrypted_group_secrets,
+        })
+    }
+}
+
+// Welcome for the members added by a Commit, with one entry per new member
+#[derive(Clone)]
+pub struct CommitWelcome {
+    pub secrets: Vec<EncryptedGroupSecrets>,
+    pub group_info: GroupInfo,
+}
+
+impl CommitWelcome {
+    // Decodes in two passes: the secrets first, and the group info with its possibly
+    // large tree only once a secret turned out to be addressed to key_package_hash
+    pub fn decode_addressed(
+        bytes: &[u8],
+        key_package_hash: &[u8],
+    ) -> Result<CommitWelcome, MelissaError> {
+        let mut cursor = Cursor::new(bytes);
+        let secrets: Vec<EncryptedGroupSecrets> = decode_vec_u32(&mut cursor)?;
+        if !secrets
+            .iter()
+            .any(|s| s.key_package_hash == key_package_hash)
+        {
+            return Err(GroupError::InvalidWelcome.into());
+        }
+        let group_info = GroupInfo::decode(&mut cursor)?;
+        cursor.expect_end()?;
+        Ok(CommitWelcome {
+            secrets,
+            group_info,
+        })
+    }
+}
+
+impl Codec for CommitWelcome {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u32(buffer, &self.secrets);
+        self.group_info.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let secrets = decode_vec_u32(cursor)?;
+        let group_info = GroupInfo::decode(cursor)?;
+        Ok(CommitWelcome {
+            secrets,
+            group_info,
+        })
+    }
+}
+
+pub const REUSE_GUARD_BYTES: usize = 4;
+pub const CIPHERTEXT_SAMPLE_BYTES: usize = 32;
+pub const PADDING_BLOCK_BYTES: usize = 32;
+
+// Sender of an encrypted message, encrypted separately from the content
+#[derive(Clone, Debug, PartialEq)]
+pub struct SenderData {
+    pub sender: u32,
+    pub generation: Generation,
+    pub reuse_guard: [u8; REUSE_GUARD_BYTES],
+}
+
+impl Codec for SenderData {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.sender.encode(buffer);
+        self.generation.encode(buffer);
+        encode_fixed(&self.reuse_guard, buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let sender = u32::decode(cursor)?;
+        let generation = Generation::decode(cursor)?;
+        let reuse_guard = decode_fixed::<REUSE_GUARD_BYTES>(cursor)?;
+        Ok(SenderData {
+            sender,
+            generation,
+            reuse_guard,
+        })
+    }
+}
+
+// The first bytes of the content ciphertext, or all of it if it is shorter
+pub fn ciphertext_sample(ciphertext: &[u8]) -> &[u8] {
+    if ciphertext.len() < CIPHERTEXT_SAMPLE_BYTES {
+        ciphertext
+    } else {
+        &ciphertext[..CIPHERTEXT_SAMPLE_BYTES]
+    }
+}
+
+fn sender_data_key_nonce(
+    sender_data_secret: &[u8],
+    ciphertext_sample: &[u8],
+) -> (Aes128Key, Nonce) {
+    let prk = hkdf::Prk::from_slice(sender_data_secret).unwrap();
+    let key = expand_with_label(prk, "key", ciphertext_sample, AES128KEYBYTES);
+    let prk = hkdf::Prk::from_slice(sender_data_secret).unwrap();
+    let nonce = expand_with_label(prk, "nonce", ciphertext_sample, NONCEBYTES);
+    (Aes128Key::from_slice(&key), Nonce::from_slice(&nonce))
+}
+
+pub fn encrypt_sender_data(
+    sender_data: &SenderData,
+    sender_data_secret: &[u8],
+    ciphertext_sample: &[u8],
+) -> Vec<u8> {
+    let (key, nonce) = sender_data_key_nonce(sender_data_secret, ciphertext_sample);
+    aes_128_seal(&sender_data.encode_detached(), &key, &nonce).unwrap()
+}
+
+pub fn decrypt_sender_data(
+    encrypted_sender_data: &[u8],
+    sender_data_secret: &[u8],
+    ciphertext_sample: &[u8],
+) -> Result<SenderData, MessageError> {
+    let (key, nonce) = sender_data_key_nonce(sender_data_secret, ciphertext_sample);
+    let sender_data = aes_128_open(encrypted_sender_data, &key, &nonce)
+        .map_err(|_| MessageError::DecryptionError)?;
+    SenderData::decode_detached(&sender_data).map_err(|_| MessageError::DecodingError)
+}
+
+// Content encrypted under a ratchet key, with the sender data encrypted under a key
+// derived from a sample of the content ciphertext. The epoch is in the clear so that
+// the receiver can pick the keys of the right epoch.
+#[derive(Clone)]
+pub struct EncryptedMessage {
+    pub epoch: GroupEpoch,
+    pub encrypted_sender_data: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl Codec for EncryptedMessage {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.epoch.encode(buffer);
+        encode_vec_u8(buffer, &self.encrypted_sender_data);
+        encode_vec_u32(buffer, &self.ciphertext);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let epoch = GroupEpoch::decode(cursor)?;
+        let encrypted_sender_data = decode_vec_u8(cursor)?;
+        let ciphertext = decode_vec_u32(cursor)?;
+        Ok(EncryptedMessage {
+            epoch,
+            encrypted_sender_data,
+            ciphertext,
+        })
+    }
+}
+
+// Leading byte of a message on the wire, telling what follows
+pub const WIRE_FORMAT_PROPOSAL: u8 = 1;
+pub const WIRE_FORMAT_COMMIT: u8 = 2;
+pub const WIRE_FORMAT_CIPHERTEXT: u8 = 3;
+pub const WIRE_FORMAT_WELCOME: u8 = 4;
+pub const WIRE_FORMAT_GROUP_INFO: u8 = 5;
+
+// Any message a client can receive
+#[derive(Clone)]
+pub enum MlsMessage {
+    Proposal(SignedProposal),
+    Commit(Commit),
+    Ciphertext(EncryptedMessage),
+    Welcome(CommitWelcome),
+    GroupInfo(GroupInfo),
+}
+
+impl Codec for MlsMessage {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            MlsMessage::Proposal(proposal) => {
+                WIRE_FORMAT_PROPOSAL.encode(buffer);
+                proposal.encode(buffer);
+            }
+            MlsMessage::Commit(commit) => {
+                WIRE_FORMAT_COMMIT.encode(buffer);
+                commit.encode(buffer);
+            }
+            MlsMessage::Ciphertext(message) => {
+                WIRE_FORMAT_CIPHERTEXT.encode(buffer);
+                message.encode(buffer);
+            }
+            MlsMessage::Welcome(welcome) => {
+                WIRE_FORMAT_WELCOME.encode(buffer);
+                welcome.encode(buffer);
+            }
+            MlsMessage::GroupInfo(group_info) => {
+                WIRE_FORMAT_GROUP_INFO.encode(buffer);
+                group_info.encode(buffer);
+            }
+        }
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        match u8::decode(cursor)? {
+            WIRE_FORMAT_PROPOSAL => Ok(MlsMessage::Proposal(SignedProposal::decode(cursor)?)),
+            WIRE_FORMAT_COMMIT => Ok(MlsMessage::Commit(Commit::decode(cursor)?)),
+            WIRE_FORMAT_CIPHERTEXT => Ok(MlsMessage::Ciphertext(EncryptedMessage::decode(cursor)?)),
+            WIRE_FORMAT_WELCOME => Ok(MlsMessage::Welcome(CommitWelcome::decode(cursor)?)),
+            WIRE_FORMAT_GROUP_INFO => Ok(MlsMessage::GroupInfo(GroupInfo::decode(cursor)?)),
+            _ => Err(CodecError::DecodingError),
+        }
+    }
+}
+
+// The one entry point for untrusted bytes off the wire. Every length is checked
+// against what is left of the buffer, so malformed input gives an error, never a
+// panic, and nothing may follow the message.
+pub fn parse_message(bytes: &[u8]) -> Result<MlsMessage, CodecError> {
+    parse_message_with_extension_limit(bytes, MAX_EXTENSION_BYTES)
+}
+
+// Same, with every extension list in the message capped at max_extension_bytes
+pub fn parse_message_with_extension_limit(
+    bytes: &[u8],
+    max_extension_bytes: usize,
+) -> Result<MlsMessage, CodecError> {
+    let mut cursor = Cursor::new(bytes).with_extension_limit(max_extension_bytes);
+    let message = MlsMessage::decode(&mut cursor)?;
+    cursor.expect_end()?;
+    Ok(message)
+}
+
+// Content of an application message, with the leaf and generation it was sent from
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecryptedMessage {
+    pub sender: u32,
+    pub generation: Generation,
+    pub content: Vec<u8>,
+}
+
+// What a received message amounts to. A commit is handed back once its signature is
+// checked, to be applied with process_commit().
+#[derive(Clone)]
+pub enum ProcessedMessage {
+    Application(DecryptedMessage),
+    Proposal(ProposalRef),
+    Commit(Box<Commit>),
+}
+
+// The generation is XORed into the end of the content nonce and the reuse guard into its
+// start, so that a repeated generation does not repeat the nonce
+fn guarded_nonce(
+    stage_secrets: &StageSecrets,
+    generation: Generation,
+    reuse_guard: &[u8; REUSE_GUARD_BYTES],
+) -> Nonce {
+    let mut nonce = generation.apply_to_nonce(&stage_secrets.nonce);
+    for (byte, guard) in nonce.iter_mut().zip(reuse_guard.iter()) {
+        *byte ^= guard;
+    }
+    Nonce(nonce)
+}
+
+// The content is length-prefixed and padded with zeros to a multiple of
+// PADDING_BLOCK_BYTES, so that the ciphertext only shows how many blocks it takes
+fn pad_content(content: &[u8]) -> Vec<u8> {
+    let mut padded = Vec::new();
+    encode_vec_u32(&mut padded, content);
+    let padding = (PADDING_BLOCK_BYTES - padded.len() % PADDING_BLOCK_BYTES) % PADDING_BLOCK_BYTES;
+    padded.resize(padded.len() + padding, 0);
+    padded
+}
+
+fn unpad_content(padded: &[u8]) -> Result<Vec<u8>, MessageError> {
+    let mut cursor = Cursor::new(padded);
+    let content = decode_vec_u32(&mut cursor).map_err(|_| MessageError::DecodingError)?;
+    if cursor.read_to_end().iter().any(|byte| *byte != 0) {
+        return Err(MessageError::DecodingError);
+    }
+    Ok(content)
+}
+
+#[cfg(any(test, not(feature = "explicit-entropy")))]
+pub fn protect(
+    content: &[u8],
+    epoch: GroupEpoch,
+    sender: u32,
+    generation: Generation,
+    stage_secrets: &StageSecrets,
+    sender_data_secret: &[u8],
+) -> EncryptedMessage {
+    protect_with_rng(
+        content,
+        epoch,
+        sender,
+        generation,
+        stage_secrets,
+        sender_data_secret,
+        &mut SystemRng,
+    )
+}
+
+pub fn protect_with_rng(
+    content: &[u8],
+    epoch: GroupEpoch,
+    sender: u32,
+    generation: Generation,
+    stage_secrets: &StageSecrets,
+    sender_data_secret: &[u8],
+    rng: &mut dyn Rng,
+) -> EncryptedMessage {
+    let mut reuse_guard = [0u8; REUSE_GUARD_BYTES];
+    rng.fill(&mut reuse_guard);
+    let sender_data = SenderData {
+        sender,
+        generation,
+        reuse_guard,
+    };
+    let key = Aes128Key::from_slice(&stage_secrets.key);
+    let nonce = guarded_nonce(stage_secrets, generation, &reuse_guard);
+    let ciphertext = aes_128_seal(&pad_content(content), &key, &nonce).unwrap();
+    let encrypted_sender_data = encrypt_sender_data(
+        &sender_data,
+        sender_data_secret,
+        ciphertext_sample(&ciphertext),
+    );
+    EncryptedMessage {
+        epoch,
+        encrypted_sender_data,
+        ciphertext,
+    }
+}
+
+// The sender data tells the receiver which ratchet secrets open the content
+pub fn unprotect_sender_data(
+    message: &EncryptedMessage,
+    sender_data_secret: &[u8],
+) -> Result<SenderData, MessageError> {
+    decrypt_sender_data(
+        &message.encrypted_sender_data,
+        sender_data_secret,
+        ciphertext_sample(&message.ciphertext),
+    )
+}
+
+pub fn unprotect(
+    message: &EncryptedMessage,
+    sender_data: &SenderData,
+    stage_secrets: &StageSecrets,
+) -> Result<Vec<u8>, MessageError> {
+    let key = Aes128Key::from_slice(&stage_secrets.key);
+    let nonce = guarded_nonce(
+        stage_secrets,
+        sender_data.generation,
+        &sender_data.reuse_guard,
+    );
+    let padded = aes_128_open(&message.ciphertext, &key, &nonce)
+        .map_err(|_| MessageError::DecryptionError)?;
+    unpad_content(&padded)
+}
+
+#[test]
+fn proposal_codec() {
+    let identity = Identity::random();
+    let init_key = UserInitKey::new(&[X25519KeyPair::new_random().public_key], &identity);
+    let proposals = [
+        Proposal::Add(init_key.clone()),
+        Proposal::Update(init_key),
+        Proposal::Remove(3),
+        Proposal::PreSharedKey(PreSharedKeyID::external(b"psk", b"nonce")),
+        Proposal::ReInit(ReInit {
+            group_id: GroupId::random(),
+            version: CURRENT_VERSION,
+            cipher_suite: AES128GCM_CURVE25519_SHA256,
+        }),
+        Proposal::ExternalInit(ExternalInit {
+            kem_output: X25519KeyPair::new_random().public_key,
+        }),
+        Proposal::GroupContextExtensions(vec![
+            RequiredCapabilitiesExtension::default().to_extension()
+        ]),
+    ];
+    for (proposal, proposal_type) in proposals.iter().zip(1..) {
+        let encoded = proposal.encode_detached();
+        assert_eq!(
+            ProposalType::decode_detached(&encoded[..2]).unwrap(),
+            proposal_type
+        );
+        let decoded = Proposal::decode_detached(&encoded).unwrap();
+        assert_eq!(decoded.encode_detached(), encoded);
+    }
+
+    let new_identity = Identity::random();
+    let credential_update = Proposal::CredentialUpdate(CredentialUpdate::new(
+        1,
+        BasicCredential {
+            identity: b"Alice".to_vec(),
+            public_key: new_identity.public_key,
+        },
+        &identity,
+        &new_identity,
+    ));
+    let encoded = credential_update.encode_detached();
+    assert_eq!(
+        ProposalType::decode_detached(&encoded[..2]).unwrap(),
+        PROPOSAL_TYPE_CREDENTIAL_UPDATE
+    );
+    assert_eq!(
+        Proposal::decode_detached(&encoded)
+            .unwrap()
+            .encode_detached(),
+        encoded
+    );
+
+    // Unknown proposal types are rejected
+    let mut encoded = Proposal::Remove(3).encode_detached();
+    encoded[1] = 0x08;
+    assert!(Proposal::decode_detached(&encoded).is_err());
+}
+
+#[test]
+fn sender_data_encryption() {
+    let sender_data_secret = [3u8; 32];
+    let ciphertext = vec![5u8; 100];
+    let sender_data = SenderData {
+        sender: 2,
+        generation: Generation(17),
+        reuse_guard: [1, 2, 3, 4],
+    };
+    let sample = ciphertext_sample(&ciphertext);
+    assert_eq!(sample.len(), CIPHERTEXT_SAMPLE_BYTES);
+    let encrypted = encrypt_sender_data(&sender_data, &sender_data_secret, sample);
+    assert_eq!(
+        decrypt_sender_data(&encrypted, &sender_data_secret, sample).unwrap(),
+        sender_data
+    );
+
+    // Short ciphertexts are sampled whole
+    assert_eq!(ciphertext_sample(&ciphertext[..10]).len(), 10);
+
+    // A truncated sample derives another key
+    assert!(decrypt_sender_data(&encrypted, &sender_data_secret, &sample[..16]).is_err());
+    assert!(decrypt_sender_data(&encrypted, &[4u8; 32], sample).is_err());
+}
+
+#[test]
+fn reuse_guard() {
+    let sender_data_secret = [3u8; 32];
+    let stage_secrets = StageSecrets::new(&[6u8; NONCEBYTES], &[7u8; AES128KEYBYTES]);
+    let content = b"hello".to_vec();
+    let message = protect(
+        &content,
+        2,
+        1,
+        Generation(5),
+        &stage_secrets,
+        &sender_data_secret,
+    );
+    let message = EncryptedMessage::decode_detached(&message.encode_detached()).unwrap();
+    assert_eq!(message.epoch, 2);
+    let sender_data = unprotect_sender_data(&message, &sender_data_secret).unwrap();
+    assert_eq!(
+        (sender_data.sender, sender_data.generation),
+        (1, Generation(5))
+    );
+    assert_eq!(
+        unprotect(&message, &sender_data, &stage_secrets).unwrap(),
+        content
+    );
+
+    // The guard is applied to the content nonce
+    let key = Aes128Key::from_slice(&stage_secrets.key);
+    let plain_nonce = Nonce(Generation(5).apply_to_nonce(&stage_secrets.nonce));
+    let unguarded = aes_128_open(&message.ciphertext, &key, &plain_nonce);
+    assert_eq!(unguarded.is_ok(), sender_data.reuse_guard == [0u8; 4]);
+
+    // A corrupted guard fails to open the content
+    let mut corrupted = sender_data.clone();
+    corrupted.reuse_guard[0] ^= 0x01;
+    assert!(unprotect(&message, &corrupted, &stage_secrets).is_err());
+}
+
+#[test]
+fn generation_nonce() {
+    let base = [0xaau8; NONCEBYTES];
+    assert_eq!(Generation(0).apply_to_nonce(&base), base);
+    let mut expected = base;
+    expected[8..].copy_from_slice(&[0xaa, 0xaa, 0xab, 0x8b]);
+    assert_eq!(Generation(0x0121).apply_to_nonce(&base), expected);
+    let mut expected = base;
+    expected[8..].copy_from_slice(&[0x55, 0x55, 0x55, 0x55]);
+    assert_eq!(Generation(u32::MAX).apply_to_nonce(&base), expected);
+
+    // The reuse guard goes on top, at the other end of the nonce
+    let stage_secrets = StageSecrets::new(&base, &[7u8; AES128KEYBYTES]);
+    let Nonce(nonce) = guarded_nonce(&stage_secrets, Generation(0x0121), &[1, 2, 3, 4]);
+    assert_eq!(nonce[..4], [0xab, 0xa8, 0xa9, 0xae]);
+    assert_eq!(nonce[8..], [0xaa, 0xaa, 0xab, 0x8b]);
+}
+
+#[test]
+fn welcome_decoded_for_addressee() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_init_key = UserInitKeyBundle::new(&Identity::random()).init_key;
+    let charlie_init_key = UserInitKeyBundle::new(&Identity::random()).init_key;
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (_, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key.clone())])
+        .unwrap();
+    let welcome = welcome.unwrap();
+    let bytes = welcome.encode_detached();
+
+    let decoded = CommitWelcome::decode_addressed(&bytes, &bob_init_key.hash()).unwrap();
+    assert_eq!(decoded.group_info.tree, welcome.group_info.tree);
+    assert_eq!(decoded.encode_detached(), bytes);
+
+    // Without the group info the Welcome can't be decoded, but a member that isn't
+    // addressed bails out before getting there
+    let secrets_length = 4 + u32::decode_detached(&bytes[..4]).unwrap() as usize;
+    let secrets_only = &bytes[..secrets_length];
+    match CommitWelcome::decode_addressed(secrets_only, &charlie_init_key.hash()) {
+        Err(MelissaError::Group(GroupError::InvalidWelcome)) => (),
+        _ => panic!("Welcome for someone else accepted"),
+    }
+    match CommitWelcome::decode_addressed(secrets_only, &bob_init_key.hash()) {
+        Err(MelissaError::Codec(CodecError::DecodingError)) => (),
+        _ => panic!("truncated Welcome accepted"),
+    }
+
+    // A tree that isn't complete is rejected
+    let mut group_info = welcome.group_info.clone();
+    group_info.tree.push(None);
+    assert!(GroupInfo::decode_detached(&group_info.encode_detached()).is_err());
+}
+
+#[test]
+fn group_info_tbs_bytes() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let mut group_alice = Group::new(alice_identity, alice_credential, GroupId::random());
+    let (commit, _) = group_alice.commit(&[]).unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let group_info = group_alice.group_info().unwrap();
+    let roster = group_info.roster.clone();
+
+    let encoded = group_info.encode_detached();
+    let decoded = GroupInfo::decode_detached(&encoded).unwrap();
+    assert!(decoded.verify(&roster));
+    assert_eq!(decoded.encode_detached(), encoded);
+    let tbs = group_info.tbs_bytes();
+    assert_eq!(decoded.tbs_bytes(), tbs);
+    assert_eq!(&encoded[..tbs.len()], &tbs[..]);
+
+    // Signing only replaces the signature
+    let mut resigned = decoded.clone();
+    resigned.sign(&Identity::random());
+    assert_eq!(resigned.tbs_bytes(), tbs);
+    assert!(!resigned.verify(&roster));
+}
+
+#[test]
+fn sender_codec() {
+    let senders = [
+        Sender::Member(7),
+        Sender::External(1),
+        Sender::NewMemberProposal,
+        Sender::NewMemberCommit,
+    ];
+    for sender in senders.iter() {
+        let encoded = sender.encode_detached();
+        assert_eq!(Sender::decode_detached(&encoded).unwrap(), *sender);
+    }
+    assert_eq!(Sender::Member(7).encode_detached(), vec![1, 0, 0, 0, 7]);
+    assert_eq!(Sender::NewMemberProposal.encode_detached(), vec![3]);
+    assert_eq!(Sender::NewMemberCommit.encode_detached(), vec![4]);
+    assert!(Sender::decode_detached(&[5]).is_err());
+}
+
+#[test]
+fn parse_malformed_messages() {
+    let alice_identity = Identity::random();
+    let alice_credential = BasicCredential {
+        identity: "Alice".as_bytes().to_vec(),
+        public_key: alice_identity.public_key,
+    };
+    let bob_init_key = UserInitKeyBundle::new(&Identity::random()).init_key;
+    let mut group_alice = Group::new(alice_identity.clone(), alice_credential, GroupId::random());
+    let (commit, welcome) = group_alice
+        .commit(&[Proposal::Add(bob_init_key.clone())])
+        .unwrap();
+    group_alice.process_commit(&commit).unwrap();
+    let proposal = SignedProposal::new(
+        &GroupId::random(),
+        1,
+        Sender::Member(0),
+        Proposal::Add(bob_init_key),
+        &alice_identity,
+    );
+    let messages = [
+        MlsMessage::Proposal(proposal),
+        MlsMessage::Commit(commit),
+        MlsMessage::Ciphertext(group_alice.encrypt_application_message(b"hello").unwrap()),
+        MlsMessage::Welcome(welcome.unwrap()),
+        MlsMessage::GroupInfo(group_alice.group_info().unwrap()),
+    ];
+
+    // xorshift, so that failures can be reproduced
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for message in messages.iter() {
+        let bytes = message.encode_detached();
+        let parsed = parse_message(&bytes).unwrap();
+        assert_eq!(parsed.encode_detached(), bytes);
+
+        for length in 0..bytes.len() {
+            assert!(parse_message(&bytes[..length]).is_err());
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(parse_message(&trailing).is_err());
+
+        // Flipped bytes may still parse, but must not panic
+        for _ in 0..2000 {
+            let mut mutated = bytes.clone();
+            for _ in 0..1 + next() % 4 {
+                let position = next() as usize % mutated.len();
+                mutated[position] = next() as u8;
+            }
+            let _ = parse_message(&mutated);
+        }
+    }
+    for _ in 0..2000 {
+        let length = next() as usize % 64;
+        let bytes: Vec<u8> = (0..length).map(|_| next() as u8).collect();
+        let _ = parse_message(&bytes);
+    }
+    assert!(parse_message(&[]).is_err());
+    assert!(parse_message(&[0]).is_err());
+    assert!(parse_message(&[6, 0, 0]).is_err());
+
+    // The extension limit reaches the key package in the proposal and the group info
+    for message in [&messages[0], &messages[4]].iter() {
+        let bytes = message.encode_detached();
+        match parse_message_with_extension_limit(&bytes, 0) {
+            Err(CodecError::ExtensionTooLarge) => (),
+            _ => panic!("extension limit ignored"),
+        }
+    }
+}
+
+#[test]
+fn content_padding() {
+    for length in [0, 1, 27, 28, 29, 100].iter() {
+        let content = vec![0xaau8; *length];
+        let padded = pad_content(&content);
+        assert_eq!(padded.len() % PADDING_BLOCK_BYTES, 0);
+        assert!(padded.len() < 4 + length + PADDING_BLOCK_BYTES);
+        assert_eq!(unpad_content(&padded).unwrap(), content);
+    }
+    // Trailing zeros of the content aren't mistaken for padding
+    assert_eq!(unpad_content(&pad_content(&[0, 0])).unwrap(), vec![0, 0]);
+
+    let mut padded = pad_content(b"hello");
+    let last = padded.len() - 1;
+    padded[last] = 0x01;
+    assert!(unpad_content(&padded).is_err());
+    assert!(unpad_content(&[0, 0, 0, 9, 1]).is_err());
+}
@@ -17,6 +17,8 @@
 use codec::*;
 use crypto::schedule::*;
 use crypto::{aesgcm, hkdf};
+use std::error::Error;
+use std::fmt;
 use utils::*;
 
 const APPLICATION_SECRET_SIZE: usize = 32;
@@ -89,12 +91,33 @@ impl Codec for StageSecrets {
     }
 }
 
-#[derive(Debug)]
+impl Drop for StageSecrets {
+    fn drop(&mut self) {
+        erase(&mut self.nonce);
+        erase(&mut self.key);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum StageError {
     TooFarInThePast,
     TooFarInTheFuture,
+    Exhausted,
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StageError::TooFarInThePast => write!(f, "generation too far in the past"),
+            StageError::TooFarInTheFuture => write!(f, "generation too far in the future"),
+            StageError::Exhausted => write!(f, "generations exhausted"),
+        }
+    }
 }
 
+impl Error for StageError {}
+
+#[derive(Clone)]
 pub struct SenderApplicationSecret {
     value: [u8; APPLICATION_SECRET_SIZE],
     sender: u32,
@@ -132,18 +155,177 @@ impl SenderApplicationSecret {
         let mut stage_secrets = StageSecrets::new(&nonce_bytes, &key_bytes);
 
         for _ in 0..steps {
-            let mut context = Vec::new();
-            (self.sender as u32).encode(&mut context);
-
-            let nonce = hkdf_expand_label(&self.value, "nonce", &[], aesgcm::NONCEBYTES);
-            let key = hkdf_expand_label(&self.value, "key", &[], aesgcm::AES128KEYBYTES);
-            stage_secrets = StageSecrets::new(&nonce, &key);
-            let next_value =
-                hkdf_expand_label(&self.value, "app sender", &context, APPLICATION_SECRET_SIZE);
-            self.value.copy_from_slice(&next_value);
-            self.stage += 1;
+            stage_secrets = self.next_stage();
         }
 
         Ok(stage_secrets)
     }
+    // Secrets of the next stage. The value they come from is ratcheted forward.
+    pub fn next_stage(&mut self) -> StageSecrets {
+        let mut context = Vec::new();
+        (self.sender as u32).encode(&mut context);
+
+        let nonce = hkdf_expand_label(&self.value, "nonce", &[], aesgcm::NONCEBYTES);
+        let key = hkdf_expand_label(&self.value, "key", &[], aesgcm::AES128KEYBYTES);
+        let next_value =
+            hkdf_expand_label(&self.value, "app sender", &context, APPLICATION_SECRET_SIZE);
+        self.value.copy_from_slice(&next_value);
+        self.stage += 1;
+        StageSecrets::new(&nonce, &key)
+    }
+}
+
+impl Drop for SenderApplicationSecret {
+    fn drop(&mut self) {
+        erase(&mut self.value)
+    }
+}
+
+impl Codec for SenderApplicationSecret {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        encode_vec_u8(buffer, &self.value);
+        self.sender.encode(buffer);
+        (self.stage as u64).encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let bytes: Vec<u8> = decode_vec_u8(cursor)?;
+        if bytes.len() != APPLICATION_SECRET_SIZE {
+            return Err(CodecError::DecodingError);
+        }
+        let mut value = [0u8; APPLICATION_SECRET_SIZE];
+        value.copy_from_slice(&bytes);
+        let sender = u32::decode(cursor)?;
+        let stage = u64::decode(cursor)? as usize;
+        Ok(SenderApplicationSecret {
+            value,
+            sender,
+            stage,
+        })
+    }
+}
+
+// Position of a message in the chain of its sender. Generations never wrap: the one after
+// u32::MAX does not exist, as it would repeat keys and nonces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Generation(pub u32);
+
+impl Generation {
+    pub fn next(self) -> Option<Generation> {
+        self.0.checked_add(1).map(Generation)
+    }
+    // The nonce of this generation: the big-endian generation XORed into the last bytes
+    // of the base nonce
+    pub fn apply_to_nonce(self, base: &[u8; aesgcm::NONCEBYTES]) -> [u8; aesgcm::NONCEBYTES] {
+        let mut nonce = *base;
+        let offset = aesgcm::NONCEBYTES - 4;
+        for (byte, counter) in nonce[offset..].iter_mut().zip(self.0.to_be_bytes().iter()) {
+            *byte ^= counter;
+        }
+        nonce
+    }
+}
+
+impl Codec for Generation {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.0.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        Ok(Generation(u32::decode(cursor)?))
+    }
+}
+
+// How far a generation may be ahead of the next expected one, which is also the
+// number of skipped stage secrets kept per sender
+pub const MAX_SKIPPED_GENERATIONS: u32 = 1000;
+
+// The application secret chain of one sender. Stage secrets of generations skipped by
+// out of order messages are kept until they are used, each of them only once.
+#[derive(Clone)]
+pub struct SenderRatchet {
+    secret: SenderApplicationSecret,
+    next_generation: Generation,
+    skipped: Vec<(Generation, StageSecrets)>,
+}
+
+impl SenderRatchet {
+    pub fn new(application_secret: &[u8], sender: u32) -> Self {
+        SenderRatchet {
+            secret: SenderApplicationSecret::from_bytes_for_sender(application_secret, sender),
+            next_generation: Generation(0),
+            skipped: Vec::new(),
+        }
+    }
+    pub fn sender(&self) -> u32 {
+        self.secret.sender
+    }
+    // For sending: the next generation and its stage secrets. A wrapped generation would
+    // repeat keys and nonces, so the ratchet stops before that and only a new epoch
+    // brings a fresh one.
+    pub fn advance(&mut self) -> Result<(Generation, StageSecrets), StageError> {
+        let generation = self.next_generation;
+        self.next_generation = generation.next().ok_or(StageError::Exhausted)?;
+        Ok((generation, self.secret.next_stage()))
+    }
+    // Lets tests get to the end of the generations without deriving all of them
+    #[cfg(test)]
+    pub fn set_next_generation(&mut self, generation: Generation) {
+        self.next_generation = generation;
+    }
+    pub fn get_secret_for_generation(
+        &mut self,
+        generation: Generation,
+    ) -> Result<StageSecrets, StageError> {
+        if generation < self.next_generation {
+            return match self.skipped.iter().position(|(g, _)| *g == generation) {
+                Some(position) => Ok(self.skipped.remove(position).1),
+                None => Err(StageError::TooFarInThePast),
+            };
+        }
+        if generation.0 - self.next_generation.0 > MAX_SKIPPED_GENERATIONS {
+            return Err(StageError::TooFarInTheFuture);
+        }
+        while self.next_generation < generation {
+            let skipped = self.advance()?;
+            self.skipped.push(skipped);
+        }
+        let excess = self
+            .skipped
+            .len()
+            .saturating_sub(MAX_SKIPPED_GENERATIONS as usize);
+        self.skipped.drain(..excess);
+        Ok(self.advance()?.1)
+    }
+    // Drops the stage secrets of skipped generations that are more than keep_window
+    // behind the next expected one and returns how many were dropped
+    pub fn forget_skipped(&mut self, keep_window: u32) -> usize {
+        let oldest = Generation(self.next_generation.0.saturating_sub(keep_window));
+        let before = self.skipped.len();
+        self.skipped.retain(|(g, _)| *g >= oldest);
+        before - self.skipped.len()
+    }
+}
+
+impl Codec for SenderRatchet {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.secret.encode(buffer);
+        self.next_generation.encode(buffer);
+        encode_vec_u32(buffer, &self.skipped);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let secret = SenderApplicationSecret::decode(cursor)?;
+        let next_generation = Generation::decode(cursor)?;
+        let skipped = decode_vec_u32(cursor)?;
+        Ok(SenderRatchet {
+            secret,
+            next_generation,
+            skipped,
+        })
+    }
+}
+
+#[test]
+fn generation_next() {
+    assert_eq!(Generation(0).next(), Some(Generation(1)));
+    assert_eq!(Generation(u32::MAX - 1).next(), Some(Generation(u32::MAX)));
+    assert_eq!(Generation(u32::MAX).next(), None);
 }
@@ -0,0 +1,160 @@
+// Wire
+// Copyright (C) 2018 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+use codec::*;
+use crypto::aesgcm::{self, Nonce, AES256KEYBYTES, NONCEBYTES};
+use crypto::rng::*;
+use error::MelissaError;
+use sodiumoxide::crypto::pwhash::argon2id13;
+use std::error::Error;
+use std::fmt;
+use utils::*;
+
+pub const STORAGE_FORMAT_VERSION: u8 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum StorageError {
+    UnsupportedFormat,
+    KeyDerivation,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::UnsupportedFormat => write!(f, "unsupported storage format"),
+            StorageError::KeyDerivation => write!(f, "key derivation failed"),
+        }
+    }
+}
+
+impl Error for StorageError {}
+
+// Everything needed to derive the key again, stored in front of the ciphertext. Changing
+// any of it changes the key or the nonce, so the ciphertext doesn't open anymore.
+struct StorageHeader {
+    version: u8,
+    salt: [u8; argon2id13::SALTBYTES],
+    opslimit: u64,
+    memlimit: u64,
+    nonce: [u8; NONCEBYTES],
+}
+
+impl Codec for StorageHeader {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.version.encode(buffer);
+        encode_fixed(&self.salt, buffer);
+        self.opslimit.encode(buffer);
+        self.memlimit.encode(buffer);
+        encode_fixed(&self.nonce, buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let version = u8::decode(cursor)?;
+        let salt = decode_fixed::<{ argon2id13::SALTBYTES }>(cursor)?;
+        let opslimit = u64::decode(cursor)?;
+        let memlimit = u64::decode(cursor)?;
+        let nonce = decode_fixed::<NONCEBYTES>(cursor)?;
+        Ok(StorageHeader {
+            version,
+            salt,
+            opslimit,
+            memlimit,
+            nonce,
+        })
+    }
+}
+
+fn derive_key(passphrase: &[u8], header: &StorageHeader) -> Result<Vec<u8>, MelissaError> {
+    let mut key = vec![0u8; AES256KEYBYTES];
+    argon2id13::derive_key(
+        &mut key,
+        passphrase,
+        &argon2id13::Salt(header.salt),
+        argon2id13::OpsLimit(header.opslimit as usize),
+        argon2id13::MemLimit(header.memlimit as usize),
+    )
+    .map_err(|_| StorageError::KeyDerivation)?;
+    Ok(key)
+}
+
+// Seals under a key derived from the passphrase with Argon2id
+#[cfg(any(test, not(feature = "explicit-entropy")))]
+pub fn seal_with_passphrase(plaintext: &[u8], passphrase: &[u8]) -> Result<Vec<u8>, MelissaError> {
+    seal_with_passphrase_with_rng(plaintext, passphrase, &mut SystemRng)
+}
+
+pub fn seal_with_passphrase_with_rng(
+    plaintext: &[u8],
+    passphrase: &[u8],
+    rng: &mut dyn Rng,
+) -> Result<Vec<u8>, MelissaError> {
+    let mut salt = [0u8; argon2id13::SALTBYTES];
+    rng.fill(&mut salt);
+    let header = StorageHeader {
+        version: STORAGE_FORMAT_VERSION,
+        salt,
+        opslimit: argon2id13::OPSLIMIT_INTERACTIVE.0 as u64,
+        memlimit: argon2id13::MEMLIMIT_INTERACTIVE.0 as u64,
+        nonce: Nonce::new_with_rng(rng).0,
+    };
+    let mut key = derive_key(passphrase, &header)?;
+    let sealed = aesgcm::seal(plaintext, &key, &Nonce(header.nonce));
+    erase(&mut key);
+    let mut buffer = header.encode_detached();
+    buffer.extend_from_slice(&sealed?);
+    Ok(buffer)
+}
+
+// A wrong passphrase fails authentication. The KDF parameters come from the input, so
+// they are capped to keep a crafted header from exhausting memory or time.
+pub fn open_with_passphrase(sealed: &[u8], passphrase: &[u8]) -> Result<Vec<u8>, MelissaError> {
+    let mut cursor = Cursor::new(sealed);
+    let header = StorageHeader::decode(&mut cursor)?;
+    if header.version != STORAGE_FORMAT_VERSION
+        || header.opslimit > argon2id13::OPSLIMIT_SENSITIVE.0 as u64
+        || header.memlimit > argon2id13::MEMLIMIT_SENSITIVE.0 as u64
+    {
+        return Err(StorageError::UnsupportedFormat.into());
+    }
+    let mut key = derive_key(passphrase, &header)?;
+    let plaintext = aesgcm::open(cursor.read_to_end(), &key, &Nonce(header.nonce));
+    erase(&mut key);
+    Ok(plaintext?)
+}
+
+#[test]
+fn passphrase_seal_open() {
+    let sealed = seal_with_passphrase(b"group state", b"correct horse").unwrap();
+    assert_encoding_roundtrip(&StorageHeader::decode(&mut Cursor::new(&sealed)).unwrap());
+    assert_eq!(
+        open_with_passphrase(&sealed, b"correct horse").unwrap(),
+        b"group state".to_vec()
+    );
+    match open_with_passphrase(&sealed, b"battery staple") {
+        Err(MelissaError::Aead(aesgcm::AeadError::AuthenticationFailed)) => (),
+        _ => panic!("wrong passphrase accepted"),
+    }
+
+    // The header is bound to the key
+    let mut tampered = sealed.clone();
+    tampered[1] ^= 0x01;
+    assert!(open_with_passphrase(&tampered, b"correct horse").is_err());
+    let mut tampered = sealed;
+    tampered[0] = STORAGE_FORMAT_VERSION + 1;
+    match open_with_passphrase(&tampered, b"correct horse") {
+        Err(MelissaError::Storage(StorageError::UnsupportedFormat)) => (),
+        _ => panic!("unknown format accepted"),
+    }
+}
@@ -0,0 +1,113 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+// Readers for the JSON test vectors published alongside the MLS specification.
+// Those only have full trees, so treemath.json also has ragged trees of 3 to 255
+// leaves, from the tree math pseudocode of the earlier drafts. The 255 leaf case is
+// the one tree_math.bin used to cover.
+// Entries that are undefined for a node (the children of a leaf, the parent and
+// sibling of the root) are null in the files.
+
+extern crate serde_json;
+
+use self::serde_json::Value;
+use std::fs::File;
+use std::io::Read;
+use treemath;
+
+fn read_json_vector(name: &str) -> Vec<Value> {
+    let mut file = File::open(format!("test_vectors/{}", name)).unwrap();
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).unwrap();
+    match serde_json::from_str(&contents).unwrap() {
+        Value::Array(cases) => cases,
+        _ => panic!("{} is not a list of test cases", name),
+    }
+}
+
+fn field_usize(case: &Value, name: &str) -> usize {
+    case[name].as_u64().unwrap() as usize
+}
+
+fn field_nodes(case: &Value, name: &str) -> Vec<Option<usize>> {
+    case[name]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|v| v.as_u64().map(|x| x as usize))
+        .collect()
+}
+
+// Our functions return the node itself where the vectors have null
+fn defined(x: usize, result: usize) -> Option<usize> {
+    if result == x {
+        None
+    } else {
+        Some(result)
+    }
+}
+
+#[test]
+fn treemath_json_vectors() {
+    let cases = read_json_vector("treemath.json");
+    assert!(!cases.is_empty());
+    for case in cases.iter() {
+        let n = field_usize(case, "n_leaves");
+        let width = field_usize(case, "n_nodes");
+        assert_eq!(treemath::node_width(n), width);
+        assert_eq!(treemath::root(n), field_usize(case, "root"));
+
+        let left = field_nodes(case, "left");
+        let right = field_nodes(case, "right");
+        let parent = field_nodes(case, "parent");
+        let sibling = field_nodes(case, "sibling");
+        assert_eq!(left.len(), width);
+        assert_eq!(right.len(), width);
+        assert_eq!(parent.len(), width);
+        assert_eq!(sibling.len(), width);
+
+        for x in 0..width {
+            assert_eq!(defined(x, treemath::left(x)), left[x], "left({})", x);
+            assert_eq!(defined(x, treemath::right(x, n)), right[x], "right({})", x);
+            assert_eq!(
+                defined(x, treemath::parent(x, n)),
+                parent[x],
+                "parent({})",
+                x
+            );
+            assert_eq!(
+                defined(x, treemath::sibling(x, n)),
+                sibling[x],
+                "sibling({})",
+                x
+            );
+        }
+
+        // The direct path and copath follow from the parent and sibling columns
+        for leaf in treemath::leaves(n) {
+            let mut dirpath = Vec::new();
+            let mut copath = Vec::new();
+            let mut x = leaf;
+            while let Some(p) = parent[x] {
+                dirpath.push(x);
+                copath.push(sibling[x].unwrap());
+                x = p;
+            }
+            assert_eq!(treemath::dirpath(leaf, n), dirpath, "dirpath({})", leaf);
+            assert_eq!(treemath::copath(leaf, n), copath, "copath({})", leaf);
+        }
+    }
+}
@@ -15,21 +15,41 @@
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
 use codec::*;
+use credential::{CredentialError, CredentialValidator};
+use crypto::hash::HashAlgorithm;
 use crypto::hpke::*;
+use crypto::rng::*;
+use group::GroupContext;
 use keys::*;
+use messages::UpdatePath;
+#[cfg(feature = "parallel")]
+use rayon;
 use sodiumoxide::crypto::hash::sha256::*;
-use sodiumoxide::randombytes;
+use std::collections::HashSet;
 use treemath;
+use utils::*;
 
 pub const NODESECRETBYTES: usize = 32;
 
+// Subtrees up to this level, 256 leaves, are hashed serially
+#[cfg(feature = "parallel")]
+pub const PARALLEL_HASH_MIN_LEVEL: usize = 8;
+
 #[derive(PartialEq, Clone, Copy, Debug)]
-pub struct NodeSecret(pub [u8; NODESECRETBYTES]);
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NodeSecret(
+    #[cfg_attr(feature = "serde", serde(with = "::utils::serde_hex"))] pub [u8; NODESECRETBYTES],
+);
 
 impl NodeSecret {
+    #[cfg(any(test, not(feature = "explicit-entropy")))]
     pub fn new_random() -> Self {
+        NodeSecret::new_with_rng(&mut SystemRng)
+    }
+
+    pub fn new_with_rng(rng: &mut dyn Rng) -> Self {
         let mut bytes = [0u8; NODESECRETBYTES];
-        bytes.clone_from_slice(randombytes::randombytes(NODESECRETBYTES).as_slice());
+        rng.fill(&mut bytes);
         NodeSecret(bytes)
     }
 
@@ -76,11 +96,18 @@ impl Codec for NodeSecret {
     }
 }
 
+// Leaves and parents are stored alike: both hold secrets, private keys and a parent
+// hash, and only parents get unmerged leaves. tree_node() gives the typed public view.
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Node {
     pub secret: Option<NodeSecret>,
     pub dh_public_key: Option<X25519PublicKey>,
     pub dh_private_key: Option<X25519PrivateKey>,
+    // Leaves added below this parent since it was last re-keyed
+    pub unmerged_leaves: Vec<u32>,
+    // Hash of the parent as it was when this node was last re-keyed, empty for the root
+    pub parent_hash: Vec<u8>,
 }
 
 impl Codec for Node {
@@ -88,15 +115,21 @@ impl Codec for Node {
         self.secret.encode(buffer);
         self.dh_public_key.encode(buffer);
         self.dh_private_key.encode(buffer);
+        encode_vec_u32(buffer, &self.unmerged_leaves);
+        encode_vec_u8(buffer, &self.parent_hash);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
         let secret = Option::<NodeSecret>::decode(cursor)?;
         let dh_public_key = Option::<X25519PublicKey>::decode(cursor)?;
         let dh_private_key = Option::<X25519PrivateKey>::decode(cursor)?;
+        let unmerged_leaves = decode_vec_u32(cursor)?;
+        let parent_hash = decode_vec_u8(cursor)?;
         Ok(Node {
             secret,
             dh_public_key,
             dh_private_key,
+            unmerged_leaves,
+            parent_hash,
         })
     }
 }
@@ -110,6 +143,8 @@ impl Node {
             secret: Some(*secret),
             dh_public_key: Some(kp.public_key),
             dh_private_key: Some(kp.private_key),
+            unmerged_leaves: Vec::new(),
+            parent_hash: Vec::new(),
         }
     }
 
@@ -118,6 +153,8 @@ impl Node {
             secret: None,
             dh_private_key: None,
             dh_public_key: Some(*key),
+            unmerged_leaves: Vec::new(),
+            parent_hash: Vec::new(),
         }
     }
 
@@ -126,6 +163,8 @@ impl Node {
             secret: None,
             dh_private_key: None,
             dh_public_key: None,
+            unmerged_leaves: Vec::new(),
+            parent_hash: Vec::new(),
         }
     }
 
@@ -163,6 +202,8 @@ impl Node {
         self.secret = None;
         self.dh_private_key = None;
         self.dh_public_key = None;
+        self.unmerged_leaves.clear();
+        self.parent_hash.clear();
     }
 
     pub fn is_blank(&self) -> bool {
@@ -170,10 +211,276 @@ impl Node {
     }
 }
 
+// Path secrets of the node and all its ancestors up to the root, each one the hash of
+// the one below
+pub fn derive_path_secrets(initial: Vec<u8>, from_node: usize, n: usize) -> Vec<(usize, Vec<u8>)> {
+    let mut path = treemath::dirpath(from_node, n);
+    path.push(treemath::root(n));
+    let mut path_secret = NodeSecret::from_bytes(&initial);
+    let mut path_secrets = Vec::new();
+    for index in path {
+        path_secrets.push((index, path_secret.0.to_vec()));
+        path_secret.hash();
+    }
+    path_secrets
+}
+
+pub fn path_node_keypair(path_secret: &[u8]) -> (X25519PublicKey, X25519PrivateKey) {
+    let key_pair = X25519KeyPair::new_from_secret(&NodeSecret::from_bytes(path_secret));
+    (key_pair.public_key, key_pair.private_key)
+}
+
+// Leaf allocation policy for new members
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum AddPosition {
+    #[default]
+    LeftmostBlank,
+    Append,
+}
+
+impl Codec for AddPosition {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            AddPosition::LeftmostBlank => 0u8.encode(buffer),
+            AddPosition::Append => 1u8.encode(buffer),
+        }
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        match u8::decode(cursor)? {
+            0 => Ok(AddPosition::LeftmostBlank),
+            1 => Ok(AddPosition::Append),
+            _ => Err(CodecError::DecodingError),
+        }
+    }
+}
+
+// What removing a member would do to the tree: the nodes from its leaf up to the root
+// are blanked, and the tree shrinks to truncated_size if that is set
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemovePreview {
+    pub blanked: Vec<usize>,
+    pub truncated_size: Option<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TreeError {
+    NoLeaves,
+    InvalidUnmergedLeaves,
+    InvalidUpdatePath,
+    PublicKeyMismatch,
+    InvalidTreeSize,
+    UnsupportedCipherSuite,
+    InvalidLeaf,
+    DuplicateKey,
+    InvalidParentHash,
+    TreeHashMismatch,
+    IndexOutOfRange,
+    InvalidCredential(CredentialError),
+}
+
+// The unmerged leaves of one parent node, as handed to new members
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UnmergedLeaves {
+    pub node: u32,
+    pub leaves: Vec<u32>,
+}
+
+impl Codec for UnmergedLeaves {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.node.encode(buffer);
+        encode_vec_u32(buffer, &self.leaves);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let node = u32::decode(cursor)?;
+        let leaves = decode_vec_u32(cursor)?;
+        Ok(UnmergedLeaves { node, leaves })
+    }
+}
+
+// Leaves only hold the init key of their member, blank leaves hash without one.
+// struct {
+//     uint32 node_index;
+//     optional<HPKEPublicKey> leaf_key;
+// } LeafNodeHashInput;
+pub struct LeafNodeHashInput {
+    pub node_index: u32,
+    pub leaf_key: Option<X25519PublicKey>,
+}
+
+impl Codec for LeafNodeHashInput {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.node_index.encode(buffer);
+        encode_option(&self.leaf_key, buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let node_index = u32::decode(cursor)?;
+        let leaf_key = decode_option(cursor)?;
+        Ok(LeafNodeHashInput {
+            node_index,
+            leaf_key,
+        })
+    }
+}
+
+// struct {
+//     HPKEPublicKey public_key;
+//     uint32 unmerged_leaves<0..2^32-1>;
+// } ParentNode;
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParentNode {
+    pub public_key: X25519PublicKey,
+    pub unmerged_leaves: Vec<u32>,
+}
+
+impl Codec for ParentNode {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.public_key.encode(buffer);
+        encode_vec_u32(buffer, &self.unmerged_leaves);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let public_key = X25519PublicKey::decode(cursor)?;
+        let unmerged_leaves = decode_vec_u32(cursor)?;
+        Ok(ParentNode {
+            public_key,
+            unmerged_leaves,
+        })
+    }
+}
+
+// The public content of a leaf. Credentials are kept in the group's roster, which is
+// aligned with the leaves.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LeafNode {
+    pub public_key: X25519PublicKey,
+}
+
+impl Codec for LeafNode {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.public_key.encode(buffer);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let public_key = X25519PublicKey::decode(cursor)?;
+        Ok(LeafNode { public_key })
+    }
+}
+
+pub const NODE_TYPE_LEAF: u8 = 1;
+pub const NODE_TYPE_PARENT: u8 = 2;
+
+// The public content of a non-blank node, typed by its position in the tree. It is
+// built from a Node by Tree::tree_node() for hashing and encoding, not stored.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TreeNode {
+    Leaf(LeafNode),
+    Parent(ParentNode),
+}
+
+impl TreeNode {
+    pub fn as_leaf(&self) -> Option<&LeafNode> {
+        match self {
+            TreeNode::Leaf(leaf) => Some(leaf),
+            TreeNode::Parent(_) => None,
+        }
+    }
+    pub fn as_parent(&self) -> Option<&ParentNode> {
+        match self {
+            TreeNode::Leaf(_) => None,
+            TreeNode::Parent(parent) => Some(parent),
+        }
+    }
+}
+
+impl Codec for TreeNode {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            TreeNode::Leaf(leaf) => {
+                NODE_TYPE_LEAF.encode(buffer);
+                leaf.encode(buffer);
+            }
+            TreeNode::Parent(parent) => {
+                NODE_TYPE_PARENT.encode(buffer);
+                parent.encode(buffer);
+            }
+        }
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        match u8::decode(cursor)? {
+            NODE_TYPE_LEAF => Ok(TreeNode::Leaf(LeafNode::decode(cursor)?)),
+            NODE_TYPE_PARENT => Ok(TreeNode::Parent(ParentNode::decode(cursor)?)),
+            _ => Err(CodecError::DecodingError),
+        }
+    }
+}
+
+// struct {
+//     uint32 node_index;
+//     optional<ParentNode> parent_node;
+//     opaque left_hash<0..255>;
+//     opaque right_hash<0..255>;
+// } ParentNodeHashInput;
+pub struct ParentNodeHashInput {
+    pub node_index: u32,
+    pub parent_node: Option<ParentNode>,
+    pub left_hash: Vec<u8>,
+    pub right_hash: Vec<u8>,
+}
+
+impl Codec for ParentNodeHashInput {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.node_index.encode(buffer);
+        encode_option(&self.parent_node, buffer);
+        encode_vec_u8(buffer, &self.left_hash);
+        encode_vec_u8(buffer, &self.right_hash);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let node_index = u32::decode(cursor)?;
+        let parent_node = decode_option(cursor)?;
+        let left_hash = decode_vec_u8(cursor)?;
+        let right_hash = decode_vec_u8(cursor)?;
+        Ok(ParentNodeHashInput {
+            node_index,
+            parent_node,
+            left_hash,
+            right_hash,
+        })
+    }
+}
+
+// What a child's parent hash covers, so that it commits to the chain of keys above it.
+// struct {
+//     HPKEPublicKey public_key;
+//     opaque parent_hash<0..255>;
+// } ParentHashInput;
+pub struct ParentHashInput {
+    pub public_key: X25519PublicKey,
+    pub parent_hash: Vec<u8>,
+}
+
+impl Codec for ParentHashInput {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        self.public_key.encode(buffer);
+        encode_vec_u8(buffer, &self.parent_hash);
+    }
+    fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
+        let public_key = X25519PublicKey::decode(cursor)?;
+        let parent_hash = decode_vec_u8(cursor)?;
+        Ok(ParentHashInput {
+            public_key,
+            parent_hash,
+        })
+    }
+}
+
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tree {
     pub nodes: Vec<Node>,
     pub own_leaf_index: usize,
+    // Follows the group's cipher suite, so it isn't encoded with the tree
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub hash_algorithm: HashAlgorithm,
 }
 
 impl Codec for Tree {
@@ -182,42 +489,122 @@ impl Codec for Tree {
         (self.own_leaf_index as u32).encode(buffer);
     }
     fn decode(cursor: &mut Cursor) -> Result<Self, CodecError> {
-        let nodes = decode_vec_u32(cursor)?;
+        let nodes: Vec<Node> = decode_vec_u32(cursor)?;
         let own_leaf_index = u32::decode(cursor)? as usize;
+        // The own leaf is a node index, so it has to be an even index inside the tree
+        check_tree_size(nodes.len())?;
+        if own_leaf_index >= nodes.len() || own_leaf_index % 2 == 1 {
+            return Err(CodecError::InconsistentTreeSize);
+        }
         Ok(Tree {
             nodes,
             own_leaf_index,
+            hash_algorithm: HashAlgorithm::default(),
         })
     }
 }
 
+// Reads the public keys of a tree one node at a time, so that a tree that is malformed
+// or too large is rejected at the first offending node instead of after the whole
+// vector was decoded
+pub struct TreeDecoder<'a> {
+    cursor: Cursor<'a>,
+}
+
+impl<'a> TreeDecoder<'a> {
+    // Takes the length-prefixed tree off the outer cursor
+    pub fn new(cursor: &mut Cursor<'a>) -> Result<TreeDecoder<'a>, CodecError> {
+        let length = usize::from(u16::decode(cursor)?);
+        Ok(TreeDecoder {
+            cursor: cursor.sub_cursor(length)?,
+        })
+    }
+
+    // The nodes have to make up a complete tree of at most max_leaves leaves
+    pub fn decode_tree(
+        self,
+        max_leaves: usize,
+    ) -> Result<Vec<Option<X25519PublicKey>>, CodecError> {
+        let max_nodes = treemath::node_width(max_leaves);
+        let mut nodes = Vec::new();
+        for node in self {
+            if nodes.len() == max_nodes {
+                return Err(CodecError::DecodingError);
+            }
+            nodes.push(node?);
+        }
+        check_tree_size(nodes.len())?;
+        Ok(nodes)
+    }
+}
+
+// A decoded node array has to be exactly as wide as a tree, otherwise the index math
+// goes out of bounds later. Returns the leaf count.
+pub fn check_tree_size(node_count: usize) -> Result<usize, CodecError> {
+    treemath::node_width_to_leaf_count(node_count).ok_or(CodecError::InconsistentTreeSize)
+}
+
+impl<'a> Iterator for TreeDecoder<'a> {
+    type Item = Result<Option<X25519PublicKey>, CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor.has_more() {
+            Some(Option::<X25519PublicKey>::decode(&mut self.cursor))
+        } else {
+            None
+        }
+    }
+}
+
 impl Tree {
     pub fn new_from_leaf(leaf: &Node) -> Tree {
         let mut tree = Tree {
             nodes: vec![leaf.clone()],
             own_leaf_index: 0,
+            hash_algorithm: HashAlgorithm::default(),
         };
         let secret = leaf.secret.unwrap();
         let new_nodes = Tree::hash_up(0, 1, &secret);
         let copath = vec![0];
-        tree.merge(copath, &new_nodes);
+        tree.merge(copath, &new_nodes).unwrap();
         tree
     }
 
+    // All parents start out blank. The own leaf index defaults to the first leaf.
+    pub fn from_leaves(leaves: Vec<Node>) -> Result<Tree, TreeError> {
+        if leaves.is_empty() {
+            return Err(TreeError::NoLeaves);
+        }
+        let n = leaves.len();
+        let mut nodes = vec![Node::new_blank(); treemath::node_width(n)];
+        for (index, leaf) in treemath::leaves(n).into_iter().zip(leaves) {
+            nodes[index] = leaf;
+        }
+        Ok(Tree {
+            nodes,
+            own_leaf_index: 0,
+            hash_algorithm: HashAlgorithm::default(),
+        })
+    }
+
     pub fn new_from_public_keys(
-        keys: &[X25519PublicKey],
+        keys: &[Option<X25519PublicKey>],
         own_leaf_index: usize,
         leaf_secret: &NodeSecret,
     ) -> Tree {
         let mut nodes: Vec<Node> = Vec::new();
         for key in keys {
-            nodes.push(Node::new_from_public_key(key));
+            match key {
+                Some(key) => nodes.push(Node::new_from_public_key(key)),
+                None => nodes.push(Node::new_blank()),
+            }
         }
         let own_node = Node::from_secret(leaf_secret);
         nodes[own_leaf_index] = own_node;
         Tree {
             nodes,
             own_leaf_index,
+            hash_algorithm: HashAlgorithm::default(),
         }
     }
 
@@ -225,42 +612,115 @@ impl Tree {
         self.nodes.len()
     }
 
-    pub fn get_root(&self) -> Node {
-        let root_index = treemath::root(self.get_leaf_count());
-        self.nodes[root_index].clone()
+    // The node at index x, an error if x is past the node width of the tree
+    pub fn node(&self, x: usize) -> Result<&Node, TreeError> {
+        if !treemath::in_range(x, self.get_leaf_count()) {
+            return Err(TreeError::IndexOutOfRange);
+        }
+        Ok(&self.nodes[x])
     }
 
-    pub fn set_root(&mut self, node: Node) {
+    pub fn node_mut(&mut self, x: usize) -> Result<&mut Node, TreeError> {
+        if !treemath::in_range(x, self.get_leaf_count()) {
+            return Err(TreeError::IndexOutOfRange);
+        }
+        Ok(&mut self.nodes[x])
+    }
+
+    fn is_blank_at(&self, x: usize) -> Result<bool, TreeError> {
+        Ok(self.node(x)?.is_blank())
+    }
+
+    pub fn get_root(&self) -> Result<Node, TreeError> {
+        self.node(treemath::root(self.get_leaf_count())).cloned()
+    }
+
+    pub fn set_root(&mut self, node: Node) -> Result<(), TreeError> {
         let index = treemath::root(self.get_leaf_count());
-        self.nodes[index] = node;
+        *self.node_mut(index)? = node;
+        Ok(())
     }
 
-    pub fn get_nodes_from_path(&self, path: Vec<usize>) -> Vec<Node> {
+    pub fn get_nodes_from_path(&self, path: Vec<usize>) -> Result<Vec<Node>, TreeError> {
         let mut nodes: Vec<Node> = Vec::new();
         for i in path {
-            nodes.push(self.nodes[i].clone());
+            nodes.push(self.node(i)?.clone());
         }
-        nodes
+        Ok(nodes)
     }
 
-    pub fn get_public_keys_from_path(&self, path: Vec<usize>) -> Vec<X25519PublicKey> {
+    // Blank nodes on the path have no key and are an error too
+    pub fn get_public_keys_from_path(
+        &self,
+        path: Vec<usize>,
+    ) -> Result<Vec<X25519PublicKey>, TreeError> {
         let mut keys = Vec::new();
         for index in path {
-            keys.push(self.nodes[index].clone().dh_public_key.unwrap());
+            match self.node(index)?.dh_public_key {
+                Some(key) => keys.push(key),
+                None => return Err(TreeError::InvalidLeaf),
+            }
         }
-        keys
+        Ok(keys)
     }
 
-    pub fn get_public_key_tree(&self) -> Vec<X25519PublicKey> {
+    pub fn get_public_key_tree(&self) -> Vec<Option<X25519PublicKey>> {
         let mut tree = Vec::new();
         for node in self.nodes.iter() {
-            tree.push(node.clone().dh_public_key.unwrap());
+            tree.push(node.dh_public_key);
         }
         tree
     }
 
-    pub fn get_own_leaf(&self) -> Node {
-        self.nodes[self.own_leaf_index].clone()
+    // The tree as it can be handed to a joiner or a server: every node keeps its public key,
+    // unmerged leaves and parent hash, but no secret or private key
+    pub fn public_tree(&self) -> Tree {
+        let mut tree = self.clone();
+        for node in tree.nodes.iter_mut() {
+            node.get_public_key();
+            node.secret = None;
+            node.dh_private_key = None;
+        }
+        tree
+    }
+
+    pub fn get_unmerged_leaves(&self) -> Vec<UnmergedLeaves> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| !node.unmerged_leaves.is_empty())
+            .map(|(index, node)| UnmergedLeaves {
+                node: index as u32,
+                leaves: node.unmerged_leaves.clone(),
+            })
+            .collect()
+    }
+
+    // Only non-blank parents can have unmerged leaves, and only leaves below them
+    pub fn set_unmerged_leaves(&mut self, unmerged: &[UnmergedLeaves]) -> Result<(), TreeError> {
+        let n = self.get_leaf_count();
+        for entry in unmerged {
+            let x = entry.node as usize;
+            if treemath::level(x) == 0 || self.is_blank_at(x).unwrap_or(true) {
+                return Err(TreeError::InvalidUnmergedLeaves);
+            }
+            for leaf in entry.leaves.iter() {
+                let index = *leaf as usize * 2;
+                if !treemath::in_range(index, n) {
+                    return Err(TreeError::InvalidUnmergedLeaves);
+                }
+                let below = x == treemath::root(n) || treemath::dirpath(index, n).contains(&x);
+                if !below {
+                    return Err(TreeError::InvalidUnmergedLeaves);
+                }
+            }
+            self.node_mut(x)?.unmerged_leaves = entry.leaves.clone();
+        }
+        Ok(())
+    }
+
+    pub fn get_own_leaf(&self) -> Result<Node, TreeError> {
+        self.node(self.own_leaf_index).cloned()
     }
 
     pub fn get_own_leaf_index(&self) -> usize {
@@ -271,32 +731,376 @@ impl Tree {
         self.get_tree_size() / 2 + 1
     }
 
-    pub fn resolve(&self, x: usize) -> Vec<usize> {
+    pub fn resolve(&self, x: usize) -> Result<Vec<usize>, TreeError> {
         let n = self.get_leaf_count();
-        if !self.nodes[x].is_blank() {
-            return vec![x];
+        let node = self.node(x)?;
+        if !node.is_blank() {
+            let mut resolution = vec![x];
+            resolution.extend(node.unmerged_leaves.iter().map(|l| *l as usize * 2));
+            return Ok(resolution);
         }
 
         if treemath::level(x) == 0 {
-            return vec![];
+            return Ok(vec![]);
         }
 
-        let mut left = self.resolve(treemath::left(x));
-        let right = self.resolve(treemath::right(x, n));
+        let mut left = self.resolve(treemath::left(x))?;
+        let right = self.resolve(treemath::right(x, n))?;
         left.extend(right);
-        left
+        Ok(left)
     }
 
-    pub fn blank_up(&mut self, x: usize) {
+    // The inverse of the resolution: the leaves below x whose members hold the private key
+    // of x. That is every occupied leaf in the subtree except the ones still unmerged at x.
+    // Nobody holds the key of a blank node.
+    pub fn authorized_leaves(&self, x: usize) -> Result<Vec<usize>, TreeError> {
+        let node = self.node(x)?;
+        if node.is_blank() {
+            return Ok(Vec::new());
+        }
+        let mut authorized = Vec::new();
+        for leaf in treemath::leaves_in_subtree(x, self.get_leaf_count()) {
+            if !self.is_blank_at(leaf)? && !node.unmerged_leaves.contains(&(leaf as u32 / 2)) {
+                authorized.push(leaf);
+            }
+        }
+        Ok(authorized)
+    }
+
+    // The smallest blank leaf index, or the leaf count if every leaf is occupied. It only
+    // depends on which leaves are blank, never on keys or on the order of earlier
+    // operations.
+    pub fn find_free_leaf(&self) -> usize {
+        self.nodes
+            .iter()
+            .step_by(2)
+            .position(Node::is_blank)
+            .unwrap_or_else(|| self.get_leaf_count())
+    }
+
+    // Puts the node at the leaf chosen by position, growing the tree if there is no free
+    // leaf, and returns the leaf index. The ancestors keep their keys and list the leaf
+    // as unmerged until they are re-keyed.
+    // The leaf is a pure function of the committed tree and the position, so replicas
+    // applying the same commit to the same tree place every Add identically.
+    pub fn add_leaf(&mut self, node: Node, position: AddPosition) -> Result<usize, TreeError> {
+        let size = self.get_leaf_count();
+        let leaf = match position {
+            AddPosition::LeftmostBlank => self.find_free_leaf(),
+            AddPosition::Append => size,
+        };
+        if leaf == size {
+            self.nodes
+                .resize(treemath::node_width(size + 1), Node::new_blank());
+        }
+        let index = leaf * 2;
         let n = self.get_leaf_count();
-        self.nodes[x].blank();
+        let mut ancestors = treemath::dirpath(index, n);
+        ancestors.push(treemath::root(n));
+        for x in ancestors.into_iter().filter(|x| *x != index) {
+            if !self.is_blank_at(x)? {
+                self.node_mut(x)?.unmerged_leaves.push(leaf as u32);
+            }
+        }
+        *self.node_mut(index)? = node;
+        Ok(leaf)
+    }
+
+    // For each parent on the direct path of the leaf, the nodes its path secret is
+    // encrypted to: the resolution of the copath node below it
+    pub fn path_secret_recipients(&self, from_leaf: usize) -> Result<Vec<Vec<usize>>, TreeError> {
+        self.node(from_leaf * 2)?;
+        treemath::copath(from_leaf * 2, self.get_leaf_count())
+            .into_iter()
+            .map(|x| self.resolve(x))
+            .collect()
+    }
+
+    // Size of an update path from the leaf before anything is encrypted: the number of path
+    // nodes, and the number of ciphertexts over all of them
+    pub fn update_path_cost(&self, from_leaf: usize) -> Result<(usize, usize), TreeError> {
+        let recipients = self.path_secret_recipients(from_leaf)?;
+        let ciphertexts = recipients.iter().map(|resolution| resolution.len()).sum();
+        Ok((recipients.len(), ciphertexts))
+    }
+
+    // Opens the path secret my_private can decrypt in an update path sent by from_leaf and
+    // ratchets it up to the root, checking every derived public key against the one the
+    // sender put in the path. Returns the root secret.
+    pub fn verify_update_path(
+        &self,
+        from_leaf: usize,
+        update_path: &UpdatePath,
+        my_leaf: usize,
+        my_private: &X25519PrivateKey,
+    ) -> Result<Vec<u8>, TreeError> {
+        let size = self.get_leaf_count();
+        if !treemath::in_range(from_leaf * 2, size)
+            || !treemath::in_range(my_leaf * 2, size)
+            || from_leaf == my_leaf
+        {
+            return Err(TreeError::InvalidUpdatePath);
+        }
+        let copath = treemath::copath(from_leaf * 2, size);
+        if update_path.nodes.len() != copath.len() {
+            return Err(TreeError::InvalidUpdatePath);
+        }
+        let position = match treemath::is_in_copath_subtree(my_leaf * 2, from_leaf * 2, size) {
+            Some(node) => copath.iter().position(|&x| x == node).unwrap(),
+            None => return Err(TreeError::InvalidUpdatePath),
+        };
+        let path_secret = match update_path.nodes[position]
+            .encrypted_path_secret
+            .iter()
+            .find_map(|ciphertext| HpkeCiphertext::decrypt(my_private, ciphertext).ok())
+        {
+            Some(path_secret) => path_secret,
+            None => return Err(TreeError::InvalidUpdatePath),
+        };
+        if path_secret.len() != NODESECRETBYTES {
+            return Err(TreeError::InvalidUpdatePath);
+        }
+
+        let mut parents = treemath::dirpath(from_leaf * 2, size);
+        parents.push(treemath::root(size));
+        let path_secrets = derive_path_secrets(path_secret, parents[position + 1], size);
+        for ((_, secret), node) in path_secrets
+            .iter()
+            .zip(update_path.nodes[position..].iter())
+        {
+            if path_node_keypair(secret).0 != node.public_key {
+                return Err(TreeError::PublicKeyMismatch);
+            }
+        }
+        Ok(path_secrets.last().unwrap().1.clone())
+    }
+
+    // Number of leaves left once trailing blank leaves are dropped
+    pub fn truncated_size(&self) -> usize {
+        self.nodes
+            .iter()
+            .step_by(2)
+            .rposition(|node| !node.is_blank())
+            .map_or(1, |leaf| leaf + 1)
+    }
+
+    pub fn truncate(&mut self) {
+        let n = self.truncated_size();
+        self.nodes.truncate(treemath::node_width(n));
+    }
+
+    // Leaf index, node index and node of every non-blank leaf, in leaf order. The nodes are
+    // borrowed from the tree, nothing is copied.
+    pub fn occupied_leaves<'a>(&'a self) -> impl Iterator<Item = (usize, usize, &'a Node)> + 'a {
+        self.nodes
+            .iter()
+            .enumerate()
+            .step_by(2)
+            .filter(|(_, node)| !node.is_blank())
+            .map(|(x, node)| (x / 2, x, node))
+    }
+
+    // Graphviz rendering for debugging, with leaves labeled by the start of their key
+    pub fn to_dot(&self) -> String {
+        self.to_dot_with_labels(&[])
+    }
+
+    // Same, with labels for the leaves by leaf index. Blank nodes are dashed, the own leaf
+    // is doubly outlined, and parents list their unmerged leaves.
+    pub fn to_dot_with_labels(&self, leaf_labels: &[String]) -> String {
+        let n = self.get_leaf_count();
+        let mut dot = String::from("digraph tree {\n    node [shape=box];\n");
+        for (x, node) in self.nodes.iter().enumerate() {
+            let level = treemath::level(x);
+            let mut label = format!("{} (level {})", x, level);
+            if level == 0 {
+                let leaf_label = match (leaf_labels.get(x / 2), node.dh_public_key) {
+                    (Some(leaf_label), _) if !node.is_blank() => {
+                        leaf_label.replace('\\', "\\\\").replace('"', "\\\"")
+                    }
+                    (_, Some(public_key)) => bytes_to_hex(&public_key.to_slice()[..4]),
+                    _ => String::new(),
+                };
+                if !leaf_label.is_empty() {
+                    label.push_str(&format!("\\n{}", leaf_label));
+                }
+            } else if !node.unmerged_leaves.is_empty() {
+                label.push_str(&format!("\\nunmerged {:?}", node.unmerged_leaves));
+            }
+            let style = if node.is_blank() {
+                ", style=dashed, color=gray"
+            } else if x == self.own_leaf_index {
+                ", peripheries=2"
+            } else {
+                ""
+            };
+            dot.push_str(&format!("    n{} [label=\"{}\"{}];\n", x, label, style));
+        }
+        for x in (0..self.nodes.len()).filter(|x| treemath::level(*x) > 0) {
+            dot.push_str(&format!("    n{} -> n{};\n", x, treemath::left(x)));
+            dot.push_str(&format!("    n{} -> n{};\n", x, treemath::right(x, n)));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // Diagnostics: blanks make update paths and welcomes larger
+
+    pub fn blank_node_indices(&self) -> Vec<usize> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.is_blank())
+            .map(|(x, _)| x)
+            .collect()
+    }
+
+    // Occupied leaves over all leaves
+    pub fn occupancy_ratio(&self) -> f64 {
+        let occupied = self
+            .nodes
+            .iter()
+            .step_by(2)
+            .filter(|node| !node.is_blank())
+            .count();
+        occupied as f64 / self.get_leaf_count() as f64
+    }
+
+    // The smaller leaf count truncate() would shrink the tree to, if any
+    pub fn suggest_truncation(&self) -> Option<usize> {
+        let n = self.truncated_size();
+        if n < self.get_leaf_count() {
+            Some(n)
+        } else {
+            None
+        }
+    }
+
+    // Same outcome as blank_up() on the leaf followed by truncate(), without touching the
+    // tree. The leaf is a leaf index.
+    pub fn preview_remove(&self, leaf: usize) -> Result<RemovePreview, TreeError> {
+        let n = self.get_leaf_count();
+        if !treemath::in_range(leaf * 2, n) {
+            return Err(TreeError::IndexOutOfRange);
+        }
+        let mut blanked = treemath::dirpath(leaf * 2, n);
+        blanked.push(treemath::root(n));
+        let size = self
+            .nodes
+            .iter()
+            .step_by(2)
+            .enumerate()
+            .rposition(|(other, node)| other != leaf && !node.is_blank())
+            .map_or(1, |other| other + 1);
+        Ok(RemovePreview {
+            blanked,
+            truncated_size: if size < n { Some(size) } else { None },
+        })
+    }
+
+    // Nodes that differ in other, with their new content or None if they are blank or
+    // outside of other. Trees of different sizes are compared over the larger width.
+    pub fn diff(&self, other: &Tree) -> Vec<(usize, Option<Node>)> {
+        let n = self.get_leaf_count().max(other.get_leaf_count());
+        let mut changes = Vec::new();
+        for index in 0..treemath::node_width(n) {
+            let before = self.nodes.get(index).filter(|node| !node.is_blank());
+            let after = other.nodes.get(index).filter(|node| !node.is_blank());
+            if before != after {
+                changes.push((index, after.cloned()));
+            }
+        }
+        changes
+    }
+
+    // Hash over the public part of the tree, computed recursively from the root
+    pub fn tree_hash(&self) -> Vec<u8> {
+        #[cfg(feature = "parallel")]
+        return self.tree_hash_parallel();
+        #[cfg(not(feature = "parallel"))]
+        return self.tree_hash_serial();
+    }
+
+    pub fn tree_hash_serial(&self) -> Vec<u8> {
+        self.node_hash(treemath::root(self.get_leaf_count()))
+    }
+
+    // Same result as tree_hash_serial(), sibling subtrees are hashed in parallel
+    #[cfg(feature = "parallel")]
+    pub fn tree_hash_parallel(&self) -> Vec<u8> {
+        self.par_node_hash(treemath::root(self.get_leaf_count()))
+    }
+
+    #[cfg(feature = "parallel")]
+    fn par_node_hash(&self, x: usize) -> Vec<u8> {
+        // Smaller subtrees aren't worth the scheduling
+        if treemath::level(x) <= PARALLEL_HASH_MIN_LEVEL {
+            return self.node_hash(x);
+        }
+        let n = self.get_leaf_count();
+        let (left_hash, right_hash) = rayon::join(
+            || self.par_node_hash(treemath::left(x)),
+            || self.par_node_hash(treemath::right(x, n)),
+        );
+        self.parent_hash(x, left_hash, right_hash)
+    }
+
+    // Public view of a node, None if it is blank
+    pub fn tree_node(&self, x: usize) -> Option<TreeNode> {
+        let node = self.node(x).ok()?;
+        let public_key = node.dh_public_key?;
+        if treemath::level(x) == 0 {
+            Some(TreeNode::Leaf(LeafNode { public_key }))
+        } else {
+            Some(TreeNode::Parent(ParentNode {
+                public_key,
+                unmerged_leaves: node.unmerged_leaves.clone(),
+            }))
+        }
+    }
+
+    fn node_hash(&self, x: usize) -> Vec<u8> {
+        if treemath::level(x) == 0 {
+            let buffer = LeafNodeHashInput {
+                node_index: x as u32,
+                leaf_key: self
+                    .tree_node(x)
+                    .and_then(|node| node.as_leaf().map(|leaf| leaf.public_key)),
+            }
+            .encode_detached();
+            return self.hash_algorithm.hash(&buffer);
+        }
+        let n = self.get_leaf_count();
+        let left_hash = self.node_hash(treemath::left(x));
+        let right_hash = self.node_hash(treemath::right(x, n));
+        self.parent_hash(x, left_hash, right_hash)
+    }
+
+    fn parent_hash(&self, x: usize, left_hash: Vec<u8>, right_hash: Vec<u8>) -> Vec<u8> {
+        let buffer = ParentNodeHashInput {
+            node_index: x as u32,
+            parent_node: self.tree_node(x).and_then(|node| node.as_parent().cloned()),
+            left_hash,
+            right_hash,
+        }
+        .encode_detached();
+        self.hash_algorithm.hash(&buffer)
+    }
+
+    pub fn blank_up(&mut self, x: usize) -> Result<(), TreeError> {
+        let n = self.get_leaf_count();
+        self.node_mut(x)?.blank();
         if x != treemath::root(n) {
-            self.blank_up(treemath::parent(x, n));
+            self.blank_up(treemath::parent(x, n))?;
         }
+        Ok(())
     }
 
-    pub fn merge(&mut self, path: Vec<usize>, nodes: &[Node]) {
-        assert_eq!(path.len(), nodes.len());
+    // Grows the tree to cover the path if needed
+    pub fn merge(&mut self, path: Vec<usize>, nodes: &[Node]) -> Result<(), TreeError> {
+        if path.len() != nodes.len() {
+            return Err(TreeError::InvalidUpdatePath);
+        }
         let mut max: usize = 0;
         for n in path.iter() {
             if *n > max {
@@ -304,35 +1108,174 @@ impl Tree {
             }
         }
         if max >= self.nodes.len() {
-            self.nodes.resize(max + 1, Node::new_blank());
+            self.nodes
+                .resize(treemath::node_width(max / 2 + 1), Node::new_blank());
         }
         for (node, index) in nodes.iter().zip(path) {
-            self.nodes[index] = node.clone();
+            *self.node_mut(index)? = node.clone();
         }
+        Ok(())
     }
 
-    pub fn hash_up(index: usize, size: usize, secret: &NodeSecret) -> Vec<Node> {
-        // Compute hashes up the tree
-        let mut nodes = Vec::new();
-        let mut node_secret = *secret;
-        let mut dirpath = treemath::dirpath(index, size);
-        dirpath.push(treemath::root(size));
-        for _ in dirpath {
-            let node = Node::from_secret(&node_secret);
-            nodes.push(node);
-            node_secret.hash();
+    // Installs the public keys of an update path sent by from_leaf on its direct path.
+    // The updated nodes lose their old key material and unmerged leaves and are chained
+    // with parent hashes from the root down. The copath is left as it is.
+    pub fn merge_update_path(
+        &mut self,
+        from_leaf: usize,
+        update_path: &UpdatePath,
+    ) -> Result<(), TreeError> {
+        let size = self.get_leaf_count();
+        if !treemath::in_range(from_leaf * 2, size)
+            || update_path.nodes.len() != treemath::copath(from_leaf * 2, size).len()
+        {
+            return Err(TreeError::InvalidUpdatePath);
+        }
+        let mut path = treemath::dirpath(from_leaf * 2, size);
+        path.push(treemath::root(size));
+        let mut public_keys = vec![update_path.leaf_key];
+        public_keys.extend(update_path.nodes.iter().map(|node| node.public_key));
+
+        let mut parent_hash = Vec::new();
+        for (x, public_key) in path.iter().zip(public_keys.iter()).rev() {
+            let mut node = Node::new_from_public_key(public_key);
+            node.parent_hash = parent_hash;
+            *self.node_mut(*x)? = node;
+            parent_hash = self.chained_hash(*x).unwrap();
         }
-        nodes
+        Ok(())
     }
 
-    pub fn kem_to(dirpath_nodes: &mut [Node], copath_nodes: &mut [Node]) -> Vec<HpkeCiphertext> {
+    // The parent hash the children of x carry, None if x is blank
+    fn chained_hash(&self, x: usize) -> Option<Vec<u8>> {
+        let node = self.node(x).ok()?;
+        Some(
+            self.hash_algorithm.hash(
+                &ParentHashInput {
+                    public_key: node.dh_public_key?,
+                    parent_hash: node.parent_hash.clone(),
+                }
+                .encode_detached(),
+            ),
+        )
+    }
+
+    // A parent whose children carry parent hashes has to be covered by one of them. The
+    // other child keeps the hash of an older key if the parent was re-keyed from its side.
+    // Blank parents cover nothing.
+    pub fn verify_parent_hashes(&self) -> bool {
+        let n = self.get_leaf_count();
+        treemath::postorder_internal(n).all(|(x, l, r)| {
+            let (left, right) = match (self.node(l), self.node(r)) {
+                (Ok(left), Ok(right)) => (&left.parent_hash, &right.parent_hash),
+                _ => return false,
+            };
+            if left.is_empty() && right.is_empty() {
+                return true;
+            }
+            match self.chained_hash(x) {
+                Some(hash) => *left == hash || *right == hash,
+                None => true,
+            }
+        })
+    }
+
+    // Accepts a full public tree handed over by another member only if it is consistent
+    // as a whole: leaves carry no private material or unmerged leaves, the unmerged
+    // leaves of parents are below them, no encryption key appears twice, the parent
+    // hashes chain and the tree hash is the one of the group context. The roster must
+    // hold a credential the validator accepts for exactly the occupied leaves, and the
+    // own leaf must be one of them. Leaves carry no signature of their own, the roster
+    // and tree are covered by the signature of the GroupInfo they came in.
+    pub fn import_and_validate(
+        nodes: Vec<Node>,
+        own_leaf_index: usize,
+        roster: &[Option<BasicCredential>],
+        validator: &dyn CredentialValidator,
+        group_context: &GroupContext,
+    ) -> Result<Tree, TreeError> {
+        let mut tree = Tree::import_and_validate_public(nodes, roster, validator, group_context)?;
+        if own_leaf_index % 2 == 1 || tree.is_blank_at(own_leaf_index).unwrap_or(true) {
+            return Err(TreeError::InvalidLeaf);
+        }
+        tree.own_leaf_index = own_leaf_index;
+        Ok(tree)
+    }
+
+    // The same checks for a party that is not in the tree yet, like one joining by
+    // external commit. The own leaf index is left at 0.
+    pub fn import_and_validate_public(
+        nodes: Vec<Node>,
+        roster: &[Option<BasicCredential>],
+        validator: &dyn CredentialValidator,
+        group_context: &GroupContext,
+    ) -> Result<Tree, TreeError> {
+        let leaf_count = check_tree_size(nodes.len()).map_err(|_| TreeError::InvalidTreeSize)?;
+        let hash_algorithm = HashAlgorithm::from_cipher_suite(group_context.cipher_suite)
+            .ok_or(TreeError::UnsupportedCipherSuite)?;
+        let mut tree = Tree {
+            nodes,
+            own_leaf_index: 0,
+            hash_algorithm,
+        };
+        if roster.len() != leaf_count {
+            return Err(TreeError::InvalidLeaf);
+        }
+        for (leaf, credential) in roster.iter().enumerate() {
+            match credential {
+                Some(_) if tree.is_blank_at(leaf * 2)? => return Err(TreeError::InvalidLeaf),
+                Some(credential) => validator
+                    .validate(credential, group_context)
+                    .map_err(TreeError::InvalidCredential)?,
+                None if !tree.is_blank_at(leaf * 2)? => return Err(TreeError::InvalidLeaf),
+                None => (),
+            }
+        }
+        let mut encryption_keys = HashSet::new();
+        for (x, node) in tree.nodes.iter().enumerate() {
+            if node.secret.is_some() || node.dh_private_key.is_some() {
+                return Err(TreeError::InvalidLeaf);
+            }
+            if treemath::level(x) == 0 && !node.unmerged_leaves.is_empty() {
+                return Err(TreeError::InvalidLeaf);
+            }
+            if let Some(public_key) = node.dh_public_key {
+                if !encryption_keys.insert(public_key) {
+                    return Err(TreeError::DuplicateKey);
+                }
+            }
+        }
+        let unmerged = tree.get_unmerged_leaves();
+        tree.set_unmerged_leaves(&unmerged)?;
+        if !tree.verify_parent_hashes() {
+            return Err(TreeError::InvalidParentHash);
+        }
+        if tree.tree_hash() != group_context.tree_hash {
+            return Err(TreeError::TreeHashMismatch);
+        }
+        Ok(tree)
+    }
+
+    pub fn hash_up(index: usize, size: usize, secret: &NodeSecret) -> Vec<Node> {
+        derive_path_secrets(secret.0.to_vec(), index, size)
+            .iter()
+            .map(|(_, path_secret)| Node::from_secret(&NodeSecret::from_bytes(path_secret)))
+            .collect()
+    }
+
+    pub fn kem_to(
+        dirpath_nodes: &mut [Node],
+        copath_nodes: &mut [Node],
+        rng: &mut dyn Rng,
+    ) -> Vec<HpkeCiphertext> {
         let mut path: Vec<HpkeCiphertext> = Vec::new();
         assert_eq!(dirpath_nodes.len(), copath_nodes.len());
         for node_pair in dirpath_nodes.iter_mut().zip(copath_nodes.iter_mut()) {
             let (dirpath_node, copath_node) = node_pair;
             let public_key = copath_node.dh_public_key.unwrap();
             let node_secret = &dirpath_node.secret.unwrap().0[..];
-            let ciphertext = HpkeCiphertext::encrypt(&public_key, node_secret).unwrap();
+            let ciphertext =
+                HpkeCiphertext::encrypt_with_rng(&public_key, node_secret, rng).unwrap();
             path.push(ciphertext);
         }
         path
@@ -343,14 +1286,17 @@ impl Tree {
         index: usize,
         size: usize,
         secret: NodeSecret,
+        rng: &mut dyn Rng,
     ) -> (Vec<X25519PublicKey>, Vec<HpkeCiphertext>) {
         let node_secret = secret;
         let mut nodes = Tree::hash_up(index, size, &node_secret);
-        let mut copath_nodes = self.get_nodes_from_path(treemath::copath(index, size));
+        let mut copath_nodes = self
+            .get_nodes_from_path(treemath::copath(index, size))
+            .unwrap();
         // strip leaf
         let leaf_node = nodes.remove(0);
         assert_eq!(copath_nodes.len(), nodes.len());
-        let ciphertexts = Tree::kem_to(&mut nodes, &mut copath_nodes);
+        let ciphertexts = Tree::kem_to(&mut nodes, &mut copath_nodes, rng);
         let mut public_keys: Vec<X25519PublicKey> = Vec::new();
         public_keys.push(leaf_node.dh_public_key.unwrap());
         for node in nodes {
@@ -361,39 +1307,48 @@ impl Tree {
         (public_keys, ciphertexts)
     }
 
+    // Opens the path secret sent to the node where the kem path meets the own direct
+    // path. Paths from another member may be malformed, so nothing here panics on them.
     pub fn decrypt(
         &self,
         size: usize,
         kem_path: &[usize],
         ciphertexts: &[HpkeCiphertext],
-    ) -> (Vec<usize>, Vec<Node>) {
+    ) -> Result<(Vec<usize>, Vec<Node>), TreeError> {
+        if kem_path.len() != ciphertexts.len() {
+            return Err(TreeError::InvalidUpdatePath);
+        }
         let own_path = treemath::dirpath(self.own_leaf_index, size);
-        let mut own_path_index = 0;
-        let mut kem_path_index = 0;
+        let mut intersection = None;
         for (opi, op_element) in own_path.iter().enumerate() {
             for (kpi, kp_element) in kem_path.iter().enumerate() {
                 if op_element == kp_element {
-                    own_path_index = opi;
-                    kem_path_index = kpi;
+                    intersection = Some((opi, kpi));
                 }
             }
         }
+        let (own_path_index, kem_path_index) = intersection.ok_or(TreeError::InvalidUpdatePath)?;
         let mut merge_path = treemath::dirpath(treemath::parent(self.own_leaf_index, size), size);
         merge_path.push(treemath::root(size));
         merge_path.drain(0..own_path_index);
-        let intersect_ciphertext = ciphertexts[kem_path_index].clone();
-        let intersect_node = self.nodes[own_path[own_path_index]].clone();
-        let private_key = intersect_node.dh_private_key.unwrap();
-        let secret = HpkeCiphertext::decrypt(&private_key, &intersect_ciphertext).unwrap();
+        let private_key = match self.node(own_path[own_path_index])?.dh_private_key {
+            Some(ref private_key) => private_key,
+            None => return Err(TreeError::InvalidUpdatePath),
+        };
+        let secret = HpkeCiphertext::decrypt(private_key, &ciphertexts[kem_path_index])
+            .map_err(|_| TreeError::InvalidUpdatePath)?;
+        if secret.len() != NODESECRETBYTES {
+            return Err(TreeError::InvalidUpdatePath);
+        }
         let node_secret = NodeSecret::from_bytes(secret.as_slice());
-        (
+        Ok((
             merge_path,
             Tree::hash_up(
                 treemath::parent(own_path[own_path_index], size),
                 size,
                 &node_secret,
             ),
-        )
+        ))
     }
 
     pub fn apply_kem_path(
@@ -403,15 +1358,19 @@ impl Tree {
         kem_path: &[usize],
         ciphertexts: &[HpkeCiphertext],
         public_keys: &[X25519PublicKey],
-    ) {
+    ) -> Result<(), TreeError> {
         let public_merge_path = treemath::dirpath(index, size);
+        if public_keys.len() != public_merge_path.len() {
+            return Err(TreeError::InvalidUpdatePath);
+        }
+        // Decrypting first leaves the tree untouched if the path is rejected
+        let (merge_path, nodes) = self.decrypt(size, &kem_path, ciphertexts)?;
         let mut public_nodes = Vec::new();
         for key in public_keys.iter() {
             public_nodes.push(Node::new_from_public_key(key));
         }
-        self.merge(public_merge_path, &public_nodes);
-        let (merge_path, nodes) = self.decrypt(size, &kem_path, ciphertexts);
-        self.merge(merge_path, &nodes);
+        self.merge(public_merge_path, &public_nodes)?;
+        self.merge(merge_path, &nodes)
     }
 }
 
@@ -453,13 +1412,14 @@ fn verify_binary_test_vector_resolution() {
         let tree = Tree {
             nodes,
             own_leaf_index: 0,
+            hash_algorithm: HashAlgorithm::default(),
         };
 
         let mut resolution_case_cursor = Cursor::new(&resolution_case);
         for node_index in 0..number_of_nodes {
             let test_resolution: Vec<u8> = decode_vec_u8(&mut resolution_case_cursor).unwrap();
 
-            let actual_resolution = tree.resolve(node_index);
+            let actual_resolution = tree.resolve(node_index).unwrap();
 
             assert_eq!(test_resolution.len(), actual_resolution.len());
 
@@ -475,3 +1435,877 @@ fn verify_binary_test_vector_resolution() {
     assert_eq!(cases_cursor.has_more(), false);
     assert_eq!(cursor.has_more(), false);
 }
+
+#[test]
+fn tree_from_leaves() {
+    assert_eq!(Tree::from_leaves(vec![]).err(), Some(TreeError::NoLeaves));
+
+    let leaves: Vec<Node> = (0..5)
+        .map(|_| Node::from_secret(&NodeSecret::new_random()))
+        .collect();
+    let tree = Tree::from_leaves(leaves.clone()).unwrap();
+    assert_eq!(tree.get_tree_size(), treemath::node_width(5));
+    assert_eq!(tree.get_leaf_count(), 5);
+    for (leaf, index) in leaves.iter().zip(treemath::leaves(5)) {
+        assert_eq!(&tree.nodes[index], leaf);
+    }
+    for index in (1..tree.get_tree_size()).step_by(2) {
+        assert!(tree.nodes[index].is_blank());
+    }
+
+    // The tree hash only covers public keys and changes with any of them
+    let mut public_tree = tree.clone();
+    for node in public_tree.nodes.iter_mut() {
+        node.secret = None;
+        node.dh_private_key = None;
+    }
+    assert_eq!(tree.tree_hash(), public_tree.tree_hash());
+    public_tree.nodes[8] = Node::from_secret(&NodeSecret::new_random());
+    assert_ne!(tree.tree_hash(), public_tree.tree_hash());
+}
+
+#[test]
+fn path_secret_chain() {
+    // Alice sends a path from the first leaf of an 8 leaf tree
+    let path_secrets = derive_path_secrets(NodeSecret::new_random().0.to_vec(), 0, 8);
+    let path: Vec<usize> = path_secrets.iter().map(|(index, _)| *index).collect();
+    assert_eq!(path, vec![0, 1, 3, 7]);
+    let root_secret = path_secrets[3].1.clone();
+
+    // Members below different copath nodes each get the secret of their intersection
+    for (index, path_secret) in path_secrets.iter().skip(1) {
+        let chain = derive_path_secrets(path_secret.clone(), *index, 8);
+        assert_eq!(chain.last().unwrap(), &(7, root_secret.clone()));
+    }
+
+    let (public_key, private_key) = path_node_keypair(&root_secret);
+    let node = Node::from_secret(&NodeSecret::from_bytes(&root_secret));
+    assert_eq!(node.dh_public_key, Some(public_key));
+    assert_eq!(node.dh_private_key, Some(private_key));
+}
+
+#[test]
+fn tree_diff() {
+    let leaves: Vec<Node> = (0..5)
+        .map(|_| Node::from_secret(&NodeSecret::new_random()))
+        .collect();
+    let tree = Tree::from_leaves(leaves).unwrap();
+    assert!(tree.diff(&tree).is_empty());
+
+    // Leaf 1 sends a path
+    let mut updated = tree.clone();
+    let mut path = treemath::dirpath(2, 5);
+    path.push(treemath::root(5));
+    updated
+        .merge(
+            path.clone(),
+            &Tree::hash_up(2, 5, &NodeSecret::new_random()),
+        )
+        .unwrap();
+    let diff = updated.diff(&tree);
+    let indices: Vec<usize> = diff.iter().map(|(index, _)| *index).collect();
+    path.sort();
+    assert_eq!(indices, path);
+    assert_eq!(diff[0].1, None);
+    assert_eq!(diff[1].1, Some(tree.nodes[2].clone()));
+
+    // Growing the tree
+    let mut grown = tree.clone();
+    grown
+        .nodes
+        .resize(treemath::node_width(6), Node::new_blank());
+    grown.nodes[10] = Node::from_secret(&NodeSecret::new_random());
+    let diff = tree.diff(&grown);
+    assert_eq!(diff.len(), 1);
+    assert_eq!(diff[0], (10, Some(grown.nodes[10].clone())));
+    assert_eq!(grown.diff(&tree), vec![(10, None)]);
+}
+
+#[test]
+fn add_leaf_positions() {
+    let leaves: Vec<Node> = (0..3)
+        .map(|_| Node::from_secret(&NodeSecret::new_random()))
+        .collect();
+    let mut tree = Tree::from_leaves(leaves).unwrap();
+    assert_eq!(tree.find_free_leaf(), 3);
+
+    // Fully occupied, so both policies grow the tree
+    let node = Node::from_secret(&NodeSecret::new_random());
+    assert_eq!(
+        tree.add_leaf(node.clone(), AddPosition::LeftmostBlank)
+            .unwrap(),
+        3
+    );
+    assert_eq!(tree.get_leaf_count(), 4);
+
+    // A freed middle leaf is reused without growing the tree
+    tree.blank_up(2).unwrap();
+    assert_eq!(tree.find_free_leaf(), 1);
+    assert_eq!(
+        tree.add_leaf(node.clone(), AddPosition::LeftmostBlank)
+            .unwrap(),
+        1
+    );
+    assert_eq!(tree.get_leaf_count(), 4);
+    assert_eq!(tree.nodes[2], node);
+    assert_eq!(tree.find_free_leaf(), 4);
+
+    // Append ignores the blank leaf
+    tree.blank_up(2).unwrap();
+    assert_eq!(tree.add_leaf(node.clone(), AddPosition::Append).unwrap(), 4);
+    assert_eq!(tree.get_leaf_count(), 5);
+    assert_eq!(tree.find_free_leaf(), 1);
+}
+
+#[test]
+fn add_leaf_convergence() {
+    // Two replicas with different keys but the same blank leaves
+    let replica = || {
+        let leaves: Vec<Node> = (0..7)
+            .map(|_| Node::from_secret(&NodeSecret::new_random()))
+            .collect();
+        let mut tree = Tree::from_leaves(leaves).unwrap();
+        for leaf in &[5, 2, 4] {
+            tree.blank_up(leaf * 2).unwrap();
+        }
+        tree
+    };
+    let mut first = replica();
+    let mut second = replica();
+    for expected in &[2, 4, 5, 7, 8] {
+        assert_eq!(first.find_free_leaf(), second.find_free_leaf());
+        let node = Node::from_secret(&NodeSecret::new_random());
+        let leaf = first
+            .add_leaf(node.clone(), AddPosition::LeftmostBlank)
+            .unwrap();
+        assert_eq!(
+            second.add_leaf(node, AddPosition::LeftmostBlank).unwrap(),
+            leaf
+        );
+        assert_eq!(leaf, *expected);
+    }
+    assert_eq!(first.get_leaf_count(), second.get_leaf_count());
+}
+
+#[test]
+fn unmerged_leaves_on_add() {
+    let leaves: Vec<Node> = (0..4)
+        .map(|_| Node::from_secret(&NodeSecret::new_random()))
+        .collect();
+    let mut tree = Tree::from_leaves(leaves).unwrap();
+    let secret = NodeSecret::new_random();
+    tree.merge(vec![1, 3], &Tree::hash_up(1, 4, &secret))
+        .unwrap();
+    tree.nodes[5] = Node::from_secret(&NodeSecret::new_random());
+
+    // Leaf 2 is freed and taken by a new member
+    tree.nodes[4].blank();
+    let node = Node::from_secret(&NodeSecret::new_random());
+    assert_eq!(tree.add_leaf(node, AddPosition::LeftmostBlank).unwrap(), 2);
+    assert_eq!(tree.nodes[5].unmerged_leaves, vec![2]);
+    assert_eq!(tree.nodes[3].unmerged_leaves, vec![2]);
+    assert!(tree.nodes[1].unmerged_leaves.is_empty());
+    assert_eq!(tree.resolve(5).unwrap(), vec![5, 4]);
+    assert_eq!(tree.resolve(3).unwrap(), vec![3, 4]);
+
+    // The same information survives the trip to a new member
+    let unmerged = tree.get_unmerged_leaves();
+    assert_eq!(unmerged.len(), 2);
+    let mut copy = tree.clone();
+    for node in copy.nodes.iter_mut() {
+        node.unmerged_leaves.clear();
+    }
+    copy.set_unmerged_leaves(&unmerged).unwrap();
+    assert_eq!(copy.get_unmerged_leaves(), unmerged);
+    let misplaced = UnmergedLeaves {
+        node: 1,
+        leaves: vec![2],
+    };
+    assert_eq!(
+        copy.set_unmerged_leaves(&[misplaced]),
+        Err(TreeError::InvalidUnmergedLeaves)
+    );
+    let out_of_range = UnmergedLeaves {
+        node: 5,
+        leaves: vec![100],
+    };
+    assert_eq!(
+        copy.set_unmerged_leaves(&[out_of_range]),
+        Err(TreeError::InvalidUnmergedLeaves)
+    );
+
+    // Re-keying the root clears it, node 5 stays unmerged
+    tree.merge(vec![3], &Tree::hash_up(3, 4, &secret)).unwrap();
+    assert!(tree.nodes[3].unmerged_leaves.is_empty());
+    assert_eq!(tree.nodes[5].unmerged_leaves, vec![2]);
+    assert_eq!(tree.resolve(3).unwrap(), vec![3]);
+}
+
+#[test]
+fn tree_hash_with_blanks() {
+    use utils::*;
+
+    // Leaf 1 and its parent are blank, node 5 has leaf 3 unmerged
+    let key = |b: u8| Some(X25519PublicKey::from_slice(&[b; 32]).unwrap());
+    let mut tree = Tree::from_leaves(vec![Node::new_blank(); 4]).unwrap();
+    for (index, public_key) in [(0, 0xa0), (3, 0x03), (4, 0xc0), (5, 0x05), (6, 0xd0)].iter() {
+        tree.nodes[*index].dh_public_key = key(*public_key);
+    }
+    tree.nodes[5].unmerged_leaves = vec![3];
+
+    // The framing written out by hand
+    let leaf = |index: u8, public_key: Option<u8>| {
+        let mut buffer = vec![0, 0, 0, index];
+        match public_key {
+            Some(b) => {
+                buffer.extend_from_slice(&[1, 0, 32]);
+                buffer.extend_from_slice(&[b; 32]);
+            }
+            None => buffer.push(0),
+        }
+        hash(&buffer).0.to_vec()
+    };
+    let parent = |index: u8, public_key: Option<(u8, &[u8])>, left: &[u8], right: &[u8]| {
+        let mut buffer = vec![0, 0, 0, index];
+        match public_key {
+            Some((b, unmerged)) => {
+                buffer.extend_from_slice(&[1, 0, 32]);
+                buffer.extend_from_slice(&[b; 32]);
+                buffer.extend_from_slice(&[0, 0, 0, unmerged.len() as u8 * 4]);
+                for leaf in unmerged {
+                    buffer.extend_from_slice(&[0, 0, 0, *leaf]);
+                }
+            }
+            None => buffer.push(0),
+        }
+        buffer.push(32);
+        buffer.extend_from_slice(left);
+        buffer.push(32);
+        buffer.extend_from_slice(right);
+        hash(&buffer).0.to_vec()
+    };
+    let node1 = parent(1, None, &leaf(0, Some(0xa0)), &leaf(2, None));
+    let node5 = parent(
+        5,
+        Some((0x05, &[3])),
+        &leaf(4, Some(0xc0)),
+        &leaf(6, Some(0xd0)),
+    );
+    let root = parent(3, Some((0x03, &[])), &node1, &node5);
+    assert_eq!(tree.tree_hash(), root);
+    assert_eq!(
+        bytes_to_hex(&tree.tree_hash()),
+        "6874438B8BEC880C44DD76A15469E8D6D66A3B3321F0EC44A35E737C19287B3A"
+    );
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_tree_hash() {
+    use sodiumoxide::randombytes;
+
+    let leaves: Vec<Node> = (0..2000)
+        .map(|_| {
+            Node::new_from_public_key(
+                &X25519PublicKey::from_slice(&randombytes::randombytes(32)).unwrap(),
+            )
+        })
+        .collect();
+    let mut tree = Tree::from_leaves(leaves).unwrap();
+    for x in (1..tree.get_tree_size()).step_by(4) {
+        tree.nodes[x] = Node::new_from_public_key(
+            &X25519PublicKey::from_slice(&randombytes::randombytes(32)).unwrap(),
+        );
+    }
+    tree.nodes[42].blank();
+    assert_eq!(tree.tree_hash_parallel(), tree.tree_hash_serial());
+    assert_eq!(tree.tree_hash(), tree.tree_hash_serial());
+
+    // Small trees take the serial path
+    let tree = Tree::from_leaves(vec![Node::new_blank(); 3]).unwrap();
+    assert_eq!(tree.tree_hash_parallel(), tree.tree_hash_serial());
+}
+
+#[test]
+fn path_secret_recipients() {
+    let leaves: Vec<Node> = (0..4)
+        .map(|_| Node::from_secret(&NodeSecret::new_random()))
+        .collect();
+    let mut tree = Tree::from_leaves(leaves).unwrap();
+    tree.nodes[5] = Node::from_secret(&NodeSecret::new_random());
+    tree.nodes[2].blank();
+
+    // Leaf 0: its blank sibling has no recipients, node 5 covers both leaves below it
+    assert_eq!(
+        tree.path_secret_recipients(0).unwrap(),
+        vec![vec![], vec![5]]
+    );
+    // Leaf 3: the blank parent 1 expands to the remaining leaf below it
+    assert_eq!(
+        tree.path_secret_recipients(3).unwrap(),
+        vec![vec![4], vec![0]]
+    );
+    tree.nodes[5].blank();
+    assert_eq!(
+        tree.path_secret_recipients(0).unwrap(),
+        vec![vec![], vec![4, 6]]
+    );
+}
+
+#[test]
+fn verify_update_path() {
+    use group::encrypt_path_secrets;
+
+    let secrets: Vec<NodeSecret> = (0..4).map(|_| NodeSecret::new_random()).collect();
+    let tree = Tree::from_leaves(secrets.iter().map(Node::from_secret).collect()).unwrap();
+
+    let leaf_secret = NodeSecret::new_random();
+    let path_nodes = Tree::hash_up(0, 4, &leaf_secret);
+    let path_secrets: Vec<NodeSecret> = path_nodes
+        .iter()
+        .skip(1)
+        .map(|node| node.secret.unwrap())
+        .collect();
+    let update_path = UpdatePath {
+        leaf_key: path_nodes[0].dh_public_key.unwrap(),
+        nodes: encrypt_path_secrets(&tree, 0, &path_secrets, &[], &mut SystemRng).unwrap(),
+    };
+    let root_secret = path_secrets.last().unwrap().0.to_vec();
+
+    // Leaf 1 decrypts at node 1, leaf 3 at the root
+    for leaf in 1..4 {
+        let private_key = tree.nodes[leaf * 2].dh_private_key.clone().unwrap();
+        assert_eq!(
+            tree.verify_update_path(0, &update_path, leaf, &private_key),
+            Ok(root_secret.clone())
+        );
+    }
+
+    let private_key = tree.nodes[6].dh_private_key.clone().unwrap();
+    let mut tampered = update_path.clone();
+    tampered.nodes[1].public_key = X25519KeyPair::new_random().public_key;
+    assert_eq!(
+        tree.verify_update_path(0, &tampered, 3, &private_key),
+        Err(TreeError::PublicKeyMismatch)
+    );
+    // A key that doesn't match its path secret, above the decrypted node
+    let private_key = tree.nodes[2].dh_private_key.clone().unwrap();
+    assert_eq!(
+        tree.verify_update_path(0, &tampered, 1, &private_key),
+        Err(TreeError::PublicKeyMismatch)
+    );
+    let mut truncated = update_path.clone();
+    truncated.nodes.pop();
+    assert_eq!(
+        tree.verify_update_path(0, &truncated, 1, &private_key),
+        Err(TreeError::InvalidUpdatePath)
+    );
+    // Not our key
+    let private_key = X25519KeyPair::new_random().private_key;
+    assert_eq!(
+        tree.verify_update_path(0, &update_path, 1, &private_key),
+        Err(TreeError::InvalidUpdatePath)
+    );
+}
+
+#[test]
+fn tree_nodes() {
+    let leaf_key = X25519KeyPair::new_random().public_key;
+    let parent_key = X25519KeyPair::new_random().public_key;
+    let leaf = TreeNode::Leaf(LeafNode {
+        public_key: leaf_key,
+    });
+    let parent = TreeNode::Parent(ParentNode {
+        public_key: parent_key,
+        unmerged_leaves: vec![2],
+    });
+    for node in [&leaf, &parent].iter() {
+        assert_eq!(
+            TreeNode::decode_detached(&node.encode_detached()).unwrap(),
+            **node
+        );
+    }
+    assert_eq!(leaf.as_leaf().unwrap().public_key, leaf_key);
+    assert!(leaf.as_parent().is_none());
+    assert_eq!(parent.as_parent().unwrap().unmerged_leaves, vec![2]);
+    assert!(parent.as_leaf().is_none());
+    assert!(TreeNode::decode_detached(&[3]).is_err());
+
+    // The position in the tree decides the kind
+    let mut tree = Tree::from_leaves(vec![
+        Node::new_from_public_key(&leaf_key),
+        Node::new_from_public_key(&X25519KeyPair::new_random().public_key),
+    ])
+    .unwrap();
+    assert_eq!(tree.tree_node(1), None);
+    tree.nodes[1] = Node::new_from_public_key(&parent_key);
+    tree.nodes[1].unmerged_leaves = vec![2];
+    assert_eq!(tree.tree_node(0), Some(leaf));
+    assert_eq!(tree.tree_node(1), Some(parent));
+}
+
+#[test]
+fn tree_health() {
+    let leaves: Vec<Node> = (0..6)
+        .map(|_| Node::from_secret(&NodeSecret::new_random()))
+        .collect();
+    let mut tree = Tree::from_leaves(leaves).unwrap();
+    tree.nodes[3] = Node::from_secret(&NodeSecret::new_random());
+    assert_eq!(tree.occupancy_ratio(), 1.0);
+    assert_eq!(tree.suggest_truncation(), None);
+
+    tree.nodes[2].blank();
+    tree.nodes[8].blank();
+    tree.nodes[10].blank();
+    assert_eq!(tree.blank_node_indices(), vec![1, 2, 5, 7, 8, 9, 10]);
+    assert_eq!(tree.occupancy_ratio(), 0.5);
+    assert_eq!(tree.suggest_truncation(), Some(4));
+
+    // Read-only, truncate() does the shrinking
+    assert_eq!(tree.get_leaf_count(), 6);
+    tree.truncate();
+    assert_eq!(tree.get_leaf_count(), 4);
+    assert_eq!(tree.suggest_truncation(), None);
+}
+
+#[test]
+fn preview_remove() {
+    let leaves: Vec<Node> = (0..5)
+        .map(|_| Node::from_secret(&NodeSecret::new_random()))
+        .collect();
+    let mut tree = Tree::from_leaves(leaves).unwrap();
+    tree.nodes[6].blank();
+
+    // Leaf 1 is in the middle, nothing is cut off
+    assert_eq!(
+        tree.preview_remove(1),
+        Ok(RemovePreview {
+            blanked: vec![2, 1, 3, 7],
+            truncated_size: None,
+        })
+    );
+    // Leaf 4 is the last one and leaf 3 is blank already, so two leaves go
+    let preview = tree.preview_remove(4).unwrap();
+    assert_eq!(preview.blanked, vec![8, 7]);
+    assert_eq!(preview.truncated_size, Some(3));
+    assert_eq!(tree.get_leaf_count(), 5);
+
+    for leaf in 0..5 {
+        let preview = tree.preview_remove(leaf).unwrap();
+        let mut removed = tree.clone();
+        removed.blank_up(leaf * 2).unwrap();
+        assert!(preview.blanked.iter().all(|x| removed.nodes[*x].is_blank()));
+        removed.truncate();
+        assert_eq!(
+            preview.truncated_size.unwrap_or(5),
+            removed.get_leaf_count()
+        );
+    }
+
+    // Same as blank_up() on a node outside the tree
+    assert_eq!(tree.preview_remove(5), Err(TreeError::IndexOutOfRange));
+    assert_eq!(tree.preview_remove(100), Err(TreeError::IndexOutOfRange));
+}
+
+#[test]
+fn merge_update_path() {
+    use messages::UpdatePathNode;
+
+    let key = |b: u8| X25519PublicKey::from_slice(&[b; 32]).unwrap();
+    let mut tree = Tree::from_leaves(vec![Node::new_blank(); 4]).unwrap();
+    for x in 0..7 {
+        tree.nodes[x] = Node::new_from_public_key(&key(x as u8));
+    }
+    tree.nodes[5].unmerged_leaves = vec![3];
+    let before = tree.clone();
+
+    // Leaf 2 re-keys nodes 4, 5 and 3
+    let update_path = UpdatePath {
+        leaf_key: key(0x40),
+        nodes: vec![0x50, 0x30]
+            .into_iter()
+            .map(|b| UpdatePathNode {
+                public_key: key(b),
+                encrypted_path_secret: Vec::new(),
+            })
+            .collect(),
+    };
+    let mut expected = before.clone();
+    expected.nodes[4] = Node::new_from_public_key(&key(0x40));
+    expected.nodes[5] = Node::new_from_public_key(&key(0x50));
+    expected.nodes[3] = Node::new_from_public_key(&key(0x30));
+
+    tree.merge_update_path(2, &update_path).unwrap();
+    assert_eq!(tree.tree_hash(), expected.tree_hash());
+    assert!(tree.nodes[5].unmerged_leaves.is_empty());
+    for x in [0, 1, 2, 6].iter() {
+        assert_eq!(tree.nodes[*x], before.nodes[*x]);
+    }
+
+    // Each parent hash covers the key and parent hash of the node above
+    assert!(tree.nodes[3].parent_hash.is_empty());
+    let parent_hash = |x: usize| {
+        hash(
+            &ParentHashInput {
+                public_key: tree.nodes[x].dh_public_key.unwrap(),
+                parent_hash: tree.nodes[x].parent_hash.clone(),
+            }
+            .encode_detached(),
+        )
+        .0
+        .to_vec()
+    };
+    assert_eq!(tree.nodes[5].parent_hash, parent_hash(3));
+    assert_eq!(tree.nodes[4].parent_hash, parent_hash(5));
+
+    let mut short = update_path.clone();
+    short.nodes.pop();
+    assert_eq!(
+        tree.merge_update_path(2, &short),
+        Err(TreeError::InvalidUpdatePath)
+    );
+}
+
+#[test]
+fn inconsistent_tree_size() {
+    let mut tree = Tree::from_leaves(vec![Node::new_blank(); 4]).unwrap();
+    tree.own_leaf_index = 6;
+    assert!(Tree::decode_detached(&tree.encode_detached()).is_ok());
+    let mut parent = tree.clone();
+    parent.own_leaf_index = 3;
+    match Tree::decode_detached(&parent.encode_detached()) {
+        Err(CodecError::InconsistentTreeSize) => (),
+        _ => panic!("own leaf on a parent node accepted"),
+    }
+
+    let mut short = tree.clone();
+    short.nodes.pop();
+    match Tree::decode_detached(&short.encode_detached()) {
+        Err(CodecError::InconsistentTreeSize) => (),
+        _ => panic!("tree one node short accepted"),
+    }
+    // The own leaf has to be in the tree
+    short.nodes.pop();
+    match Tree::decode_detached(&short.encode_detached()) {
+        Err(CodecError::InconsistentTreeSize) => (),
+        _ => panic!("own leaf outside of the tree accepted"),
+    }
+}
+
+#[test]
+fn tree_hash_algorithms() {
+    let leaves: Vec<Node> = (0..5u8)
+        .map(|b| Node::new_from_public_key(&X25519PublicKey::from_slice(&[b; 32]).unwrap()))
+        .collect();
+    let mut tree = Tree::from_leaves(leaves).unwrap();
+    let sha256 = tree.tree_hash();
+    assert_eq!(sha256.len(), HashAlgorithm::Sha256.output_len());
+    tree.hash_algorithm = HashAlgorithm::Sha512;
+    let sha512 = tree.tree_hash();
+    assert_eq!(sha512.len(), HashAlgorithm::Sha512.output_len());
+    assert_eq!(tree.clone().tree_hash(), sha512);
+    assert_eq!(tree.tree_hash_serial(), sha512);
+    tree.hash_algorithm = HashAlgorithm::Sha256;
+    assert_eq!(tree.tree_hash(), sha256);
+}
+
+#[test]
+fn public_tree() {
+    let mut tree = Tree::from_leaves(
+        (0..4)
+            .map(|_| Node::from_secret(&NodeSecret::new_random()))
+            .collect(),
+    )
+    .unwrap();
+    let mut path = treemath::dirpath(2, 4);
+    path.push(treemath::root(4));
+    tree.merge(path, &Tree::hash_up(2, 4, &NodeSecret::new_random()))
+        .unwrap();
+    tree.nodes[5] = Node::from_secret(&NodeSecret::new_random());
+    tree.nodes[5].unmerged_leaves = vec![3];
+    // A node that only holds its secret so far
+    tree.nodes[3].secret = Some(NodeSecret::new_random());
+    tree.nodes[3].dh_public_key = None;
+    tree.nodes[3].dh_private_key = None;
+
+    let mut private_material = Vec::new();
+    for node in tree.clone().nodes.iter_mut() {
+        if let Some(secret) = node.secret {
+            private_material.push(secret.0.to_vec());
+        }
+        if let Some(private_key) = node.get_private_key() {
+            let encoded = private_key.encode_detached();
+            private_material.push(encoded[encoded.len() - 32..].to_vec());
+        }
+    }
+    assert_eq!(private_material.len(), 2 * 7);
+
+    let public = tree.public_tree();
+    assert!(public
+        .nodes
+        .iter()
+        .all(|node| node.secret.is_none() && node.dh_private_key.is_none()));
+    assert!(public.nodes.iter().all(|node| node.dh_public_key.is_some()));
+    assert_eq!(public.nodes[5].unmerged_leaves, vec![3]);
+    let encoded = public.encode_detached();
+    for bytes in private_material.iter() {
+        assert!(!encoded
+            .windows(bytes.len())
+            .any(|window| window == &bytes[..]));
+    }
+    assert_eq!(public.tree_hash(), tree.public_tree().tree_hash());
+    tree.nodes[3].get_public_key();
+    assert_eq!(public.tree_hash(), tree.tree_hash());
+}
+
+#[test]
+fn update_path_cost() {
+    let leaves: Vec<Node> = (0..8)
+        .map(|_| Node::from_secret(&NodeSecret::new_random()))
+        .collect();
+    let mut tree = Tree::from_leaves(leaves).unwrap();
+    // Only the leaves have keys, every resolution ends up at the leaves
+    assert_eq!(tree.update_path_cost(0).unwrap(), (3, 7));
+    tree.nodes[11] = Node::from_secret(&NodeSecret::new_random());
+    tree.nodes[11].unmerged_leaves = vec![7];
+    tree.nodes[12].blank();
+    // Leaf 0 encrypts to leaf 1, leaves 2 and 3, and node 11 plus unmerged leaf 7
+    assert_eq!(
+        tree.path_secret_recipients(0).unwrap(),
+        vec![vec![2], vec![4, 6], vec![11, 14]]
+    );
+    let (path_length, ciphertexts) = tree.update_path_cost(0).unwrap();
+    assert_eq!((path_length, ciphertexts), (3, 5));
+    assert!(ciphertexts > path_length);
+    // Leaf 7 has a blank sibling, which takes no ciphertext
+    assert_eq!(tree.update_path_cost(7).unwrap(), (3, 6));
+}
+
+#[test]
+fn import_and_validate() {
+    use credential::BasicCredentialValidator;
+    use group::GroupId;
+    use messages::UpdatePathNode;
+
+    let key = || {
+        Node::from_secret(&NodeSecret::new_random())
+            .dh_public_key
+            .unwrap()
+    };
+    let mut tree = Tree::from_leaves(
+        (0..4)
+            .map(|_| Node::from_secret(&NodeSecret::new_random()))
+            .collect(),
+    )
+    .unwrap();
+    let update_path = UpdatePath {
+        leaf_key: key(),
+        nodes: (0..2)
+            .map(|_| UpdatePathNode {
+                public_key: key(),
+                encrypted_path_secret: Vec::new(),
+            })
+            .collect(),
+    };
+    tree.merge_update_path(2, &update_path).unwrap();
+    let nodes = tree.public_tree().nodes;
+    let context = GroupContext {
+        version: CURRENT_VERSION,
+        cipher_suite: AES128GCM_CURVE25519_SHA256,
+        group_id: GroupId::random(),
+        epoch: 1,
+        tree_hash: tree.tree_hash(),
+        roster_hash: Vec::new(),
+        confirmed_transcript_hash: Vec::new(),
+        extensions: Vec::new(),
+    };
+    let roster: Vec<_> = (0..4)
+        .map(|_| {
+            Some(BasicCredential {
+                identity: vec![],
+                public_key: Identity::random().public_key,
+            })
+        })
+        .collect();
+    let import = |nodes: Vec<Node>, context: &GroupContext| {
+        Tree::import_and_validate(nodes, 2, &roster, &BasicCredentialValidator, context)
+    };
+    let imported = import(nodes.clone(), &context).unwrap();
+    assert_eq!(imported.tree_hash(), tree.tree_hash());
+    assert_eq!(imported.get_own_leaf_index(), 2);
+
+    // The tree hash doesn't cover parent hashes, only their own check catches this
+    let mut bad_parent_hash = nodes.clone();
+    bad_parent_hash[5].parent_hash[0] ^= 0x01;
+    assert_eq!(
+        import(bad_parent_hash, &context).err(),
+        Some(TreeError::InvalidParentHash)
+    );
+
+    let mut duplicate = nodes.clone();
+    duplicate[6] = duplicate[0].clone();
+    assert_eq!(
+        import(duplicate, &context).err(),
+        Some(TreeError::DuplicateKey)
+    );
+    let mut private = nodes.clone();
+    private[0] = tree.nodes[0].clone();
+    assert_eq!(
+        import(private, &context).err(),
+        Some(TreeError::InvalidLeaf)
+    );
+    let mut unmerged = nodes.clone();
+    unmerged[1].unmerged_leaves = vec![3];
+    assert_eq!(
+        import(unmerged, &context).err(),
+        Some(TreeError::InvalidUnmergedLeaves)
+    );
+    let mut other_context = context.clone();
+    other_context.tree_hash = vec![0u8; 32];
+    assert_eq!(
+        import(nodes.clone(), &other_context).err(),
+        Some(TreeError::TreeHashMismatch)
+    );
+    assert_eq!(
+        import(nodes[..6].to_vec(), &context).err(),
+        Some(TreeError::InvalidTreeSize)
+    );
+
+    // The roster has to match the occupied leaves, and the own leaf has to be one
+    let mut blanked = nodes.clone();
+    blanked[6] = Node::new_blank();
+    assert_eq!(
+        import(blanked, &context).err(),
+        Some(TreeError::InvalidLeaf)
+    );
+    for own_leaf_index in [1, 8].iter() {
+        assert_eq!(
+            Tree::import_and_validate(
+                nodes.clone(),
+                *own_leaf_index,
+                &roster,
+                &BasicCredentialValidator,
+                &context
+            )
+            .err(),
+            Some(TreeError::InvalidLeaf)
+        );
+    }
+    assert_eq!(
+        Tree::import_and_validate(
+            nodes.clone(),
+            2,
+            &roster[..3],
+            &BasicCredentialValidator,
+            &context
+        )
+        .err(),
+        Some(TreeError::InvalidLeaf)
+    );
+
+    // Every credential goes through the validator
+    struct RejectAll;
+    impl CredentialValidator for RejectAll {
+        fn validate(&self, _: &BasicCredential, _: &GroupContext) -> Result<(), CredentialError> {
+            Err(CredentialError::Rejected)
+        }
+    }
+    assert_eq!(
+        Tree::import_and_validate(nodes.clone(), 2, &roster, &RejectAll, &context).err(),
+        Some(TreeError::InvalidCredential(CredentialError::Rejected))
+    );
+}
+
+#[test]
+fn occupied_leaves() {
+    let mut tree = Tree::from_leaves(
+        (0..6)
+            .map(|_| Node::from_secret(&NodeSecret::new_random()))
+            .collect(),
+    )
+    .unwrap();
+    tree.blank_up(2).unwrap();
+    tree.blank_up(6).unwrap();
+    let occupied: Vec<(usize, usize, &Node)> = tree.occupied_leaves().collect();
+    let expected: Vec<(usize, usize)> = vec![(0, 0), (2, 4), (4, 8), (5, 10)];
+    assert_eq!(
+        occupied
+            .iter()
+            .map(|(leaf, x, _)| (*leaf, *x))
+            .collect::<Vec<_>>(),
+        expected
+    );
+    for (_, x, node) in occupied {
+        assert!(std::ptr::eq(node, &tree.nodes[x]));
+    }
+    tree.blank_up(10).unwrap();
+    assert_eq!(
+        tree.occupied_leaves().last().map(|(leaf, _, _)| leaf),
+        Some(4)
+    );
+}
+
+#[test]
+fn to_dot() {
+    let mut tree = Tree::from_leaves(
+        (0..5)
+            .map(|_| Node::from_secret(&NodeSecret::new_random()))
+            .collect(),
+    )
+    .unwrap();
+    tree.blank_up(4).unwrap();
+    tree.nodes[3] = Node::from_secret(&NodeSecret::new_random());
+    tree.nodes[3].unmerged_leaves = vec![1];
+    let n = tree.get_leaf_count();
+    let dot = tree.to_dot();
+    assert!(dot.starts_with("digraph tree {") && dot.ends_with("}\n"));
+    let nodes = dot.lines().filter(|line| line.contains("[label=")).count();
+    assert_eq!(nodes, treemath::node_width(n));
+    let edges = dot.lines().filter(|line| line.contains("->")).count();
+    assert_eq!(edges, 2 * (treemath::node_width(n) - n));
+    let blanks = dot.lines().filter(|line| line.contains("dashed")).count();
+    assert_eq!(blanks, tree.blank_node_indices().len());
+    assert!(dot.contains("n4 [label=\"4 (level 0)\", style=dashed"));
+    assert!(dot.contains("n0 [label=\"0 (level 0)\\n"));
+    assert!(dot.contains("peripheries=2"));
+    assert!(dot.contains("unmerged [1]"));
+    assert!(dot.contains("n3 -> n1;") && dot.contains("n7 -> n8;"));
+
+    let labels: Vec<String> = vec!["Alice".to_string(), "\"Bob\"".to_string()];
+    let dot = tree.to_dot_with_labels(&labels);
+    assert!(dot.contains("0 (level 0)\\nAlice"));
+    assert!(dot.contains("2 (level 0)\\n\\\"Bob\\\""));
+}
+
+#[test]
+fn node_bounds() {
+    let mut tree = Tree::from_leaves(
+        (0..5)
+            .map(|_| Node::from_secret(&NodeSecret::new_random()))
+            .collect(),
+    )
+    .unwrap();
+    let width = treemath::node_width(5);
+    assert!(tree.node(width - 1).is_ok());
+    assert_eq!(tree.node(width).err(), Some(TreeError::IndexOutOfRange));
+    assert_eq!(
+        tree.node_mut(width + 7).err(),
+        Some(TreeError::IndexOutOfRange)
+    );
+    assert!(tree.tree_node(width).is_none());
+    assert_eq!(tree.resolve(width).err(), Some(TreeError::IndexOutOfRange));
+    assert_eq!(
+        tree.authorized_leaves(width).err(),
+        Some(TreeError::IndexOutOfRange)
+    );
+    assert_eq!(tree.blank_up(width).err(), Some(TreeError::IndexOutOfRange));
+    assert_eq!(
+        tree.is_blank_at(width).err(),
+        Some(TreeError::IndexOutOfRange)
+    );
+    assert_eq!(
+        tree.path_secret_recipients(5).err(),
+        Some(TreeError::IndexOutOfRange)
+    );
+    assert_eq!(tree.get_tree_size(), width);
+}
@@ -14,9 +14,18 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
-use codec::*;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(not(feature = "std"))]
+use core::fmt;
+#[cfg(not(feature = "std"))]
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::fmt;
+#[cfg(feature = "std")]
 use std::ops::Range;
 
+// Floor of the binary logarithm. log2(0) is 0, like log2(1).
 pub fn log2(n: usize) -> usize {
     let mut r = 0;
     let mut m = n;
@@ -27,13 +36,19 @@ pub fn log2(n: usize) -> usize {
     r
 }
 
-pub fn pow2(n: usize) -> usize {
-    match n {
-        0 => 1,
-        _ => 2 << (n - 1),
+// None if 2^n doesn't fit in a usize
+pub fn checked_pow2(n: usize) -> Option<usize> {
+    if n < usize::BITS as usize {
+        Some(1 << n)
+    } else {
+        None
     }
 }
 
+pub fn pow2(n: usize) -> usize {
+    checked_pow2(n).expect("power of two out of range")
+}
+
 pub fn level(n: usize) -> usize {
     if (n & 0x01) == 0 {
         return 0;
@@ -45,19 +60,36 @@ pub fn level(n: usize) -> usize {
     k
 }
 
+// Panics instead of wrapping when n is 0 or too large for the tree to be addressable
 pub fn node_width(n: usize) -> usize {
-    2 * (n - 1) + 1
+    n.checked_sub(1)
+        .and_then(|m| m.checked_mul(2))
+        .and_then(|m| m.checked_add(1))
+        .expect("tree size out of range")
+}
+
+// Inverse of node_width(), None if no tree has w nodes
+pub fn node_width_to_leaf_count(w: usize) -> Option<usize> {
+    if w % 2 == 1 {
+        Some(w / 2 + 1)
+    } else {
+        None
+    }
+}
+
+pub fn in_range(x: usize, n: usize) -> bool {
+    x < node_width(n)
 }
 
 pub fn assert_in_range(x: usize, n: usize) {
-    if x > node_width(n) {
-        panic!("node index out of range ({} > {})", x, n);
+    if !in_range(x, n) {
+        panic!("node index out of range ({} >= {})", x, node_width(n));
     }
 }
 
 pub fn root(n: usize) -> usize {
     let w = node_width(n);
-    (1 << log2(w)) - 1
+    pow2(log2(w)) - 1
 }
 
 pub fn left(x: usize) -> usize {
@@ -79,6 +111,10 @@ pub fn right(x: usize, n: usize) -> usize {
     r
 }
 
+pub fn rightmost_leaf(n: usize) -> usize {
+    node_width(n) - 1
+}
+
 pub fn parent_step(x: usize) -> usize {
     let k = level(x);
     (x | (1 << k)) & !(1 << (k + 1))
@@ -138,216 +174,395 @@ pub fn dirpath(x: usize, n: usize) -> Vec<usize> {
     dirpath
 }
 
+// Same as dirpath(x, n).len(). In a full tree that is the level difference to the root,
+// a ragged tree skips the ancestors that fall outside of it.
+pub fn dirpath_len(x: usize, n: usize) -> usize {
+    assert_in_range(x, n);
+    let root_level = level(root(n));
+    if x == root(n) {
+        return 0;
+    }
+    let width = node_width(n);
+    let ancestors = (level(x) + 1..root_level)
+        .filter(|&k| ((x >> (k + 1)) << (k + 1)) | ((1 << k) - 1) < width)
+        .count();
+    1 + ancestors
+}
+
 // Ordered from leaf to root
 pub fn copath(x: usize, n: usize) -> Vec<usize> {
     dirpath(x, n).iter().map(|&x| sibling(x, n)).collect()
 }
 
+// The node on the copath of sender whose subtree holds x, which is the one the path
+// secret for x is encrypted to. None when x is the sender itself or above it.
+pub fn is_in_copath_subtree(x: usize, sender: usize, n: usize) -> Option<usize> {
+    assert_in_range(x, n);
+    copath(sender, n).into_iter().find(|&c| {
+        let span = (1 << level(c)) - 1;
+        c - span <= x && x <= c + span
+    })
+}
+
 pub fn leaves(n: usize) -> Vec<usize> {
     Range { start: 0, end: n }.map(|x| 2 * x).collect()
 }
 
-#[derive(Clone, Copy)]
-pub enum FunctionType {
-    OneArg(fn(usize) -> usize),
-    TwoArgs(fn(usize, usize) -> usize),
-    TwoArgsPath(fn(usize, usize) -> Vec<usize>),
+// Leaf node indices below x, cut off at the right edge of a tree with n leaves
+pub fn leaves_in_subtree(x: usize, n: usize) -> Vec<usize> {
+    assert_in_range(x, n);
+    let span = (1 << level(x)) - 1;
+    let end = (x + span).min(node_width(n) - 1);
+    (x - span..=end).step_by(2).collect()
 }
 
-pub enum ReturnType {
-    Primitive(Vec<usize>),
-    Vector(Vec<Vec<usize>>),
+// Number of nodes below x, x included, cut off at the right edge like leaves_in_subtree
+pub fn subtree_node_count(x: usize, n: usize) -> usize {
+    assert_in_range(x, n);
+    let span = (1 << level(x)) - 1;
+    let end = (x + span).min(node_width(n) - 1);
+    end + 1 - (x - span)
 }
 
-pub fn gen_vector(range_start: usize, range_end: usize, size: usize, ft: FunctionType) -> Vec<u8> {
-    let range = Range {
-        start: range_start,
-        end: range_end,
-    };
-    let mut test_vector: Vec<u32> = Vec::new();
-    let mut test_vector_2d: Vec<Vec<u32>> = Vec::new();
-    for i in range {
-        match ft {
-            FunctionType::OneArg(f) => {
-                test_vector.push(f(i) as u32);
-            }
-            FunctionType::TwoArgs(f) => {
-                test_vector.push(f(i, size) as u32);
-            }
-            FunctionType::TwoArgsPath(f) => {
-                let sub_vector_usize = f(i, size);
-                let mut sub_vector_u32 = Vec::new();
-                sub_vector_usize
-                    .iter()
-                    .for_each(|&x| sub_vector_u32.push(x as u32));
-                test_vector_2d.push(sub_vector_u32);
-            }
+// Nodes from the first leaf of [leaf_start, leaf_end) up to the parent right after its
+// last leaf, so that shards over consecutive leaf ranges get disjoint node ranges that
+// together cover the tree. Only a parent whose leaves all fall in the shard has its
+// whole subtree there. The others, like the parent at the end of each range and the
+// ancestors above several shards, are shared and have to be handled once every shard
+// below them is done.
+pub fn leaf_range_nodes(leaf_start: usize, leaf_end: usize, n: usize) -> Range<usize> {
+    assert!(
+        leaf_start <= leaf_end && leaf_end <= n,
+        "leaf range out of bounds"
+    );
+    Range {
+        start: 2 * leaf_start,
+        end: (2 * leaf_end).min(node_width(n)),
+    }
+}
+
+// Every parent node as (node, left child, right child), children before their parents.
+// On the ragged edge the right child is the one right() walks down to.
+pub fn postorder_internal(n: usize) -> impl Iterator<Item = (usize, usize, usize)> {
+    let mut nodes = Vec::new();
+    postorder_walk(root(n), n, &mut nodes);
+    nodes.into_iter()
+}
+
+fn postorder_walk(x: usize, n: usize, nodes: &mut Vec<(usize, usize, usize)>) {
+    if level(x) == 0 {
+        return;
+    }
+    let (l, r) = (left(x), right(x, n));
+    postorder_walk(l, n, nodes);
+    postorder_walk(r, n, nodes);
+    nodes.push((x, l, r));
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TreeMathError {
+    OutOfRange,
+}
+
+impl fmt::Display for TreeMathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TreeMathError::OutOfRange => write!(f, "node index out of range"),
         }
     }
+}
 
-    let mut buffer = Vec::new();
+#[cfg(feature = "std")]
+impl ::std::error::Error for TreeMathError {}
 
-    match ft {
-        FunctionType::OneArg(_) => {
-            encode_vec_u32(&mut buffer, &test_vector);
+// Node storage sized for n leaves, with the index math attached
+#[derive(Clone, Debug, PartialEq)]
+pub struct BinaryTree<T> {
+    pub nodes: Vec<Option<T>>,
+    leaf_count: usize,
+}
+
+impl<T> BinaryTree<T> {
+    pub fn new(n: usize) -> BinaryTree<T> {
+        assert!(n > 0, "a tree needs at least one leaf");
+        BinaryTree {
+            nodes: (0..node_width(n)).map(|_| None).collect(),
+            leaf_count: n,
         }
-        FunctionType::TwoArgs(_) => {
-            encode_vec_u32(&mut buffer, &test_vector);
+    }
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
+    pub fn root(&self) -> usize {
+        root(self.leaf_count)
+    }
+    fn check(&self, x: usize) -> Result<usize, TreeMathError> {
+        if in_range(x, self.leaf_count) {
+            Ok(x)
+        } else {
+            Err(TreeMathError::OutOfRange)
         }
-        FunctionType::TwoArgsPath(_) => {
-            for e in test_vector_2d.iter_mut() {
-                (e.len() as u32).encode(&mut buffer);
-                encode_vec_u32(&mut buffer, e);
-            }
+    }
+    pub fn node(&self, x: usize) -> Result<Option<&T>, TreeMathError> {
+        self.check(x).map(|x| self.nodes[x].as_ref())
+    }
+    pub fn set_node(&mut self, x: usize, value: Option<T>) -> Result<(), TreeMathError> {
+        let x = self.check(x)?;
+        self.nodes[x] = value;
+        Ok(())
+    }
+    // i is a leaf index, not a node index
+    pub fn leaf(&self, i: usize) -> Result<Option<&T>, TreeMathError> {
+        if i >= self.leaf_count {
+            return Err(TreeMathError::OutOfRange);
         }
+        self.node(2 * i)
+    }
+    pub fn parent_of(&self, x: usize) -> Result<usize, TreeMathError> {
+        self.check(x).map(|x| parent(x, self.leaf_count))
+    }
+    pub fn sibling_of(&self, x: usize) -> Result<usize, TreeMathError> {
+        self.check(x).map(|x| sibling(x, self.leaf_count))
+    }
+    pub fn direct_path(&self, x: usize) -> Result<Vec<usize>, TreeMathError> {
+        self.check(x).map(|x| dirpath(x, self.leaf_count))
+    }
+    pub fn copath(&self, x: usize) -> Result<Vec<usize>, TreeMathError> {
+        self.check(x).map(|x| copath(x, self.leaf_count))
     }
-    buffer
 }
 
-pub fn read_vector(rt: &ReturnType, buffer: &[u8]) -> ReturnType {
-    let mut vector = Vec::new();
-    let mut vector2d = Vec::new();
-    let mut cursor = Cursor::new(buffer);
+#[test]
+fn binary_tree_wrappers() {
+    for n in 1..20 {
+        let mut tree: BinaryTree<u32> = BinaryTree::new(n);
+        assert_eq!(tree.nodes.len(), node_width(n));
+        assert_eq!(tree.root(), root(n));
+        for x in 0..node_width(n) {
+            assert_eq!(tree.parent_of(x), Ok(parent(x, n)));
+            assert_eq!(tree.sibling_of(x), Ok(sibling(x, n)));
+            assert_eq!(tree.direct_path(x), Ok(dirpath(x, n)));
+            assert_eq!(tree.copath(x), Ok(copath(x, n)));
+        }
+        let w = node_width(n);
+        assert_eq!(tree.parent_of(w), Err(TreeMathError::OutOfRange));
+        assert_eq!(tree.sibling_of(w), Err(TreeMathError::OutOfRange));
+        assert_eq!(tree.direct_path(w), Err(TreeMathError::OutOfRange));
+        assert_eq!(tree.copath(w), Err(TreeMathError::OutOfRange));
+        assert_eq!(tree.leaf(n), Err(TreeMathError::OutOfRange));
+        assert_eq!(tree.set_node(w, Some(0)), Err(TreeMathError::OutOfRange));
+
+        tree.set_node(2 * (n - 1), Some(7)).unwrap();
+        assert_eq!(tree.leaf(n - 1), Ok(Some(&7)));
+        assert_eq!(tree.leaf(0).unwrap().is_some(), n == 1);
+    }
+}
+
+#[test]
+fn subtree_leaves() {
+    for n in 1..20 {
+        assert_eq!(leaves_in_subtree(root(n), n), leaves(n));
+        for leaf in leaves(n) {
+            assert_eq!(leaves_in_subtree(leaf, n), vec![leaf]);
+        }
+    }
+    assert_eq!(leaves_in_subtree(5, 4), vec![4, 6]);
+    assert_eq!(leaves_in_subtree(11, 8), vec![8, 10, 12, 14]);
+    // Node 11 of a tree with 7 leaves is cut off at the ragged edge
+    assert_eq!(leaves_in_subtree(11, 7), vec![8, 10, 12]);
+    assert_eq!(leaves_in_subtree(9, 6), vec![8, 10]);
+}
 
-    match *rt {
-        ReturnType::Primitive(_) => {
-            let vector_usize: Vec<u32> = decode_vec_u32(&mut cursor).unwrap();
-            vector_usize.iter().for_each(|&x| vector.push(x as usize));
-            ReturnType::Primitive(vector)
+#[test]
+fn copath_subtrees() {
+    // Senders 0 and 3 of a tree with 4 leaves
+    let expected = [
+        (0, [None, Some(2), Some(5), Some(5)]),
+        (6, [Some(1), Some(1), Some(4), None]),
+    ];
+    for (sender, nodes) in expected.iter() {
+        for (leaf, node) in leaves(4).into_iter().zip(nodes.iter()) {
+            assert_eq!(is_in_copath_subtree(leaf, *sender, 4), *node);
         }
-        ReturnType::Vector(_) => {
-            let size = u32::decode(&mut cursor).unwrap();
-            //let size = cursor.take(1).unwrap()[0];
-            for _ in 0..size {
-                let mut sub_vector = Vec::new();
-                let sub_vector_usize: Vec<u32> = decode_vec_u32(&mut cursor).unwrap();
-                sub_vector_usize
-                    .iter()
-                    .for_each(|&x| sub_vector.push(x as usize));
-                vector2d.push(sub_vector);
+    }
+    for n in 1..20 {
+        for sender in leaves(n) {
+            for leaf in leaves(n) {
+                match is_in_copath_subtree(leaf, sender, n) {
+                    Some(c) => {
+                        assert!(copath(sender, n).contains(&c));
+                        assert!(leaves_in_subtree(c, n).contains(&leaf));
+                    }
+                    None => assert_eq!(leaf, sender),
+                }
             }
-            ReturnType::Vector(vector2d)
         }
     }
 }
 
 #[test]
-fn print_test_vectors() {
-    use utils::*;
-
-    let size = 255;
-    println!(
-        "Test vector for root() with size {}:\n{}",
-        size,
-        bytes_to_hex(&gen_vector(1, size, size, FunctionType::OneArg(root),))
-    );
-    println!(
-        "Test vector for level() with size {}:\n{}",
-        size,
-        bytes_to_hex(&gen_vector(0, size - 1, size, FunctionType::OneArg(level),))
-    );
-    println!(
-        "Test vector for node_width() with size {}:\n{}",
-        size,
-        bytes_to_hex(&gen_vector(1, size, size, FunctionType::OneArg(node_width),))
-    );
-    println!(
-        "Test vector for left() with size {}:\n{}",
-        size,
-        bytes_to_hex(&gen_vector(0, size - 1, size, FunctionType::OneArg(left),))
-    );
-    println!(
-        "Test vector for parent_step() with size {}:\n{}",
-        size,
-        bytes_to_hex(&gen_vector(
-            0,
-            size - 1,
-            size,
-            FunctionType::OneArg(parent_step),
-        ))
-    );
-    println!(
-        "Test vector for right() with size {}:\n{}",
-        size,
-        bytes_to_hex(&gen_vector(0, size - 1, size, FunctionType::TwoArgs(right),))
-    );
-    println!(
-        "Test vector for parent() with size {}:\n{}",
-        size,
-        bytes_to_hex(&gen_vector(
-            0,
-            size - 1,
-            size,
-            FunctionType::TwoArgs(parent),
-        ))
-    );
-    println!(
-        "Test vector for sibling() with size {}:\n{}",
-        size,
-        bytes_to_hex(&gen_vector(
-            0,
-            size - 1,
-            size,
-            FunctionType::TwoArgs(sibling),
-        ))
-    );
-    println!(
-        "Test vector for dirpath() with size {}:\n{}",
-        size,
-        bytes_to_hex(&gen_vector(
-            0,
-            size - 1,
-            size,
-            FunctionType::TwoArgsPath(dirpath),
-        ))
-    );
-    println!(
-        "Test vector for copath() with size {}:\n{}",
-        size,
-        bytes_to_hex(&gen_vector(
-            0,
-            size - 1,
-            size,
-            FunctionType::TwoArgsPath(copath),
-        ))
-    );
+fn subtree_node_counts() {
+    for n in 1..40 {
+        let r = root(n);
+        assert_eq!(subtree_node_count(r, n), node_width(n));
+        if n > 1 {
+            assert_eq!(
+                subtree_node_count(left(r), n) + subtree_node_count(right(r, n), n) + 1,
+                node_width(n)
+            );
+        }
+        for leaf in leaves(n) {
+            assert_eq!(subtree_node_count(leaf, n), 1);
+        }
+        for x in 0..node_width(n) {
+            let leaves = leaves_in_subtree(x, n).len();
+            assert_eq!(subtree_node_count(x, n), 2 * leaves - 1);
+        }
+    }
+    // Node 11 of a tree with 7 leaves only has nodes 8 to 12
+    assert_eq!(subtree_node_count(11, 7), 5);
 }
 
 #[test]
-fn verify_binary_test_vector_treemath() {
-    use codec::*;
-    use std::fs::File;
-    use std::io::Read;
-    use treemath;
-
-    let mut file = File::open("test_vectors/tree_math.bin").unwrap();
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
-
-    let mut cursor = Cursor::new(&buffer);
+fn right_on_ragged_edge() {
+    assert_eq!(rightmost_leaf(1), 0);
+    assert_eq!(rightmost_leaf(5), 8);
+
+    // The computed child is beyond the width and right() walks down to a node that exists
+    assert_eq!(right(3, 3), 4);
+    assert_eq!(right(7, 5), 8);
+    assert_eq!(right(7, 6), 9);
+    assert_eq!(right(9, 6), 10);
+    assert_eq!(right(7, 7), 11);
+    assert_eq!(right(11, 7), 12);
+
+    for n in 1..64 {
+        assert_eq!(
+            rightmost_leaf(n),
+            *leaves_in_subtree(root(n), n).last().unwrap()
+        );
+        for x in (1..node_width(n)).step_by(2) {
+            let r = right(x, n);
+            assert!(in_range(r, n));
+            assert!(r > x);
+            assert_eq!(parent(r, n), x);
+        }
+        // Following right() from the root ends at the last leaf
+        let mut x = root(n);
+        while level(x) > 0 {
+            x = right(x, n);
+        }
+        assert_eq!(x, rightmost_leaf(n));
+    }
+}
 
-    let tree_size = u32::decode(&mut cursor).unwrap() as usize;
+#[test]
+#[should_panic(expected = "tree size out of range")]
+fn node_width_overflow() {
+    node_width(usize::MAX);
+}
 
-    let root: Vec<u32> = decode_vec_u32(&mut cursor).unwrap();
-    let left: Vec<u32> = decode_vec_u32(&mut cursor).unwrap();
-    let right: Vec<u32> = decode_vec_u32(&mut cursor).unwrap();
-    let parent: Vec<u32> = decode_vec_u32(&mut cursor).unwrap();
-    let sibling: Vec<u32> = decode_vec_u32(&mut cursor).unwrap();
+#[test]
+fn pow2_boundary() {
+    let bits = usize::BITS as usize;
+    assert_eq!(log2(0), 0);
+    assert_eq!(log2(1), 0);
+    assert_eq!(checked_pow2(0), Some(1));
+    assert_eq!(checked_pow2(bits - 1), Some(1 << (bits - 1)));
+    assert_eq!(checked_pow2(bits), None);
+    // Doesn't wrap around when truncated to a shift amount
+    assert_eq!(checked_pow2(bits + 1), None);
+    assert_eq!(checked_pow2(usize::MAX), None);
+    assert_eq!(root(usize::MAX / 2 + 1), pow2(bits - 1) - 1);
+}
 
-    for i in 0..root.len() {
-        assert_eq!(root[i] as usize, treemath::root(i + 1));
-    }
-    for i in 0..left.len() {
-        assert_eq!(left[i] as usize, treemath::left(i));
+#[test]
+fn node_width_inverse() {
+    for n in 1..100 {
+        assert_eq!(node_width_to_leaf_count(node_width(n)), Some(n));
+        assert_eq!(node_width_to_leaf_count(node_width(n) + 1), None);
     }
-    for i in 0..right.len() {
-        assert_eq!(right[i] as usize, treemath::right(i, tree_size));
+    assert_eq!(node_width_to_leaf_count(0), None);
+}
+
+#[test]
+fn dirpath_len_matches_walk() {
+    for n in 1..70 {
+        for x in 0..node_width(n) {
+            assert_eq!(dirpath_len(x, n), dirpath(x, n).len(), "x={} n={}", x, n);
+        }
     }
-    for i in 0..parent.len() {
-        assert_eq!(parent[i] as usize, treemath::parent(i, tree_size));
+    assert_eq!(dirpath_len(root(5), 5), 0);
+}
+
+#[test]
+fn postorder_internal_nodes() {
+    assert_eq!(postorder_internal(1).count(), 0);
+    assert_eq!(
+        postorder_internal(3).collect::<Vec<_>>(),
+        vec![(1, 0, 2), (3, 1, 4)]
+    );
+    for n in 1..70 {
+        let width = node_width(n);
+        let mut seen = Vec::new();
+        for (x, l, r) in postorder_internal(n) {
+            assert!(level(x) > 0);
+            assert!(l < width && r < width);
+            assert_eq!((l, r), (left(x), right(x, n)));
+            // Children come first
+            for child in [l, r].iter() {
+                assert!(level(*child) == 0 || seen.contains(child));
+            }
+            seen.push(x);
+        }
+        assert_eq!(seen.len(), n - 1);
+        assert_eq!(seen.last().cloned().unwrap_or(0), root(n));
     }
-    for i in 0..sibling.len() {
-        assert_eq!(sibling[i] as usize, treemath::sibling(i, tree_size));
+}
+
+#[test]
+fn leaf_range_shards() {
+    for n in 1..70 {
+        for shard in 1..9 {
+            let mut covered = Vec::new();
+            let mut start = 0;
+            while start < n {
+                let end = (start + shard).min(n);
+                let nodes = leaf_range_nodes(start, end, n);
+                // Contiguous and disjoint
+                assert_eq!(nodes.start, covered.len());
+                for leaf in start..end {
+                    assert!(nodes.contains(&(2 * leaf)));
+                }
+                covered.extend(nodes);
+                start = end;
+            }
+            assert_eq!(covered, (0..node_width(n)).collect::<Vec<_>>());
+        }
     }
-    assert_eq!(cursor.has_more(), false);
+    assert_eq!(leaf_range_nodes(2, 2, 4), 4..4);
+}
+
+#[test]
+fn single_leaf() {
+    // The tree of a one-member group is its leaf, which is also the root
+    assert_eq!(node_width(1), 1);
+    assert_eq!(root(1), 0);
+    assert_eq!(parent(0, 1), 0);
+    assert_eq!(sibling(0, 1), 0);
+    assert!(dirpath(0, 1).is_empty());
+    assert_eq!(dirpath_len(0, 1), 0);
+    assert!(copath(0, 1).is_empty());
+    assert_eq!(leaves(1), vec![0]);
+    assert_eq!(leaves_in_subtree(0, 1), vec![0]);
+    assert_eq!(leaf_range_nodes(0, 1, 1), 0..1);
+    assert_eq!(node_width_to_leaf_count(1), Some(1));
+    assert!(!in_range(1, 1));
+
+    // Growing to two leaves puts a parent above both
+    assert_eq!(node_width(2), 3);
+    assert_eq!(root(2), 1);
+    assert_eq!(dirpath(0, 2), vec![0]);
+    assert_eq!(copath(0, 2), vec![2]);
+    assert_eq!(copath(2, 2), vec![0]);
 }
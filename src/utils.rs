@@ -14,25 +14,155 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see http://www.gnu.org/licenses/.
 
+use sodiumoxide::crypto::hash::sha512;
 use sodiumoxide::utils::memzero;
+use std::error::Error;
+use std::fmt;
 
 pub fn erase(ptr: &mut [u8]) {
     memzero(ptr);
 }
 
-pub fn bytes_to_hex(bytes: &[u8]) -> String {
-    let mut hex = String::new();
+#[derive(Debug, PartialEq)]
+pub enum HexError {
+    OddLength,
+    InvalidDigit(usize),
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HexError::OddLength => write!(f, "odd number of hex digits"),
+            HexError::InvalidDigit(position) => write!(f, "invalid hex digit at {}", position),
+        }
+    }
+}
+
+impl Error for HexError {}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+pub fn hex_encode_to(bytes: &[u8], out: &mut String) {
+    out.reserve(bytes.len() * 2);
     for b in bytes {
-        hex += &format!("{:02X}", *b);
+        out.push(HEX_DIGITS[usize::from(*b >> 4)] as char);
+        out.push(HEX_DIGITS[usize::from(*b & 0x0f)] as char);
     }
+}
+
+pub fn bytes_to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    hex_encode_to(bytes, &mut hex);
     hex
 }
 
-pub fn hex_to_bytes(hex: &str) -> Vec<u8> {
-    let mut bytes = Vec::new();
-    for i in 0..(hex.len() / 2) {
-        let b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
-        bytes.push(b);
+pub fn hex_decode(hex: &str) -> Result<Vec<u8>, HexError> {
+    let digits = hex.as_bytes();
+    if digits.len() % 2 == 1 {
+        return Err(HexError::OddLength);
+    }
+    let digit = |i: usize| match digits[i] {
+        b @ b'0'..=b'9' => Ok(b - b'0'),
+        b @ b'a'..=b'f' => Ok(b - b'a' + 10),
+        b @ b'A'..=b'F' => Ok(b - b'A' + 10),
+        _ => Err(HexError::InvalidDigit(i)),
+    };
+    let mut bytes = Vec::with_capacity(digits.len() / 2);
+    for i in (0..digits.len()).step_by(2) {
+        bytes.push(digit(i)? << 4 | digit(i + 1)?);
     }
+    Ok(bytes)
+}
+
+pub fn hex_to_bytes(hex: &str) -> Vec<u8> {
+    hex_decode(hex).unwrap()
+}
+
+// Renders the first 30 bytes as six blocks of five digits, safety number style
+pub fn bytes_to_decimal_blocks(bytes: &[u8]) -> String {
+    decimal_blocks(bytes, 6)
+}
+
+// Sixty digits in blocks of five, the same for every member of the group in the same
+// epoch. The group id is length prefixed so that the two inputs can't run into
+// each other.
+pub fn safety_number(epoch_authenticator: &[u8], group_id: &[u8]) -> String {
+    let mut input = b"melissa safety number".to_vec();
+    input.extend_from_slice(&(group_id.len() as u32).to_be_bytes());
+    input.extend_from_slice(group_id);
+    input.extend_from_slice(epoch_authenticator);
+    decimal_blocks(&sha512::hash(&input).0, 12)
+}
+
+fn decimal_blocks(bytes: &[u8], blocks: usize) -> String {
     bytes
+        .chunks(5)
+        .take(blocks)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// Byte strings as hex in serde formats, for fields tagged with serde(with = "...")
+#[cfg(feature = "serde")]
+pub mod serde_hex {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::convert::TryFrom;
+    use utils::{bytes_to_hex, hex_decode};
+
+    pub fn serialize<S: Serializer, T: AsRef<[u8]>>(
+        bytes: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&bytes_to_hex(bytes.as_ref()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: TryFrom<Vec<u8>>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        let bytes = hex_decode(&hex).map_err(D::Error::custom)?;
+        let length = bytes.len();
+        T::try_from(bytes).map_err(|_| D::Error::custom(format!("unexpected length {}", length)))
+    }
+}
+
+#[test]
+fn hex_codec() {
+    let bytes: Vec<u8> = (0..=255).collect();
+    let mut hex = String::from("0x");
+    hex_encode_to(&bytes, &mut hex);
+    assert_eq!(&hex[..8], "0x000102");
+    assert_eq!(hex_decode(&hex[2..]).unwrap(), bytes);
+    assert_eq!(hex_decode("abCD").unwrap(), vec![0xab, 0xcd]);
+    assert_eq!(hex_decode(""), Ok(Vec::new()));
+
+    assert_eq!(hex_decode("abc"), Err(HexError::OddLength));
+    assert_eq!(hex_decode("0g"), Err(HexError::InvalidDigit(1)));
+    assert_eq!(hex_decode("+1"), Err(HexError::InvalidDigit(0)));
+    assert_eq!(hex_decode("\u{e9}"), Err(HexError::InvalidDigit(0)));
+}
+
+#[test]
+fn safety_numbers() {
+    let group_id = [0x01u8; 16];
+    let number = safety_number(&[0xaa; 32], &group_id);
+    assert_eq!(number, safety_number(&[0xaa; 32], &group_id));
+    assert_eq!(number.len(), 12 * 5 + 11);
+    assert!(number
+        .split(' ')
+        .all(|block| block.len() == 5 && block.bytes().all(|b| b.is_ascii_digit())));
+
+    // Another epoch or another group gives another number
+    assert_ne!(number, safety_number(&[0xab; 32], &group_id));
+    assert_ne!(number, safety_number(&[0xaa; 32], &[0x02; 16]));
+    // Bytes can't move between the group id and the authenticator
+    assert_ne!(
+        safety_number(&[0x01, 0xaa], &[0x01; 16]),
+        safety_number(&[0xaa], &[0x01; 17])
+    );
 }
@@ -0,0 +1,123 @@
+// Wire
+// Copyright (C) 2019 Wire Swiss GmbH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see http://www.gnu.org/licenses/.
+
+// A whole conversation through the public API: members join by Welcome, talk, and
+// one of them is removed again.
+#![cfg(not(feature = "explicit-entropy"))]
+
+extern crate melissa;
+
+use melissa::codec::*;
+use melissa::error::MelissaError;
+use melissa::group::*;
+use melissa::keys::*;
+use melissa::messages::*;
+
+fn credential(name: &str, identity: &Identity) -> BasicCredential {
+    BasicCredential {
+        identity: name.as_bytes().to_vec(),
+        public_key: identity.public_key,
+    }
+}
+
+// Every member that is still in the group processes the commit
+fn process_commit(groups: &mut [Group], commit: &Commit) {
+    for group in groups.iter_mut() {
+        group.process_commit(commit).unwrap();
+    }
+}
+
+fn assert_converged(groups: &[Group]) {
+    let authenticator = groups[0].epoch_authenticator().unwrap();
+    for group in groups.iter() {
+        assert_eq!(group.epoch(), groups[0].epoch());
+        assert_eq!(group.epoch_authenticator().unwrap(), authenticator);
+        assert_eq!(
+            group.confirmed_transcript_hash(),
+            groups[0].confirmed_transcript_hash()
+        );
+        assert!(group.validate().is_ok());
+    }
+}
+
+#[test]
+fn multi_member_conversation() {
+    let alice = Identity::random();
+    let alice_credential = credential("Alice", &alice);
+    let mut groups = vec![Group::new(alice, alice_credential, GroupId::random())];
+
+    // Alice adds three members one commit at a time
+    for _ in 0..3 {
+        let identity = Identity::random();
+        let public_key = identity.public_key;
+        let bundle = UserInitKeyBundle::new(&identity);
+        let (commit, welcome) = groups[0]
+            .commit(&[Proposal::Add(bundle.init_key.clone())])
+            .unwrap();
+        process_commit(&mut groups, &commit);
+        let group = Group::from_welcome(identity, &bundle, &welcome.unwrap()).unwrap();
+        assert_eq!(group.members().last().unwrap().1.public_key, public_key);
+        groups.push(group);
+        // A joiner only gets epoch secrets with the next commit, so it sends one
+        let joiner = groups.len() - 1;
+        let (commit, _) = groups[joiner].commit(&[]).unwrap();
+        process_commit(&mut groups, &commit);
+        assert_converged(&groups);
+    }
+    assert_eq!(groups[0].members().len(), 4);
+
+    // Everybody says hello and everybody else reads it
+    for sender in 0..groups.len() {
+        let text = format!("hello from {}", sender);
+        let message = groups[sender]
+            .encrypt_application_message(text.as_bytes())
+            .unwrap();
+        let bytes = message.encode_detached();
+        for (receiver, group) in groups.iter_mut().enumerate() {
+            if receiver == sender {
+                continue;
+            }
+            let decrypted = group.decrypt_application_message_bytes(&bytes).unwrap();
+            assert_eq!(decrypted.sender, sender as u32);
+            assert_eq!(decrypted.content, text.as_bytes().to_vec());
+        }
+    }
+
+    // The third member removes the fourth, who learns that it is out
+    let (commit, _) = groups[2].commit(&[Proposal::Remove(3)]).unwrap();
+    let mut removed = groups.pop().unwrap();
+    process_commit(&mut groups, &commit);
+    match removed.process_commit(&commit) {
+        Err(MelissaError::Group(GroupError::Removed)) => (),
+        _ => panic!("removed member kept up"),
+    }
+    assert_converged(&groups);
+    assert_eq!(groups[0].members().len(), 3);
+    assert_ne!(
+        removed.epoch_authenticator().unwrap(),
+        groups[0].epoch_authenticator().unwrap()
+    );
+
+    // The others still talk, the removed member can't read along anymore
+    let message = groups[1].encrypt_application_message(b"secret").unwrap();
+    for receiver in [0, 2].iter() {
+        let decrypted = groups[*receiver]
+            .decrypt_application_message(&message)
+            .unwrap();
+        assert_eq!(decrypted.content, b"secret".to_vec());
+    }
+    assert!(removed.decrypt_application_message(&message).is_err());
+}